@@ -1,4 +1,15 @@
+use std::path::{Path, PathBuf};
+
+use crate::encoding::Encoding;
+use crate::fold::Folds;
+use crate::line_ending::LineEnding;
+use crate::lsp::Diagnostic;
 use crate::rope::HeliosRope;
+use crate::swap::{self, Swap};
+use crate::syntax::{Highlighter, Language, Token};
+use crate::syntax_tree::{self, SyntaxTree};
+use crate::undo::UndoTree;
+use crate::view::View;
 
 /// Represents a single open document.
 ///
@@ -8,8 +19,42 @@ pub struct HBuffer {
     pub text: HeliosRope,
     pub file_format: String,
     pub file_path: Option<String>,
-    pub undo_stack: Vec<HeliosRope>,
-    pub redo_stack: Vec<HeliosRope>,
+    /// Absolute path of the file, resolved when it's named, for the language server
+    pub absolute_path: Option<String>,
+    /// Line break the file is written with; the text itself always uses `\n`
+    pub line_ending: LineEnding,
+    /// Encoding the file is read and written in
+    pub encoding: Encoding,
+    /// Shown and edited as a hex dump, which needs the raw encoding's one char per byte
+    pub hex: bool,
+    pub undo: UndoTree,
+    /// View kept while the buffer isn't focused, restored when it's focused again
+    pub view: View,
+    /// Revision of the text when it was loaded or last written
+    pub saved_revision: u64,
+    /// Line break of the file when it was loaded or last written
+    pub saved_line_ending: LineEnding,
+    /// Encoding of the file when it was loaded or last written
+    pub saved_encoding: Encoding,
+    /// Opened read-only, so writing needs `!`
+    pub read_only: bool,
+    /// Swap file last written for the buffer, with the revision of the text in it
+    pub swap: Option<(PathBuf, u64)>,
+    /// Swap file another Heliolisk left for the file, until it's recovered or dropped
+    pub found_swap: Option<Swap>,
+    /// Loaded from a large file, so no swap or undo file is kept and autosave skips it
+    pub large: bool,
+    /// Empty stand-in for a file still being loaded in the background
+    pub loading: bool,
+    /// Syntax highlighting of the text, brought up to date before each draw
+    pub highlighter: Highlighter,
+    /// Syntax tree of the text, for a language with a grammar
+    pub syntax_tree: Option<SyntaxTree>,
+    pub folds: Folds,
+    /// Opened in a language server, whose diagnostics show in a gutter
+    pub language_server: bool,
+    /// Problems the language server found, sorted by where they start
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl HBuffer {
@@ -19,15 +64,149 @@ impl HBuffer {
             text: HeliosRope::new(),
             file_format: ".txt".to_string(),
             file_path: None,
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
+            absolute_path: None,
+            line_ending: LineEnding::default(),
+            encoding: Encoding::default(),
+            hex: false,
+            undo: UndoTree::default(),
+            view: View::default(),
+            saved_revision: 0,
+            saved_line_ending: LineEnding::default(),
+            saved_encoding: Encoding::default(),
+            read_only: false,
+            swap: None,
+            found_swap: None,
+            large: false,
+            loading: false,
+            highlighter: Highlighter::default(),
+            syntax_tree: None,
+            folds: Folds::default(),
+            language_server: false,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Creates an empty buffer for a file that doesn't exist yet.
+    pub fn for_new_file(file_name: &str) -> Self {
+        let mut buffer = HBuffer::new();
+        buffer.set_file_path(file_name.to_string());
+        buffer.found_swap = swap::find(file_name);
+        buffer
+    }
+
+    /// Creates the stand-in for a large file while it's loaded in the background.
+    pub fn for_loading(file_name: &str) -> Self {
+        let mut buffer = HBuffer::for_new_file(file_name);
+        buffer.found_swap = None;
+        buffer.large = true;
+        buffer.loading = true;
+        buffer
+    }
+
+    /// Names the file the buffer is written to, which gives it that file's format.
+    pub fn set_file_path(&mut self, file_path: String) {
+        self.file_format = std::path::Path::new(&file_path)
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("txt")
+            .to_string();
+        self.absolute_path = absolute_path(Path::new(&file_path));
+        self.file_path = Some(file_path);
+    }
+
+    /// Language the buffer is highlighted as, from its file format or a `#!` line.
+    ///
+    /// Hex dumps and large files aren't highlighted.
+    pub fn language(&self) -> Option<Language> {
+        if self.hex || self.large {
+            return None;
         }
+        Language::from_file_format(&self.file_format).or_else(|| {
+            let first_line: String = self
+                .text
+                .chars_at(0)
+                .take_while(|&c| c != '\n')
+                .take(80)
+                .collect();
+            Language::from_shebang(&first_line)
+        })
+    }
+
+    /// Brings the highlighting, the syntax tree and the folds up to date with the
+    /// text, highlighting through `last_line`.
+    pub fn update_syntax(&mut self, last_line: usize) {
+        let changed_line = self
+            .text
+            .take_changed_from()
+            .map(|char_idx| self.text.char_to_line(char_idx.min(self.text.len_chars())));
+        self.folds.follow_edit(changed_line, self.text.len_lines());
+        if self.parse_tree().is_some() {
+            return;
+        }
+        let language = self.language();
+        self.highlighter
+            .update(language, &self.text, changed_line, last_line);
+    }
+
+    /// The syntax tree of the text, parsed again from the parts edited since it was
+    /// last parsed. None for a language without a grammar.
+    pub fn parse_tree(&mut self) -> Option<&SyntaxTree> {
+        let Some(language) = self.language().filter(|&l| syntax_tree::has_grammar(l)) else {
+            self.syntax_tree = None;
+            return None;
+        };
+        let edits = self.text.take_tree_edits();
+        let old = self
+            .syntax_tree
+            .take()
+            .filter(|tree| tree.language() == language);
+        self.syntax_tree = match (old, edits) {
+            (Some(tree), Some(edits)) if edits.is_empty() => Some(tree),
+            (Some(tree), Some(edits)) => tree.reparse(&self.text, &edits),
+            _ => SyntaxTree::new(language, &self.text),
+        };
+        self.syntax_tree.as_ref()
+    }
+
+    /// Syntax highlighting of line `line_idx`, whose text without its line break is `line`.
+    pub fn syntax_tokens(&self, line_idx: usize, line: &str) -> Vec<Token> {
+        match &self.syntax_tree {
+            Some(tree) => tree.tokens(&self.text, line_idx, line),
+            None => self.highlighter.tokens(line, line_idx),
+        }
+    }
+
+    /// Columns left of the text for the signs of diagnostics.
+    pub fn gutter_width(&self) -> usize {
+        if self.language_server { 2 } else { 0 }
+    }
+
+    /// The most severe diagnostic touching line `line_idx`.
+    pub fn diagnostic_on_line(&self, line_idx: usize) -> Option<&Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.start.0 <= line_idx && line_idx <= diagnostic.end.0)
+            .min_by_key(|diagnostic| diagnostic.severity)
+    }
+
+    /// Name shown in the title and in `:ls`.
+    pub fn display_name(&self) -> &str {
+        self.file_path.as_deref().unwrap_or("[No Name]")
     }
 
     pub fn line_length(&self, line_idx: usize) -> usize {
         self.text.line_len(line_idx)
     }
 
+    /// Length of a line in characters, excluding its line break.
+    pub fn line_content_length(&self, line_idx: usize) -> usize {
+        self.text
+            .line(line_idx)
+            .trim_end_matches(['\n', '\r'])
+            .chars()
+            .count()
+    }
+
     pub fn line_count(&self) -> usize {
         self.text.len_lines()
     }
@@ -36,8 +215,19 @@ impl HBuffer {
         self.text.len_chars()
     }
 
+    /// True when the text or its fileformat has changed since it was loaded or last written.
     pub fn has_unsaved_changes(&self) -> bool {
-        todo!("implement unsaved changes check")
+        self.text.revision() != self.saved_revision
+            || self.line_ending != self.saved_line_ending
+            || self.encoding != self.saved_encoding
+    }
+
+    /// Notes that the file holds the text at `revision`, broken with `line_ending`
+    /// and written in `encoding`.
+    pub fn mark_saved(&mut self, revision: u64, line_ending: LineEnding, encoding: Encoding) {
+        self.saved_revision = revision;
+        self.saved_line_ending = line_ending;
+        self.saved_encoding = encoding;
     }
 
     pub fn insert_char(&mut self, line_idx: usize, col_idx: usize, c: char) {
@@ -63,6 +253,21 @@ impl HBuffer {
         self.text.remove(start_char..end_char);
     }
 
+    /// Deletes the lines `first..=last`, returning them with a trailing line break.
+    pub fn delete_lines(&mut self, first: usize, last: usize) -> String {
+        let mut start = self.text.line_to_char(first);
+        let end = self.text.line_to_char(last + 1);
+        let mut removed = self.text.slice_to_string(start..end);
+
+        // The last line has no line break of its own, so take the one before it
+        if !removed.ends_with('\n') {
+            start = start.saturating_sub(1);
+            removed.push('\n');
+        }
+        self.text.remove(start..end);
+        removed
+    }
+
     pub fn delete_char(&mut self, line_idx: usize, col_idx: usize) {
         let line_start_char = self.text.line_to_char(line_idx);
         let char_idx = line_start_char + col_idx;
@@ -73,28 +278,68 @@ impl HBuffer {
         }
     }
 
-    pub fn quit(&self) {
-        if self.has_unsaved_changes() {
-            println!("Couldn't exit! File has unsaved changes!");
-        }
+    /// Converts a line/col pair into an absolute char index.
+    pub fn char_index(&self, line_idx: usize, col_idx: usize) -> usize {
+        (self.text.line_to_char(line_idx) + col_idx).min(self.text.len_chars())
     }
 
-    pub fn save_snapshot(&mut self) {
-        self.undo_stack.push(self.text.clone());
-        self.redo_stack.clear();
+    /// Converts an absolute char index back into a line/col pair.
+    pub fn line_col(&self, char_idx: usize) -> (usize, usize) {
+        let char_idx = char_idx.min(self.text.len_chars());
+        let line_idx = self.text.char_to_line(char_idx);
+        (line_idx, char_idx - self.text.line_to_char(line_idx))
     }
 
-    pub fn undo(&mut self) {
-        if let Some(prev_text) = self.undo_stack.pop() {
-            self.redo_stack.push(self.text.clone());
-            self.text = prev_text;
-        }
+    pub fn insert_text(&mut self, char_idx: usize, text: &str) {
+        self.text.insert(char_idx, text);
     }
 
-    pub fn redo(&mut self) {
-        if let Some(next_text) = self.redo_stack.pop() {
-            self.undo_stack.push(self.text.clone());
-            self.text = next_text;
+    pub fn delete_range(&mut self, char_range: std::ops::Range<usize>) -> String {
+        let removed = self.text.slice_to_string(char_range.clone());
+        let end = char_range.end.min(self.text.len_chars());
+        self.text.remove(char_range.start.min(end)..end);
+        removed
+    }
+
+    /// Replaces the chars in `char_range` with `text`.
+    pub fn replace_range(&mut self, char_range: std::ops::Range<usize>, text: &str) {
+        self.delete_range(char_range.clone());
+        self.insert_text(char_range.start, text);
+    }
+
+    /// Ends the undo step being recorded, so the edits after it are undone separately.
+    pub fn commit_undo_step(&mut self) {
+        if let Some((edits, time)) = self.text.take_changes() {
+            let revision = self.text.revision();
+            self.undo.commit(edits, time, revision);
         }
     }
+
+    /// Returns the char index the undone change started at, or None if there's
+    /// nothing to undo.
+    pub fn undo(&mut self) -> Option<usize> {
+        self.commit_undo_step();
+        self.undo.undo(&mut self.text)
+    }
+
+    /// Returns the char index the redone change started at, or None if there's
+    /// nothing to redo.
+    pub fn redo(&mut self) -> Option<usize> {
+        self.commit_undo_step();
+        self.undo.redo(&mut self.text)
+    }
+
+    /// Moves to the undo state numbered `seq`, on whichever branch it is.
+    pub fn undo_to(&mut self, seq: usize) -> Option<usize> {
+        self.commit_undo_step();
+        self.undo.go_to(seq, &mut self.text)
+    }
+}
+
+/// `path` made absolute, with its links resolved when the file exists.
+pub fn absolute_path(path: &Path) -> Option<String> {
+    let absolute = std::fs::canonicalize(path)
+        .or_else(|_| std::env::current_dir().map(|dir| dir.join(path)))
+        .ok()?;
+    absolute.to_str().map(String::from)
 }
@@ -1,8 +1,35 @@
 use crate::buffer::HBuffer;
+use crate::encoding::{self, Encoding};
+use crate::ex::{self, CommandKind, CompletionKind, ExCommand, RangeContext};
+use crate::file_ops::{self, LoadEvent};
+use crate::grammar::{
+    self, FoldCommand, LspCommand, Motion, NormalCommand, Operator, ParseResult, SelectCommand,
+    Target,
+};
+use crate::hex::{self, HexCursor};
+use crate::history::History;
+use crate::keyword;
+use crate::line_ending::LineEnding;
+use crate::lsp::{self, Location, Lsp, LspEvent, TextEdit};
+use crate::menu::{CompletionMenu, MenuItem};
+use crate::options::{OPTIONS, Options};
+use crate::register::{Register, Registers};
+use crate::search::{Search, SearchState};
+use crate::selection::{Selection, SelectionKind};
+use crate::substitute::{self, Substitute};
+use crate::swap::{self, Swap, SwapJob};
+use crate::syntax::Token;
+use crate::text_object::{TextObject, TextObjectKind};
+use crate::undo::{self, UndoDistance};
+use crate::view::View;
+use crate::window::{Side, SplitDirection, Window, WindowLayout};
 use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, TryRecvError};
 use std::time::Instant;
 
-use crossterm::event::{KeyCode, KeyEvent};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::layout::Rect;
 
 use crossterm::event::KeyCode::Char;
 
@@ -22,6 +49,8 @@ pub struct EditMode;
 /// # Allows:
 /// - Selecting tokens in the buffers
 /// - Tokens include words, sentences and lines
+/// - Charwise (`v`), Linewise (`V`) and Blockwise (`Ctrl-v`) selections
+/// - Running operators over the selection
 pub struct SelectMode;
 /// Command Mode:
 /// # Allows:
@@ -34,21 +63,86 @@ pub struct CommandMode;
 /// - Multiple Buffers and their Focus
 /// - The Cursor
 /// - The Command Line
+/// - Registers shared by all Buffers
 /// - State of the Editor Independent from their Buffers
 pub struct Editor<State = NavigateMode> {
     buffers: Vec<HBuffer>,
     current_focused_index: usize,
-    cursor_col: usize,
-    cursor_line: usize,
-    scroll_offset: usize,
+    /// View of the focused window; the others keep theirs until focused again
+    view: View,
+    /// Window layout of each tab page
+    tabs: Vec<WindowLayout>,
+    current_tab: usize,
+    /// Area the windows were last laid out in
+    screen_area: Rect,
+    /// `Ctrl-w` was pressed and the window command key is expected
+    window_prefix: bool,
+    /// Swap files of closed buffers, removed with the next swap update
+    dropped_swaps: Vec<PathBuf>,
+    /// Large files being loaded in the background
+    loads: Vec<FileLoad>,
     is_quittable: bool,
     command_line: String,
+    /// Byte offset of the cursor in the command line
+    command_cursor: usize,
+    command_prompt: char,
+    command_history: History,
+    search_history: History,
+    completion: Option<Completion>,
     error_line: String,
     error_timestamp: Option<Instant>,
     input_seq: String,
+    registers: Registers,
+    select_anchor: (usize, usize),
+    select_kind: SelectionKind,
+    block_insert: Option<BlockInsert>,
+    /// Where typing goes in a hex buffer
+    hex_cursor: HexCursor,
+    search: SearchState,
+    substitution: Option<PendingSubstitution>,
+    options: Options,
+    /// Language servers, kept in step with the buffers
+    lsp: Lsp,
+    /// Completions of the word being typed in Edit Mode
+    completion_menu: Option<CompletionMenu>,
     state: PhantomData<State>,
 }
 
+/// Candidates Tab cycles through on the command line.
+struct Completion {
+    candidates: Vec<String>,
+    index: usize,
+    /// Byte offset where the completed word starts
+    start: usize,
+}
+
+/// A `:s` with the `c` flag, waiting for an answer at each match.
+struct PendingSubstitution {
+    substitute: Substitute,
+    line: usize,
+    last_line: usize,
+    /// Byte offset in the line where the next match is searched from
+    from: usize,
+    substitutions: usize,
+    changed_lines: usize,
+    /// Line of the last replacement, to count each changed line once
+    last_changed: Option<usize>,
+}
+
+/// A large file loading in the background into the buffer of the same name.
+struct FileLoad {
+    file_name: String,
+    events: Receiver<LoadEvent>,
+}
+
+/// Text typed after a blockwise change is repeated on the other lines of the block.
+#[derive(Clone, Copy)]
+struct BlockInsert {
+    line: usize,
+    last_line: usize,
+    col: usize,
+}
+
 pub enum EditorAction {
     Quit,
     Save(Option<String>),
@@ -57,6 +151,8 @@ pub enum EditorAction {
     EnterCommandMode,
     EnterEditMode,
     EnterEditModeInNewLine,
+    /// Enter Edit Mode after an operator already started the undo step
+    EnterEditModeAfterChange,
     EnterSelectMode,
     EnterNavigateMode,
     DebugPrintLinesToConsole,
@@ -72,42 +168,47 @@ impl Editor {
             buffers,
             current_focused_index: 0,
             is_quittable: true,
-            cursor_line: 0,
-            cursor_col: 0,
-            scroll_offset: 0,
+            view: View::default(),
+            tabs: vec![WindowLayout::new(0)],
+            current_tab: 0,
+            screen_area: Rect::default(),
+            window_prefix: false,
+            dropped_swaps: Vec::new(),
+            loads: Vec::new(),
             command_line: String::new(),
+            command_cursor: 0,
+            command_prompt: ':',
+            command_history: History::default(),
+            search_history: History::default(),
+            completion: None,
             error_line: String::new(),
             error_timestamp: None,
             input_seq: String::new(),
+            registers: Registers::default(),
+            select_anchor: (0, 0),
+            select_kind: SelectionKind::Charwise,
+            block_insert: None,
+            hex_cursor: HexCursor::default(),
+            search: SearchState::default(),
+            substitution: None,
+            options: Options::default(),
+            lsp: Lsp::default(),
+            completion_menu: None,
             state: PhantomData::<NavigateMode>,
         }
     }
 
-    pub fn add_new_buffer(&mut self) {
-        dbg!("Adding new Buffer");
-        let new_buf = HBuffer::new();
-
-        self.buffers.push(new_buf);
-        self.current_focused_index = self.buffers.len() - 1;
-    }
-
-    pub fn buffer_switch_forward(&mut self) {
-        if self.buffers.len() >= 2 {
-            if self.current_focused_index + 1 == self.buffers.len() {
-                self.current_focused_index = 0;
-            } else {
-                self.current_focused_index += 1;
-            }
+    /// Loads the command and search histories saved by earlier sessions.
+    pub fn load_histories(&mut self) {
+        if let Some(dir) = file_ops::data_dir() {
+            self.command_history = History::load(dir.join("command_history"));
+            self.search_history = History::load(dir.join("search_history"));
         }
     }
 
-    pub fn buffer_switch_backward(&mut self) {
-        if self.buffers.len() < 2 {
-        } else if self.current_focused_index == 0 {
-            self.current_focused_index = self.buffers.len();
-        } else {
-            self.current_focused_index -= 1;
-        }
+    /// Reads which language servers to run from the config file.
+    pub fn load_language_servers(&mut self) {
+        self.lsp.load_config();
     }
 }
 
@@ -117,662 +218,4093 @@ impl<S> Editor<S> {
             buffers: self.buffers,
             current_focused_index: self.current_focused_index,
             is_quittable: self.is_quittable,
-            cursor_col: self.cursor_col,
-            cursor_line: self.cursor_line,
-            scroll_offset: self.scroll_offset,
+            view: self.view,
+            tabs: self.tabs,
+            current_tab: self.current_tab,
+            screen_area: self.screen_area,
+            window_prefix: self.window_prefix,
+            dropped_swaps: self.dropped_swaps,
+            loads: self.loads,
             command_line: self.command_line,
+            command_cursor: self.command_cursor,
+            command_prompt: self.command_prompt,
+            command_history: self.command_history,
+            search_history: self.search_history,
+            completion: self.completion,
             error_line: self.error_line,
             error_timestamp: self.error_timestamp,
             input_seq: self.input_seq,
+            registers: self.registers,
+            select_anchor: self.select_anchor,
+            select_kind: self.select_kind,
+            block_insert: self.block_insert,
+            hex_cursor: self.hex_cursor,
+            search: self.search,
+            substitution: self.substitution,
+            options: self.options,
+            lsp: self.lsp,
+            completion_menu: self.completion_menu,
             state: PhantomData,
         }
     }
 
-    pub fn update_viewport(&mut self, height: usize) {
-        if self.cursor_line < self.scroll_offset {
-            self.scroll_offset = self.cursor_line;
-        } else if self.cursor_line >= self.scroll_offset + height {
-            self.scroll_offset = self.cursor_line - height + 1;
-        }
-    }
+    pub fn add_new_buffer(&mut self) {
+        dbg!("Adding new Buffer");
+        let new_buf = HBuffer::new();
 
-    pub fn get_scroll_offset(&self) -> usize {
-        self.scroll_offset
+        self.buffers.push(new_buf);
+        self.focus_buffer(self.buffers.len() - 1);
     }
 
-    pub fn move_cursor_left(&mut self) {
-        if self.cursor_col > 0 {
-            self.cursor_col -= 1;
-        }
+    pub fn buffer_switch_forward(&mut self) {
+        let next = (self.current_focused_index + 1) % self.buffers.len();
+        self.focus_buffer(next);
     }
 
-    pub fn move_cursor_right(&mut self) {
-        let buffer = &self.buffers[self.current_focused_index];
-        let line_len = buffer.line_length(self.cursor_line);
-        if self.cursor_col < line_len {
-            self.cursor_col += 1;
-        }
+    pub fn buffer_switch_backward(&mut self) {
+        let count = self.buffers.len();
+        self.focus_buffer((self.current_focused_index + count - 1) % count);
     }
 
-    fn move_cursor_up(&mut self) {
-        // todo!("Panics for some reason. Fix this!");
-        if self.cursor_line > 0 {
-            self.cursor_line -= 1;
-            self.clamp_cursor_col();
+    /// Shows the buffer at `index` in the focused window, leaving a copy of the
+    /// current view with the buffer it showed.
+    pub fn focus_buffer(&mut self, index: usize) {
+        if index == self.current_focused_index || index >= self.buffers.len() {
+            return;
         }
-    }
+        let view = self.view.clone();
+        self.get_active_buffer_mut().view = view;
 
-    fn move_cursor_start(&mut self) {
-        if self.cursor_col > 0 {
-            self.cursor_col = 0;
-        }
+        self.current_focused_index = index;
+        self.windows_mut().focused_window_mut().buffer = index;
+        self.restore_buffer_view();
     }
 
-    fn move_cursor_down(&mut self) {
-        // todo!("Panics for some reason. Fix this!");
-        let buffer = &self.buffers[self.current_focused_index];
-        if self.cursor_line < buffer.line_count() - 1 {
-            self.cursor_line += 1;
-            self.clamp_cursor_col();
-        }
+    /// Goes back to the view the active buffer had when it was last shown.
+    fn restore_buffer_view(&mut self) {
+        self.view = self.get_active_buffer().view.clone();
+        self.clamp_view();
     }
 
-    fn clamp_cursor_col(&mut self) {
-        let buffer = &self.buffers[self.current_focused_index];
-        let line_len = buffer.line_length(self.cursor_line);
-        if self.cursor_col > line_len {
-            self.cursor_col = line_len;
+    /// Keeps the cursor inside the active buffer, which may have changed in another window.
+    fn clamp_view(&mut self) {
+        if self.get_active_buffer().hex {
+            self.set_hex_offset(self.hex_offset(), false);
+            self.view.scroll_offset = self.view.scroll_offset.min(self.view.cursor_line);
+            return;
         }
+        let line_count = self.get_active_buffer().line_count();
+        self.view.clamp_to(line_count);
+        self.clamp_cursor_col();
     }
 
-    pub fn get_command_line(&self) -> String {
-        self.command_line.clone()
+    /// Offset of the byte under the cursor in a hex buffer, whose rows are the view's lines.
+    pub fn hex_offset(&self) -> usize {
+        self.view.cursor_line * hex::BYTES_PER_ROW + self.view.cursor_col
     }
 
-    pub fn get_error_line(&self) -> String {
-        self.error_line.clone()
+    /// Moves the cursor of a hex buffer to the byte at `offset`, or to the last byte.
+    /// With `past_end`, it may stop just after the last byte, where typing appends.
+    fn set_hex_offset(&mut self, offset: usize, past_end: bool) {
+        let len = self.get_active_buffer().char_count();
+        let last = if past_end { len } else { len.saturating_sub(1) };
+        let offset = offset.min(last);
+        self.view.cursor_line = offset / hex::BYTES_PER_ROW;
+        self.view.cursor_col = offset % hex::BYTES_PER_ROW;
     }
 
-    pub fn get_buffers(&self) -> &Vec<HBuffer> {
-        &self.buffers
+    /// Where typing goes in a hex buffer, for placing the cursor.
+    pub fn get_hex_cursor(&self) -> HexCursor {
+        self.hex_cursor
     }
 
-    pub fn get_active_buffer(&self) -> &HBuffer {
-        &self.buffers[self.current_focused_index]
-    }
+    /// Switches the active buffer between its hex dump and its text, keeping the
+    /// cursor on the same byte.
+    pub fn toggle_hex_view(&mut self) -> Result<(), String> {
+        let buffer = self.get_active_buffer();
+        if buffer.encoding != Encoding::Raw {
+            return Err("Not a binary buffer (read it as bytes with :e ++enc=raw)".to_string());
+        }
 
-    pub fn get_active_buffer_mut(&mut self) -> &mut HBuffer {
-        &mut self.buffers[self.current_focused_index]
+        if buffer.hex {
+            let offset = self.hex_offset();
+            self.get_active_buffer_mut().hex = false;
+            (self.view.cursor_line, self.view.cursor_col) =
+                self.get_active_buffer().line_col(offset);
+            self.clamp_cursor_col();
+        } else {
+            let offset = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+            self.get_active_buffer_mut().hex = true;
+            self.set_hex_offset(offset, false);
+        }
+        self.view.scroll_offset = self.view.scroll_offset.min(self.view.cursor_line);
+        Ok(())
     }
 
-    pub fn set_error_line(&mut self, error: String) {
-        self.error_line = error;
-        self.error_timestamp = Some(Instant::now());
+    /// Closes the focused window, or quits from the last one.
+    ///
+    /// Buffers stay open when their windows close, so unsaved changes only stop
+    /// the quit from the last window, unless `force` is set.
+    pub fn quit_window(&mut self, force: bool) -> Result<EditorAction, String> {
+        if self.windows().len() > 1 || self.tabs.len() > 1 {
+            self.close_window()?;
+            return Ok(EditorAction::EnterNavigateMode);
+        }
+        if !force {
+            self.check_unsaved_buffers()?;
+        }
+        Ok(EditorAction::Quit)
     }
 
-    pub fn check_error_expiry(&mut self) {
-        if let Some(time) = self.error_timestamp
-            && time.elapsed() >= std::time::Duration::from_secs(10)
+    /// Fails if a buffer has unsaved changes, checking the active one first.
+    pub fn check_unsaved_buffers(&self) -> Result<(), String> {
+        if self.get_active_buffer().has_unsaved_changes() {
+            return Err("No write since last change (add ! to override)".to_string());
+        }
+        match self
+            .buffers
+            .iter()
+            .find(|buffer| buffer.has_unsaved_changes())
         {
-            self.error_line.clear();
-            self.error_timestamp = None;
+            Some(buffer) => Err(format!(
+                "No write since last change for buffer \"{}\" (add ! to override)",
+                buffer.display_name()
+            )),
+            None => Ok(()),
         }
     }
 
-    pub fn get_cursor_position(&self) -> (usize, usize) {
-        (self.cursor_col, self.cursor_line)
+    /// Writes every buffer with unsaved changes to its file. Returns how many were written.
+    ///
+    /// Read-only buffers are only written when `force` is set.
+    pub fn write_all_buffers(&mut self, force: bool) -> Result<usize, String> {
+        let mut written = 0;
+        for (i, buffer) in self.buffers.iter_mut().enumerate() {
+            if !buffer.has_unsaved_changes() {
+                continue;
+            }
+            if buffer.read_only && !force {
+                return Err(format!(
+                    "'readonly' option is set for buffer {} (add ! to override)",
+                    i + 1
+                ));
+            }
+            let file_name = buffer
+                .file_path
+                .clone()
+                .ok_or_else(|| format!("No file name for buffer {}", i + 1))?;
+            buffer.commit_undo_step();
+            file_ops::write_buffer_to_file(buffer, Some(file_name))?;
+            buffer.mark_saved(buffer.text.revision(), buffer.line_ending, buffer.encoding);
+            written += 1;
+        }
+        Ok(written)
     }
 
-    pub fn undo(&mut self) {
-        let buffer = &mut self.buffers[self.current_focused_index];
-        buffer.undo();
+    /// How long typing must stop for before an autosave, None when `autosave` is off.
+    pub fn autosave_delay(&self) -> Option<std::time::Duration> {
+        self.options
+            .autosave
+            .then(|| std::time::Duration::from_millis(self.options.autosavetime))
     }
 
-    pub fn redo(&mut self) {
-        let buffer = &mut self.buffers[self.current_focused_index];
-        buffer.redo();
+    /// Copies of the buffers an autosave writes, with their file names: those with
+    /// unsaved changes and a name, unless they're read-only.
+    pub fn autosave_buffers(&self) -> Vec<(String, HBuffer)> {
+        self.buffers
+            .iter()
+            .filter(|buffer| {
+                buffer.has_unsaved_changes()
+                    && !buffer.read_only
+                    && !buffer.large
+                    && buffer.found_swap.is_none()
+            })
+            .filter_map(|buffer| Some((buffer.file_path.clone()?, buffer.clone())))
+            .collect()
     }
 
-    pub fn delete_to_next_whitespace(&mut self) {
-        let buffer = &mut self.buffers[self.current_focused_index];
+    /// Marks the buffer of `file_name` as saved at `revision` with `line_ending` and
+    /// `encoding`, once a background write finishes.
+    pub fn mark_saved(
+        &mut self,
+        file_name: &str,
+        revision: u64,
+        line_ending: LineEnding,
+        encoding: Encoding,
+    ) {
+        let Some(index) = self.buffers.iter().position(|buffer| {
+            buffer
+                .file_path
+                .as_deref()
+                .is_some_and(|path| file_ops::is_same_file(path, file_name))
+        }) else {
+            return;
+        };
+        self.buffers[index].mark_saved(revision, line_ending, encoding);
+        // The server hears of the edits made before the write first
+        self.lsp.sync(&mut self.buffers);
+        self.lsp.did_save(&self.buffers[index]);
+    }
+
+    /// Windows of the current tab page.
+    fn windows(&self) -> &WindowLayout {
+        &self.tabs[self.current_tab]
+    }
 
-        // Snapshot before modification
-        buffer.save_snapshot();
+    fn windows_mut(&mut self) -> &mut WindowLayout {
+        &mut self.tabs[self.current_tab]
+    }
 
-        let line_len = buffer.line_length(self.cursor_line);
-        if self.cursor_col >= line_len {
+    /// Moves the focus to another window.
+    pub fn focus_window(&mut self, window: usize) {
+        if window == self.windows().focused() || window >= self.windows().len() {
             return;
         }
+        let view = std::mem::take(&mut self.view);
+        self.windows_mut().focused_window_mut().view = view;
+        self.windows_mut().set_focused(window);
+        self.enter_focused_window();
+    }
 
-        let line_text = buffer.text.line(self.cursor_line);
-        // line_text includes chars
-        // We need to look ahead from cursor_col
+    /// Takes over the view and buffer of the window that just got the focus.
+    fn enter_focused_window(&mut self) {
+        let window = self.windows_mut().focused_window_mut();
+        let (buffer, view) = (window.buffer, std::mem::take(&mut window.view));
+        self.current_focused_index = buffer;
+        self.view = view;
+        self.clamp_view();
+    }
 
-        // Since line_text is a Cow<str>, and we want to iterate chars.
-        // It's easier to just work with char indices if possible or converting to string/vec.
-        // Accessing via chars iterator is O(N).
+    /// Splits the focused window, showing the same buffer in the new window above or left of it.
+    pub fn split_window(&mut self, direction: SplitDirection) {
+        let window = Window {
+            buffer: self.current_focused_index,
+            view: self.view.clone(),
+        };
+        let new = self.windows_mut().split(direction, window);
+        self.focus_window(new);
+    }
 
-        let chars: Vec<char> = line_text.chars().collect();
-        if self.cursor_col >= chars.len() {
-            return; // Should be covered by line_len check but just in case
+    /// Closes the focused window. The buffer it showed stays open.
+    ///
+    /// Closing the last window of a tab page closes the tab page.
+    pub fn close_window(&mut self) -> Result<(), String> {
+        if self.windows().len() == 1 {
+            if self.tabs.len() == 1 {
+                return Err("Cannot close last window".to_string());
+            }
+            return self.close_tab();
         }
+        let view = self.view.clone();
+        self.get_active_buffer_mut().view = view;
 
-        let mut delete_count = 0;
-        let started_on_whitespace = chars[self.cursor_col].is_whitespace();
-
-        for ch in chars.iter().skip(self.cursor_col) {
-            let c = ch;
+        let focused = self.windows().focused();
+        self.windows_mut().close(focused);
+        self.enter_focused_window();
+        Ok(())
+    }
 
-            if (started_on_whitespace && !c.is_whitespace()) || c.is_whitespace() {
-                break;
+    /// Opens a tab page with a single window, showing `file_name` or a new empty buffer.
+    pub fn new_tab(&mut self, file_name: Option<&str>) -> Result<(), String> {
+        let buffer = match file_name {
+            Some(file_name) => self.open_buffer(file_name)?,
+            None => {
+                self.buffers.push(HBuffer::new());
+                self.buffers.len() - 1
             }
-            delete_count += 1;
-        }
+        };
 
-        // Perform deletion
-        for _ in 0..delete_count {
-            // We always delete at current cursor_col, shrinking the line
-            buffer.delete_char(self.cursor_line, self.cursor_col);
-        }
+        let mut tab = WindowLayout::new(buffer);
+        tab.focused_window_mut().view = self.buffers[buffer].view.clone();
+        self.tabs.insert(self.current_tab + 1, tab);
+        self.focus_tab(self.current_tab + 1);
+        Ok(())
     }
 
-    pub fn move_word_forward(&mut self) {
-        let buffer = &self.buffers[self.current_focused_index];
+    /// Moves to the tab page at `index`, where the window focused last gets the focus again.
+    pub fn focus_tab(&mut self, index: usize) {
+        if index == self.current_tab || index >= self.tabs.len() {
+            return;
+        }
+        let view = std::mem::take(&mut self.view);
+        self.windows_mut().focused_window_mut().view = view;
+        self.current_tab = index;
+        self.enter_focused_window();
+    }
 
-        loop {
-            let line_len = buffer.line_length(self.cursor_line);
+    /// Moves `count` tab pages forward or backward, wrapping around.
+    pub fn cycle_tabs(&mut self, count: usize, backward: bool) {
+        let len = self.tabs.len();
+        let steps = count % len;
+        let index = if backward {
+            (self.current_tab + len - steps) % len
+        } else {
+            (self.current_tab + steps) % len
+        };
+        self.focus_tab(index);
+    }
 
-            // If strictly inside the line
-            if self.cursor_col < line_len {
-                let line_text = buffer.text.line(self.cursor_line);
-                let chars: Vec<char> = line_text.chars().collect();
+    /// Closes the current tab page and its windows. Their buffers stay open.
+    pub fn close_tab(&mut self) -> Result<(), String> {
+        if self.tabs.len() == 1 {
+            return Err("Cannot close last tab page".to_string());
+        }
+        let view = self.view.clone();
+        self.get_active_buffer_mut().view = view;
 
-                // Check if current is whitespace (or special) to know if we crossed a boundary
-                // Simple logic for now: skip current word (non-whitespace), then skip whitespace.
+        self.tabs.remove(self.current_tab);
+        self.current_tab = self.current_tab.min(self.tabs.len() - 1);
+        self.enter_focused_window();
+        Ok(())
+    }
 
-                // Better logic:
-                // 1. If on word char, skip until whitespace.
-                // 2. Skip whitespace until word char.
-                // But vim 'w' is "start of next word".
+    /// A label for each tab page: the name of the buffer in its focused window,
+    /// after the number of windows when there are several and `+` when a buffer
+    /// shown in the tab page has unsaved changes. The current tab page comes with true.
+    pub fn get_tabs(&self) -> Vec<(String, bool)> {
+        self.tabs
+            .iter()
+            .enumerate()
+            .map(|(i, tab)| {
+                let buffer = &self.buffers[tab.window(tab.focused()).buffer];
+                let modified = (0..tab.len())
+                    .any(|w| self.buffers[tab.window(w).buffer].has_unsaved_changes());
+                let mut label = String::new();
+                if tab.len() > 1 {
+                    label.push_str(&tab.len().to_string());
+                }
+                if modified {
+                    label.push('+');
+                }
+                if !label.is_empty() {
+                    label.push(' ');
+                }
+                label.push_str(buffer.display_name());
+                (label, i == self.current_tab)
+            })
+            .collect()
+    }
 
-                // Let's implement simple step-by-step advance.
-                // Note: This is an O(N) naive implementation in a loop.
+    /// Changes the size of the focused window along `direction`, see [`WindowLayout::resize`].
+    pub fn resize_window(&mut self, direction: SplitDirection, size: &dyn Fn(u16) -> u16) {
+        let area = self.screen_area;
+        self.windows_mut().resize(area, direction, size);
+    }
 
-                let c = chars.get(self.cursor_col).unwrap_or(&'\n');
+    /// Area of the focused window, as last laid out.
+    pub fn get_focused_window_area(&self) -> Rect {
+        self.windows()
+            .areas(self.screen_area)
+            .into_iter()
+            .find(|(window, _)| *window == self.windows().focused())
+            .map_or(self.screen_area, |(_, area)| area)
+    }
 
-                if c.is_whitespace() {
-                    // If we are on whitespace, we are looking for non-whitespace
-                    self.cursor_col += 1;
-                    if self.cursor_col < chars.len() && !chars[self.cursor_col].is_whitespace() {
-                        // Found start of next word
-                        break;
-                    }
+    /// Every window with its area, the buffer it shows and its view,
+    /// in the order they are laid out. The focused window comes with the live view.
+    pub fn get_windows(&self) -> Vec<(Rect, usize, &View, bool)> {
+        self.windows()
+            .areas(self.screen_area)
+            .into_iter()
+            .map(|(window, area)| {
+                if window == self.windows().focused() {
+                    (area, self.current_focused_index, &self.view, true)
                 } else {
-                    // We are on a word, move until whitespace or end
-                    self.cursor_col += 1;
-                    // But we might hit whitespace immediately.
-                    // If we hit whitespace, we continue loop to next iteration which handles whitespace.
+                    let window = self.windows().window(window);
+                    (area, window.buffer, &window.view, false)
                 }
-            } else {
-                // End of line, move to next line
-                if self.cursor_line < buffer.line_count() - 1 {
-                    self.cursor_line += 1;
-                    self.cursor_col = 0;
-                    // Check if 0 is a word char
-                    let line_text = buffer.text.line(self.cursor_line);
-                    if let Some(c) = line_text.chars().next()
-                        && !c.is_whitespace()
-                    {
-                        break;
-                    }
-                } else {
-                    break; // End of file
+            })
+            .collect()
+    }
+
+    /// Runs the window command typed after `Ctrl-w`.
+    fn window_command(&mut self, key: KeyEvent) -> EditorAction {
+        let c = match key.code {
+            Char(c) => c,
+            KeyCode::Left => 'h',
+            KeyCode::Down => 'j',
+            KeyCode::Up => 'k',
+            KeyCode::Right => 'l',
+            _ => return EditorAction::None,
+        };
+
+        let order = self.windows().order();
+        let position = order
+            .iter()
+            .position(|w| *w == self.windows().focused())
+            .unwrap_or(0);
+        match c {
+            's' | 'S' => self.split_window(SplitDirection::Horizontal),
+            'v' => self.split_window(SplitDirection::Vertical),
+            'w' => self.focus_window(order[(position + 1) % order.len()]),
+            'W' => self.focus_window(order[(position + order.len() - 1) % order.len()]),
+            'h' | 'j' | 'k' | 'l' => {
+                let side = match c {
+                    'h' => Side::Left,
+                    'j' => Side::Below,
+                    'k' => Side::Above,
+                    _ => Side::Right,
+                };
+                let area = self.get_focused_window_area();
+                let cursor = (
+                    area.x + 1 + self.view.cursor_col as u16,
+                    area.y
+                        + 1
+                        + self
+                            .view
+                            .cursor_line
+                            .saturating_sub(self.view.scroll_offset)
+                            as u16,
+                );
+                if let Some(window) = self.windows().neighbor(self.screen_area, side, cursor) {
+                    self.focus_window(window);
+                }
+            }
+            'c' => {
+                if let Err(e) = self.close_window() {
+                    self.set_error_line(e);
                 }
             }
+            'q' => match self.quit_window(false) {
+                Ok(action) => return action,
+                Err(e) => self.set_error_line(e),
+            },
+            'o' => self.windows_mut().only(),
+            '+' => self.resize_window(SplitDirection::Horizontal, &|size| size.saturating_add(1)),
+            '-' => self.resize_window(SplitDirection::Horizontal, &|size| size.saturating_sub(1)),
+            '>' => self.resize_window(SplitDirection::Vertical, &|size| size.saturating_add(1)),
+            '<' => self.resize_window(SplitDirection::Vertical, &|size| size.saturating_sub(1)),
+            '_' => self.resize_window(SplitDirection::Horizontal, &|_| u16::MAX),
+            '|' => self.resize_window(SplitDirection::Vertical, &|_| u16::MAX),
+            '=' => self.windows_mut().equalize(),
+            _ => {}
         }
-        self.clamp_cursor_col();
+        EditorAction::None
     }
 
-    pub fn move_word_backward(&mut self) {
-        // Simplified 'b' implementation
-        let buffer = &self.buffers[self.current_focused_index];
+    /// Opens `file_name` in a new buffer, or focuses the buffer already showing it.
+    pub fn edit_file(&mut self, file_name: &str) -> Result<(), String> {
+        let index = self.open_buffer(file_name)?;
+        self.focus_buffer(index);
+        Ok(())
+    }
 
-        loop {
-            if self.cursor_col > 0 {
-                self.cursor_col -= 1;
+    /// Finds the buffer of `file_name`, loading the file into a new buffer if needed.
+    fn open_buffer(&mut self, file_name: &str) -> Result<usize, String> {
+        if let Some(index) = self.buffers.iter().position(|buffer| {
+            buffer
+                .file_path
+                .as_deref()
+                .is_some_and(|path| file_ops::is_same_file(path, file_name))
+        }) {
+            return Ok(index);
+        }
 
-                let line_text = buffer.text.line(self.cursor_line);
-                let chars: Vec<char> = line_text.chars().collect();
-                let c = chars.get(self.cursor_col).unwrap_or(&' ');
+        let path = std::path::PathBuf::from(file_name);
+        if file_ops::is_large_file(&path) {
+            self.buffers.push(HBuffer::for_loading(file_name));
+            self.load_in_background(self.buffers.len() - 1, None);
+            return Ok(self.buffers.len() - 1);
+        }
+        let (buffer, message) = if path.exists() {
+            let buffer = file_ops::load_file(&path, None)?;
+            let message = describe_loaded(file_name, &buffer);
+            (buffer, message)
+        } else {
+            let message = format!("\"{}\" [New]", file_name);
+            (HBuffer::for_new_file(file_name), message)
+        };
 
-                // If we moved onto a word char, check if it's the start
-                if !c.is_whitespace() {
-                    // Check if prev is whitespace or start of line
-                    if self.cursor_col == 0 {
+        self.buffers.push(buffer);
+        self.set_error_line(message);
+        self.show_swap_prompt(self.buffers.len() - 1);
+        Ok(self.buffers.len() - 1)
+    }
+
+    /// Loads the large file of the stand-in buffer at `index` on another thread.
+    pub fn load_in_background(&mut self, index: usize, encoding: Option<Encoding>) {
+        let Some(file_name) = self.buffers[index].file_path.clone() else {
+            return;
+        };
+        let events = file_ops::spawn_load(std::path::PathBuf::from(&file_name), encoding);
+        self.set_error_line(format!("Loading \"{}\"...", file_name));
+        self.loads.push(FileLoad { file_name, events });
+    }
+
+    /// Shows how far the background loads got, and puts each loaded file in place of
+    /// its stand-in buffer.
+    pub fn check_loads(&mut self) {
+        let mut finished = Vec::new();
+        let mut message = None;
+        for (i, load) in self.loads.iter().enumerate() {
+            loop {
+                match load.events.try_recv() {
+                    Ok(LoadEvent::Progress { read, total }) => {
+                        message = Some(format!(
+                            "Loading \"{}\"... {}%",
+                            load.file_name,
+                            read * 100 / total.max(1)
+                        ));
+                    }
+                    Ok(LoadEvent::Done(result)) => {
+                        finished.push((i, result));
                         break;
                     }
-                    let prev = chars.get(self.cursor_col - 1).unwrap_or(&' ');
-                    if prev.is_whitespace() {
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        finished.push((i, Err("Loading stopped".to_string())));
                         break;
                     }
                 }
-                // If we are on whitespace, keep going back (loop continues)
-            } else {
-                // Start of line, go to prev line end
-                if self.cursor_line > 0 {
-                    self.cursor_line -= 1;
-                    let line_len = buffer.line_length(self.cursor_line);
-                    // Set to end of line, but we need strictly inside?
-                    // Vim 'b' from start of line goes to end of prev line's last word.
-                    self.cursor_col = if line_len > 0 { line_len } else { 0 };
-                } else {
-                    break; // Start of file
-                }
             }
         }
+        if let Some(message) = message {
+            self.set_error_line(message);
+        }
+        for (i, result) in finished.into_iter().rev() {
+            let load = self.loads.remove(i);
+            self.finish_load(&load.file_name, result);
+        }
     }
 
-    pub fn move_to_start_of_file(&mut self) {
-        self.cursor_line = 0;
-        self.cursor_col = 0;
-    }
+    /// Replaces the stand-in buffer of `file_name` with the loaded buffer. A stand-in
+    /// whose load failed stays empty and read-only, so it can't overwrite the file.
+    fn finish_load(&mut self, file_name: &str, result: Result<Box<HBuffer>, String>) {
+        // The stand-in may have been deleted while loading
+        let Some(index) = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.loading && buffer.file_path.as_deref() == Some(file_name))
+        else {
+            return;
+        };
 
-    pub fn move_to_end_of_file(&mut self) {
-        let buffer = &self.buffers[self.current_focused_index];
-        let count = buffer.line_count();
-        if count > 0 {
-            self.cursor_line = count - 1;
-            self.cursor_col = 0; // Ideally end of line? standard G goes to start of last line usually?
-            // User request just said "G to move to the end of the file".
+        match result {
+            Ok(mut buffer) => {
+                let message = describe_loaded(file_name, &buffer);
+                buffer.view = std::mem::take(&mut self.buffers[index].view);
+                buffer.swap = self.buffers[index].swap.take();
+                self.buffers[index] = *buffer;
+                if index == self.current_focused_index {
+                    self.clamp_view();
+                }
+                self.set_error_line(message);
+            }
+            Err(e) => {
+                let buffer = &mut self.buffers[index];
+                buffer.loading = false;
+                buffer.read_only = true;
+                self.set_error_line(format!("Can't load \"{}\": {}", file_name, e));
+            }
         }
     }
 
-    pub fn move_word_end_forward(&mut self) {
-        let buffer = &self.buffers[self.current_focused_index];
-        // 1. Advance once
-        if self.cursor_col + 1 < buffer.line_length(self.cursor_line) {
-            self.cursor_col += 1;
-        } else if self.cursor_line + 1 < buffer.line_count() {
-            self.cursor_line += 1;
-            self.cursor_col = 0;
-        } else {
-            return;
+    /// Asks what to do with the swap file found for the buffer at `index`, if any.
+    ///
+    /// The answer is the next key pressed while the buffer is active.
+    pub fn show_swap_prompt(&mut self, index: usize) {
+        if let Some(swap) = &self.buffers[index].found_swap {
+            let message = format!(
+                "Found a swap file: {}\n[r]ecover, [d]elete it or [o]pen read-only (Esc)",
+                swap.describe()
+            );
+            self.set_error_line(message);
         }
+    }
 
-        loop {
-            let buffer = &self.buffers[self.current_focused_index];
-            let line_text = buffer.text.line(self.cursor_line);
-            let chars: Vec<char> = line_text.chars().collect();
-
-            if self.cursor_col >= chars.len() {
-                break;
+    /// Handles a key pressed while the swap file prompt of the active buffer is shown.
+    fn answer_swap_prompt(&mut self, key: KeyEvent) {
+        let Some(swap) = self.get_active_buffer_mut().found_swap.take() else {
+            return;
+        };
+        match key.code {
+            Char('r') => self.recover_swap(swap),
+            Char('d') => {
+                self.dropped_swaps.push(swap.path);
+                self.set_error_line("Swap file deleted".to_string());
             }
+            Char('o') | KeyCode::Esc => {
+                self.get_active_buffer_mut().read_only = true;
+                let message = format!("\"{}\" [RO]", self.get_active_buffer().display_name());
+                self.set_error_line(message);
+            }
+            _ => {
+                self.get_active_buffer_mut().found_swap = Some(swap);
+                self.show_swap_prompt(self.current_focused_index);
+            }
+        }
+    }
 
-            let c = chars[self.cursor_col];
+    /// Replaces the active buffer's text with what `swap` kept, as one change that
+    /// can be undone to get the file's text back.
+    ///
+    /// The swap file is dropped: its text lives in the buffer, which writes its own.
+    pub fn recover_swap(&mut self, swap: Swap) {
+        let buffer = self.get_active_buffer_mut();
+        if buffer.text.to_string() != swap.text {
+            buffer.commit_undo_step();
+            let end = buffer.char_count();
+            buffer.replace_range(0..end, &swap.text);
+            buffer.commit_undo_step();
+        }
+        self.clamp_view();
+        self.dropped_swaps.push(swap.path);
+        let message = format!(
+            "Recovered \"{}\"; write it to keep the recovered text",
+            self.get_active_buffer().display_name()
+        );
+        self.set_error_line(message);
+    }
 
-            if c.is_whitespace() {
-                // Skip whitespace
-                if self.cursor_col + 1 < chars.len() {
-                    self.cursor_col += 1;
-                } else if self.cursor_line + 1 < buffer.line_count() {
-                    self.cursor_line += 1;
-                    self.cursor_col = 0;
-                } else {
-                    break;
-                }
+    /// Brings the swap files up to date, to be done by the swap thread: buffers
+    /// with unsaved changes get theirs written, the others have theirs removed.
+    ///
+    /// Read-only buffers and buffers still asking about another swap file get none.
+    pub fn swap_jobs(&mut self) -> Vec<SwapJob> {
+        let mut jobs: Vec<SwapJob> = self.dropped_swaps.drain(..).map(SwapJob::Remove).collect();
+        for (i, buffer) in self.buffers.iter_mut().enumerate() {
+            let revision = buffer.text.revision();
+            let wanted = if buffer.has_unsaved_changes()
+                && !buffer.read_only
+                && !buffer.large
+                && buffer.found_swap.is_none()
+            {
+                swap::swap_file_path(buffer.file_path.as_deref(), i)
             } else {
-                // Check next char
-                let next_idx = self.cursor_col + 1;
-                if next_idx >= chars.len() {
-                    break;
+                None
+            };
+
+            match (buffer.swap.take(), wanted) {
+                (Some((path, written)), Some(wanted)) if path == wanted && written == revision => {
+                    buffer.swap = Some((path, written));
                 }
-                let next_c = chars[next_idx];
-                if next_c.is_whitespace() {
-                    break;
+                (old, Some(wanted)) => {
+                    // The buffer was renamed or renumbered
+                    if let Some((path, _)) = old
+                        && path != wanted
+                    {
+                        jobs.push(SwapJob::Remove(path));
+                    }
+                    jobs.push(SwapJob::Write {
+                        path: wanted.clone(),
+                        file_path: buffer.file_path.clone(),
+                        text: buffer.text.clone(),
+                    });
+                    buffer.swap = Some((wanted, revision));
                 }
-                self.cursor_col += 1;
+                (Some((path, _)), None) => jobs.push(SwapJob::Remove(path)),
+                (None, None) => {}
             }
         }
+        jobs
     }
 
-    pub fn move_to_line_end(&mut self) {
-        let buffer = &self.buffers[self.current_focused_index];
-        let line_text = buffer.text.line(self.cursor_line);
-        let len = line_text.chars().count(); // Chars count
+    /// Removes every swap file when quitting.
+    pub fn close_swap_files(&mut self) -> Vec<SwapJob> {
+        let mut jobs: Vec<SwapJob> = self.dropped_swaps.drain(..).map(SwapJob::Remove).collect();
+        jobs.extend(
+            self.buffers
+                .iter_mut()
+                .filter_map(|buffer| buffer.swap.take())
+                .map(|(path, _)| SwapJob::Remove(path)),
+        );
+        jobs
+    }
 
-        // Exclude newline if present
-        let chars: Vec<char> = line_text.chars().collect();
-        if let Some(last) = chars.last()
-            && (*last == '\n' || *last == '\r')
-        {
-            self.cursor_col = if len > 1 { len - 2 } else { 0 };
-            return;
+    /// Loads the active buffer's file again, dropping changes only when `force` is set.
+    ///
+    /// The file is read in `encoding`, or in the one it's found to be in.
+    pub fn reload_buffer(&mut self, force: bool, encoding: Option<Encoding>) -> Result<(), String> {
+        let buffer = self.get_active_buffer();
+        let file_name = buffer.file_path.clone().ok_or("No file name")?;
+        if !force && buffer.has_unsaved_changes() {
+            return Err("No write since last change (add ! to override)".to_string());
+        }
+
+        let path = std::path::PathBuf::from(&file_name);
+        if file_ops::is_large_file(&path) {
+            let mut stand_in = HBuffer::for_loading(&file_name);
+            stand_in.swap = self.get_active_buffer_mut().swap.take();
+            *self.get_active_buffer_mut() = stand_in;
+            self.clamp_view();
+            self.load_in_background(self.current_focused_index, encoding);
+            return Ok(());
         }
-        self.cursor_col = if len > 0 { len - 1 } else { 0 };
+        let mut reloaded = file_ops::load_file(&path, encoding)?;
+        let message = describe_loaded(&file_name, &reloaded);
+        // The old swap file is removed with the next swap update
+        reloaded.swap = self.get_active_buffer_mut().swap.take();
+        *self.get_active_buffer_mut() = reloaded;
+        self.clamp_view();
+        self.set_error_line(message);
+        Ok(())
     }
 
-    pub fn move_to_line_start_non_whitespace(&mut self) {
-        let buffer = &self.buffers[self.current_focused_index];
-        let line_text = buffer.text.line(self.cursor_line);
+    /// Finds a buffer by its number in `:ls`, or by a unique part of its name.
+    pub fn find_buffer(&self, name: &str) -> Result<usize, String> {
+        if let Ok(number) = name.parse::<usize>() {
+            return (1..=self.buffers.len())
+                .contains(&number)
+                .then(|| number - 1)
+                .ok_or_else(|| format!("Buffer {} does not exist", number));
+        }
 
-        let mut idx = 0;
-        for c in line_text.chars() {
-            if !c.is_whitespace() {
-                break;
-            }
-            idx += 1;
+        if let Some(index) = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.display_name() == name)
+        {
+            return Ok(index);
         }
-        // If line is all whitespace, maybe go to end?
-        // But let's clamp to line length - 1 (before newline) if possible.
-        let len = buffer.line_length(self.cursor_line);
+        let matches: Vec<usize> = (0..self.buffers.len())
+            .filter(|i| self.buffers[*i].display_name().contains(name))
+            .collect();
+        match matches[..] {
+            [index] => Ok(index),
+            [] => Err(format!("No matching buffer for {}", name)),
+            _ => Err(format!("More than one match for {}", name)),
+        }
+    }
 
-        // Handle empty lines or just newline
+    /// Closes the buffer at `index`. Unsaved changes are only dropped when `force` is set.
+    ///
+    /// Closing the last buffer leaves an empty one.
+    pub fn delete_buffer(&mut self, index: usize, force: bool) -> Result<(), String> {
+        if !force && self.buffers[index].has_unsaved_changes() {
+            return Err(format!(
+                "No write since last change for buffer {} (add ! to override)",
+                index + 1
+            ));
+        }
+
+        if let Some((path, _)) = self.buffers[index].swap.take() {
+            self.dropped_swaps.push(path);
+        }
+        if self.buffers.len() == 1 {
+            self.buffers[0] = HBuffer::new();
+            self.view = View::default();
+            return Ok(());
+        }
+
+        self.buffers.remove(index);
+        let replacement = index.min(self.buffers.len() - 1);
+        for window in self.tabs.iter_mut().flat_map(|tab| tab.windows_mut()) {
+            if window.buffer == index {
+                window.buffer = replacement;
+                window.view = self.buffers[replacement].view.clone();
+            } else if window.buffer > index {
+                window.buffer -= 1;
+            }
+        }
+
+        if index < self.current_focused_index {
+            self.current_focused_index -= 1;
+        } else if index == self.current_focused_index {
+            self.current_focused_index = replacement;
+            self.restore_buffer_view();
+        }
+        self.windows_mut().focused_window_mut().buffer = self.current_focused_index;
+        Ok(())
+    }
+
+    /// One line per buffer for `:ls`: its number, `%` for the active one,
+    /// `+` when modified, its name and the cursor line.
+    pub fn list_buffers(&self) -> String {
+        self.buffers
+            .iter()
+            .enumerate()
+            .map(|(i, buffer)| {
+                let active = i == self.current_focused_index;
+                let line = if active {
+                    self.view.cursor_line
+                } else {
+                    buffer.view.cursor_line
+                };
+                format!(
+                    "{:>3} {}{} \"{}\" line {}",
+                    i + 1,
+                    if active { '%' } else { ' ' },
+                    if buffer.has_unsaved_changes() {
+                        '+'
+                    } else {
+                        ' '
+                    },
+                    buffer.display_name(),
+                    line + 1
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Lays the windows out over `area` and scrolls the focused one to keep the cursor in view.
+    pub fn update_layout(&mut self, area: Rect) {
+        self.screen_area = area;
+        let height = (self.get_focused_window_area().height as usize).saturating_sub(2);
+
+        // Highlight through the last line each window shows, after moving the
+        // folds along with the edits made since the last draw
+        let shown: Vec<(usize, usize)> = self
+            .get_windows()
+            .into_iter()
+            .map(|(area, buffer, view, _)| (buffer, view.scroll_offset + area.height as usize))
+            .collect();
+        for (buffer, last_line) in shown {
+            self.buffers[buffer].update_syntax(last_line);
+        }
+
+        // A cursor moved into a closed fold opens it
+        let folds = &mut self.buffers[self.current_focused_index].folds;
+        while folds.shown_line(self.view.cursor_line) != self.view.cursor_line {
+            folds.open(self.view.cursor_line);
+        }
+
+        self.view.scroll_offset = folds.shown_line(self.view.scroll_offset);
+        if self.view.cursor_line < self.view.scroll_offset {
+            self.view.scroll_offset = self.view.cursor_line;
+        }
+        while folds.rows_between(self.view.scroll_offset, self.view.cursor_line) >= height.max(1) {
+            self.view.scroll_offset = folds.next_shown_line(self.view.scroll_offset);
+        }
+    }
+
+    pub fn get_scroll_offset(&self) -> usize {
+        self.view.scroll_offset
+    }
+
+    pub fn move_cursor_left(&mut self) {
+        if self.view.cursor_col > 0 {
+            self.view.cursor_col -= 1;
+        }
+    }
+
+    pub fn move_cursor_right(&mut self) {
+        let buffer = &self.buffers[self.current_focused_index];
+        let line_len = buffer.line_length(self.view.cursor_line);
+        if self.view.cursor_col < line_len {
+            self.view.cursor_col += 1;
+        }
+    }
+
+    fn move_cursor_up(&mut self) {
+        // todo!("Panics for some reason. Fix this!");
+        if self.view.cursor_line > 0 {
+            // A closed fold is stepped over as the one line shown for it
+            let folds = &self.buffers[self.current_focused_index].folds;
+            self.view.cursor_line = folds.shown_line(folds.shown_line(self.view.cursor_line) - 1);
+            self.clamp_cursor_col();
+        }
+    }
+
+    fn move_cursor_start(&mut self) {
+        if self.view.cursor_col > 0 {
+            self.view.cursor_col = 0;
+        }
+    }
+
+    fn move_cursor_down(&mut self) {
+        // todo!("Panics for some reason. Fix this!");
+        let buffer = &self.buffers[self.current_focused_index];
+        let next = buffer.folds.next_shown_line(self.view.cursor_line);
+        if next < buffer.line_count() {
+            self.view.cursor_line = next;
+            self.clamp_cursor_col();
+        }
+    }
+
+    fn clamp_cursor_col(&mut self) {
+        let buffer = &self.buffers[self.current_focused_index];
+        let line_len = buffer.line_length(self.view.cursor_line);
+        if self.view.cursor_col > line_len {
+            self.view.cursor_col = line_len;
+        }
+    }
+
+    pub fn get_command_line(&self) -> String {
+        self.command_line.clone()
+    }
+
+    /// The char shown before the command line: `:` for commands, `/` or `?` for searches.
+    pub fn get_command_prompt(&self) -> char {
+        self.command_prompt
+    }
+
+    /// Opens the command line with the given prompt.
+    fn open_prompt(&mut self, prompt: char) -> EditorAction {
+        self.command_prompt = prompt;
+        self.command_cursor = self.command_line.len();
+        self.search.origin = (self.view.cursor_line, self.view.cursor_col);
+        EditorAction::EnterCommandMode
+    }
+
+    pub fn get_error_line(&self) -> String {
+        self.error_line.clone()
+    }
+
+    pub fn get_buffers(&self) -> &Vec<HBuffer> {
+        &self.buffers
+    }
+
+    pub fn get_active_buffer(&self) -> &HBuffer {
+        &self.buffers[self.current_focused_index]
+    }
+
+    pub fn get_active_buffer_mut(&mut self) -> &mut HBuffer {
+        &mut self.buffers[self.current_focused_index]
+    }
+
+    pub fn set_error_line(&mut self, error: String) {
+        self.error_line = error;
+        self.error_timestamp = Some(Instant::now());
+    }
+
+    pub fn check_error_expiry(&mut self) {
+        if let Some(time) = self.error_timestamp
+            && time.elapsed() >= std::time::Duration::from_secs(10)
+        {
+            self.error_line.clear();
+            self.error_timestamp = None;
+        }
+    }
+
+    pub fn get_cursor_position(&self) -> (usize, usize) {
+        (self.view.cursor_col, self.view.cursor_line)
+    }
+
+    pub fn undo(&mut self) {
+        let buffer = self.get_active_buffer_mut();
+        buffer.commit_undo_step();
+        let (seq, time) = (buffer.undo.seq(), buffer.undo.time());
+        let lines = buffer.line_count();
+
+        match buffer.undo() {
+            Some(cursor) => {
+                self.move_to_undo_change(cursor);
+                let message = format!(
+                    "{}before #{}  {}",
+                    describe_line_change(lines, self.get_active_buffer().line_count()),
+                    seq,
+                    undo::describe_age(time)
+                );
+                self.set_error_line(message);
+            }
+            None => self.set_error_line("Already at oldest change".to_string()),
+        }
+    }
+
+    pub fn redo(&mut self) {
+        let lines = self.get_active_buffer().line_count();
+        match self.get_active_buffer_mut().redo() {
+            Some(cursor) => self.show_undo_state(lines, cursor),
+            None => self.set_error_line("Already at newest change".to_string()),
+        }
+    }
+
+    /// Goes back or forward through the text states in the order they were made,
+    /// across undo branches (`g-`, `g+`, `:earlier`, `:later`).
+    pub fn undo_travel(&mut self, distance: UndoDistance, backward: bool) {
+        let buffer = self.get_active_buffer_mut();
+        buffer.commit_undo_step();
+        let target = match distance {
+            UndoDistance::Steps(steps) => buffer.undo.step_target(steps, backward),
+            UndoDistance::Time(span) => buffer.undo.time_target(span, backward),
+        };
+        let lines = buffer.line_count();
+
+        match buffer.undo_to(target) {
+            Some(cursor) => self.show_undo_state(lines, cursor),
+            None if backward => self.set_error_line("Already at oldest change".to_string()),
+            None => self.set_error_line("Already at newest change".to_string()),
+        }
+    }
+
+    /// Lists the tips of the undo branches for `:undolist`.
+    pub fn list_undo_branches(&mut self) -> String {
+        let buffer = self.get_active_buffer_mut();
+        buffer.commit_undo_step();
+        let leaves = buffer.undo.leaves();
+        if leaves.is_empty() {
+            return "Nothing to undo".to_string();
+        }
+
+        let mut list = String::from("number changes  when");
+        for leaf in leaves {
+            list.push_str(&format!(
+                "\n{:>6} {:>7}  {}{}",
+                leaf.seq,
+                leaf.changes,
+                undo::describe_age(leaf.time),
+                if leaf.revision == buffer.saved_revision {
+                    "  (saved)"
+                } else {
+                    ""
+                }
+            ));
+        }
+        list
+    }
+
+    /// Tells where an undo, redo or time travel left the text, like
+    /// `1 line less; after #4  5 seconds ago`.
+    fn show_undo_state(&mut self, lines_before: usize, cursor: usize) {
+        self.move_to_undo_change(cursor);
+        let buffer = self.get_active_buffer();
+        let state = if buffer.undo.is_at_root() {
+            "original text".to_string()
+        } else {
+            format!(
+                "after #{}  {}",
+                buffer.undo.seq(),
+                undo::describe_age(buffer.undo.time())
+            )
+        };
+        let message = format!(
+            "{}{}",
+            describe_line_change(lines_before, buffer.line_count()),
+            state
+        );
+        self.set_error_line(message);
+    }
+
+    fn move_to_undo_change(&mut self, cursor: usize) {
+        if self.get_active_buffer().hex {
+            self.set_hex_offset(cursor, false);
+            return;
+        }
+        (self.view.cursor_line, self.view.cursor_col) = self.get_active_buffer().line_col(cursor);
+        self.clamp_cursor_col();
+    }
+
+    pub fn move_word_forward(&mut self) {
+        let buffer = &self.buffers[self.current_focused_index];
+
+        loop {
+            let line_len = buffer.line_length(self.view.cursor_line);
+
+            // If strictly inside the line
+            if self.view.cursor_col < line_len {
+                let line_text = buffer.text.line(self.view.cursor_line);
+                let chars: Vec<char> = line_text.chars().collect();
+
+                // Check if current is whitespace (or special) to know if we crossed a boundary
+                // Simple logic for now: skip current word (non-whitespace), then skip whitespace.
+
+                // Better logic:
+                // 1. If on word char, skip until whitespace.
+                // 2. Skip whitespace until word char.
+                // But vim 'w' is "start of next word".
+
+                // Let's implement simple step-by-step advance.
+                // Note: This is an O(N) naive implementation in a loop.
+
+                let c = chars.get(self.view.cursor_col).unwrap_or(&'\n');
+
+                if c.is_whitespace() {
+                    // If we are on whitespace, we are looking for non-whitespace
+                    self.view.cursor_col += 1;
+                    if self.view.cursor_col < chars.len()
+                        && !chars[self.view.cursor_col].is_whitespace()
+                    {
+                        // Found start of next word
+                        break;
+                    }
+                } else {
+                    // We are on a word, move until whitespace or end
+                    self.view.cursor_col += 1;
+                    // But we might hit whitespace immediately.
+                    // If we hit whitespace, we continue loop to next iteration which handles whitespace.
+                }
+            } else {
+                // End of line, move to next line
+                if self.view.cursor_line < buffer.line_count() - 1 {
+                    self.view.cursor_line += 1;
+                    self.view.cursor_col = 0;
+                    // Check if 0 is a word char
+                    let line_text = buffer.text.line(self.view.cursor_line);
+                    if let Some(c) = line_text.chars().next()
+                        && !c.is_whitespace()
+                    {
+                        break;
+                    }
+                } else {
+                    break; // End of file
+                }
+            }
+        }
+        self.clamp_cursor_col();
+    }
+
+    pub fn move_word_backward(&mut self) {
+        // Simplified 'b' implementation
+        let buffer = &self.buffers[self.current_focused_index];
+
+        loop {
+            if self.view.cursor_col > 0 {
+                self.view.cursor_col -= 1;
+
+                let line_text = buffer.text.line(self.view.cursor_line);
+                let chars: Vec<char> = line_text.chars().collect();
+                let c = chars.get(self.view.cursor_col).unwrap_or(&' ');
+
+                // If we moved onto a word char, check if it's the start
+                if !c.is_whitespace() {
+                    // Check if prev is whitespace or start of line
+                    if self.view.cursor_col == 0 {
+                        break;
+                    }
+                    let prev = chars.get(self.view.cursor_col - 1).unwrap_or(&' ');
+                    if prev.is_whitespace() {
+                        break;
+                    }
+                }
+                // If we are on whitespace, keep going back (loop continues)
+            } else {
+                // Start of line, go to prev line end
+                if self.view.cursor_line > 0 {
+                    self.view.cursor_line -= 1;
+                    let line_len = buffer.line_length(self.view.cursor_line);
+                    // Set to end of line, but we need strictly inside?
+                    // Vim 'b' from start of line goes to end of prev line's last word.
+                    self.view.cursor_col = if line_len > 0 { line_len } else { 0 };
+                } else {
+                    break; // Start of file
+                }
+            }
+        }
+    }
+
+    pub fn move_to_start_of_file(&mut self) {
+        self.view.cursor_line = 0;
+        self.view.cursor_col = 0;
+    }
+
+    pub fn move_to_end_of_file(&mut self) {
+        let buffer = &self.buffers[self.current_focused_index];
+        let count = buffer.line_count();
+        if count > 0 {
+            self.view.cursor_line = count - 1;
+            self.view.cursor_col = 0; // Ideally end of line? standard G goes to start of last line usually?
+            // User request just said "G to move to the end of the file".
+        }
+    }
+
+    pub fn move_word_end_forward(&mut self) {
+        let buffer = &self.buffers[self.current_focused_index];
+        // 1. Advance once
+        if self.view.cursor_col + 1 < buffer.line_length(self.view.cursor_line) {
+            self.view.cursor_col += 1;
+        } else if self.view.cursor_line + 1 < buffer.line_count() {
+            self.view.cursor_line += 1;
+            self.view.cursor_col = 0;
+        } else {
+            return;
+        }
+
+        loop {
+            let buffer = &self.buffers[self.current_focused_index];
+            let line_text = buffer.text.line(self.view.cursor_line);
+            let chars: Vec<char> = line_text.chars().collect();
+
+            if self.view.cursor_col >= chars.len() {
+                break;
+            }
+
+            let c = chars[self.view.cursor_col];
+
+            if c.is_whitespace() {
+                // Skip whitespace
+                if self.view.cursor_col + 1 < chars.len() {
+                    self.view.cursor_col += 1;
+                } else if self.view.cursor_line + 1 < buffer.line_count() {
+                    self.view.cursor_line += 1;
+                    self.view.cursor_col = 0;
+                } else {
+                    break;
+                }
+            } else {
+                // Check next char
+                let next_idx = self.view.cursor_col + 1;
+                if next_idx >= chars.len() {
+                    break;
+                }
+                let next_c = chars[next_idx];
+                if next_c.is_whitespace() {
+                    break;
+                }
+                self.view.cursor_col += 1;
+            }
+        }
+    }
+
+    pub fn move_to_line_end(&mut self) {
+        let buffer = &self.buffers[self.current_focused_index];
+        let line_text = buffer.text.line(self.view.cursor_line);
+        let len = line_text.chars().count(); // Chars count
+
+        // Exclude newline if present
+        let chars: Vec<char> = line_text.chars().collect();
+        if let Some(last) = chars.last()
+            && (*last == '\n' || *last == '\r')
+        {
+            self.view.cursor_col = if len > 1 { len - 2 } else { 0 };
+            return;
+        }
+        self.view.cursor_col = if len > 0 { len - 1 } else { 0 };
+    }
+
+    pub fn move_to_line_start_non_whitespace(&mut self) {
+        let buffer = &self.buffers[self.current_focused_index];
+        let line_text = buffer.text.line(self.view.cursor_line);
+
+        let mut idx = 0;
+        for c in line_text.chars() {
+            if !c.is_whitespace() {
+                break;
+            }
+            idx += 1;
+        }
+        // If line is all whitespace, maybe go to end?
+        // But let's clamp to line length - 1 (before newline) if possible.
+        let len = buffer.line_length(self.view.cursor_line);
+
+        // Handle empty lines or just newline
         if len == 0 || (len == 1 && idx == 1) {
             // mostly newline
-            self.cursor_col = 0;
+            self.view.cursor_col = 0;
         } else if idx >= len {
-            self.cursor_col = len - 1;
+            self.view.cursor_col = len - 1;
+        } else {
+            self.view.cursor_col = idx;
+        }
+    }
+
+    pub fn move_paragraph_forward(&mut self) {
+        let buffer = &self.buffers[self.current_focused_index];
+        let count = buffer.line_count();
+        let mut line = self.view.cursor_line + 1;
+
+        while line < count && is_blank_line(buffer, line) {
+            line += 1;
+        }
+        while line < count && !is_blank_line(buffer, line) {
+            line += 1;
+        }
+
+        if line >= count {
+            self.view.cursor_line = count.saturating_sub(1);
+            self.move_to_line_end();
+        } else {
+            self.view.cursor_line = line;
+            self.view.cursor_col = 0;
+        }
+    }
+
+    pub fn move_paragraph_backward(&mut self) {
+        let buffer = &self.buffers[self.current_focused_index];
+        let mut line = self.view.cursor_line;
+
+        while line > 0 && is_blank_line(buffer, line - 1) {
+            line -= 1;
+        }
+        while line > 0 && !is_blank_line(buffer, line - 1) {
+            line -= 1;
+        }
+
+        self.view.cursor_line = line.saturating_sub(1);
+        self.view.cursor_col = 0;
+    }
+
+    /// Jumps to the next match of the last search, or the previous one when `reverse` is set.
+    pub fn search_next(&mut self, reverse: bool) {
+        let Some(search) = self.search.last.clone() else {
+            self.set_error_line("No previous search".to_string());
+            return;
+        };
+        let backward = search.backward != reverse;
+
+        let buffer = self.get_active_buffer();
+        let cursor_char = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+        let Some(found) = search.find_from(&buffer.text, cursor_char, backward) else {
+            self.set_error_line(format!("Pattern not found: {}", search.pattern));
+            return;
+        };
+
+        (self.view.cursor_line, self.view.cursor_col) = buffer.line_col(found.range.start);
+        self.search.highlight = true;
+        if found.wrapped {
+            let message = if backward {
+                "search hit TOP, continuing at BOTTOM"
+            } else {
+                "search hit BOTTOM, continuing at TOP"
+            };
+            self.set_error_line(message.to_string());
+        }
+    }
+
+    /// Searches for the whole word under the cursor (`*` and `#`).
+    pub fn search_word_under_cursor(&mut self, backward: bool) {
+        let word = TextObject {
+            kind: TextObjectKind::Word,
+            around: false,
+        };
+        let buffer = self.get_active_buffer();
+        let cursor_char = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+        let Some(range) = word.find(buffer, cursor_char) else {
+            self.set_error_line("No string under cursor".to_string());
+            return;
+        };
+
+        let pattern = buffer.text.slice_to_string(range.clone());
+        if pattern.trim().is_empty() {
+            self.set_error_line("No string under cursor".to_string());
+            return;
+        }
+
+        (self.view.cursor_line, self.view.cursor_col) = buffer.line_col(range.start);
+        self.search.last = Some(Search {
+            pattern,
+            backward,
+            whole_word: true,
+        });
+        self.search_next(false);
+    }
+
+    /// Syntax highlighting of line `line_idx` of a buffer, whose text is `line`.
+    pub fn get_syntax_tokens(&self, buffer: usize, line_idx: usize, line: &str) -> Vec<Token> {
+        if !self.options.syntax {
+            return Vec::new();
+        }
+        self.buffers[buffer].syntax_tokens(line_idx, line)
+    }
+
+    /// Search matches to highlight on the lines `first_line..=last_line` of a buffer,
+    /// along with the current match.
+    pub fn get_search_highlights(
+        &self,
+        buffer: usize,
+        first_line: usize,
+        last_line: usize,
+    ) -> (Vec<std::ops::Range<usize>>, Option<std::ops::Range<usize>>) {
+        // The current match is the one under the cursor, in the focused buffer only
+        let active = buffer == self.current_focused_index;
+        let buffer = &self.buffers[buffer];
+        let visible = buffer.char_index(first_line, 0)..buffer.char_index(last_line + 1, 0);
+
+        // While typing at the prompt, matches of the partial pattern are shown
+        if let Some(preview) = &self.search.preview {
+            let typed = Search {
+                pattern: self.command_line.clone(),
+                backward: false,
+                whole_word: false,
+            };
+            return (
+                typed.matches_in(&buffer.text, visible),
+                Some(preview.clone()).filter(|_| active),
+            );
+        }
+
+        let highlight = self.search.highlight && self.options.hlsearch;
+        let Some(search) = self.search.last.as_ref().filter(|_| highlight) else {
+            return (Vec::new(), None);
+        };
+        let matches = search.matches_in(&buffer.text, visible);
+        let cursor_char = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+        let current = matches
+            .iter()
+            .find(|m| active && m.start == cursor_char)
+            .cloned();
+        (matches, current)
+    }
+
+    /// Moves the cursor by a motion, repeated `count` times.
+    ///
+    /// For line-number motions (`gg`, `G`) the count is the target line instead.
+    pub fn apply_motion(&mut self, motion: Motion, count: Option<usize>) {
+        let from = (self.view.cursor_line, self.view.cursor_col);
+        self.move_by_motion(motion, count);
+        if motion.is_jump() {
+            self.record_jump(from);
+        }
+    }
+
+    fn move_by_motion(&mut self, motion: Motion, count: Option<usize>) {
+        if motion.takes_line_number()
+            && let Some(line) = count
+        {
+            let line_count = self.buffers[self.current_focused_index].line_count();
+            self.view.cursor_line = line.saturating_sub(1).min(line_count.saturating_sub(1));
+            self.move_to_line_start_non_whitespace();
+            return;
+        }
+
+        let times = count.unwrap_or(1).max(1);
+        match motion {
+            Motion::LineStart => self.view.cursor_col = 0,
+            Motion::LineFirstNonWhitespace => self.move_to_line_start_non_whitespace(),
+            Motion::FileStart => self.move_to_start_of_file(),
+            Motion::FileEnd => self.move_to_end_of_file(),
+            Motion::LineEnd => {
+                for _ in 1..times {
+                    self.move_cursor_down();
+                }
+                self.move_to_line_end();
+            }
+            _ => {
+                for _ in 0..times {
+                    match motion {
+                        Motion::Left => self.move_cursor_left(),
+                        Motion::Right => self.move_cursor_right(),
+                        Motion::Up => self.move_cursor_up(),
+                        Motion::Down => self.move_cursor_down(),
+                        Motion::WordForward => self.move_word_forward(),
+                        Motion::WordBackward => self.move_word_backward(),
+                        Motion::WordEndForward => self.move_word_end_forward(),
+                        Motion::ParagraphForward => self.move_paragraph_forward(),
+                        Motion::ParagraphBackward => self.move_paragraph_backward(),
+                        Motion::SearchNext => self.search_next(false),
+                        Motion::SearchPrevious => self.search_next(true),
+                        Motion::SearchWordForward => self.search_word_under_cursor(false),
+                        Motion::SearchWordBackward => self.search_word_under_cursor(true),
+                        Motion::NextFunction => self.move_to_function(false),
+                        Motion::PreviousFunction => self.move_to_function(true),
+                        Motion::ParentNode => self.move_to_parent_node(),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves to the start of the next function, or the previous one, in the syntax tree.
+    fn move_to_function(&mut self, backward: bool) {
+        self.get_active_buffer_mut().parse_tree();
+        let buffer = self.get_active_buffer();
+        let cursor_char = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+        let Some(tree) = &buffer.syntax_tree else {
+            self.set_error_line("No syntax tree for this buffer".to_string());
+            return;
+        };
+        if let Some(char_idx) = tree.function_start(&buffer.text, cursor_char, backward) {
+            (self.view.cursor_line, self.view.cursor_col) = buffer.line_col(char_idx);
+        }
+    }
+
+    /// Moves to the start of the syntax node around the cursor.
+    fn move_to_parent_node(&mut self) {
+        self.get_active_buffer_mut().parse_tree();
+        let buffer = self.get_active_buffer();
+        let cursor_char = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+        let Some(tree) = &buffer.syntax_tree else {
+            self.set_error_line("No syntax tree for this buffer".to_string());
+            return;
+        };
+        if let Some(char_idx) = tree.parent_start(&buffer.text, cursor_char) {
+            (self.view.cursor_line, self.view.cursor_col) = buffer.line_col(char_idx);
+        }
+    }
+
+    /// Opens or closes folds over the nodes of the syntax tree.
+    ///
+    /// `zc` closes the smallest fold around the cursor that's still open, so closing
+    /// again closes the one around that.
+    fn fold(&mut self, command: FoldCommand) -> Result<(), String> {
+        let line = self.view.cursor_line;
+        let buffer = &mut self.buffers[self.current_focused_index];
+        // Folds made now are over the text as it is, so they must not move along
+        // with edits not yet seen
+        buffer.update_syntax(line);
+        let shown = buffer.folds.closed_at(line);
+        let command = match command {
+            FoldCommand::Toggle if shown.is_some() => FoldCommand::Open,
+            FoldCommand::Toggle => FoldCommand::Close,
+            command => command,
+        };
+        match command {
+            FoldCommand::Open => {
+                if !buffer.folds.open(line) {
+                    return Err("No fold found".to_string());
+                }
+            }
+            FoldCommand::OpenAll => buffer.folds.open_all(),
+            FoldCommand::Close | FoldCommand::Toggle => {
+                buffer.parse_tree();
+                let tree = buffer
+                    .syntax_tree
+                    .as_ref()
+                    .ok_or("No syntax tree for this buffer")?;
+                let (first, last) = tree
+                    .folds_at(&buffer.text, line)
+                    .into_iter()
+                    .find(|&(first, last)| match shown {
+                        Some((shown_first, shown_last)) => {
+                            first <= shown_first
+                                && last >= shown_last
+                                && (first, last) != (shown_first, shown_last)
+                        }
+                        None => true,
+                    })
+                    .ok_or("No fold found")?;
+                buffer.folds.close(first, last);
+                self.view.cursor_line = first;
+            }
+            FoldCommand::CloseAll => {
+                buffer.parse_tree();
+                let tree = buffer
+                    .syntax_tree
+                    .as_ref()
+                    .ok_or("No syntax tree for this buffer")?;
+                buffer.folds.close_all(tree.folds());
+                self.view.cursor_line = buffer.folds.shown_line(line);
+            }
+        }
+        self.clamp_cursor_col();
+        Ok(())
+    }
+
+    /// Keeps the language servers in step with the buffers and acts on what they sent.
+    pub fn check_language_servers(&mut self) {
+        self.lsp.sync(&mut self.buffers);
+        for event in self.lsp.poll(&self.buffers) {
+            self.handle_lsp_event(event);
+        }
+    }
+
+    /// Asks the language servers to exit, when quitting.
+    pub fn shutdown_language_servers(&mut self) {
+        self.lsp.shutdown();
+    }
+
+    /// Asks the language server of the active buffer about the symbol under the
+    /// cursor. The answer is acted on when it comes.
+    fn request_language_server(&mut self, command: LspCommand) -> Result<(), String> {
+        self.lsp.sync(&mut self.buffers);
+        let buffer = &self.buffers[self.current_focused_index];
+        let at = (self.view.cursor_line, self.view.cursor_col);
+        match command {
+            LspCommand::Hover => self.lsp.request_hover(buffer, at),
+            LspCommand::Definition => self.lsp.request_definition(buffer, at),
+            LspCommand::References => self.lsp.request_references(buffer, at),
+        }
+    }
+
+    fn handle_lsp_event(&mut self, event: LspEvent) {
+        match event {
+            LspEvent::Completion { path, start, items } => {
+                self.show_completions(&path, start, items)
+            }
+            LspEvent::Hover(text) => {
+                // Diagnostics of the cursor line come first
+                let line = self.view.cursor_line;
+                let mut lines: Vec<String> = self
+                    .get_active_buffer()
+                    .diagnostics
+                    .iter()
+                    .filter(|diagnostic| diagnostic.start.0 <= line && line <= diagnostic.end.0)
+                    .map(|diagnostic| {
+                        format!("{}: {}", diagnostic.severity.name(), diagnostic.message)
+                    })
+                    .collect();
+                if !text.is_empty() {
+                    lines.push(text);
+                }
+                if lines.is_empty() {
+                    self.set_error_line("No hover information".to_string());
+                } else {
+                    self.set_error_line(lines.join("\n"));
+                }
+            }
+            LspEvent::Definition(locations) => match locations.first() {
+                Some(location) => {
+                    if let Err(e) = self.jump_to_location(location) {
+                        self.set_error_line(e);
+                    }
+                }
+                None => self.set_error_line("No definition found".to_string()),
+            },
+            LspEvent::References(locations) => {
+                let list = self.list_locations(&locations);
+                self.set_error_line(list);
+            }
+            LspEvent::Rename(files) => match self.apply_rename(files) {
+                Ok(message) | Err(message) => self.set_error_line(message),
+            },
+            LspEvent::Diagnostics { path, diagnostics } => {
+                for buffer in &mut self.buffers {
+                    if lsp::document_path(buffer).as_deref() == Some(&path) {
+                        buffer.diagnostics = diagnostics.clone();
+                    }
+                }
+            }
+            LspEvent::Message(message) => self.set_error_line(message),
+        }
+    }
+
+    /// Opens the completion menu for the word starting at `start`, if the cursor is
+    /// still after it in the file at `path`.
+    fn show_completions(&mut self, path: &str, start: (usize, usize), items: Vec<MenuItem>) {
+        let buffer = self.get_active_buffer();
+        let cursor = (self.view.cursor_line, self.view.cursor_col);
+        if lsp::document_path(buffer).as_deref() != Some(path)
+            || cursor.0 != start.0
+            || cursor.1 < start.1
+        {
+            return;
+        }
+        let typed = buffer.text.slice_to_string(
+            buffer.char_index(start.0, start.1)..buffer.char_index(cursor.0, cursor.1),
+        );
+        let mut menu = CompletionMenu::new(start.0, start.1, items);
+        if menu.filter(&typed) {
+            self.completion_menu = Some(menu);
+        } else {
+            self.completion_menu = None;
+            self.set_error_line("No completions".to_string());
+        }
+    }
+
+    /// Moves the cursor to `location`, opening its file if it's another one.
+    fn jump_to_location(&mut self, location: &Location) -> Result<(), String> {
+        let from = (self.view.cursor_line, self.view.cursor_col);
+        let same_file =
+            lsp::document_path(self.get_active_buffer()).as_deref() == Some(&location.path);
+        if !same_file {
+            self.edit_file(&lsp::display_path(&location.path))?;
+        }
+        (self.view.cursor_line, self.view.cursor_col) = location.start;
+        self.clamp_view();
+        if same_file {
+            self.record_jump(from);
+        }
+        Ok(())
+    }
+
+    /// One line per location, like `src/main.rs:12:5: let x = 1;`.
+    fn list_locations(&self, locations: &[Location]) -> String {
+        if locations.is_empty() {
+            return "No references found".to_string();
+        }
+        let mut files = std::collections::HashMap::new();
+        locations
+            .iter()
+            .map(|location| {
+                let text =
+                    match self.buffers.iter().find(|buffer| {
+                        lsp::document_path(buffer).as_deref() == Some(&location.path)
+                    }) {
+                        Some(buffer) => buffer.text.line(location.start.0).to_string(),
+                        None => files
+                            .entry(location.path.clone())
+                            .or_insert_with(|| {
+                                std::fs::read_to_string(&location.path).unwrap_or_default()
+                            })
+                            .lines()
+                            .nth(location.start.0)
+                            .unwrap_or_default()
+                            .to_string(),
+                    };
+                format!(
+                    "{}:{}:{}: {}",
+                    lsp::display_path(&location.path),
+                    location.start.0 + 1,
+                    location.start.1 + 1,
+                    text.trim()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Makes the edits of a rename, opening the files that aren't open yet. The
+    /// edits of each buffer are undone as one change.
+    fn apply_rename(&mut self, files: Vec<(String, Vec<TextEdit>)>) -> Result<String, String> {
+        let (mut changes, mut changed_files) = (0, 0);
+        for (path, mut edits) in files {
+            if edits.is_empty() {
+                continue;
+            }
+            let index = match self
+                .buffers
+                .iter()
+                .position(|buffer| lsp::document_path(buffer).as_deref() == Some(&path))
+            {
+                Some(index) => index,
+                None => self.open_buffer(&lsp::display_path(&path))?,
+            };
+            let buffer = &mut self.buffers[index];
+            if buffer.loading {
+                return Err(format!("Can't rename in \"{}\" while it loads", path));
+            }
+
+            // From the last edit back, so the positions of the others stay right
+            edits.sort_by_key(|edit| std::cmp::Reverse(edit.start));
+            buffer.commit_undo_step();
+            for edit in &edits {
+                let start = buffer.char_index(edit.start.0, edit.start.1);
+                let end = buffer.char_index(edit.end.0, edit.end.1);
+                buffer.replace_range(start..end, &edit.text);
+            }
+            buffer.commit_undo_step();
+            changes += edits.len();
+            changed_files += 1;
+        }
+        self.clamp_view();
+        if changes == 0 {
+            return Err("Nothing to rename".to_string());
+        }
+        Ok(format!(
+            "Renamed {} occurrence(s) in {} file(s)",
+            changes, changed_files
+        ))
+    }
+
+    /// One line per diagnostic of the active buffer for `:diagnostics`.
+    pub fn list_diagnostics(&self) -> String {
+        let diagnostics = &self.get_active_buffer().diagnostics;
+        if diagnostics.is_empty() {
+            return "No diagnostics".to_string();
+        }
+        diagnostics
+            .iter()
+            .map(|diagnostic| {
+                format!(
+                    "{}:{} {}: {}",
+                    diagnostic.start.0 + 1,
+                    diagnostic.start.1 + 1,
+                    diagnostic.severity.name(),
+                    diagnostic.message
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn get_completion_menu(&self) -> Option<&CompletionMenu> {
+        self.completion_menu.as_ref()
+    }
+
+    /// Adds `from` to the jump list if the cursor has left its line.
+    fn record_jump(&mut self, from: (usize, usize)) {
+        if self.view.cursor_line != from.0 {
+            self.view.jumps.push(from);
+        }
+    }
+
+    /// Walks the jump list: back to older jumps (Ctrl-O) or forward again (Ctrl-I).
+    pub fn walk_jumps(&mut self, backward: bool) {
+        let current = (self.view.cursor_line, self.view.cursor_col);
+        let target = if backward {
+            self.view.jumps.back(current)
+        } else {
+            self.view.jumps.forward()
+        };
+        if let Some((line, col)) = target {
+            self.view.cursor_line =
+                line.min(self.get_active_buffer().line_count().saturating_sub(1));
+            self.view.cursor_col = col;
+            self.clamp_cursor_col();
+        }
+    }
+
+    /// Runs an Operator over the text between the cursor and the end of its Target.
+    pub fn apply_operator(
+        &mut self,
+        operator: Operator,
+        target: Target,
+        count: Option<usize>,
+        register: Option<char>,
+    ) -> EditorAction {
+        let start = (self.view.cursor_line, self.view.cursor_col);
+
+        let (linewise, inclusive) = match target {
+            Target::Lines => {
+                let last_line = self.get_active_buffer().line_count().saturating_sub(1);
+                self.view.cursor_line =
+                    (self.view.cursor_line + count.unwrap_or(1).max(1) - 1).min(last_line);
+                (true, true)
+            }
+            Target::TextObject(object) => {
+                return self.apply_text_object_operator(operator, object, count, register);
+            }
+            Target::Motion(motion) => {
+                // Like vim, `cw` on a word changes to the end of the word
+                let on_word = self
+                    .get_active_buffer()
+                    .text
+                    .line(self.view.cursor_line)
+                    .chars()
+                    .nth(self.view.cursor_col)
+                    .is_some_and(|c| !c.is_whitespace());
+                let motion =
+                    if operator == Operator::Change && motion == Motion::WordForward && on_word {
+                        Motion::WordEndForward
+                    } else {
+                        motion
+                    };
+                self.apply_motion(motion, count);
+
+                // `dw` on the last word of a line stops at the line end instead of the next
+                // word, when that is the first word of a later line
+                if motion == Motion::WordForward
+                    && self.view.cursor_line > start.0
+                    && self.view.cursor_col <= self.first_non_whitespace_col(self.view.cursor_line)
+                {
+                    self.view.cursor_line -= 1;
+                    self.view.cursor_col = self
+                        .get_active_buffer()
+                        .line_content_length(self.view.cursor_line);
+                }
+                (motion.is_linewise(), motion.is_inclusive())
+            }
+        };
+
+        let end = (self.view.cursor_line, self.view.cursor_col);
+        let (from, mut to) = if start <= end {
+            (start, end)
         } else {
-            self.cursor_col = idx;
+            (end, start)
+        };
+        let mut linewise =
+            linewise || matches!(operator, Operator::IndentRight | Operator::IndentLeft);
+
+        // An exclusive motion from a line's indentation to column 0 of a later line acts on whole lines
+        if !linewise
+            && !inclusive
+            && to.1 == 0
+            && to.0 > from.0
+            && from.1 <= self.first_non_whitespace_col(from.0)
+        {
+            linewise = true;
+            to.0 -= 1;
+        }
+        let range = self.operator_range(from, to, linewise, inclusive);
+        self.run_operator(operator, register, from, to, range, linewise)
+    }
+
+    /// Runs an Operator over the text object around the cursor.
+    fn apply_text_object_operator(
+        &mut self,
+        operator: Operator,
+        object: TextObject,
+        count: Option<usize>,
+        register: Option<char>,
+    ) -> EditorAction {
+        self.get_active_buffer_mut().parse_tree();
+        let buffer = self.get_active_buffer();
+        let cursor_char = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+        let Some(range) = object.range(buffer, cursor_char, count.unwrap_or(1).max(1)) else {
+            return EditorAction::None;
+        };
+
+        let from = buffer.line_col(range.start);
+        let to = buffer.line_col(range.end.saturating_sub(1).max(range.start));
+        self.run_operator(operator, register, from, to, range, object.is_linewise())
+    }
+
+    /// Runs an Operator over a char range spanning the lines `from.0..=to.0`.
+    fn run_operator(
+        &mut self,
+        operator: Operator,
+        register: Option<char>,
+        from: (usize, usize),
+        to: (usize, usize),
+        range: std::ops::Range<usize>,
+        linewise: bool,
+    ) -> EditorAction {
+        let mut text = self.get_active_buffer().text.slice_to_string(range.clone());
+        if linewise && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let kind = if linewise {
+            SelectionKind::Linewise
+        } else {
+            SelectionKind::Charwise
+        };
+        let content = Register { text, kind };
+
+        match operator {
+            Operator::Yank => {
+                let message = if linewise {
+                    format!("{} lines yanked", to.0 - from.0 + 1)
+                } else {
+                    format!("{} characters yanked", content.text.chars().count())
+                };
+                self.registers.record_yank(register, content);
+                self.view.cursor_line = from.0;
+                self.view.cursor_col = from.1;
+                self.set_error_line(message);
+            }
+            Operator::Delete => {
+                self.registers.record_delete(register, content);
+                let buffer = self.get_active_buffer_mut();
+                buffer.commit_undo_step();
+
+                if linewise {
+                    buffer.delete_lines(from.0, to.0);
+                    self.view.cursor_line = from.0.min(buffer.line_count().saturating_sub(1));
+                    self.move_to_line_start_non_whitespace();
+                } else {
+                    buffer.delete_range(range.clone());
+                    let (line, col) = buffer.line_col(range.start);
+                    self.view.cursor_line = line;
+                    self.view.cursor_col = col;
+                    self.clamp_cursor_col();
+                }
+            }
+            Operator::Change => {
+                self.registers.record_delete(register, content);
+                let buffer = self.get_active_buffer_mut();
+                buffer.commit_undo_step();
+
+                let mut range = range;
+                // Changing lines keeps an empty line to type into
+                if linewise && buffer.text.char_at(range.end.saturating_sub(1)) == Some('\n') {
+                    range.end -= 1;
+                }
+                let cursor_char = range.start;
+                buffer.delete_range(range);
+
+                let (line, col) = buffer.line_col(cursor_char);
+                self.view.cursor_line = line;
+                self.view.cursor_col = col;
+                return EditorAction::EnterEditModeAfterChange;
+            }
+            Operator::IndentRight | Operator::IndentLeft => {
+                let buffer = self.get_active_buffer_mut();
+                buffer.commit_undo_step();
+
+                for line in from.0..=to.0 {
+                    if operator == Operator::IndentRight {
+                        if !is_blank_line(buffer, line) {
+                            buffer.insert_char(line, 0, '\t');
+                        }
+                    } else {
+                        let leading: Vec<char> = buffer.text.line(line).chars().take(4).collect();
+                        let remove = if leading.first() == Some(&'\t') {
+                            1
+                        } else {
+                            leading.iter().take_while(|c| **c == ' ').count()
+                        };
+                        let line_start = buffer.char_index(line, 0);
+                        buffer.delete_range(line_start..line_start + remove);
+                    }
+                }
+
+                self.view.cursor_line = from.0;
+                self.move_to_line_start_non_whitespace();
+            }
+            Operator::ToggleCase | Operator::Lowercase | Operator::Uppercase => {
+                let buffer = self.get_active_buffer_mut();
+                buffer.commit_undo_step();
+
+                let original = buffer.delete_range(range.clone());
+                buffer.insert_text(range.start, &convert_case(&original, operator));
+
+                self.view.cursor_line = from.0;
+                self.view.cursor_col = from.1;
+            }
+        }
+        EditorAction::None
+    }
+
+    /// Runs an Operator over the rectangle of a blockwise selection.
+    fn run_block_operator(
+        &mut self,
+        operator: Operator,
+        register: Option<char>,
+        selection: Selection,
+    ) -> EditorAction {
+        let (first, last) = selection.line_span();
+        let (left, right) = selection.block_columns();
+
+        if matches!(operator, Operator::IndentRight | Operator::IndentLeft) {
+            let range = self.operator_range((first, 0), (last, 0), true, true);
+            return self.run_operator(operator, register, (first, 0), (last, 0), range, true);
+        }
+
+        let buffer = self.get_active_buffer();
+        let block_ranges: Vec<std::ops::Range<usize>> = (first..=last)
+            .map(|line| {
+                let len = buffer.line_content_length(line);
+                buffer.char_index(line, left.min(len))
+                    ..buffer.char_index(line, (right + 1).min(len))
+            })
+            .collect();
+        let pieces: Vec<String> = block_ranges
+            .iter()
+            .map(|range| buffer.text.slice_to_string(range.clone()))
+            .collect();
+        let content = Register {
+            text: pieces.join("\n"),
+            kind: SelectionKind::Blockwise,
+        };
+
+        self.view.cursor_line = first;
+        self.view.cursor_col = left;
+
+        if operator == Operator::Yank {
+            self.registers.record_yank(register, content);
+            self.set_error_line(format!("block of {} lines yanked", last - first + 1));
+            return EditorAction::None;
+        }
+
+        if matches!(operator, Operator::Delete | Operator::Change) {
+            self.registers.record_delete(register, content);
+        }
+
+        let buffer = self.get_active_buffer_mut();
+        buffer.commit_undo_step();
+
+        // Work bottom-up so earlier ranges stay valid
+        for (range, piece) in block_ranges.into_iter().zip(pieces).rev() {
+            buffer.delete_range(range.clone());
+            if !matches!(operator, Operator::Delete | Operator::Change) {
+                buffer.insert_text(range.start, &convert_case(&piece, operator));
+            }
+        }
+
+        if operator == Operator::Change {
+            self.block_insert = Some(BlockInsert {
+                line: first,
+                last_line: last,
+                col: left,
+            });
+            return EditorAction::EnterEditModeAfterChange;
+        }
+        self.clamp_cursor_col();
+        EditorAction::None
+    }
+
+    /// Puts register contents after (or before) the cursor, `count` times.
+    pub fn put(&mut self, register: Option<char>, count: Option<usize>, before: bool) {
+        let Some(content) = self.registers.get(register).cloned() else {
+            self.set_error_line(format!("Nothing in register {}", register.unwrap_or('"')));
+            return;
+        };
+        self.get_active_buffer_mut().commit_undo_step();
+        self.put_content(content, count.unwrap_or(1).max(1), before);
+    }
+
+    /// Inserts register contents at the cursor. The edits join the undo step being
+    /// recorded, so a put undone on its own needs `commit_undo_step` before it.
+    fn put_content(&mut self, content: Register, count: usize, before: bool) {
+        let (cursor_line, cursor_col) = (self.view.cursor_line, self.view.cursor_col);
+        let buffer = self.get_active_buffer_mut();
+
+        match content.kind {
+            SelectionKind::Linewise => {
+                let mut text = content.text.repeat(count);
+                let line = if before { cursor_line } else { cursor_line + 1 };
+                let at = buffer.char_index(line, 0);
+
+                // The last line has no line break to put after
+                if at > 0 && at == buffer.char_count() && buffer.text.char_at(at - 1) != Some('\n')
+                {
+                    text.pop();
+                    text.insert(0, '\n');
+                }
+                buffer.insert_text(at, &text);
+
+                self.view.cursor_line = line.min(buffer.line_count().saturating_sub(1));
+                self.move_to_line_start_non_whitespace();
+            }
+            SelectionKind::Charwise => {
+                let text = content.text.repeat(count);
+                let mut at = buffer.char_index(cursor_line, cursor_col);
+                if !before && buffer.text.char_at(at).is_some_and(|c| c != '\n') {
+                    at += 1;
+                }
+                buffer.insert_text(at, &text);
+
+                let (line, col) = buffer.line_col(at + text.chars().count().saturating_sub(1));
+                self.view.cursor_line = line;
+                self.view.cursor_col = col;
+            }
+            SelectionKind::Blockwise => {
+                let col = if !before && buffer.line_content_length(cursor_line) > 0 {
+                    cursor_col + 1
+                } else {
+                    cursor_col
+                };
+
+                for (offset, piece) in content.text.split('\n').enumerate() {
+                    let line = cursor_line + offset;
+                    if line >= buffer.line_count() {
+                        let end = buffer.char_count();
+                        buffer.insert_text(end, "\n");
+                    }
+                    // Pad short lines so every piece lands in the same column
+                    let len = buffer.line_content_length(line);
+                    if len < col {
+                        let line_end = buffer.char_index(line, len);
+                        buffer.insert_text(line_end, &" ".repeat(col - len));
+                    }
+                    let at = buffer.char_index(line, col);
+                    buffer.insert_text(at, &piece.repeat(count));
+                }
+
+                self.view.cursor_line = cursor_line;
+                self.view.cursor_col = col;
+            }
+        }
+    }
+
+    fn first_non_whitespace_col(&self, line_idx: usize) -> usize {
+        self.get_active_buffer()
+            .text
+            .line(line_idx)
+            .chars()
+            .take_while(|c| c.is_whitespace() && *c != '\n')
+            .count()
+    }
+
+    /// Converts two cursor positions into the char range an Operator acts on.
+    fn operator_range(
+        &self,
+        from: (usize, usize),
+        to: (usize, usize),
+        linewise: bool,
+        inclusive: bool,
+    ) -> std::ops::Range<usize> {
+        let buffer = self.get_active_buffer();
+
+        if linewise {
+            return buffer.char_index(from.0, 0)..buffer.char_index(to.0 + 1, 0);
+        }
+
+        let start = buffer.char_index(from.0, from.1);
+        let mut end = buffer.char_index(to.0, to.1);
+
+        if inclusive {
+            // Never swallow the line break under an inclusive motion
+            if buffer.text.char_at(end).is_some_and(|c| c != '\n') {
+                end += 1;
+            }
+        } else if to.1 == 0 && to.0 > from.0 {
+            // An exclusive motion ending at column 0 stops at the previous line's end
+            end = end.saturating_sub(1).max(start);
+        }
+
+        start..end
+    }
+}
+
+/// Message for a file just loaded, like `"notes.txt" [latin1] 12L`.
+fn describe_loaded(file_name: &str, buffer: &HBuffer) -> String {
+    let encoding = match buffer.encoding {
+        Encoding::Utf8 => String::new(),
+        Encoding::Raw => " [binary]".to_string(),
+        encoding => format!(" [{}]", encoding.name()),
+    };
+    let large = if buffer.large { " [large]" } else { "" };
+    format!(
+        "\"{}\"{}{} {}L",
+        file_name,
+        encoding,
+        large,
+        buffer.line_count()
+    )
+}
+
+/// Describes how many lines an undo added or took away, like `3 more lines; `.
+fn describe_line_change(before: usize, after: usize) -> String {
+    match after.cmp(&before) {
+        std::cmp::Ordering::Equal => String::new(),
+        std::cmp::Ordering::Greater if after - before == 1 => "1 more line; ".to_string(),
+        std::cmp::Ordering::Greater => format!("{} more lines; ", after - before),
+        std::cmp::Ordering::Less if before - after == 1 => "1 line less; ".to_string(),
+        std::cmp::Ordering::Less => format!("{} fewer lines; ", before - after),
+    }
+}
+
+fn is_blank_line(buffer: &HBuffer, line_idx: usize) -> bool {
+    buffer.text.line(line_idx).trim().is_empty()
+}
+
+fn convert_case(text: &str, operator: Operator) -> String {
+    match operator {
+        Operator::Lowercase => text.to_lowercase(),
+        Operator::Uppercase => text.to_uppercase(),
+        _ => text
+            .chars()
+            .flat_map(|c| {
+                if c.is_uppercase() {
+                    c.to_lowercase().collect::<Vec<char>>()
+                } else {
+                    c.to_uppercase().collect::<Vec<char>>()
+                }
+            })
+            .collect(),
+    }
+}
+
+impl Editor<NavigateMode> {
+    pub fn handle_input(&mut self, key: KeyEvent) -> EditorAction {
+        let mut action = EditorAction::None;
+
+        if self.get_active_buffer().found_swap.is_some() {
+            self.input_seq.clear();
+            self.answer_swap_prompt(key);
+            return EditorAction::None;
+        }
+        // Whatever is typed into a stand-in would be lost once its file is loaded
+        if self.get_active_buffer().loading
+            && !matches!(key.code, Char(':') | KeyCode::Tab | KeyCode::BackTab)
+        {
+            return EditorAction::None;
+        }
+
+        if key.modifiers.contains(KeyModifiers::CONTROL)
+            && key.code == Char('v')
+            && !self.get_active_buffer().hex
+        {
+            self.input_seq.clear();
+            self.select_kind = SelectionKind::Blockwise;
+            return EditorAction::EnterSelectMode;
+        }
+        if self.window_prefix {
+            self.window_prefix = false;
+            return self.window_command(key);
+        }
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == Char('w') {
+            self.input_seq.clear();
+            self.window_prefix = true;
+            return EditorAction::None;
+        }
+        if self.get_active_buffer().hex {
+            return self.handle_hex_input(key);
+        }
+        // Most terminals send Ctrl-I as Tab, which switches buffers instead
+        if key.modifiers.contains(KeyModifiers::CONTROL)
+            && let Char(c @ ('o' | 'i')) = key.code
+        {
+            self.input_seq.clear();
+            self.walk_jumps(c == 'o');
+            return EditorAction::None;
+        }
+
+        // Counts, operators and motions are parsed as one pending sequence
+        if let Char(c) = key.code
+            && (!self.input_seq.is_empty() || grammar::starts_sequence(c))
+        {
+            self.input_seq.push(c);
+            return match grammar::parse(&self.input_seq) {
+                ParseResult::Pending => EditorAction::None,
+                ParseResult::Complete(command) => {
+                    self.input_seq.clear();
+                    self.execute_normal_command(command)
+                }
+                ParseResult::Invalid => {
+                    self.input_seq.clear();
+                    EditorAction::None
+                }
+            };
+        }
+        self.input_seq.clear();
+
+        match key.code {
+            Char('i') => action = EditorAction::EnterEditMode,
+            Char('a') => {
+                self.move_cursor_right();
+                action = EditorAction::EnterEditMode;
+            }
+            Char('o') => {
+                action = EditorAction::EnterEditModeInNewLine;
+            }
+            Char(':') => action = self.open_prompt(':'),
+            Char('/') => action = self.open_prompt('/'),
+            Char('?') => action = self.open_prompt('?'),
+            Char('v') => {
+                self.select_kind = SelectionKind::Charwise;
+                action = EditorAction::EnterSelectMode;
+            }
+            Char('V') => {
+                self.select_kind = SelectionKind::Linewise;
+                action = EditorAction::EnterSelectMode;
+            }
+            Char('u') => self.undo(),
+            Char('U') => self.redo(),
+            KeyCode::Tab => self.buffer_switch_forward(),
+            KeyCode::BackTab => self.buffer_switch_backward(),
+            _ => {}
+        }
+        action
+    }
+
+    /// Handles a key in a hex buffer, which moves by bytes: `h` and `l` by one,
+    /// `j` and `k` by a row, `0` and `$` to the ends of the row, `gg` and `G` to
+    /// the ends of the buffer. `i` starts overwriting bytes.
+    fn handle_hex_input(&mut self, key: KeyEvent) -> EditorAction {
+        let offset = self.hex_offset();
+        let page = (self.get_focused_window_area().height as usize)
+            .saturating_sub(2)
+            .max(1)
+            * hex::BYTES_PER_ROW;
+        let row_start = offset - offset % hex::BYTES_PER_ROW;
+
+        if key.code == Char('g') && self.input_seq.is_empty() {
+            self.input_seq.push('g');
+            return EditorAction::None;
+        }
+        let pending_g = std::mem::take(&mut self.input_seq) == "g";
+
+        match key.code {
+            Char('g') if pending_g => self.set_hex_offset(0, false),
+            Char('h') | KeyCode::Left | KeyCode::Backspace => {
+                self.set_hex_offset(offset.saturating_sub(1), false)
+            }
+            Char('l') | Char(' ') | KeyCode::Right => self.set_hex_offset(offset + 1, false),
+            Char('k') | KeyCode::Up => self.set_hex_offset(
+                offset.checked_sub(hex::BYTES_PER_ROW).unwrap_or(offset),
+                false,
+            ),
+            Char('j') | KeyCode::Down
+                if offset + hex::BYTES_PER_ROW < self.get_active_buffer().char_count() =>
+            {
+                self.set_hex_offset(offset + hex::BYTES_PER_ROW, false)
+            }
+            Char('0') | KeyCode::Home => self.set_hex_offset(row_start, false),
+            Char('$') | KeyCode::End => {
+                self.set_hex_offset(row_start + hex::BYTES_PER_ROW - 1, false)
+            }
+            Char('G') => self.set_hex_offset(usize::MAX, false),
+            KeyCode::PageUp => self.set_hex_offset(offset.saturating_sub(page), false),
+            KeyCode::PageDown => self.set_hex_offset(offset + page, false),
+            Char('i') => {
+                self.hex_cursor.low_nibble = false;
+                return EditorAction::EnterEditMode;
+            }
+            Char(':') => return self.open_prompt(':'),
+            Char('u') => self.undo(),
+            Char('U') => self.redo(),
+            KeyCode::Tab => self.buffer_switch_forward(),
+            KeyCode::BackTab => self.buffer_switch_backward(),
+            _ => {}
+        }
+        EditorAction::None
+    }
+
+    fn execute_normal_command(&mut self, command: NormalCommand) -> EditorAction {
+        match command {
+            NormalCommand::Move { count, motion } => {
+                self.apply_motion(motion, count);
+                EditorAction::None
+            }
+            NormalCommand::Operate {
+                count,
+                register,
+                operator,
+                target,
+            } => self.apply_operator(operator, target, count, register),
+            NormalCommand::Put {
+                count,
+                register,
+                before,
+            } => {
+                self.put(register, count, before);
+                EditorAction::None
+            }
+            NormalCommand::Fold(command) => {
+                if let Err(e) = self.fold(command) {
+                    self.set_error_line(e);
+                }
+                EditorAction::None
+            }
+            NormalCommand::Lsp(command) => {
+                if let Err(e) = self.request_language_server(command) {
+                    self.set_error_line(e);
+                }
+                EditorAction::None
+            }
+            NormalCommand::UndoTravel { count, backward } => {
+                self.undo_travel(UndoDistance::Steps(count.unwrap_or(1)), backward);
+                EditorAction::None
+            }
+            // `3gt` goes to the third tab page, `3gT` goes back three
+            NormalCommand::TabPage { count, backward } => {
+                match (count, backward) {
+                    (Some(number), false) => self.focus_tab(number - 1),
+                    (count, _) => self.cycle_tabs(count.unwrap_or(1), backward),
+                }
+                EditorAction::None
+            }
+        }
+    }
+
+    pub fn enter_edit_mode(mut self) -> Editor<EditMode> {
+        self.get_active_buffer_mut().commit_undo_step();
+        self.transition()
+    }
+
+    pub fn enter_edit_mode_after_change(self) -> Editor<EditMode> {
+        self.transition()
+    }
+
+    pub fn enter_command_mode(self) -> Editor<CommandMode> {
+        self.transition()
+    }
+
+    pub fn enter_select_mode(mut self) -> Editor<SelectMode> {
+        self.select_anchor = (self.view.cursor_line, self.view.cursor_col);
+        self.transition()
+    }
+}
+
+impl Editor<EditMode> {
+    /// Everything typed since entering Edit Mode is undone as one change.
+    pub fn enter_navigate_mode(mut self) -> Editor<NavigateMode> {
+        self.get_active_buffer_mut().commit_undo_step();
+        self.close_completion_menu();
+        self.transition()
+    }
+
+    pub fn enter_select_mode(mut self) -> Editor<SelectMode> {
+        self.get_active_buffer_mut().commit_undo_step();
+        self.close_completion_menu();
+        self.block_insert = None;
+        self.select_anchor = (self.view.cursor_line, self.view.cursor_col);
+        self.transition()
+    }
+
+    /// Handles a key in the Edit Mode of a hex buffer, which overwrites bytes: two
+    /// hex digits make a byte, or an ASCII char does after Tab switches columns.
+    ///
+    /// Typing past the last byte appends one.
+    fn handle_hex_edit_input(&mut self, key: KeyEvent) -> EditorAction {
+        let offset = self.hex_offset();
+        let cursor = self.hex_cursor;
+        match key.code {
+            KeyCode::Esc | KeyCode::CapsLock => {
+                self.set_hex_offset(offset, false);
+                return EditorAction::EnterNavigateMode;
+            }
+            KeyCode::Tab => {
+                self.hex_cursor = HexCursor {
+                    ascii: !cursor.ascii,
+                    low_nibble: false,
+                };
+            }
+            Char(c) if cursor.ascii && (c == ' ' || c.is_ascii_graphic()) => {
+                self.overwrite_byte(offset, c as u8);
+                self.set_hex_offset(offset + 1, true);
+            }
+            Char(c) if !cursor.ascii && c.is_ascii_hexdigit() => {
+                let digit = c.to_digit(16).unwrap_or(0) as u8;
+                let old = hex::byte_at(&self.get_active_buffer().text, offset).unwrap_or(0);
+                self.hex_cursor.low_nibble = !cursor.low_nibble;
+                if cursor.low_nibble {
+                    self.overwrite_byte(offset, (old & 0xF0) | digit);
+                    self.set_hex_offset(offset + 1, true);
+                } else {
+                    self.overwrite_byte(offset, (digit << 4) | (old & 0x0F));
+                }
+            }
+            code => {
+                let target = match code {
+                    KeyCode::Left | KeyCode::Backspace => offset.checked_sub(1),
+                    KeyCode::Right => Some(offset + 1),
+                    KeyCode::Up => offset.checked_sub(hex::BYTES_PER_ROW),
+                    KeyCode::Down => Some(offset + hex::BYTES_PER_ROW),
+                    _ => None,
+                };
+                if let Some(target) = target {
+                    self.hex_cursor.low_nibble = false;
+                    self.set_hex_offset(target, true);
+                }
+            }
+        }
+        EditorAction::None
+    }
+
+    /// Replaces the byte at `offset` of a hex buffer, or appends one at its end.
+    fn overwrite_byte(&mut self, offset: usize, byte: u8) {
+        let buffer = self.get_active_buffer_mut();
+        let end = (offset + 1).min(buffer.char_count());
+        buffer.replace_range(offset..end, &encoding::raw_char(byte).to_string());
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        let buffer = &mut self.buffers[self.current_focused_index];
+        buffer.insert_char(self.view.cursor_line, self.view.cursor_col, c);
+        self.view.cursor_col += 1;
+    }
+
+    pub fn insert_line(&mut self) {
+        self.buffers[self.current_focused_index]
+            .insert_line(self.view.cursor_line, self.view.cursor_col);
+        self.view.cursor_line += 1;
+        self.move_cursor_start();
+    }
+
+    pub fn delete_char(&mut self) {
+        let buffer = &mut self.buffers[self.current_focused_index];
+
+        if self.view.cursor_col == 0 {
+            if self.view.cursor_line > 0 {
+                let prev_line_idx = self.view.cursor_line - 1;
+                let prev_line_len = buffer.line_length(prev_line_idx);
+
+                // The newline character is at len - 1
+                let new_cursor_col = if prev_line_len > 0 {
+                    prev_line_len - 1
+                } else {
+                    0
+                };
+
+                buffer.delete_char(prev_line_idx, new_cursor_col);
+
+                self.view.cursor_line = prev_line_idx;
+                self.view.cursor_col = new_cursor_col;
+            }
+        } else {
+            buffer.delete_char(self.view.cursor_line, self.view.cursor_col - 1);
+            self.view.cursor_col -= 1;
+        }
+    }
+
+    pub fn delete_line(&mut self) {
+        let buffer = &mut self.buffers[self.current_focused_index];
+
+        let line_to_delete = self.view.cursor_line;
+        self.view.cursor_line -= 1;
+        buffer.delete_line(line_to_delete);
+    }
+
+    pub fn open_line_below(&mut self) {
+        let buffer = &mut self.buffers[self.current_focused_index];
+        let len = buffer.line_length(self.view.cursor_line);
+
+        buffer.insert_char(self.view.cursor_line, len, '\n');
+        self.view.cursor_line += 1;
+        self.view.cursor_col = 0;
+    }
+
+    /// Repeats the text typed since a blockwise change on the rest of the block.
+    fn finish_block_insert(&mut self) {
+        let Some(block) = self.block_insert.take() else {
+            return;
+        };
+        if self.view.cursor_line != block.line || self.view.cursor_col <= block.col {
+            return;
+        }
+
+        let buffer = &mut self.buffers[self.current_focused_index];
+        let start = buffer.char_index(block.line, block.col);
+        let end = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+        let typed = buffer.text.slice_to_string(start..end);
+
+        for line in block.line + 1..=block.last_line {
+            if buffer.line_content_length(line) >= block.col {
+                let at = buffer.char_index(line, block.col);
+                buffer.insert_text(at, &typed);
+            }
+        }
+    }
+
+    fn close_completion_menu(&mut self) {
+        self.completion_menu = None;
+        self.lsp.cancel_completion();
+    }
+
+    /// Column where the word before the cursor starts.
+    fn completed_word_start(&self) -> usize {
+        let buffer = &self.buffers[self.current_focused_index];
+        let line_start = buffer.char_index(self.view.cursor_line, 0);
+        let mut start = self.view.cursor_col;
+        while start > 0
+            && buffer
+                .text
+                .char_at(line_start + start - 1)
+                .is_some_and(|c| c.is_alphanumeric() || c == '_')
+        {
+            start -= 1;
+        }
+        start
+    }
+
+    /// Asks the language server for completions of the word before the cursor.
+    fn request_completion(&mut self) {
+        self.lsp.sync(&mut self.buffers);
+        let start = self.completed_word_start();
+        let buffer = &self.buffers[self.current_focused_index];
+        let (line, col) = (self.view.cursor_line, self.view.cursor_col);
+        if let Err(e) = self
+            .lsp
+            .request_completion(buffer, (line, col), (line, start))
+        {
+            self.set_error_line(e);
+        }
+    }
+
+    /// Opens the completion menu with the words of the open buffers completing the
+    /// word before the cursor, the last one selected when going `backward`.
+    fn complete_keyword(&mut self, backward: bool) {
+        let start = self.completed_word_start();
+        let (line, col) = (self.view.cursor_line, self.view.cursor_col);
+        let items = keyword::completions(
+            &self.buffers,
+            self.current_focused_index,
+            (line, col),
+            start,
+        );
+        if items.is_empty() {
+            self.set_error_line("No completions".to_string());
+            return;
+        }
+        // A language server's answer would replace this menu
+        self.lsp.cancel_completion();
+        let mut menu = CompletionMenu::new(line, start, items);
+        if backward {
+            menu.select_next(true);
+        }
+        self.completion_menu = Some(menu);
+    }
+
+    /// Handles a key while the completion menu is open. Returns false for keys
+    /// it leaves to Edit Mode.
+    fn handle_menu_input(&mut self, key: KeyEvent) -> bool {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        let Some(menu) = &mut self.completion_menu else {
+            return false;
+        };
+        match key.code {
+            Char('n') if control => menu.select_next(false),
+            Char('p') if control => menu.select_next(true),
+            KeyCode::Down => menu.select_next(false),
+            KeyCode::Up => menu.select_next(true),
+            Char('y') if control => self.accept_completion(),
+            KeyCode::Enter | KeyCode::Tab => self.accept_completion(),
+            Char('e') if control => self.close_completion_menu(),
+            // Esc also leaves Edit Mode
+            KeyCode::Esc => {
+                self.close_completion_menu();
+                return false;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Replaces the word being completed with the selected candidate, which is
+    /// undone on its own.
+    fn accept_completion(&mut self) {
+        let Some(menu) = self.completion_menu.take() else {
+            return;
+        };
+        self.lsp.cancel_completion();
+        let Some(item) = menu.selected() else {
+            return;
+        };
+        let buffer = &mut self.buffers[self.current_focused_index];
+        let start = buffer.char_index(menu.line, menu.start_col);
+        let end = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+        buffer.commit_undo_step();
+        buffer.replace_range(start..end, &item.insert);
+        buffer.commit_undo_step();
+        (self.view.cursor_line, self.view.cursor_col) =
+            buffer.line_col(start + item.insert.chars().count());
+    }
+
+    /// Filters the completion menu by what's typed now, closing it once the
+    /// cursor has left the completed word.
+    fn update_completion_menu(&mut self) {
+        let Some(menu) = &mut self.completion_menu else {
+            return;
+        };
+        let (line, col) = (self.view.cursor_line, self.view.cursor_col);
+        let buffer = &self.buffers[self.current_focused_index];
+        if line != menu.line || col < menu.start_col {
+            self.close_completion_menu();
+            return;
+        }
+        let typed = buffer
+            .text
+            .slice_to_string(buffer.char_index(line, menu.start_col)..buffer.char_index(line, col));
+        if !menu.filter(&typed) {
+            self.close_completion_menu();
+        }
+    }
+
+    pub fn handle_input(&mut self, key: KeyEvent) -> EditorAction {
+        if self.get_active_buffer().hex {
+            return self.handle_hex_edit_input(key);
+        }
+        if self.handle_menu_input(key) {
+            return EditorAction::None;
+        }
+        if key.modifiers.contains(KeyModifiers::CONTROL) {
+            match key.code {
+                Char(' ') => {
+                    self.request_completion();
+                    return EditorAction::None;
+                }
+                Char('n') | Char('p') => {
+                    self.complete_keyword(key.code == Char('p'));
+                    return EditorAction::None;
+                }
+                _ => {}
+            }
         }
+        let action = match key.code {
+            KeyCode::Esc | KeyCode::CapsLock => {
+                self.finish_block_insert();
+                EditorAction::EnterNavigateMode
+            }
+            KeyCode::Char(c) => {
+                self.insert_char(c);
+                let buffer = &self.buffers[self.current_focused_index];
+                if self.completion_menu.is_none() && self.lsp.is_trigger_character(buffer, c) {
+                    self.request_completion();
+                }
+                EditorAction::None
+            }
+            KeyCode::Backspace => {
+                self.delete_char();
+                EditorAction::None
+            }
+            KeyCode::Delete => {
+                self.delete_char();
+                EditorAction::None
+            }
+            KeyCode::Up => {
+                self.move_cursor_up();
+                EditorAction::None
+            }
+            KeyCode::Left => {
+                self.move_cursor_left();
+                EditorAction::None
+            }
+            KeyCode::Right => {
+                self.move_cursor_right();
+                EditorAction::None
+            }
+            KeyCode::Down => {
+                self.move_cursor_down();
+                EditorAction::None
+            }
+            KeyCode::Enter => {
+                self.insert_line();
+                EditorAction::None
+            }
+            KeyCode::Tab => {
+                self.insert_char('\t');
+                EditorAction::None
+            }
+            KeyCode::Home => {
+                self.move_cursor_start();
+                EditorAction::None
+            }
+            _ => EditorAction::None,
+        };
+        self.update_completion_menu();
+        action
     }
 }
 
-impl Editor<NavigateMode> {
+impl Editor<SelectMode> {
+    pub fn enter_navigate_mode(mut self) -> Editor<NavigateMode> {
+        self.input_seq.clear();
+        self.remember_selection();
+        self.transition()
+    }
+
+    pub fn enter_command_mode(mut self) -> Editor<CommandMode> {
+        self.remember_selection();
+        self.transition()
+    }
+
+    pub fn enter_edit_mode(mut self) -> Editor<EditMode> {
+        self.remember_selection();
+        self.transition()
+    }
+
+    pub fn enter_edit_mode_after_change(mut self) -> Editor<EditMode> {
+        self.input_seq.clear();
+        self.remember_selection();
+        self.transition()
+    }
+
+    /// Keeps the selected lines for the `'<` and `'>` marks.
+    fn remember_selection(&mut self) {
+        self.view.last_selection = Some(self.get_selection().line_span());
+    }
+
+    pub fn get_selection(&self) -> Selection {
+        Selection {
+            anchor: self.select_anchor,
+            cursor: (self.view.cursor_line, self.view.cursor_col),
+            kind: self.select_kind,
+        }
+    }
+
+    /// Runs an Operator over the selection.
+    pub fn apply_selection_operator(
+        &mut self,
+        operator: Operator,
+        register: Option<char>,
+    ) -> EditorAction {
+        let selection = self.get_selection();
+        let (from, to) = selection.ordered();
+
+        let action = match selection.kind {
+            SelectionKind::Charwise => {
+                let range = self.operator_range(from, to, false, true);
+                self.run_operator(operator, register, from, to, range, false)
+            }
+            SelectionKind::Linewise => {
+                let (from, to) = ((from.0, 0), (to.0, 0));
+                let range = self.operator_range(from, to, true, true);
+                self.run_operator(operator, register, from, to, range, true)
+            }
+            SelectionKind::Blockwise => self.run_block_operator(operator, register, selection),
+        };
+
+        match action {
+            EditorAction::None => EditorAction::EnterNavigateMode,
+            action => action,
+        }
+    }
+
+    /// Replaces the selection with register contents.
+    pub fn put_over_selection(&mut self, register: Option<char>) {
+        let Some(mut content) = self.registers.get(register).cloned() else {
+            self.set_error_line(format!("Nothing in register {}", register.unwrap_or('"')));
+            return;
+        };
+        let selection = self.get_selection();
+        let (from, to) = selection.ordered();
+        self.get_active_buffer_mut().commit_undo_step();
+
+        let mut before = true;
+        let replaced = match selection.kind {
+            SelectionKind::Charwise => {
+                let range = self.operator_range(from, to, false, true);
+                let buffer = self.get_active_buffer_mut();
+                let replaced = buffer.delete_range(range.clone());
+                (self.view.cursor_line, self.view.cursor_col) = buffer.line_col(range.start);
+
+                // Lines put into the middle of a line get a line of their own
+                if content.kind == SelectionKind::Linewise && self.view.cursor_col > 0 {
+                    content.kind = SelectionKind::Charwise;
+                    content.text.insert(0, '\n');
+                }
+                Register {
+                    text: replaced,
+                    kind: SelectionKind::Charwise,
+                }
+            }
+            SelectionKind::Linewise => {
+                let buffer = self.get_active_buffer_mut();
+                let replaced = buffer.delete_lines(from.0, to.0);
+
+                if content.kind != SelectionKind::Linewise {
+                    content.kind = SelectionKind::Linewise;
+                    content.text.push('\n');
+                }
+                // With the last lines gone there is nothing left to put before
+                if from.0 >= buffer.line_count() {
+                    self.view.cursor_line = buffer.line_count() - 1;
+                    before = false;
+                } else {
+                    self.view.cursor_line = from.0;
+                }
+                Register {
+                    text: replaced,
+                    kind: SelectionKind::Linewise,
+                }
+            }
+            SelectionKind::Blockwise => {
+                let replaced = self.run_block_delete(selection);
+                self.view.cursor_line = from.0;
+                self.view.cursor_col = selection.block_columns().0;
+                Register {
+                    text: replaced,
+                    kind: SelectionKind::Blockwise,
+                }
+            }
+        };
+
+        self.put_content(content, 1, before);
+        self.registers.record_delete(None, replaced);
+    }
+
+    /// Removes the text of a blockwise selection without touching registers or history.
+    fn run_block_delete(&mut self, selection: Selection) -> String {
+        let (first, last) = selection.line_span();
+        let (left, right) = selection.block_columns();
+        let buffer = self.get_active_buffer_mut();
+
+        let mut pieces: Vec<String> = (first..=last)
+            .rev()
+            .map(|line| {
+                let len = buffer.line_content_length(line);
+                let start = buffer.char_index(line, left.min(len));
+                let end = buffer.char_index(line, (right + 1).min(len));
+                buffer.delete_range(start..end)
+            })
+            .collect();
+        pieces.reverse();
+        pieces.join("\n")
+    }
+
+    /// Switches to another kind of selection, or leaves Select Mode when it's the current one.
+    fn switch_selection_kind(&mut self, kind: SelectionKind) -> EditorAction {
+        if self.select_kind == kind {
+            return EditorAction::EnterNavigateMode;
+        }
+        self.select_kind = kind;
+        EditorAction::None
+    }
+
+    fn execute_select_command(&mut self, command: SelectCommand) -> EditorAction {
+        match command {
+            SelectCommand::Move { count, motion } => {
+                self.apply_motion(motion, count);
+                EditorAction::None
+            }
+            SelectCommand::Operate { register, operator } => {
+                self.apply_selection_operator(operator, register)
+            }
+            SelectCommand::Put { register } => {
+                self.put_over_selection(register);
+                EditorAction::EnterNavigateMode
+            }
+            SelectCommand::SelectObject { count, object } => {
+                self.select_text_object(object, count);
+                EditorAction::None
+            }
+        }
+    }
+
+    /// Selects the text object around the cursor, or grows the selection
+    /// to the next object when it already covers this one.
+    pub fn select_text_object(&mut self, object: TextObject, count: Option<usize>) {
+        let selection = self.get_selection();
+        let (from, to) = selection.ordered();
+        self.get_active_buffer_mut().parse_tree();
+        let buffer = self.get_active_buffer();
+        let cursor_char = buffer.char_index(self.view.cursor_line, self.view.cursor_col);
+        let selected = buffer.char_index(from.0, from.1)..buffer.char_index(to.0, to.1) + 1;
+
+        let mut range = object.range(buffer, cursor_char, count.unwrap_or(1).max(1));
+        if range.as_ref().is_some_and(|r| {
+            r.start >= selected.start
+                && r.end <= selected.end
+                && (selection.anchor != selection.cursor || *r == selected)
+        }) {
+            range = object.grow(buffer, selected);
+        }
+
+        let Some(range) = range.filter(|r| !r.is_empty()) else {
+            return;
+        };
+        let (anchor, cursor) = (buffer.line_col(range.start), buffer.line_col(range.end - 1));
+        self.select_anchor = anchor;
+        (self.view.cursor_line, self.view.cursor_col) = cursor;
+        if object.is_linewise() {
+            self.select_kind = SelectionKind::Linewise;
+        }
+    }
+
     pub fn handle_input(&mut self, key: KeyEvent) -> EditorAction {
-        let mut action = EditorAction::None;
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == Char('v') {
+            self.input_seq.clear();
+            return self.switch_selection_kind(SelectionKind::Blockwise);
+        }
 
-        // Handle pending sequences (like 'd' waiting for 'w')
-        if self.input_seq == "d" {
-            if let Char('w') = key.code {
-                self.delete_to_next_whitespace();
-                self.input_seq.clear();
-                return EditorAction::None;
+        if let Char(c) = key.code
+            && (!self.input_seq.is_empty() || grammar::starts_select_sequence(c))
+        {
+            self.input_seq.push(c);
+            return match grammar::parse_select(&self.input_seq) {
+                ParseResult::Pending => EditorAction::None,
+                ParseResult::Complete(command) => {
+                    self.input_seq.clear();
+                    self.execute_select_command(command)
+                }
+                ParseResult::Invalid => {
+                    self.input_seq.clear();
+                    EditorAction::None
+                }
+            };
+        }
+        self.input_seq.clear();
+
+        match key.code {
+            KeyCode::Esc => EditorAction::EnterNavigateMode,
+            KeyCode::CapsLock => EditorAction::EnterNavigateMode,
+            Char('v') => self.switch_selection_kind(SelectionKind::Charwise),
+            Char('V') => self.switch_selection_kind(SelectionKind::Linewise),
+            Char('o') => {
+                // Jump to the other end of the selection
+                let cursor = (self.view.cursor_line, self.view.cursor_col);
+                (self.view.cursor_line, self.view.cursor_col) = self.select_anchor;
+                self.select_anchor = cursor;
+                EditorAction::None
+            }
+            Char(':') => {
+                // Commands typed from a selection run on the selected lines
+                self.command_line = "'<,'>".to_string();
+                self.completion = None;
+                self.open_prompt(':')
+            }
+            _ => EditorAction::None,
+        }
+    }
+}
+
+impl Editor<CommandMode> {
+    pub fn enter_navigate_mode(self) -> Editor<NavigateMode> {
+        self.transition()
+    }
+
+    pub fn clear_command_line(&mut self) {
+        self.command_line.clear();
+        self.command_cursor = 0;
+        self.completion = None;
+    }
+
+    /// Replaces the command line, leaving the cursor at its end.
+    fn set_command_line(&mut self, text: String) {
+        self.command_cursor = text.len();
+        self.command_line = text;
+    }
+
+    /// Column of the cursor on the command line, after the prompt.
+    ///
+    /// None while a substitution is being confirmed.
+    pub fn get_command_cursor(&self) -> Option<usize> {
+        if self.substitution.is_some() {
+            return None;
+        }
+        Some(1 + self.command_line[..self.command_cursor].chars().count())
+    }
+
+    fn history(&mut self) -> &mut History {
+        if self.is_search_prompt() {
+            &mut self.search_history
+        } else {
+            &mut self.command_history
+        }
+    }
+
+    /// Byte offset of the char boundary before (or after) the command line cursor.
+    fn command_char_boundary(&self, forward: bool) -> usize {
+        if forward {
+            self.command_line[self.command_cursor..]
+                .chars()
+                .next()
+                .map_or(self.command_cursor, |c| self.command_cursor + c.len_utf8())
+        } else {
+            self.command_line[..self.command_cursor]
+                .chars()
+                .next_back()
+                .map_or(0, |c| self.command_cursor - c.len_utf8())
+        }
+    }
+
+    /// Deletes the word before the cursor, along with the spaces after it (Ctrl-W).
+    fn delete_word_before_cursor(&mut self) {
+        let before = &self.command_line[..self.command_cursor];
+        let trimmed = before.trim_end();
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let start = match trimmed.chars().next_back() {
+            Some(last) if is_word(last) => trimmed
+                .char_indices()
+                .rev()
+                .find(|(_, c)| !is_word(*c))
+                .map_or(0, |(i, c)| i + c.len_utf8()),
+            Some(last) => trimmed.len() - last.len_utf8(),
+            None => 0,
+        };
+        self.command_line
+            .replace_range(start..self.command_cursor, "");
+        self.command_cursor = start;
+    }
+
+    /// Completes the word before the cursor, or cycles to the next (or previous) candidate.
+    fn complete(&mut self, backward: bool) {
+        if let Some(completion) = &mut self.completion {
+            let count = completion.candidates.len();
+            let current_end = completion.start + completion.candidates[completion.index].len();
+            completion.index = if backward {
+                (completion.index + count - 1) % count
             } else {
-                self.input_seq.clear();
+                (completion.index + 1) % count
+            };
+            let next = completion.candidates[completion.index].clone();
+            let start = completion.start;
+            self.command_line.replace_range(start..current_end, &next);
+            self.command_cursor = start + next.len();
+            return;
+        }
+
+        let before = &self.command_line[..self.command_cursor];
+        let Some((kind, start)) = ex::completion_context(before) else {
+            return;
+        };
+        let word = &before[start..];
+        let candidates = self.completion_candidates(kind, word);
+        let Some(first) = candidates.first().cloned() else {
+            return;
+        };
+
+        self.command_line
+            .replace_range(start..self.command_cursor, &first);
+        self.command_cursor = start + first.len();
+        if candidates.len() > 1 {
+            self.completion = Some(Completion {
+                candidates,
+                index: 0,
+                start,
+            });
+        }
+    }
+
+    fn completion_candidates(&self, kind: CompletionKind, word: &str) -> Vec<String> {
+        let escape = |s: &str| s.replace(' ', "\\ ");
+        let mut candidates: Vec<String> = match kind {
+            CompletionKind::Command => ex::COMMANDS
+                .iter()
+                .map(|spec| spec.name.to_string())
+                .filter(|name| name.starts_with(word))
+                .collect(),
+            CompletionKind::File => file_ops::complete_path(&word.replace("\\ ", " "))
+                .iter()
+                .map(|path| escape(path))
+                .collect(),
+            CompletionKind::Buffer => {
+                let name = word.replace("\\ ", " ");
+                self.buffers
+                    .iter()
+                    .map(|buffer| buffer.display_name())
+                    .filter(|display_name| display_name.contains(&name))
+                    .map(escape)
+                    .collect()
             }
-        } else if self.input_seq == "g" {
-            if let Char('g') = key.code {
-                self.move_to_start_of_file();
-                self.input_seq.clear();
-                return EditorAction::None;
+            CompletionKind::Option => {
+                // `no` and `inv` prefixes are kept in front of the completed name
+                let (prefix, name) = ["no", "inv"]
+                    .iter()
+                    .find_map(|prefix| {
+                        let name = word.strip_prefix(prefix)?;
+                        OPTIONS
+                            .iter()
+                            .any(|spec| spec.name.starts_with(name))
+                            .then_some((*prefix, name))
+                    })
+                    .unwrap_or(("", word));
+                OPTIONS
+                    .iter()
+                    .filter(|spec| spec.name.starts_with(name))
+                    .map(|spec| format!("{}{}", prefix, spec.name))
+                    .collect()
+            }
+        };
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Runs `:set` with each argument. Without arguments, shows every option.
+    fn set_options(&mut self, args: &[String]) -> Result<(), String> {
+        let mut messages = Vec::new();
+        if args.is_empty() {
+            for spec in OPTIONS {
+                messages.extend(self.set_option(&format!("{}?", spec.name))?);
+            }
+        }
+        for arg in args {
+            messages.extend(self.set_option(arg)?);
+        }
+        if !messages.is_empty() {
+            self.set_error_line(messages.join("  "));
+        }
+        Ok(())
+    }
+
+    /// Applies one `:set` argument, to the active buffer for `fileformat`.
+    fn set_option(&mut self, arg: &str) -> Result<Option<String>, String> {
+        let name = arg.split(['=', '?']).next().unwrap_or(arg);
+        if name != "fileformat" && name != "ff" {
+            return self.options.set(arg);
+        }
+
+        let buffer = self.get_active_buffer_mut();
+        match &arg[name.len()..] {
+            "" | "?" => Ok(Some(format!("fileformat={}", buffer.line_ending.name()))),
+            value => {
+                let line_ending = value
+                    .strip_prefix('=')
+                    .and_then(LineEnding::from_name)
+                    .ok_or_else(|| format!("Invalid argument: {}", arg))?;
+                // The text stays the same, but differs from the file until written
+                buffer.line_ending = line_ending;
+                Ok(None)
+            }
+        }
+    }
+
+    fn is_search_prompt(&self) -> bool {
+        self.command_prompt == '/' || self.command_prompt == '?'
+    }
+
+    /// Moves the cursor to the first match of the pattern typed so far.
+    fn update_search_preview(&mut self) {
+        if !self.is_search_prompt() || !self.options.incsearch {
+            return;
+        }
+        let search = Search {
+            pattern: self.command_line.clone(),
+            backward: self.command_prompt == '?',
+            whole_word: false,
+        };
+        let (line, col) = self.search.origin;
+        let buffer = self.get_active_buffer();
+        let origin_char = buffer.char_index(line, col);
+
+        let preview = search
+            .find_from(&buffer.text, origin_char, search.backward)
+            .map(|found| found.range);
+        (self.view.cursor_line, self.view.cursor_col) = match &preview {
+            Some(range) => buffer.line_col(range.start),
+            None => self.search.origin,
+        };
+        self.search.preview = preview;
+    }
+
+    /// Runs the search typed at the prompt. An empty pattern repeats the last search.
+    fn execute_search(&mut self) -> EditorAction {
+        let pattern = std::mem::take(&mut self.command_line);
+        self.command_cursor = 0;
+        if let Err(e) = self.search_history.add(&pattern) {
+            self.set_error_line(format!("Couldn't save search history: {}", e));
+        }
+        let backward = self.command_prompt == '?';
+        self.search.preview = None;
+        (self.view.cursor_line, self.view.cursor_col) = self.search.origin;
+
+        let pattern = if pattern.is_empty() {
+            match &self.search.last {
+                Some(last) => last.pattern.clone(),
+                None => {
+                    self.set_error_line("No previous search".to_string());
+                    return EditorAction::EnterNavigateMode;
+                }
+            }
+        } else {
+            pattern
+        };
+
+        self.search.last = Some(Search {
+            pattern,
+            backward,
+            whole_word: false,
+        });
+        let from = self.search.origin;
+        self.search_next(false);
+        self.record_jump(from);
+        EditorAction::EnterNavigateMode
+    }
+
+    fn cancel_prompt(&mut self) -> EditorAction {
+        self.clear_command_line();
+        self.history().reset();
+        if self.is_search_prompt() {
+            self.search.preview = None;
+            (self.view.cursor_line, self.view.cursor_col) = self.search.origin;
+        }
+        EditorAction::EnterNavigateMode
+    }
+
+    /// What the command line shows: the prompt and the typed text,
+    /// or the question asked while confirming substitutions.
+    pub fn get_command_display(&self) -> String {
+        match &self.substitution {
+            Some(pending) => format!(
+                "replace with {} (y/n/a/q/l)?",
+                pending.substitute.replacement
+            ),
+            None => format!("{}{}", self.command_prompt, self.command_line),
+        }
+    }
+
+    /// Runs `:s/pattern/replacement/flags` on the lines `first..=last`.
+    ///
+    /// Lines are read from the rope one at a time, and the whole substitution is a
+    /// single undo step.
+    fn substitute(
+        &mut self,
+        first: usize,
+        last: usize,
+        args: &str,
+    ) -> Result<EditorAction, String> {
+        let last_pattern = self.search.last.as_ref().map(|s| s.pattern.clone());
+        let substitute = Substitute::parse(args, last_pattern.as_deref())?;
+
+        let mut pending = PendingSubstitution {
+            substitute,
+            line: first,
+            last_line: last,
+            from: 0,
+            substitutions: 0,
+            changed_lines: 0,
+            last_changed: None,
+        };
+
+        if pending.substitute.count_only {
+            return Ok(self.count_matches(pending));
+        }
+        if pending.substitute.confirm {
+            return Ok(if self.find_next_substitution(&mut pending) {
+                self.substitution = Some(pending);
+                EditorAction::None
             } else {
-                self.input_seq.clear();
+                self.finish_substitution(pending)
+            });
+        }
+
+        while pending.line <= pending.last_line {
+            let content = self.line_content(pending.line);
+            if let Some((replaced, count)) = pending.substitute.replace_line(&content) {
+                if pending.substitutions == 0 {
+                    self.get_active_buffer_mut().commit_undo_step();
+                }
+                let start = self.get_active_buffer().char_index(pending.line, 0);
+                let len = content.chars().count();
+                self.get_active_buffer_mut()
+                    .replace_range(start..start + len, &replaced);
+
+                // Line breaks in the replacement push the remaining lines down
+                let added = replaced.matches('\n').count();
+                pending.line += added;
+                pending.last_line += added;
+                pending.substitutions += count;
+                pending.changed_lines += 1;
+                pending.last_changed = Some(pending.line);
             }
+            pending.line += 1;
         }
+        Ok(self.finish_substitution(pending))
+    }
 
-        match key.code {
-            Char('i') => action = EditorAction::EnterEditMode,
-            Char('a') => {
-                self.move_cursor_right();
-                action = EditorAction::EnterEditMode;
-            }
-            Char('o') => {
-                action = EditorAction::EnterEditModeInNewLine;
+    /// Reports how many matches a `:s///n` would replace, changing nothing.
+    fn count_matches(&mut self, mut pending: PendingSubstitution) -> EditorAction {
+        let (mut matches, mut lines) = (0, 0);
+        while pending.line <= pending.last_line {
+            let content = self.line_content(pending.line);
+            if let Some((_, count)) = pending.substitute.replace_line(&content) {
+                matches += count;
+                lines += 1;
             }
-            Char('d') => {
-                self.input_seq.push('d');
-            }
-            Char('g') => {
-                self.input_seq.push('g');
-            }
-            Char('w') => self.move_word_forward(),
-            Char('e') => self.move_word_end_forward(),
-            Char('b') => self.move_word_backward(),
-            Char('G') => self.move_to_end_of_file(),
-            Char('^') => self.move_to_line_start_non_whitespace(),
-            Char('$') => self.move_to_line_end(),
-            Char(':') => action = EditorAction::EnterCommandMode,
-            Char('v') => action = EditorAction::EnterSelectMode,
-            Char('h') => self.move_cursor_left(),
-            Char('l') => self.move_cursor_right(),
-            Char('k') => self.move_cursor_up(),
-            Char('j') => self.move_cursor_down(),
-            Char('u') => self.undo(),
-            Char('U') => self.redo(),
-            KeyCode::Tab => self.buffer_switch_forward(),
-            KeyCode::BackTab => self.buffer_switch_backward(),
-            _ => {}
+            pending.line += 1;
         }
-        action
-    }
 
-    pub fn enter_edit_mode(mut self) -> Editor<EditMode> {
-        self.get_active_buffer_mut().save_snapshot();
-        self.transition()
+        let plural = |n: usize| if n == 1 { "" } else { "es" };
+        self.set_error_line(if matches == 0 {
+            format!("Pattern not found: {}", pending.substitute.regex.as_str())
+        } else {
+            format!(
+                "{} match{} on {} line{}",
+                matches,
+                plural(matches),
+                lines,
+                if lines == 1 { "" } else { "s" }
+            )
+        });
+        EditorAction::EnterNavigateMode
     }
 
-    pub fn enter_command_mode(self) -> Editor<CommandMode> {
-        self.transition()
+    /// Text of a line without its line break.
+    fn line_content(&self, line_idx: usize) -> String {
+        self.get_active_buffer()
+            .text
+            .line(line_idx)
+            .trim_end_matches(['\n', '\r'])
+            .to_string()
     }
 
-    pub fn enter_select_mode(self) -> Editor<SelectMode> {
-        self.transition()
-    }
-}
+    /// Moves the cursor to the next match of a confirmed substitution.
+    ///
+    /// Returns false once there are no matches left in the range.
+    fn find_next_substitution(&mut self, pending: &mut PendingSubstitution) -> bool {
+        while pending.line <= pending.last_line {
+            let content = self.line_content(pending.line);
+            if let Some(found) = pending
+                .substitute
+                .find_at(&content, pending.from)
+                .and_then(|captures| captures.get(0))
+            {
+                pending.from = found.start();
+                let col = content[..found.start()].chars().count();
+                let len = found.as_str().chars().count();
+                let start = self.get_active_buffer().char_index(pending.line, col);
 
-impl Editor<EditMode> {
-    pub fn enter_navigate_mode(self) -> Editor<NavigateMode> {
-        self.transition()
+                (self.view.cursor_line, self.view.cursor_col) = (pending.line, col);
+                self.search.preview = Some(start..start + len.max(1));
+                return true;
+            }
+            pending.line += 1;
+            pending.from = 0;
+        }
+        false
     }
 
-    pub fn enter_select_mode(self) -> Editor<SelectMode> {
-        self.transition()
-    }
+    /// Replaces the match the cursor is on and moves past it.
+    fn replace_current_match(&mut self, pending: &mut PendingSubstitution) {
+        let content = self.line_content(pending.line);
+        let Some(captures) = pending.substitute.find_at(&content, pending.from) else {
+            return;
+        };
+        let Some(found) = captures.get(0) else {
+            return;
+        };
+        let replaced = pending.substitute.expand(&captures);
 
-    pub fn insert_char(&mut self, c: char) {
-        let buffer = &mut self.buffers[self.current_focused_index];
-        buffer.insert_char(self.cursor_line, self.cursor_col, c);
-        self.cursor_col += 1;
-    }
+        if pending.substitutions == 0 {
+            self.get_active_buffer_mut().commit_undo_step();
+        }
+        let col = content[..found.start()].chars().count();
+        let start = self.get_active_buffer().char_index(pending.line, col);
+        let len = found.as_str().chars().count();
+        self.get_active_buffer_mut()
+            .replace_range(start..start + len, &replaced);
 
-    pub fn insert_line(&mut self) {
-        self.buffers[self.current_focused_index].insert_line(self.cursor_line, self.cursor_col);
-        self.cursor_line += 1;
-        self.move_cursor_start();
-    }
+        pending.substitutions += 1;
+        if pending.last_changed != Some(pending.line) {
+            pending.changed_lines += 1;
+        }
 
-    pub fn delete_char(&mut self) {
-        let buffer = &mut self.buffers[self.current_focused_index];
+        let added = replaced.matches('\n').count();
+        pending.last_line += added;
+        pending.line += added;
+        pending.last_changed = Some(pending.line);
 
-        if self.cursor_col == 0 {
-            if self.cursor_line > 0 {
-                let prev_line_idx = self.cursor_line - 1;
-                let prev_line_len = buffer.line_length(prev_line_idx);
+        if !pending.substitute.global {
+            pending.line += 1;
+            pending.from = 0;
+            return;
+        }
 
-                // The newline character is at len - 1
-                let new_cursor_col = if prev_line_len > 0 {
-                    prev_line_len - 1
-                } else {
-                    0
-                };
+        // Continue after the replacement, on the line it ended on
+        let end = match replaced.rfind('\n') {
+            Some(i) => replaced.len() - i - 1,
+            None => found.start() + replaced.len(),
+        };
+        pending.from = if found.is_empty() {
+            substitute::next_search_start(&self.line_content(pending.line), end, end)
+        } else {
+            end
+        };
+    }
 
-                buffer.delete_char(prev_line_idx, new_cursor_col);
+    /// Answers the confirmation asked at each match of a `:s///c`.
+    fn handle_substitution_input(&mut self, key: KeyEvent) -> EditorAction {
+        let Some(mut pending) = self.substitution.take() else {
+            return EditorAction::None;
+        };
 
-                self.cursor_line = prev_line_idx;
-                self.cursor_col = new_cursor_col;
+        match key.code {
+            Char('y') => self.replace_current_match(&mut pending),
+            Char('l') => {
+                self.replace_current_match(&mut pending);
+                return self.finish_substitution(pending);
+            }
+            Char('n') if !pending.substitute.global => {
+                pending.line += 1;
+                pending.from = 0;
+            }
+            Char('n') => {
+                let content = self.line_content(pending.line);
+                pending.from = match pending.substitute.find_at(&content, pending.from) {
+                    Some(captures) => {
+                        let found = captures.get(0).map_or(0..0, |m| m.range());
+                        substitute::next_search_start(&content, found.start, found.end)
+                    }
+                    None => content.len() + 1,
+                };
+            }
+            Char('a') => {
+                while self.find_next_substitution(&mut pending) {
+                    self.replace_current_match(&mut pending);
+                }
             }
+            Char('q') | KeyCode::Esc => return self.finish_substitution(pending),
+            _ => {
+                self.substitution = Some(pending);
+                return EditorAction::None;
+            }
+        }
+
+        if self.find_next_substitution(&mut pending) {
+            self.substitution = Some(pending);
+            EditorAction::None
         } else {
-            buffer.delete_char(self.cursor_line, self.cursor_col - 1);
-            self.cursor_col -= 1;
+            self.finish_substitution(pending)
         }
     }
 
-    pub fn delete_line(&mut self) {
-        let buffer = &mut self.buffers[self.current_focused_index];
+    /// Reports the substitutions made and leaves the cursor on the last changed line.
+    fn finish_substitution(&mut self, pending: PendingSubstitution) -> EditorAction {
+        self.search.preview = None;
 
-        let line_to_delete = self.cursor_line;
-        self.cursor_line -= 1;
-        buffer.delete_line(line_to_delete);
+        let Some(line) = pending.last_changed else {
+            self.set_error_line(format!(
+                "Pattern not found: {}",
+                pending.substitute.regex.as_str()
+            ));
+            return EditorAction::EnterNavigateMode;
+        };
+
+        self.view.cursor_line = line;
+        self.view.cursor_col = self.first_non_whitespace_col(line);
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        self.set_error_line(format!(
+            "{} substitution{} on {} line{}",
+            pending.substitutions,
+            plural(pending.substitutions),
+            pending.changed_lines,
+            plural(pending.changed_lines)
+        ));
+        EditorAction::EnterNavigateMode
     }
 
-    pub fn open_line_below(&mut self) {
-        let buffer = &mut self.buffers[self.current_focused_index];
-        let len = buffer.line_length(self.cursor_line);
+    /// Runs a command line, which may chain several commands with `|`.
+    ///
+    /// A command that saves or quits ends the chain, except for `:w | q`.
+    pub fn execute_command(&mut self, cmd: &str) -> EditorAction {
+        self.clear_command_line();
+
+        let commands = match ex::parse(cmd) {
+            Ok(commands) => commands,
+            Err(e) => {
+                self.set_error_line(e);
+                return EditorAction::EnterNavigateMode;
+            }
+        };
+
+        let mut commands = commands.into_iter().peekable();
+        while let Some(command) = commands.next() {
+            let action = match self.run_ex_command(&command) {
+                Ok(action) => action,
+                Err(e) => {
+                    self.set_error_line(e);
+                    return EditorAction::EnterNavigateMode;
+                }
+            };
+
+            let action = match action {
+                EditorAction::EnterNavigateMode => continue,
+                EditorAction::Save(file_name)
+                    if commands
+                        .next_if(|next| next.kind == CommandKind::Quit)
+                        .is_some() =>
+                {
+                    EditorAction::SaveAndQuit(file_name)
+                }
+                action => action,
+            };
+            if let Some(next) = commands.peek() {
+                self.set_error_line(format!("Not run after :{}: {}", command.name, next.name));
+            }
+            return action;
+        }
+        EditorAction::EnterNavigateMode
+    }
+
+    /// What Ex line ranges are resolved against.
+    fn range_context(&self) -> RangeContext<'_> {
+        let buffer = self.get_active_buffer();
+        RangeContext {
+            text: &buffer.text,
+            cursor_line: self.view.cursor_line,
+            last_line: buffer.line_count().saturating_sub(1),
+            selection: self.view.last_selection,
+        }
+    }
+
+    fn run_ex_command(&mut self, command: &ExCommand) -> Result<EditorAction, String> {
+        let (first, last) = match &command.range {
+            Some(range) => range.resolve(&self.range_context())?,
+            None => (self.view.cursor_line, self.view.cursor_line),
+        };
 
-        buffer.insert_char(self.cursor_line, len, '\n');
-        self.cursor_line += 1;
-        self.cursor_col = 0;
+        let action = match command.kind {
+            CommandKind::Goto => {
+                let from = (self.view.cursor_line, self.view.cursor_col);
+                self.view.cursor_line = last;
+                self.view.cursor_col = self.first_non_whitespace_col(last);
+                self.record_jump(from);
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Quit => self.quit_window(command.bang)?,
+            CommandKind::QuitAll => {
+                if !command.bang {
+                    self.check_unsaved_buffers()?;
+                }
+                EditorAction::QuitAll
+            }
+            // A stand-in written over its file would empty it
+            CommandKind::Write | CommandKind::WriteQuit | CommandKind::Exit
+                if self.get_active_buffer().loading
+                    && (command.kind != CommandKind::Exit || !command.args.is_empty()) =>
+            {
+                return Err(format!(
+                    "\"{}\" is still loading",
+                    self.get_active_buffer().display_name()
+                ));
+            }
+            CommandKind::Write | CommandKind::WriteQuit | CommandKind::Exit
+                if self.get_active_buffer().read_only
+                    && !command.bang
+                    && (command.kind != CommandKind::Exit
+                        || self.get_active_buffer().has_unsaved_changes()) =>
+            {
+                return Err("'readonly' option is set (add ! to override)".to_string());
+            }
+            CommandKind::Write | CommandKind::WriteQuit | CommandKind::Exit
+                if command.encoding.is_some() =>
+            {
+                // Later writes keep the encoding, as they keep the file name. Until
+                // then the file differs, so `:x` writes it.
+                let mut command = command.clone();
+                self.get_active_buffer_mut().encoding = command.encoding.take().unwrap_or_default();
+                return self.run_ex_command(&command);
+            }
+            CommandKind::Write => EditorAction::Save(command.args.first().cloned()),
+            CommandKind::WriteQuit => EditorAction::SaveAndQuit(command.args.first().cloned()),
+            CommandKind::WriteAll => {
+                let written = self.write_all_buffers(command.bang)?;
+                self.set_error_line(format!("{} buffer(s) written", written));
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::WriteQuitAll => {
+                self.write_all_buffers(command.bang)?;
+                EditorAction::QuitAll
+            }
+            CommandKind::Exit => {
+                if self.get_active_buffer().has_unsaved_changes() || !command.args.is_empty() {
+                    EditorAction::SaveAndQuit(command.args.first().cloned())
+                } else {
+                    self.quit_window(command.bang)?
+                }
+            }
+            CommandKind::Edit => {
+                if let Some(file_name) = command.args.first() {
+                    self.edit_file(file_name)?;
+                }
+                // `++enc` reads the file again, even one just opened
+                if command.args.is_empty() || command.encoding.is_some() {
+                    self.reload_buffer(command.bang, command.encoding)?;
+                }
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::NewBuffer => EditorAction::AddNewBuffer,
+            CommandKind::ListBuffers => {
+                let list = self.list_buffers();
+                self.set_error_line(list);
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Buffer => {
+                if let Some(name) = command.args.first() {
+                    let index = self.find_buffer(name)?;
+                    self.focus_buffer(index);
+                }
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::BufferNext | CommandKind::BufferPrevious => {
+                for _ in 0..command.count.unwrap_or(1) {
+                    if command.kind == CommandKind::BufferNext {
+                        self.buffer_switch_forward();
+                    } else {
+                        self.buffer_switch_backward();
+                    }
+                }
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::BufferDelete => {
+                let index = match command.args.first() {
+                    Some(name) => self.find_buffer(name)?,
+                    None => self.current_focused_index,
+                };
+                self.delete_buffer(index, command.bang)?;
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Split | CommandKind::VerticalSplit => {
+                self.split_window(if command.kind == CommandKind::Split {
+                    SplitDirection::Horizontal
+                } else {
+                    SplitDirection::Vertical
+                });
+                if let Some(file_name) = command.args.first() {
+                    self.edit_file(file_name)?;
+                }
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::TabNew => {
+                self.new_tab(command.args.first().map(String::as_str))?;
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::TabNext => {
+                match command.count {
+                    Some(number) if number <= self.tabs.len() => self.focus_tab(number - 1),
+                    Some(number) => return Err(format!("Tab page {} does not exist", number)),
+                    None => self.cycle_tabs(1, false),
+                }
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::TabPrevious => {
+                self.cycle_tabs(command.count.unwrap_or(1), true);
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::TabClose => {
+                self.close_tab()?;
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Close => {
+                self.close_window()?;
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Only => {
+                self.windows_mut().only();
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Resize => {
+                // Sizes count lines of text, the window also has its borders
+                let arg = command.args.first().map(String::as_str).unwrap_or("");
+                let lines: u16 = arg
+                    .trim_start_matches(['+', '-'])
+                    .parse()
+                    .unwrap_or(u16::MAX);
+                if arg.starts_with('+') {
+                    self.resize_window(SplitDirection::Horizontal, &|size| {
+                        size.saturating_add(lines)
+                    });
+                } else if arg.starts_with('-') {
+                    self.resize_window(SplitDirection::Horizontal, &|size| {
+                        size.saturating_sub(lines)
+                    });
+                } else {
+                    self.resize_window(SplitDirection::Horizontal, &|_| lines.saturating_add(2));
+                }
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Substitute => {
+                let args = command.args.first().map_or("", String::as_str);
+                self.substitute(first, last, args)?
+            }
+            CommandKind::Delete | CommandKind::Yank => {
+                let operator = if command.kind == CommandKind::Delete {
+                    Operator::Delete
+                } else {
+                    Operator::Yank
+                };
+                // With a count, the count lines start at the last line of the range
+                let (first, count) = match command.count {
+                    Some(count) => (last, count),
+                    None => (first, last - first + 1),
+                };
+                let cursor = (self.view.cursor_line, self.view.cursor_col);
+                (self.view.cursor_line, self.view.cursor_col) = (first, 0);
+                self.apply_operator(operator, Target::Lines, Some(count), command.register);
+                // Yanking leaves the cursor where it was
+                if operator == Operator::Yank {
+                    (self.view.cursor_line, self.view.cursor_col) = cursor;
+                }
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::UndoList => {
+                let list = self.list_undo_branches();
+                self.set_error_line(list);
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::UndoPurge => {
+                let purged = file_ops::purge_undo_files()?;
+                self.set_error_line(format!("{} undo file(s) purged", purged));
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Earlier | CommandKind::Later => {
+                let distance = command
+                    .args
+                    .first()
+                    .and_then(|arg| UndoDistance::parse(arg))
+                    .unwrap_or(UndoDistance::Steps(1));
+                self.undo_travel(distance, command.kind == CommandKind::Earlier);
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::NoHighlight => {
+                self.search.highlight = false;
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Set => {
+                self.set_options(&command.args)?;
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::HexMode => {
+                self.toggle_hex_view()?;
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Lsp => {
+                match command.args.split_first() {
+                    None => {
+                        let servers = self.lsp.describe();
+                        self.set_error_line(servers);
+                    }
+                    Some((file_format, [])) => self.lsp.restart(file_format)?,
+                    Some((file_format, server_command)) => {
+                        self.lsp.configure(file_format, server_command.to_vec())
+                    }
+                }
+                self.lsp.sync(&mut self.buffers);
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Rename => {
+                let [new_name] = command.args.as_slice() else {
+                    return Err("Usage: :rename {new name}".to_string());
+                };
+                self.lsp.sync(&mut self.buffers);
+                let buffer = &self.buffers[self.current_focused_index];
+                let at = (self.view.cursor_line, self.view.cursor_col);
+                self.lsp.request_rename(buffer, at, new_name)?;
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Diagnostics => {
+                let list = self.list_diagnostics();
+                self.set_error_line(list);
+                EditorAction::EnterNavigateMode
+            }
+            CommandKind::Welcome => EditorAction::EnterNavigateMode,
+            CommandKind::DebugPrintLinesAll => EditorAction::DebugPrintLinesToConsole,
+            CommandKind::DebugPrintLineCurrent => EditorAction::DebugPrintCurrentLineToConsole,
+        };
+        Ok(action)
     }
 
     pub fn handle_input(&mut self, key: KeyEvent) -> EditorAction {
+        if self.substitution.is_some() {
+            return self.handle_substitution_input(key);
+        }
+
+        if !matches!(key.code, KeyCode::Tab | KeyCode::BackTab) {
+            self.completion = None;
+        }
+        if !matches!(key.code, KeyCode::Up | KeyCode::Down) {
+            self.history().reset();
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
         match key.code {
-            KeyCode::Esc => EditorAction::EnterNavigateMode,
-            KeyCode::CapsLock => EditorAction::EnterNavigateMode,
+            KeyCode::Esc => self.cancel_prompt(),
+            KeyCode::CapsLock => self.cancel_prompt(),
+            KeyCode::Char('w') if ctrl => {
+                self.delete_word_before_cursor();
+                self.update_search_preview();
+                EditorAction::None
+            }
+            KeyCode::Char('u') if ctrl => {
+                self.command_line.replace_range(..self.command_cursor, "");
+                self.command_cursor = 0;
+                self.update_search_preview();
+                EditorAction::None
+            }
+            KeyCode::Char(_) if ctrl => EditorAction::None,
             KeyCode::Char(c) => {
-                self.insert_char(c);
+                self.command_line.insert(self.command_cursor, c);
+                self.command_cursor += c.len_utf8();
+                self.update_search_preview();
                 EditorAction::None
             }
+            // Like vim, erasing past the start of an empty command line leaves it
+            KeyCode::Backspace if self.command_line.is_empty() => self.cancel_prompt(),
             KeyCode::Backspace => {
-                self.delete_char();
+                let start = self.command_char_boundary(false);
+                self.command_line
+                    .replace_range(start..self.command_cursor, "");
+                self.command_cursor = start;
+                self.update_search_preview();
                 EditorAction::None
             }
             KeyCode::Delete => {
-                self.delete_char();
-                EditorAction::None
-            }
-            KeyCode::Up => {
-                self.move_cursor_up();
+                let end = self.command_char_boundary(true);
+                self.command_line
+                    .replace_range(self.command_cursor..end, "");
+                self.update_search_preview();
                 EditorAction::None
             }
             KeyCode::Left => {
-                self.move_cursor_left();
+                self.command_cursor = self.command_char_boundary(false);
                 EditorAction::None
             }
             KeyCode::Right => {
-                self.move_cursor_right();
+                self.command_cursor = self.command_char_boundary(true);
                 EditorAction::None
             }
-            KeyCode::Down => {
-                self.move_cursor_down();
+            KeyCode::Home => {
+                self.command_cursor = 0;
                 EditorAction::None
             }
-            KeyCode::Enter => {
-                self.insert_line();
+            KeyCode::End => {
+                self.command_cursor = self.command_line.len();
                 EditorAction::None
             }
-            KeyCode::Tab => {
-                self.insert_char('\t');
+            KeyCode::Up => {
+                let typed = self.command_line.clone();
+                if let Some(entry) = self.history().older(&typed) {
+                    self.set_command_line(entry);
+                    self.update_search_preview();
+                }
                 EditorAction::None
             }
-            KeyCode::Home => {
-                self.move_cursor_start();
+            KeyCode::Down => {
+                if let Some(entry) = self.history().newer() {
+                    self.set_command_line(entry);
+                    self.update_search_preview();
+                }
+                EditorAction::None
+            }
+            KeyCode::Tab if !self.is_search_prompt() => {
+                self.complete(false);
                 EditorAction::None
             }
+            KeyCode::BackTab if !self.is_search_prompt() => {
+                self.complete(true);
+                EditorAction::None
+            }
+            KeyCode::Enter if self.is_search_prompt() => self.execute_search(),
+            KeyCode::Enter => {
+                let cmd = self.command_line.clone();
+                if let Err(e) = self.command_history.add(&cmd) {
+                    self.set_error_line(format!("Couldn't save command history: {}", e));
+                }
+                self.execute_command(&cmd)
+            }
             _ => EditorAction::None,
         }
     }
 }
 
-impl Editor<SelectMode> {
-    pub fn enter_navigate_mode(self) -> Editor<NavigateMode> {
-        self.transition()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rope::HeliosRope;
+
+    fn editor(text: &str, cursor: (usize, usize)) -> Editor<NavigateMode> {
+        let mut buffer = HBuffer::new();
+        buffer.text = HeliosRope::from_str(text);
+        let mut editor = Editor::<NavigateMode>::new(vec![buffer]);
+        (editor.view.cursor_line, editor.view.cursor_col) = cursor;
+        editor
     }
 
-    pub fn enter_command_mode(self) -> Editor<CommandMode> {
-        self.transition()
+    fn text(editor: &Editor<NavigateMode>) -> String {
+        editor.get_active_buffer().text.to_string()
     }
 
-    pub fn enter_edit_mode(self) -> Editor<EditMode> {
-        self.transition()
+    fn delete_words(text: &str, cursor: (usize, usize), count: Option<usize>) -> String {
+        let mut editor = editor(text, cursor);
+        editor.apply_operator(
+            Operator::Delete,
+            Target::Motion(Motion::WordForward),
+            count,
+            None,
+        );
+        self::text(&editor)
     }
 
-    pub fn handle_input(&mut self, key: KeyEvent) -> EditorAction {
-        match key.code {
-            KeyCode::Esc => EditorAction::EnterNavigateMode,
-            KeyCode::CapsLock => EditorAction::EnterNavigateMode,
-            KeyCode::Char('h') => {
-                self.move_cursor_left();
-                EditorAction::None
-            }
+    #[test]
+    fn dw_on_the_last_word_of_a_line_keeps_the_line_break() {
+        assert_eq!(delete_words("a b\nc d\n", (0, 2), None), "a \nc d\n");
+        assert_eq!(delete_words("a b\n  c d\n", (0, 2), None), "a \n  c d\n");
+        // Up to the first word of a later line stops at the end of the line before
+        assert_eq!(delete_words("a b\nc d\n", (0, 0), Some(2)), "\nc d\n");
+    }
 
-            KeyCode::Char('l') => {
-                self.move_cursor_right();
-                EditorAction::None
-            }
-            KeyCode::Char('k') => {
-                self.move_cursor_up();
-                EditorAction::None
-            }
-            KeyCode::Char('j') => {
-                self.move_cursor_down();
-                EditorAction::None
-            }
-            KeyCode::Char(c) => {
-                if c == 'i' {
-                    return EditorAction::EnterEditMode;
-                } else if c == ':' {
-                    return EditorAction::EnterCommandMode;
-                }
-                EditorAction::None
-            }
-            _ => EditorAction::None,
-        }
+    #[test]
+    fn counted_dw_deletes_across_lines() {
+        assert_eq!(delete_words("a b\nc d\n", (0, 0), Some(3)), "d\n");
+        assert_eq!(delete_words("a b\n  c d e\n", (0, 2), Some(3)), "a e\n");
+        assert_eq!(delete_words("one two\n", (0, 0), Some(2)), "\n");
     }
-}
 
-impl Editor<CommandMode> {
-    pub fn enter_navigate_mode(self) -> Editor<NavigateMode> {
-        self.transition()
+    #[test]
+    fn substitutions_over_a_range_undo_in_one_step() {
+        let original = "a a\na\nb a\na\n";
+        let mut editor = editor(original, (0, 0)).enter_command_mode();
+        editor.execute_command("2,3s/a/x/g");
+        assert_eq!(
+            editor.get_active_buffer().text.to_string(),
+            "a a\nx\nb x\na\n"
+        );
+        assert_eq!(editor.error_line, "2 substitutions on 2 lines");
+        assert_eq!(editor.view.cursor_line, 2);
+        editor.undo();
+        assert_eq!(editor.get_active_buffer().text.to_string(), original);
+        assert!(editor.get_active_buffer().undo.is_at_root());
+
+        // Line breaks in the replacement move the lines after them along
+        editor.execute_command("%s/ /\\r/g");
+        assert_eq!(
+            editor.get_active_buffer().text.to_string(),
+            "a\na\na\nb\na\na\n"
+        );
+        editor.undo();
+        assert_eq!(editor.get_active_buffer().text.to_string(), original);
+        assert!(editor.get_active_buffer().undo.is_at_root());
     }
 
-    pub fn clear_command_line(&mut self) {
-        self.command_line.clear();
+    #[test]
+    fn counting_substitutions_changes_nothing() {
+        let original = "a a\na\nb\n";
+        let mut editor = editor(original, (0, 0)).enter_command_mode();
+        editor.execute_command("%s/a/x/gn");
+        assert_eq!(editor.get_active_buffer().text.to_string(), original);
+        assert_eq!(editor.error_line, "3 matches on 2 lines");
+        assert!(editor.get_active_buffer().undo.is_empty());
+        editor.execute_command("%s/z/x/n");
+        assert_eq!(editor.error_line, "Pattern not found: z");
     }
 
-    pub fn execute_command(&mut self, cmd: &str) -> EditorAction {
-        self.clear_command_line();
-        match cmd {
-            "q" => EditorAction::Quit,
-            // "w" => EditorAction::Save,
-            // "wq" => EditorAction::SaveAndQuit,
-            "qa" => EditorAction::QuitAll,
-            "wel" => EditorAction::None,
-            "dla" => EditorAction::DebugPrintLinesToConsole, // DebugPrint Line All
-            "dlc" => EditorAction::DebugPrintCurrentLineToConsole, // DebugPrint Line Current
-            _ => {
-                // Spaghetti code btw
-                if cmd.starts_with("w") || cmd.starts_with("wq") {
-                    let splits = cmd.split(" ");
-
-                    if cmd.starts_with("wq") {
-                        if splits.clone().count() == 2 {
-                            return EditorAction::SaveAndQuit(Some(
-                                splits.last().unwrap().to_string().clone(),
-                            ));
-                        } else {
-                            return EditorAction::SaveAndQuit(None);
-                        }
-                    }
-                    if splits.clone().count() == 2 {
-                        EditorAction::Save(Some(splits.last().unwrap().to_string().clone()))
-                    } else {
-                        EditorAction::Save(None)
-                    }
-                } else {
-                    EditorAction::None
-                }
-            }
-        }
+    #[test]
+    fn changing_the_fileformat_back_leaves_nothing_to_save() {
+        let mut editor = editor("one\n", (0, 0)).enter_command_mode();
+        editor
+            .get_active_buffer_mut()
+            .set_file_path("notes.txt".to_string());
+        editor.execute_command("set ff=dos");
+        assert_eq!(editor.get_active_buffer().line_ending, LineEnding::Dos);
+        assert!(editor.get_active_buffer().has_unsaved_changes());
+        assert_eq!(editor.autosave_buffers().len(), 1);
+
+        editor.execute_command("set ff=unix");
+        assert!(!editor.get_active_buffer().has_unsaved_changes());
+        assert!(editor.autosave_buffers().is_empty());
+
+        // Written as `dos`, that's what the file has
+        editor.execute_command("set ff=dos");
+        editor.mark_saved("notes.txt", 0, LineEnding::Dos, Encoding::Utf8);
+        assert!(!editor.get_active_buffer().has_unsaved_changes());
+        editor.execute_command("set ff=unix");
+        assert!(editor.get_active_buffer().has_unsaved_changes());
     }
 
-    pub fn handle_input(&mut self, key: KeyEvent) -> EditorAction {
-        match key.code {
-            KeyCode::Esc => EditorAction::EnterNavigateMode,
-            KeyCode::CapsLock => EditorAction::EnterNavigateMode,
-            KeyCode::Char(c) => {
-                self.command_line.push(c);
-                EditorAction::None
-            }
-            KeyCode::Backspace => {
-                self.command_line.pop();
-                EditorAction::None
-            }
-            KeyCode::Enter => {
-                let cmd = self.command_line.clone();
-                self.execute_command(&cmd)
-            }
-            _ => EditorAction::None,
-        }
+    #[test]
+    fn writing_in_another_encoding_is_a_change_until_written() {
+        let mut editor = editor("one\n", (0, 0)).enter_command_mode();
+        editor
+            .get_active_buffer_mut()
+            .set_file_path("notes.txt".to_string());
+        assert!(matches!(
+            editor.execute_command("x ++enc=latin1"),
+            EditorAction::SaveAndQuit(None)
+        ));
+        assert_eq!(editor.get_active_buffer().encoding, Encoding::Latin1);
+        assert!(editor.get_active_buffer().has_unsaved_changes());
+
+        editor.mark_saved("notes.txt", 0, LineEnding::Unix, Encoding::Latin1);
+        assert!(!editor.get_active_buffer().has_unsaved_changes());
+        // Nothing left to write, so `:x` only quits
+        assert!(!matches!(
+            editor.execute_command("x"),
+            EditorAction::SaveAndQuit(_)
+        ));
     }
 }
@@ -0,0 +1,466 @@
+use crate::line_ending::LineEnding;
+
+/// Chars of Windows-1252 for the bytes 0x80 to 0x9F. The five bytes it leaves
+/// undefined keep their Latin-1 control chars, so every byte decodes.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+/// Where the non-ASCII bytes of a raw buffer are kept, in the private use area:
+/// byte `b` is the char `RAW_BYTE_BASE + b`.
+const RAW_BYTE_BASE: u32 = 0xF700;
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+/// How a file's bytes map to the text of its buffer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// UTF-8 starting with a byte order mark
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Windows1252,
+    /// Bytes that didn't decode: ASCII is kept as text and every other byte as a
+    /// placeholder char, so writing the buffer gives back the same bytes
+    Raw,
+}
+
+impl Encoding {
+    /// Decodes a file, finding its encoding from a byte order mark or from which
+    /// encoding its bytes are valid in.
+    ///
+    /// A file with NUL bytes is binary and read raw, as is a broken UTF-16 one.
+    /// Text that isn't UTF-8 is taken as Windows-1252 if it uses the chars that
+    /// differ from Latin-1.
+    pub fn detect(bytes: &[u8]) -> (String, Self) {
+        let encoding = Self::guess(bytes, true);
+        match encoding.decode(bytes) {
+            Some(text) => (text, encoding),
+            None => (Self::Raw.decode(bytes).unwrap_or_default(), Self::Raw),
+        }
+    }
+
+    /// Guesses the encoding of a file from its first bytes, for a file too large to
+    /// read whole before decoding it. A char cut off at the end doesn't count.
+    pub fn sniff(prefix: &[u8]) -> Self {
+        Self::guess(prefix, false)
+    }
+
+    fn guess(bytes: &[u8], complete: bool) -> Self {
+        let utf8 = match std::str::from_utf8(bytes) {
+            Ok(_) => true,
+            Err(e) => !complete && e.error_len().is_none(),
+        };
+        if bytes.starts_with(UTF8_BOM) {
+            Self::Utf8Bom
+        } else if bytes.starts_with(UTF16LE_BOM) {
+            Self::Utf16Le
+        } else if bytes.starts_with(UTF16BE_BOM) {
+            Self::Utf16Be
+        } else if bytes.contains(&0) {
+            Self::Raw
+        } else if utf8 {
+            Self::Utf8
+        } else if bytes.iter().any(|&b| {
+            (0x80..0xA0).contains(&b) && WINDOWS_1252_HIGH[usize::from(b - 0x80)] > '\u{FF}'
+        }) {
+            Self::Windows1252
+        } else {
+            Self::Latin1
+        }
+    }
+
+    /// Decodes `bytes` in this encoding, None if they aren't valid in it.
+    pub fn decode(self, bytes: &[u8]) -> Option<String> {
+        let bytes = bytes.strip_prefix(self.bom()).unwrap_or(bytes);
+        // A BOM is optional when the encoding is given
+        let bytes = match self {
+            Self::Utf8 => bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes),
+            _ => bytes,
+        };
+        self.decode_text(bytes)
+    }
+
+    /// Decodes bytes that don't start with a BOM.
+    fn decode_text(self, bytes: &[u8]) -> Option<String> {
+        match self {
+            Self::Utf8 | Self::Utf8Bom => String::from_utf8(bytes.to_vec()).ok(),
+            Self::Utf16Le | Self::Utf16Be => {
+                if !bytes.len().is_multiple_of(2) {
+                    return None;
+                }
+                let units = bytes
+                    .chunks_exact(2)
+                    .map(|pair| self.unit([pair[0], pair[1]]));
+                char::decode_utf16(units)
+                    .collect::<Result<String, _>>()
+                    .ok()
+            }
+            Self::Latin1 => Some(bytes.iter().map(|&b| char::from(b)).collect()),
+            Self::Windows1252 => Some(
+                bytes
+                    .iter()
+                    .map(|&b| match b {
+                        0x80..0xA0 => WINDOWS_1252_HIGH[usize::from(b - 0x80)],
+                        _ => char::from(b),
+                    })
+                    .collect(),
+            ),
+            Self::Raw => Some(bytes.iter().map(|&b| raw_char(b)).collect()),
+        }
+    }
+
+    /// A UTF-16 code unit from its two bytes.
+    fn unit(self, pair: [u8; 2]) -> u16 {
+        if self == Self::Utf16Le {
+            u16::from_le_bytes(pair)
+        } else {
+            u16::from_be_bytes(pair)
+        }
+    }
+
+    /// Bytes written before the text.
+    pub fn bom(self) -> &'static [u8] {
+        match self {
+            Self::Utf8Bom => UTF8_BOM,
+            Self::Utf16Le => UTF16LE_BOM,
+            Self::Utf16Be => UTF16BE_BOM,
+            _ => &[],
+        }
+    }
+
+    /// Appends `text` in this encoding to `out`.
+    ///
+    /// Fails with the first char the encoding can't hold. Raw writes the chars it
+    /// didn't come from as UTF-8.
+    pub fn encode(self, text: &str, out: &mut Vec<u8>) -> Result<(), char> {
+        match self {
+            Self::Utf8 | Self::Utf8Bom => out.extend_from_slice(text.as_bytes()),
+            Self::Utf16Le | Self::Utf16Be => {
+                for unit in text.encode_utf16() {
+                    out.extend_from_slice(&if self == Self::Utf16Le {
+                        unit.to_le_bytes()
+                    } else {
+                        unit.to_be_bytes()
+                    });
+                }
+            }
+            Self::Latin1 => {
+                for c in text.chars() {
+                    out.push(u8::try_from(c).map_err(|_| c)?);
+                }
+            }
+            Self::Windows1252 => {
+                for c in text.chars() {
+                    let byte = match WINDOWS_1252_HIGH.iter().position(|&high| high == c) {
+                        Some(i) => 0x80 + i as u8,
+                        None => u8::try_from(c)
+                            .ok()
+                            .filter(|b| !(0x80..0xA0).contains(b))
+                            .ok_or(c)?,
+                    };
+                    out.push(byte);
+                }
+            }
+            Self::Raw => {
+                for c in text.chars() {
+                    match raw_byte(c) {
+                        Some(byte) => out.push(byte),
+                        None => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Name shown in the status bar and taken by `++enc`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf8Bom => "utf-8-bom",
+            Self::Utf16Le => "utf-16le",
+            Self::Utf16Be => "utf-16be",
+            Self::Latin1 => "latin1",
+            Self::Windows1252 => "cp1252",
+            Self::Raw => "raw",
+        }
+    }
+
+    /// Finds an encoding by its name or a common alias, ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Self::Utf8),
+            "utf-8-bom" | "utf8-bom" => Some(Self::Utf8Bom),
+            "utf-16le" | "utf16le" => Some(Self::Utf16Le),
+            "utf-16be" | "utf16be" => Some(Self::Utf16Be),
+            "latin1" | "iso-8859-1" => Some(Self::Latin1),
+            "cp1252" | "windows-1252" => Some(Self::Windows1252),
+            "raw" => Some(Self::Raw),
+            _ => None,
+        }
+    }
+}
+
+/// The char a raw buffer holds byte `b` as.
+pub fn raw_char(b: u8) -> char {
+    match b {
+        0..0x80 => char::from(b),
+        _ => char::from_u32(RAW_BYTE_BASE + u32::from(b)).unwrap_or('\u{FFFD}'),
+    }
+}
+
+/// The byte a char of a raw buffer stands for, None for chars typed into it.
+pub fn raw_byte(c: char) -> Option<u8> {
+    match u32::from(c) {
+        code @ 0..0x80 => Some(code as u8),
+        code => match code.checked_sub(RAW_BYTE_BASE) {
+            Some(byte @ 0x80..=0xFF) => Some(byte as u8),
+            _ => None,
+        },
+    }
+}
+
+/// Why a chunk of a file didn't decode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// The bytes aren't valid in the encoding
+    Invalid,
+    /// A line break other than the one the file was taken to use
+    LineEnding,
+}
+
+/// Decodes a file chunk by chunk, holding back the bytes of a char split between
+/// two chunks. Line breaks are turned into `\n` as [`LineEnding::normalize`] does.
+pub struct Decoder {
+    encoding: Encoding,
+    line_ending: LineEnding,
+    pending: Vec<u8>,
+    /// The BOM at the start of the file was looked for
+    started: bool,
+    /// The last chunk ended in a `\r`, which may be half of a `\r\n`
+    carriage_return: bool,
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding, line_ending: LineEnding) -> Self {
+        Self {
+            encoding,
+            line_ending,
+            pending: Vec::new(),
+            started: false,
+            carriage_return: false,
+        }
+    }
+
+    /// Decodes the next chunk, with `last` set for the end of the file.
+    pub fn decode(&mut self, chunk: &[u8], last: bool) -> Result<String, DecodeError> {
+        let text = self.decode_chunk(chunk, last).ok_or(DecodeError::Invalid)?;
+        self.normalize(text, last)
+    }
+
+    /// Decodes the next chunk, None if the bytes aren't valid in the encoding.
+    fn decode_chunk(&mut self, chunk: &[u8], last: bool) -> Option<String> {
+        self.pending.extend_from_slice(chunk);
+        if !self.started {
+            let bom = self.encoding.bom();
+            if self.pending.len() < bom.len() && !last {
+                return Some(String::new());
+            }
+            if self.pending.starts_with(bom) {
+                self.pending.drain(..bom.len());
+            }
+            self.started = true;
+        }
+
+        let end = match self.encoding {
+            Encoding::Utf8 | Encoding::Utf8Bom => match std::str::from_utf8(&self.pending) {
+                Ok(_) => self.pending.len(),
+                Err(e) if e.error_len().is_none() && !last => e.valid_up_to(),
+                Err(_) => return None,
+            },
+            Encoding::Utf16Le | Encoding::Utf16Be if !last => {
+                let mut end = self.pending.len() & !1;
+                // The first half of a surrogate pair waits for its second
+                if end >= 2 {
+                    let unit = self
+                        .encoding
+                        .unit([self.pending[end - 2], self.pending[end - 1]]);
+                    if (0xD800..0xDC00).contains(&unit) {
+                        end -= 2;
+                    }
+                }
+                end
+            }
+            _ => self.pending.len(),
+        };
+        let rest = self.pending.split_off(end);
+        let bytes = std::mem::replace(&mut self.pending, rest);
+        self.encoding.decode_text(&bytes)
+    }
+
+    /// Turns the line breaks of decoded text into `\n`, failing at one that isn't
+    /// the file's: a `\n` without its `\r` in a `dos` file, or any `\n` in a `mac` one.
+    fn normalize(&mut self, mut text: String, last: bool) -> Result<String, DecodeError> {
+        match self.line_ending {
+            LineEnding::Unix => Ok(text),
+            LineEnding::Mac if text.contains('\n') => Err(DecodeError::LineEnding),
+            LineEnding::Mac => Ok(text.replace('\r', "\n")),
+            LineEnding::Dos => {
+                if std::mem::take(&mut self.carriage_return) {
+                    text.insert(0, '\r');
+                }
+                if !last && text.ends_with('\r') {
+                    text.pop();
+                    self.carriage_return = true;
+                }
+                let mut previous = None;
+                for c in text.chars() {
+                    if c == '\n' && previous != Some('\r') {
+                        return Err(DecodeError::LineEnding);
+                    }
+                    previous = Some(c);
+                }
+                Ok(text.replace("\r\n", "\n"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes `bytes` cut into chunks at `cuts`.
+    fn decode_chunks(
+        encoding: Encoding,
+        line_ending: LineEnding,
+        bytes: &[u8],
+        cuts: &[usize],
+    ) -> Result<String, DecodeError> {
+        let mut decoder = Decoder::new(encoding, line_ending);
+        let mut text = String::new();
+        let mut start = 0;
+        for &cut in cuts.iter().chain([bytes.len()].iter()) {
+            text.push_str(&decoder.decode(&bytes[start..cut], false)?);
+            start = cut;
+        }
+        text.push_str(&decoder.decode(&[], true)?);
+        Ok(text)
+    }
+
+    /// Decodes `bytes` cut at every offset, expecting `expected` each time.
+    fn assert_every_cut(encoding: Encoding, line_ending: LineEnding, bytes: &[u8], expected: &str) {
+        for cut in 0..=bytes.len() {
+            assert_eq!(
+                decode_chunks(encoding, line_ending, bytes, &[cut]).as_deref(),
+                Ok(expected),
+                "cut at {}",
+                cut
+            );
+        }
+    }
+
+    #[test]
+    fn decoder_joins_a_utf8_char_split_between_chunks() {
+        assert_every_cut(
+            Encoding::Utf8,
+            LineEnding::Unix,
+            "añ😀b".as_bytes(),
+            "añ😀b",
+        );
+        // Split in three
+        let bytes = "😀".as_bytes();
+        assert_eq!(
+            decode_chunks(Encoding::Utf8, LineEnding::Unix, bytes, &[1, 2]).as_deref(),
+            Ok("😀")
+        );
+    }
+
+    #[test]
+    fn decoder_fails_on_a_utf8_char_cut_off_at_the_end() {
+        let bytes = &"a😀".as_bytes()[..3];
+        assert_eq!(
+            decode_chunks(Encoding::Utf8, LineEnding::Unix, bytes, &[1]),
+            Err(DecodeError::Invalid)
+        );
+        assert_eq!(
+            decode_chunks(Encoding::Utf8, LineEnding::Unix, b"a\xFFb", &[2]),
+            Err(DecodeError::Invalid)
+        );
+    }
+
+    #[test]
+    fn decoder_joins_a_utf16_surrogate_pair_split_between_chunks() {
+        let mut le = UTF16LE_BOM.to_vec();
+        let mut be = UTF16BE_BOM.to_vec();
+        for unit in "a😀b".encode_utf16() {
+            le.extend(unit.to_le_bytes());
+            be.extend(unit.to_be_bytes());
+        }
+        assert_every_cut(Encoding::Utf16Le, LineEnding::Unix, &le, "a😀b");
+        assert_every_cut(Encoding::Utf16Be, LineEnding::Unix, &be, "a😀b");
+
+        // A lone first half at the end of the file is broken
+        let lone = [0xFF, 0xFE, 0x3D, 0xD8];
+        assert_eq!(
+            decode_chunks(Encoding::Utf16Le, LineEnding::Unix, &lone, &[3]),
+            Err(DecodeError::Invalid)
+        );
+    }
+
+    #[test]
+    fn decoder_strips_a_bom_spanning_the_first_chunk() {
+        let mut bytes = UTF8_BOM.to_vec();
+        bytes.extend_from_slice(b"hi");
+        assert_every_cut(Encoding::Utf8Bom, LineEnding::Unix, &bytes, "hi");
+        // Only at the start of the file
+        let mut twice = bytes.clone();
+        twice.splice(0..0, UTF8_BOM.iter().copied());
+        assert_eq!(
+            decode_chunks(Encoding::Utf8Bom, LineEnding::Unix, &twice, &[2]).as_deref(),
+            Ok("\u{FEFF}hi")
+        );
+        // A file shorter than its BOM
+        assert_eq!(
+            decode_chunks(Encoding::Utf16Le, LineEnding::Unix, &[0xFF], &[]),
+            Err(DecodeError::Invalid)
+        );
+    }
+
+    #[test]
+    fn decoder_joins_a_crlf_split_between_chunks() {
+        assert_every_cut(Encoding::Utf8, LineEnding::Dos, b"a\r\nb\r\n", "a\nb\n");
+        // A lone `\r` stays, also at a cut and at the end
+        assert_every_cut(Encoding::Utf8, LineEnding::Dos, b"a\rb\r\n\r", "a\rb\n\r");
+        assert_every_cut(Encoding::Utf8, LineEnding::Mac, b"a\rb\r", "a\nb\n");
+    }
+
+    #[test]
+    fn decoder_fails_on_line_breaks_of_another_kind() {
+        for cut in 0..=6 {
+            assert_eq!(
+                decode_chunks(Encoding::Utf8, LineEnding::Dos, b"a\r\nb\nc", &[cut]),
+                Err(DecodeError::LineEnding),
+                "cut at {}",
+                cut
+            );
+        }
+        assert_eq!(
+            decode_chunks(Encoding::Utf8, LineEnding::Mac, b"a\rb\n", &[2]),
+            Err(DecodeError::LineEnding)
+        );
+        assert_eq!(
+            decode_chunks(Encoding::Utf8, LineEnding::Unix, b"a\r\nb\n", &[2]).as_deref(),
+            Ok("a\r\nb\n")
+        );
+    }
+}
@@ -0,0 +1,1216 @@
+use crate::encoding::Encoding;
+use crate::rope::HeliosRope;
+use crate::search::Search;
+use crate::undo::UndoDistance;
+
+/// Everything the Command Line can run, as declared in [`COMMANDS`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CommandKind {
+    /// A range without a command, like `:10`, moves the cursor
+    Goto,
+    Quit,
+    QuitAll,
+    Write,
+    WriteQuit,
+    WriteAll,
+    WriteQuitAll,
+    /// Writes the buffer only when it has unsaved changes, then quits
+    Exit,
+    Edit,
+    NewBuffer,
+    ListBuffers,
+    Buffer,
+    BufferNext,
+    BufferPrevious,
+    BufferDelete,
+    Split,
+    VerticalSplit,
+    Close,
+    Only,
+    Resize,
+    TabNew,
+    TabNext,
+    TabPrevious,
+    TabClose,
+    UndoList,
+    /// Removes undo files whose file is gone or was changed outside Heliolisk
+    UndoPurge,
+    Earlier,
+    Later,
+    Substitute,
+    Delete,
+    Yank,
+    NoHighlight,
+    Set,
+    /// Switches a binary buffer between its hex dump and its text
+    HexMode,
+    /// Lists the language servers, or sets the one of a file format
+    Lsp,
+    /// Renames the symbol under the cursor with the language server
+    Rename,
+    /// Lists the diagnostics of the buffer
+    Diagnostics,
+    Welcome,
+    DebugPrintLinesAll,
+    DebugPrintLineCurrent,
+}
+
+/// How the text after a command name is read.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArgKind {
+    /// Nothing may follow the command
+    None,
+    /// An optional file name, which may be quoted
+    File,
+    /// An optional buffer number or name
+    Buffer,
+    /// An optional count, like `:bn 2`
+    Count,
+    /// An optional size, absolute like `:resize 20` or relative like `:resize +5`
+    Size,
+    /// An optional number of changes or time span, like `:earlier 10` or `:earlier 5m`
+    UndoDistance,
+    /// Any number of `:set` arguments
+    Options,
+    /// Any number of words, like the file format and server command of `:lsp`
+    Words,
+    /// An optional register name followed by an optional count, like `:d a 3`
+    RegisterCount,
+    /// A delimited `/pattern/replacement/`, which may contain `|`, and its flags,
+    /// left to the command to parse
+    Pattern,
+}
+
+/// Declares a command: its name, how short it may be abbreviated and what it accepts.
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Length of the shortest accepted abbreviation, e.g. 1 for `:w[rite]`
+    pub min_len: usize,
+    pub kind: CommandKind,
+    pub args: ArgKind,
+    /// Whether `!` may follow the name
+    pub bang: bool,
+    /// Whether a line range may precede the name
+    pub range: bool,
+}
+
+/// Every Ex command, in lookup priority: when an abbreviation fits several names,
+/// the first one wins.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "quit",
+        min_len: 1,
+        kind: CommandKind::Quit,
+        args: ArgKind::None,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "qall",
+        min_len: 2,
+        kind: CommandKind::QuitAll,
+        args: ArgKind::None,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "quitall",
+        min_len: 5,
+        kind: CommandKind::QuitAll,
+        args: ArgKind::None,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "write",
+        min_len: 1,
+        kind: CommandKind::Write,
+        args: ArgKind::File,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "wq",
+        min_len: 2,
+        kind: CommandKind::WriteQuit,
+        args: ArgKind::File,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "wall",
+        min_len: 2,
+        kind: CommandKind::WriteAll,
+        args: ArgKind::None,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "wqall",
+        min_len: 3,
+        kind: CommandKind::WriteQuitAll,
+        args: ArgKind::None,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "xit",
+        min_len: 1,
+        kind: CommandKind::Exit,
+        args: ArgKind::File,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "xall",
+        min_len: 2,
+        kind: CommandKind::WriteQuitAll,
+        args: ArgKind::None,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "edit",
+        min_len: 1,
+        kind: CommandKind::Edit,
+        args: ArgKind::File,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "enew",
+        min_len: 3,
+        kind: CommandKind::NewBuffer,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "ls",
+        min_len: 2,
+        kind: CommandKind::ListBuffers,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "buffers",
+        min_len: 7,
+        kind: CommandKind::ListBuffers,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "files",
+        min_len: 5,
+        kind: CommandKind::ListBuffers,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "buffer",
+        min_len: 1,
+        kind: CommandKind::Buffer,
+        args: ArgKind::Buffer,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "bnext",
+        min_len: 2,
+        kind: CommandKind::BufferNext,
+        args: ArgKind::Count,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "bprevious",
+        min_len: 2,
+        kind: CommandKind::BufferPrevious,
+        args: ArgKind::Count,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "bNext",
+        min_len: 2,
+        kind: CommandKind::BufferPrevious,
+        args: ArgKind::Count,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "bdelete",
+        min_len: 2,
+        kind: CommandKind::BufferDelete,
+        args: ArgKind::Buffer,
+        bang: true,
+        range: false,
+    },
+    CommandSpec {
+        name: "split",
+        min_len: 2,
+        kind: CommandKind::Split,
+        args: ArgKind::File,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "vsplit",
+        min_len: 2,
+        kind: CommandKind::VerticalSplit,
+        args: ArgKind::File,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "close",
+        min_len: 3,
+        kind: CommandKind::Close,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "only",
+        min_len: 2,
+        kind: CommandKind::Only,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "resize",
+        min_len: 3,
+        kind: CommandKind::Resize,
+        args: ArgKind::Size,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "tabnew",
+        min_len: 6,
+        kind: CommandKind::TabNew,
+        args: ArgKind::File,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "tabedit",
+        min_len: 4,
+        kind: CommandKind::TabNew,
+        args: ArgKind::File,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "tabnext",
+        min_len: 4,
+        kind: CommandKind::TabNext,
+        args: ArgKind::Count,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "tabprevious",
+        min_len: 4,
+        kind: CommandKind::TabPrevious,
+        args: ArgKind::Count,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "tabNext",
+        min_len: 4,
+        kind: CommandKind::TabPrevious,
+        args: ArgKind::Count,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "tabclose",
+        min_len: 4,
+        kind: CommandKind::TabClose,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "substitute",
+        min_len: 1,
+        kind: CommandKind::Substitute,
+        args: ArgKind::Pattern,
+        bang: false,
+        range: true,
+    },
+    CommandSpec {
+        name: "delete",
+        min_len: 1,
+        kind: CommandKind::Delete,
+        args: ArgKind::RegisterCount,
+        bang: false,
+        range: true,
+    },
+    CommandSpec {
+        name: "yank",
+        min_len: 1,
+        kind: CommandKind::Yank,
+        args: ArgKind::RegisterCount,
+        bang: false,
+        range: true,
+    },
+    CommandSpec {
+        name: "undolist",
+        min_len: 5,
+        kind: CommandKind::UndoList,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "undopurge",
+        min_len: 5,
+        kind: CommandKind::UndoPurge,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "earlier",
+        min_len: 2,
+        kind: CommandKind::Earlier,
+        args: ArgKind::UndoDistance,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "later",
+        min_len: 3,
+        kind: CommandKind::Later,
+        args: ArgKind::UndoDistance,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "nohlsearch",
+        min_len: 3,
+        kind: CommandKind::NoHighlight,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "set",
+        min_len: 2,
+        kind: CommandKind::Set,
+        args: ArgKind::Options,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "hexmode",
+        min_len: 3,
+        kind: CommandKind::HexMode,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "lsp",
+        min_len: 3,
+        kind: CommandKind::Lsp,
+        args: ArgKind::Words,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "rename",
+        min_len: 3,
+        kind: CommandKind::Rename,
+        args: ArgKind::Words,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "diagnostics",
+        min_len: 4,
+        kind: CommandKind::Diagnostics,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    CommandSpec {
+        name: "wel",
+        min_len: 3,
+        kind: CommandKind::Welcome,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    // DebugPrint Line All
+    CommandSpec {
+        name: "dla",
+        min_len: 3,
+        kind: CommandKind::DebugPrintLinesAll,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+    // DebugPrint Line Current
+    CommandSpec {
+        name: "dlc",
+        min_len: 3,
+        kind: CommandKind::DebugPrintLineCurrent,
+        args: ArgKind::None,
+        bang: false,
+        range: false,
+    },
+];
+
+/// Finds the command `name` abbreviates.
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| name.len() >= spec.min_len && spec.name.starts_with(name))
+}
+
+/// A parsed command, ready to run.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ExCommand {
+    pub kind: CommandKind,
+    /// The name as typed, for messages
+    pub name: String,
+    pub range: Option<LineRange>,
+    pub bang: bool,
+    pub args: Vec<String>,
+    pub register: Option<char>,
+    pub count: Option<usize>,
+    /// Encoding given with `++enc=`, to read or write the file in
+    pub encoding: Option<Encoding>,
+}
+
+/// Parses a command line into its `|` separated commands.
+pub fn parse(input: &str) -> Result<Vec<ExCommand>, String> {
+    let mut commands = Vec::new();
+    let mut rest = input;
+    loop {
+        let (command, remaining) = parse_command(rest)?;
+        commands.extend(command);
+        match remaining {
+            Some(remaining) => rest = remaining,
+            None => return Ok(commands),
+        }
+    }
+}
+
+/// Parses one command off `input`, returning what follows a `|` if there is one.
+///
+/// Blank commands (like the ones around `||`) parse to None.
+fn parse_command(input: &str) -> Result<(Option<ExCommand>, Option<&str>), String> {
+    let input = input.trim_start_matches([' ', ':']);
+    let (range, rest) = LineRange::parse(input)?;
+    let rest = rest.trim_start();
+
+    let name_len = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    let (name, rest) = rest.split_at(name_len);
+
+    if name.is_empty() {
+        let (args, next) = split_at_bar(rest);
+        if !args.trim().is_empty() {
+            return Err(format!("Not an editor command: {}", input.trim()));
+        }
+        let command = range.map(|range| ExCommand {
+            kind: CommandKind::Goto,
+            name: String::new(),
+            range: Some(range),
+            bang: false,
+            args: Vec::new(),
+            register: None,
+            count: None,
+            encoding: None,
+        });
+        return Ok((command, next));
+    }
+
+    // `:s` takes any punctuation as delimiter, so `:s#a#b#` has the name `s`
+    let spec = lookup(name).ok_or_else(|| format!("Not an editor command: {}", input.trim()))?;
+
+    let (bang, rest) = match rest.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    if bang && !spec.bang {
+        return Err(format!("No ! allowed: {}", name));
+    }
+    if range.is_some() && !spec.range {
+        return Err(format!("No range allowed: {}", name));
+    }
+
+    let (args, next) = match spec.args {
+        ArgKind::Pattern => {
+            let pattern_len = delimited_len(rest, 2);
+            let (flags, next) = split_at_bar(&rest[pattern_len..]);
+            (&rest[..pattern_len + flags.len()], next)
+        }
+        _ => split_at_bar(rest),
+    };
+
+    let mut command = ExCommand {
+        kind: spec.kind,
+        name: name.to_string(),
+        range,
+        bang,
+        args: Vec::new(),
+        register: None,
+        count: None,
+        encoding: None,
+    };
+
+    match spec.args {
+        ArgKind::None => {
+            if !args.trim().is_empty() {
+                return Err(format!("Trailing characters: {}", args.trim()));
+            }
+        }
+        ArgKind::Pattern => command.args.push(args.to_string()),
+        ArgKind::File | ArgKind::Buffer => {
+            command.args = split_words(args)?;
+            if spec.args == ArgKind::File {
+                command.encoding = take_encoding(spec.kind, &mut command.args)?;
+            }
+            if command.args.len() > 1 {
+                return Err(format!("Only one argument allowed: {}", name));
+            }
+        }
+        ArgKind::Options | ArgKind::Words => command.args = split_words(args)?,
+        ArgKind::Count => {
+            let mut words = split_words(args)?.into_iter();
+            if let Some(word) = words.next() {
+                command.count = Some(parse_count(&word)?);
+            }
+            if let Some(word) = words.next() {
+                return Err(format!("Trailing characters: {}", word));
+            }
+        }
+        ArgKind::Size => {
+            command.args = split_words(args)?;
+            if let Some(word) = command.args.get(1) {
+                return Err(format!("Trailing characters: {}", word));
+            }
+            if let Some(word) = command.args.first()
+                && word.trim_start_matches(['+', '-']).parse::<u16>().is_err()
+            {
+                return Err(format!("Invalid argument: {}", word));
+            }
+        }
+        ArgKind::UndoDistance => {
+            command.args = split_words(args)?;
+            if let Some(word) = command.args.get(1) {
+                return Err(format!("Trailing characters: {}", word));
+            }
+            if let Some(word) = command.args.first()
+                && UndoDistance::parse(word).is_none()
+            {
+                return Err(format!("Invalid argument: {}", word));
+            }
+        }
+        ArgKind::RegisterCount => {
+            let mut words = split_words(args)?.into_iter().peekable();
+            if let Some(word) = words.next_if(|w| is_register_name(w)) {
+                command.register = word.chars().next();
+            }
+            if let Some(word) = words.next() {
+                command.count = Some(parse_count(&word)?);
+            }
+            if let Some(word) = words.next() {
+                return Err(format!("Trailing characters: {}", word));
+            }
+        }
+    }
+
+    Ok((Some(command), next))
+}
+
+/// Removes a `++enc=name` from the arguments of a command that reads or writes a
+/// file, returning the encoding it names.
+fn take_encoding(kind: CommandKind, args: &mut Vec<String>) -> Result<Option<Encoding>, String> {
+    let Some(index) = args.iter().position(|word| word.starts_with("++")) else {
+        return Ok(None);
+    };
+    let word = args.remove(index);
+    let takes_encoding = matches!(
+        kind,
+        CommandKind::Write | CommandKind::WriteQuit | CommandKind::Exit | CommandKind::Edit
+    );
+    let encoding = word
+        .strip_prefix("++enc=")
+        .or_else(|| word.strip_prefix("++encoding="))
+        .filter(|_| takes_encoding)
+        .ok_or_else(|| format!("Invalid argument: {}", word))?;
+    Encoding::from_name(encoding)
+        .map(Some)
+        .ok_or_else(|| format!("Unknown encoding: {}", encoding))
+}
+
+fn parse_count(word: &str) -> Result<usize, String> {
+    word.parse::<usize>()
+        .ok()
+        .filter(|count| *count > 0)
+        .ok_or_else(|| format!("Invalid count: {}", word))
+}
+
+fn is_register_name(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!((chars.next(), chars.next()), (Some(c), None) if !c.is_ascii_digit() && crate::register::Registers::is_valid_name(c))
+}
+
+/// Length of the delimiter and the `sections` delimited parts that follow it, like the
+/// `/pattern/replacement/` of `:s`. Escaped delimiters don't end a part.
+fn delimited_len(input: &str, sections: usize) -> usize {
+    let mut chars = input.char_indices();
+    let Some((_, delimiter)) = chars.next() else {
+        return 0;
+    };
+    if delimiter.is_alphanumeric() || delimiter.is_whitespace() || delimiter == '|' {
+        return 0;
+    }
+
+    let mut found = 0;
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c == delimiter {
+            found += 1;
+            if found == sections {
+                return i + c.len_utf8();
+            }
+        }
+    }
+    input.len()
+}
+
+/// Splits `input` at the first `|` outside quotes and not escaped with a backslash.
+fn split_at_bar(input: &str) -> (&str, Option<&str>) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            '|' if !quoted => return (&input[..i], Some(&input[i + 1..])),
+            _ => {}
+        }
+    }
+    (input, None)
+}
+
+/// Splits arguments at whitespace. Double quotes group words and a backslash
+/// escapes the next char.
+fn split_words(input: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = chars.next().unwrap_or('\\');
+                word.get_or_insert_default().push(escaped);
+            }
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_default();
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    if quoted {
+        return Err("Missing closing quote".to_string());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Where a line address starts from, before its offset is applied.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AddressBase {
+    /// `.`, or an offset without a base: the cursor line
+    Current,
+    /// `$`: the last line
+    Last,
+    /// A 1-based line number
+    Number(usize),
+    /// `'<` or `'>`: first or last line of the last selection
+    Mark(char),
+    /// `/pattern/` or `?pattern?`: the next or previous line containing the pattern
+    Pattern { pattern: String, backward: bool },
+}
+
+/// A line address in a range, like `.+2` or `/fn main/`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Address {
+    pub base: AddressBase,
+    /// Sum of the `+n` and `-n` after the base
+    pub offset: isize,
+}
+
+/// The lines an Ex command runs on, before being resolved against a buffer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LineRange {
+    pub start: Address,
+    pub end: Address,
+}
+
+/// What line addresses are resolved against.
+pub struct RangeContext<'a> {
+    pub text: &'a HeliosRope,
+    pub cursor_line: usize,
+    pub last_line: usize,
+    /// First and last line of the last selection, if there was one.
+    pub selection: Option<(usize, usize)>,
+}
+
+impl LineRange {
+    /// Parses a leading range (`%`, or one or two addresses separated by `,`) off `input`.
+    ///
+    /// Returns the range, if any, and the rest of the input.
+    pub fn parse(input: &str) -> Result<(Option<Self>, &str), String> {
+        if let Some(rest) = input.strip_prefix('%') {
+            let range = LineRange {
+                start: Address {
+                    base: AddressBase::Number(1),
+                    offset: 0,
+                },
+                end: Address {
+                    base: AddressBase::Last,
+                    offset: 0,
+                },
+            };
+            return Ok((Some(range), rest));
+        }
+
+        let Some((start, rest)) = parse_address(input)? else {
+            return Ok((None, input));
+        };
+        let Some(after_comma) = rest.strip_prefix(',') else {
+            let end = start.clone();
+            return Ok((Some(LineRange { start, end }), rest));
+        };
+        // A missing second address means the cursor line, as in `:3,`
+        let (end, rest) = parse_address(after_comma)?.unwrap_or((
+            Address {
+                base: AddressBase::Current,
+                offset: 0,
+            },
+            after_comma,
+        ));
+        Ok((Some(LineRange { start, end }), rest))
+    }
+
+    /// Resolves the range into 0-based `(first, last)` lines.
+    pub fn resolve(&self, context: &RangeContext) -> Result<(usize, usize), String> {
+        let first = self.start.resolve(context)?;
+        let last = self.end.resolve(context)?;
+        if first > last {
+            return Err("Backwards range given".to_string());
+        }
+        Ok((first, last))
+    }
+}
+
+fn parse_address(input: &str) -> Result<Option<(Address, &str)>, String> {
+    let mut chars = input.chars();
+    let (base, mut rest) = match chars.next() {
+        Some('.') => (Some(AddressBase::Current), chars.as_str()),
+        Some('$') => (Some(AddressBase::Last), chars.as_str()),
+        Some('\'') => match chars.next() {
+            Some(mark @ ('<' | '>')) => (Some(AddressBase::Mark(mark)), chars.as_str()),
+            Some(mark) => return Err(format!("Mark not set: '{}", mark)),
+            None => return Err("Missing mark name".to_string()),
+        },
+        Some(delimiter @ ('/' | '?')) => {
+            let body = chars.as_str();
+            let end = body.find(delimiter).unwrap_or(body.len());
+            let base = AddressBase::Pattern {
+                pattern: body[..end].to_string(),
+                backward: delimiter == '?',
+            };
+            let rest = body.get(end + 1..).unwrap_or("");
+            (Some(base), rest)
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let (number, rest) = parse_number(input);
+            (Some(AddressBase::Number(number)), rest)
+        }
+        _ => (None, input),
+    };
+
+    let mut offset = 0;
+    let mut has_offset = false;
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        let after_sign = &rest[1..];
+        let (amount, after_amount) = if after_sign.starts_with(|c: char| c.is_ascii_digit()) {
+            parse_number(after_sign)
+        } else {
+            (1, after_sign)
+        };
+        let amount = amount as isize;
+        offset += if sign == '+' { amount } else { -amount };
+        has_offset = true;
+        rest = after_amount;
+    }
+
+    let base = match base {
+        Some(base) => base,
+        None if has_offset => AddressBase::Current,
+        None => return Ok(None),
+    };
+    Ok(Some((Address { base, offset }, rest)))
+}
+
+fn parse_number(input: &str) -> (usize, &str) {
+    let digits = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let number = input[..digits].parse().unwrap_or(usize::MAX);
+    (number, &input[digits..])
+}
+
+impl Address {
+    fn resolve(&self, context: &RangeContext) -> Result<usize, String> {
+        let line = match &self.base {
+            AddressBase::Current => context.cursor_line,
+            AddressBase::Last => context.last_line,
+            AddressBase::Number(number) => number.saturating_sub(1),
+            AddressBase::Mark(mark) => {
+                let (first, last) = context
+                    .selection
+                    .ok_or_else(|| format!("Mark not set: '{}", mark))?;
+                if *mark == '<' { first } else { last }
+            }
+            AddressBase::Pattern { pattern, backward } => {
+                let search = Search {
+                    pattern: pattern.clone(),
+                    backward: *backward,
+                    whole_word: false,
+                };
+                // Searches start on the line after (or before) the cursor line
+                let from = if *backward {
+                    context.text.line_to_char(context.cursor_line)
+                } else {
+                    context
+                        .text
+                        .line_to_char(context.cursor_line + 1)
+                        .saturating_sub(1)
+                };
+                let found = search
+                    .find_from(context.text, from, *backward)
+                    .ok_or_else(|| format!("Pattern not found: {}", pattern))?;
+                context.text.char_to_line(found.range.start)
+            }
+        };
+
+        let line = line
+            .checked_add_signed(self.offset)
+            .filter(|line| *line <= context.last_line)
+            .ok_or_else(|| "Invalid range".to_string())?;
+        Ok(line)
+    }
+}
+
+/// What Tab completes on the command line.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompletionKind {
+    Command,
+    File,
+    Buffer,
+    Option,
+}
+
+/// Finds what the word at the end of `line` completes to, and the byte offset
+/// where that word starts. Only the last command of a `|` chain is completed.
+pub fn completion_context(line: &str) -> Option<(CompletionKind, usize)> {
+    let mut command = line;
+    while let (_, Some(next)) = split_at_bar(command) {
+        command = next;
+    }
+    let command = command.trim_start_matches([' ', ':']);
+    let (_, rest) = LineRange::parse(command).ok()?;
+    let rest = rest.trim_start();
+    let name_start = line.len() - rest.len();
+
+    let name_len = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    if name_len == rest.len() {
+        return Some((CompletionKind::Command, name_start));
+    }
+
+    let kind = match lookup(&rest[..name_len])?.args {
+        ArgKind::File => CompletionKind::File,
+        ArgKind::Buffer => CompletionKind::Buffer,
+        ArgKind::Options => CompletionKind::Option,
+        _ => return None,
+    };
+    let args = &rest[name_len..];
+    if !args.trim_start_matches('!').starts_with(' ') {
+        return None;
+    }
+
+    // The word starts after the last space that isn't escaped
+    let args_start = line.len() - args.len();
+    let mut word_start = args_start;
+    let mut escaped = false;
+    for (i, c) in line.char_indices().skip_while(|(i, _)| *i < args_start) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ' ' => word_start = i + 1,
+            _ => {}
+        }
+    }
+    Some((kind, word_start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(input: &str) -> ExCommand {
+        let mut commands = parse(input).unwrap();
+        assert_eq!(commands.len(), 1, "{input}");
+        commands.remove(0)
+    }
+
+    fn number(number: usize, offset: isize) -> Address {
+        Address {
+            base: AddressBase::Number(number),
+            offset,
+        }
+    }
+
+    #[test]
+    fn abbreviations_find_their_command() {
+        let cases = [
+            ("w", CommandKind::Write),
+            ("write", CommandKind::Write),
+            ("wa", CommandKind::WriteAll),
+            ("wall", CommandKind::WriteAll),
+            ("wq", CommandKind::WriteQuit),
+            ("wqa", CommandKind::WriteQuitAll),
+            ("x", CommandKind::Exit),
+            ("xa", CommandKind::WriteQuitAll),
+            ("q", CommandKind::Quit),
+            ("qa", CommandKind::QuitAll),
+            ("s/a/b/", CommandKind::Substitute),
+            ("se", CommandKind::Set),
+            ("sp", CommandKind::Split),
+            ("tabn", CommandKind::TabNext),
+            ("tabnew", CommandKind::TabNew),
+            ("tabe", CommandKind::TabNew),
+            ("b", CommandKind::Buffer),
+            ("bN", CommandKind::BufferPrevious),
+            ("e", CommandKind::Edit),
+            ("ene", CommandKind::NewBuffer),
+            ("10", CommandKind::Goto),
+        ];
+        for (input, kind) in cases {
+            assert_eq!(parse_one(input).kind, kind, "{input}");
+        }
+    }
+
+    #[test]
+    fn too_short_or_unknown_names_are_refused() {
+        for input in ["tab", "tabnewx", "wqal x", "quitl", "bogus", "10x"] {
+            assert!(parse(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn the_first_command_in_the_table_wins() {
+        // `wqall` is declared after `wall`, so `wa` is never `wqall`
+        let position = |name| COMMANDS.iter().position(|spec| spec.name == name);
+        assert!(position("wall") < position("wqall"));
+        assert_eq!(lookup("s").unwrap().kind, CommandKind::Substitute);
+        assert_eq!(lookup("d").unwrap().kind, CommandKind::Delete);
+        // No abbreviation reaches a command through a longer one declared before it
+        for spec in COMMANDS {
+            let abbreviation = &spec.name[..spec.min_len];
+            let found = lookup(abbreviation).unwrap();
+            assert_eq!(found.kind, spec.kind, "{abbreviation}");
+        }
+    }
+
+    #[test]
+    fn bang_arguments_and_encoding() {
+        let command = parse_one("w! \"my file.txt\" ++enc=latin1");
+        assert!(command.bang);
+        assert_eq!(command.args, ["my file.txt"]);
+        assert_eq!(command.encoding, Some(Encoding::Latin1));
+
+        let command = parse_one("d a 3");
+        assert_eq!((command.register, command.count), (Some('a'), Some(3)));
+
+        assert!(parse("w a b").is_err());
+        assert!(parse("wa ++enc=latin1").is_err());
+        assert!(parse("ls!").is_err());
+        assert!(parse("3w").is_err());
+    }
+
+    #[test]
+    fn bars_chain_commands() {
+        let commands = parse("w | q!").unwrap();
+        let kinds: Vec<_> = commands.iter().map(|c| (c.kind, c.bang)).collect();
+        assert_eq!(
+            kinds,
+            [(CommandKind::Write, false), (CommandKind::Quit, true)]
+        );
+        // Blank commands are skipped
+        assert_eq!(parse("w || q").unwrap().len(), 2);
+        // A `|` in quotes or escaped belongs to the argument
+        assert_eq!(parse_one("w \"a|b\"").args, ["a|b"]);
+        assert_eq!(parse_one("w a\\|b").args, ["a|b"]);
+        // The pattern of `:s` may contain `|`, its flags may not
+        let commands = parse("%s/a|b/c/g | w").unwrap();
+        assert_eq!(commands[0].args, ["/a|b/c/g "]);
+        assert_eq!(commands[1].kind, CommandKind::Write);
+    }
+
+    #[test]
+    fn splitting_at_bars() {
+        let cases = [
+            ("w", ("w", None)),
+            ("w|q", ("w", Some("q"))),
+            ("w \"a|b\"|q", ("w \"a|b\"", Some("q"))),
+            ("w a\\|b", ("w a\\|b", None)),
+            ("|", ("", Some(""))),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(split_at_bar(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn splitting_words() {
+        let cases: [(&str, &[&str]); 6] = [
+            ("", &[]),
+            ("  a  b ", &["a", "b"]),
+            ("\"a b\" c", &["a b", "c"]),
+            ("a\\ b", &["a b"]),
+            ("\"\"", &[""]),
+            ("a\"b c\"d", &["ab cd"]),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(split_words(input).unwrap(), expected, "{input}");
+        }
+        assert!(split_words("\"a b").is_err());
+    }
+
+    #[test]
+    fn delimited_lengths() {
+        let cases = [
+            ("/a/b/g", 2, 5),
+            ("#a#b#", 2, 5),
+            ("/a\\/b/c/", 2, 8),
+            ("/a/b", 2, 4),
+            ("", 2, 0),
+            ("a/b/", 2, 0),
+            (" /a/b/", 2, 0),
+            ("/é/ü/", 2, 7),
+        ];
+        for (input, sections, expected) in cases {
+            assert_eq!(delimited_len(input, sections), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn parsing_ranges() {
+        let current = |offset| Address {
+            base: AddressBase::Current,
+            offset,
+        };
+        let last = Address {
+            base: AddressBase::Last,
+            offset: 0,
+        };
+        let cases = [
+            ("d", None, "d"),
+            ("%d", Some((number(1, 0), last.clone())), "d"),
+            ("3d", Some((number(3, 0), number(3, 0))), "d"),
+            ("3,$d", Some((number(3, 0), last.clone())), "d"),
+            ("3,d", Some((number(3, 0), current(0))), "d"),
+            (".+2,+-d", Some((current(2), current(0))), "d"),
+            ("2-3+1", Some((number(2, -2), number(2, -2))), ""),
+        ];
+        for (input, range, rest) in cases {
+            let range = range.map(|(start, end)| LineRange { start, end });
+            assert_eq!(LineRange::parse(input).unwrap(), (range, rest), "{input}");
+        }
+
+        let (range, rest) = LineRange::parse("'<,'>d").unwrap();
+        let range = range.unwrap();
+        assert_eq!(range.start.base, AddressBase::Mark('<'));
+        assert_eq!(range.end.base, AddressBase::Mark('>'));
+        assert_eq!(rest, "d");
+
+        let (range, rest) = LineRange::parse("?fn?+1s").unwrap();
+        let expected = AddressBase::Pattern {
+            pattern: "fn".to_string(),
+            backward: true,
+        };
+        assert_eq!(
+            range.unwrap().start,
+            Address {
+                base: expected,
+                offset: 1
+            }
+        );
+        assert_eq!(rest, "s");
+
+        assert!(LineRange::parse("'ad").is_err());
+    }
+
+    #[test]
+    fn resolving_ranges() {
+        let text = HeliosRope::from_str("a\nfn main\nb\nfn x\nc\n");
+        let context = RangeContext {
+            text: &text,
+            cursor_line: 2,
+            last_line: 4,
+            selection: Some((1, 3)),
+        };
+        let cases = [
+            ("%", Ok((0, 4))),
+            (".", Ok((2, 2))),
+            ("1,.+1", Ok((0, 3))),
+            ("$-1,$", Ok((3, 4))),
+            ("'<,'>", Ok((1, 3))),
+            ("/fn/", Ok((3, 3))),
+            ("?fn?", Ok((1, 1))),
+            ("/fn/-1,/fn/", Ok((2, 3))),
+            ("3,1", Err("Backwards range given")),
+            ("6", Err("Invalid range")),
+            ("1-1", Err("Invalid range")),
+            ("/nowhere/", Err("Pattern not found: nowhere")),
+        ];
+        for (input, expected) in cases {
+            let (range, _) = LineRange::parse(input).unwrap();
+            let resolved = range.unwrap().resolve(&context);
+            assert_eq!(resolved, expected.map_err(str::to_string), "{input}");
+        }
+
+        let context = RangeContext {
+            selection: None,
+            ..context
+        };
+        let (range, _) = LineRange::parse("'<").unwrap();
+        assert!(range.unwrap().resolve(&context).is_err());
+    }
+}
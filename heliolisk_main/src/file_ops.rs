@@ -1,6 +1,20 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    sync::mpsc::{self, Receiver},
+};
 
-use crate::buffer::HBuffer;
+use ropey::{Rope, RopeBuilder};
+
+use crate::buffer::{self, HBuffer};
+use crate::encoding::{DecodeError, Decoder, Encoding};
+use crate::fold::Folds;
+use crate::line_ending::LineEnding;
+use crate::swap;
+use crate::syntax::Highlighter;
+use crate::undo::{self, UndoTree};
+use crate::view::View;
 
 #[allow(dead_code)]
 pub fn buffer_to_string(bf: &HBuffer) -> String {
@@ -8,12 +22,20 @@ pub fn buffer_to_string(bf: &HBuffer) -> String {
 }
 
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufWriter, Read, Write};
+
+/// Numbers the temp files of writes, which may run at the same time on other threads.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 pub fn write_buffer_to_file(bf: &HBuffer, file_name: Option<String>) -> Result<(), String> {
     let actual_name = file_name.unwrap_or_else(|| "helios_test.txt".to_string());
     let file_path = PathBuf::from(&actual_name);
 
+    // The stand-in of a file still loading holds none of its text
+    if bf.loading {
+        return Err(format!("\"{}\" is still loading", actual_name));
+    }
+
     if file_path.is_dir() {
         return Err(String::from("Couldn't open path, file is a directory!"));
     }
@@ -22,16 +44,26 @@ pub fn write_buffer_to_file(bf: &HBuffer, file_name: Option<String>) -> Result<(
         .parent()
         .unwrap_or_else(|| std::path::Path::new("."));
     let file_stem = file_path.file_name().unwrap_or_default().to_string_lossy();
-    let temp_name = format!(".{}.tmp", file_stem);
+    // Each write has a temp file of its own, as an autosave may run along with `:wq`
+    let temp_name = format!(
+        ".{}.{}.{}.tmp",
+        file_stem,
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
     let temp_path = parent.join(temp_name);
 
-    let file = File::create(&temp_path).map_err(|e| e.to_string())?;
+    let file = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+        .map_err(|e| e.to_string())?;
     // Use BufWriter for better performance
-    let writer = BufWriter::new(file);
+    let mut writer = BufWriter::new(file);
 
-    if let Err(e) = bf.text.inner.write_to(writer) {
+    if let Err(e) = write_text(bf, &mut writer) {
         let _ = fs::remove_file(&temp_path);
-        return Err(e.to_string());
+        return Err(e);
     }
 
     fs::rename(&temp_path, &file_path).map_err(|e| {
@@ -39,33 +71,525 @@ pub fn write_buffer_to_file(bf: &HBuffer, file_name: Option<String>) -> Result<(
         e.to_string()
     })?;
 
+    // Losing the undo history is no reason to fail a write that worked, and hashing
+    // a large file on every write costs more than its history is worth
+    if !bf.large {
+        let _ = write_undo_file(&bf.text, &bf.undo, &file_path);
+    }
+
     Ok(())
 }
 
+/// Writes the buffer's text with its line breaks, in its encoding.
+fn write_text(bf: &HBuffer, writer: &mut impl Write) -> Result<(), String> {
+    writer
+        .write_all(bf.encoding.bom())
+        .map_err(|e| e.to_string())?;
+    let mut bytes = Vec::new();
+    for chunk in bf.text.inner.chunks() {
+        bytes.clear();
+        bf.encoding
+            .encode(&bf.line_ending.apply(chunk), &mut bytes)
+            .map_err(|c| {
+                format!(
+                    "Can't write {:?} as {} (add ++enc=utf-8 to convert)",
+                    c,
+                    bf.encoding.name()
+                )
+            })?;
+        writer.write_all(&bytes).map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())
+}
+
 use crate::rope::HeliosRope;
 
-pub fn load_file(file_path: &PathBuf) -> Result<HBuffer, String> {
+/// Decodes a file's bytes into the text of its buffer, with the encoding and line
+/// break they were found in.
+fn decode_file(
+    bytes: &[u8],
+    encoding: Option<Encoding>,
+) -> Result<(String, Encoding, LineEnding), String> {
+    let (content, encoding) = match encoding {
+        Some(encoding) => {
+            let content = encoding
+                .decode(bytes)
+                .ok_or_else(|| format!("File isn't valid {}", encoding.name()))?;
+            (content, encoding)
+        }
+        None => Encoding::detect(bytes),
+    };
+    // Bytes keep their offsets, so a binary file's `\r`s are left alone
+    let line_ending = match encoding {
+        Encoding::Raw => LineEnding::Unix,
+        _ => LineEnding::detect(&content),
+    };
+    let content = line_ending.normalize(&content).into_owned();
+    Ok((content, encoding, line_ending))
+}
+
+/// Loads a file into a new buffer, decoding it in `encoding` or, without one, in
+/// the encoding it's found to be in.
+///
+/// A binary file, or one that doesn't decode, is read as raw bytes and shown as a
+/// hex dump. A large file is streamed in, see [`load_large_file`].
+pub fn load_file(file_path: &PathBuf, encoding: Option<Encoding>) -> Result<HBuffer, String> {
     if !file_path.exists() {
         return Err(format!("File not found: {:?}", file_path));
     }
+    if is_large_file(file_path) {
+        return load_large_file(file_path, encoding, &mut |_| {});
+    }
 
     // Read the file content
-    let content = fs::read_to_string(file_path).map_err(|e| e.to_string())?;
+    let bytes = fs::read(file_path).map_err(|e| e.to_string())?;
+    let (content, encoding, line_ending) = decode_file(&bytes, encoding)?;
 
     // Create buffer
-    let buffer = HBuffer {
-        text: HeliosRope::from_str(&content),
+    let mut buffer = file_buffer(
+        file_path,
+        HeliosRope::from_str(&content),
+        encoding,
+        line_ending,
+    );
+    buffer.undo =
+        read_undo_file(file_path, &buffer.text, buffer.saved_revision).unwrap_or_default();
+    buffer.found_swap = swap::find(&file_path.to_string_lossy());
+
+    dbg!(buffer.text.len_lines());
+
+    Ok(buffer)
+}
+
+/// A buffer for the text read from `file_path`.
+fn file_buffer(
+    file_path: &Path,
+    text: HeliosRope,
+    encoding: Encoding,
+    line_ending: LineEnding,
+) -> HBuffer {
+    HBuffer {
+        saved_revision: text.revision(),
+        saved_line_ending: line_ending,
+        saved_encoding: encoding,
+        text,
         file_format: file_path
             .extension()
             .and_then(|s| s.to_str())
             .unwrap_or("txt")
             .to_string(),
         file_path: Some(file_path.to_string_lossy().to_string()),
-        undo_stack: Vec::new(),
-        redo_stack: Vec::new(),
-    };
+        absolute_path: buffer::absolute_path(file_path),
+        line_ending,
+        encoding,
+        hex: encoding == Encoding::Raw,
+        undo: UndoTree::default(),
+        view: View::default(),
+        read_only: false,
+        swap: None,
+        found_swap: None,
+        large: false,
+        loading: false,
+        highlighter: Highlighter::default(),
+        syntax_tree: None,
+        folds: Folds::default(),
+        language_server: false,
+        diagnostics: Vec::new(),
+    }
+}
 
-    dbg!(buffer.text.len_lines());
+/// Files from this size on are streamed into their buffer, in the background when
+/// opened from the editor, and keep no swap or undo file.
+pub const LARGE_FILE_BYTES: u64 = 64 * 1024 * 1024;
 
+/// Bytes read from a large file at a time.
+const LOAD_CHUNK_BYTES: usize = 1024 * 1024;
+
+pub fn is_large_file(file_path: &Path) -> bool {
+    fs::metadata(file_path).is_ok_and(|metadata| metadata.len() >= LARGE_FILE_BYTES)
+}
+
+/// What a background load reports.
+pub enum LoadEvent {
+    Progress { read: u64, total: u64 },
+    Done(Result<Box<HBuffer>, String>),
+}
+
+/// Loads a large file on another thread, which reports its progress and then the
+/// buffer to the returned receiver.
+pub fn spawn_load(file_path: PathBuf, encoding: Option<Encoding>) -> Receiver<LoadEvent> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let total = fs::metadata(&file_path).map_or(0, |metadata| metadata.len());
+        let result = load_large_file(&file_path, encoding, &mut |read| {
+            let _ = tx.send(LoadEvent::Progress { read, total });
+        });
+        let _ = tx.send(LoadEvent::Done(result.map(Box::new)));
+    });
+    rx
+}
+
+/// Loads a file chunk by chunk into the rope, so the file is never held twice, calling
+/// `progress` with the bytes read so far.
+///
+/// The encoding and line break are guessed from the start of the file. A file that
+/// turns out not to be UTF-8 is read again as Windows-1252, and one that isn't
+/// UTF-16 as raw bytes. A file mixing line breaks is read again keeping them as
+/// they are, so it's written back unchanged.
+pub fn load_large_file(
+    file_path: &Path,
+    encoding: Option<Encoding>,
+    progress: &mut dyn FnMut(u64),
+) -> Result<HBuffer, String> {
+    let mut prefix = Vec::new();
+    File::open(file_path)
+        .and_then(|file| file.take(LOAD_CHUNK_BYTES as u64).read_to_end(&mut prefix))
+        .map_err(|e| e.to_string())?;
+    let mut guessed = encoding.unwrap_or_else(|| Encoding::sniff(&prefix));
+    let mut line_ending = line_ending_of_prefix(&prefix, guessed);
+
+    let rope = loop {
+        match stream_file(file_path, guessed, line_ending, progress)? {
+            Ok(rope) => break rope,
+            Err(DecodeError::LineEnding) => line_ending = LineEnding::Unix,
+            Err(DecodeError::Invalid) if encoding.is_some() => {
+                return Err(format!("File isn't valid {}", guessed.name()));
+            }
+            Err(DecodeError::Invalid) => {
+                guessed = match guessed {
+                    Encoding::Utf8 | Encoding::Utf8Bom => Encoding::Windows1252,
+                    _ => Encoding::Raw,
+                };
+                line_ending = line_ending_of_prefix(&prefix, guessed);
+            }
+        }
+    };
+
+    let mut buffer = file_buffer(file_path, HeliosRope::from_rope(rope), guessed, line_ending);
+    buffer.large = true;
     Ok(buffer)
 }
+
+/// The line break of a file from its first bytes, in `encoding`.
+fn line_ending_of_prefix(prefix: &[u8], encoding: Encoding) -> LineEnding {
+    // Bytes keep their offsets, so a binary file's `\r`s are left alone
+    if encoding == Encoding::Raw {
+        return LineEnding::Unix;
+    }
+    Decoder::new(encoding, LineEnding::Unix)
+        .decode(prefix, false)
+        .map_or(LineEnding::Unix, |text| LineEnding::detect(&text))
+}
+
+/// Streams the file into a rope, failing with why it isn't valid in `encoding` or
+/// doesn't break its lines with `line_ending`.
+fn stream_file(
+    file_path: &Path,
+    encoding: Encoding,
+    line_ending: LineEnding,
+    progress: &mut dyn FnMut(u64),
+) -> Result<Result<Rope, DecodeError>, String> {
+    let mut file = File::open(file_path).map_err(|e| e.to_string())?;
+    let mut decoder = Decoder::new(encoding, line_ending);
+    let mut builder = RopeBuilder::new();
+    let mut chunk = vec![0; LOAD_CHUNK_BYTES];
+    let mut read = 0;
+    loop {
+        let len = file.read(&mut chunk).map_err(|e| e.to_string())?;
+        let text = match decoder.decode(&chunk[..len], len == 0) {
+            Ok(text) => text,
+            Err(e) => return Ok(Err(e)),
+        };
+        builder.append(&text);
+        if len == 0 {
+            return Ok(Ok(builder.finish()));
+        }
+        read += len as u64;
+        progress(read);
+    }
+}
+
+/// Returns true if both names lead to the same file.
+pub fn is_same_file(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Directory for files Heliolisk keeps between sessions, like the command history.
+///
+/// Uses `$XDG_DATA_HOME/heliolisk`, falling back to `~/.local/share/heliolisk`.
+pub fn data_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+        })?;
+    Some(base.join("heliolisk"))
+}
+
+/// Directory for settings written by hand, like the language servers to run.
+///
+/// Uses `$XDG_CONFIG_HOME/heliolisk`, falling back to `~/.config/heliolisk`.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("heliolisk"))
+}
+
+/// Directory the undo history of written files is kept in.
+fn undo_dir() -> Option<PathBuf> {
+    Some(data_dir()?.join("undo"))
+}
+
+/// The undo file of the file at `full_path`, named after a hash of the path.
+fn undo_file_path(full_path: &str) -> Option<PathBuf> {
+    let hash = hash_chunks([full_path.as_bytes()]);
+    Some(undo_dir()?.join(format!("{:016x}.undo", hash)))
+}
+
+/// FNV-1a, which unlike the std hasher gives the same hash in every run.
+pub fn hash_chunks<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in chunks.into_iter().flatten() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Keeps the undo history of a buffer just written to `file_path`, so it can be
+/// undone after reopening the file. An empty history removes the file's old one.
+///
+/// Edits not yet in an undo step, like those of an autosave while typing, are
+/// written as one, leaving the buffer's own step open.
+fn write_undo_file(text: &HeliosRope, undo: &UndoTree, file_path: &Path) -> Result<(), String> {
+    let full_path = fs::canonicalize(file_path).map_err(|e| e.to_string())?;
+    let full_path = full_path.to_string_lossy();
+    let undo_path = undo_file_path(&full_path).ok_or("No directory for undo files")?;
+    if undo.is_empty() && text.changes().is_none() {
+        let _ = fs::remove_file(&undo_path);
+        return Ok(());
+    }
+
+    if let Some(parent) = undo_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let content_hash = hash_chunks(text.inner.chunks().map(str::as_bytes));
+    fs::write(&undo_path, undo.encode(text, &full_path, content_hash)).map_err(|e| e.to_string())
+}
+
+/// Loads the undo history kept for `file_path`, if it was written with `text`.
+///
+/// A file changed since, outside Heliolisk, has a history that no longer fits it.
+fn read_undo_file(file_path: &Path, text: &HeliosRope, revision: u64) -> Option<UndoTree> {
+    let full_path = fs::canonicalize(file_path).ok()?;
+    let bytes = fs::read(undo_file_path(&full_path.to_string_lossy())?).ok()?;
+    let (_, content_hash) = undo::decode_header(&bytes)?;
+    if content_hash != hash_chunks(text.inner.chunks().map(str::as_bytes)) {
+        return None;
+    }
+    UndoTree::decode(&bytes, text.len_chars(), revision)
+}
+
+/// Removes the undo files of files that are gone or were changed outside
+/// Heliolisk. Returns how many were removed.
+pub fn purge_undo_files() -> Result<usize, String> {
+    let Some(Ok(entries)) = undo_dir().map(fs::read_dir) else {
+        return Ok(0);
+    };
+
+    let mut purged = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let undo_path = entry.path();
+        let header = fs::read(&undo_path)
+            .ok()
+            .and_then(|bytes| undo::decode_header(&bytes));
+        let stale = match header {
+            Some((file, content_hash)) => fs::read(file)
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| decode_file(&bytes, None))
+                .map_or(true, |(content, ..)| {
+                    hash_chunks([content.as_bytes()]) != content_hash
+                }),
+            None => true,
+        };
+        if stale {
+            fs::remove_file(&undo_path).map_err(|e| e.to_string())?;
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+/// Reads a history file, one entry per line. A missing file is an empty history.
+pub fn read_history(path: &PathBuf) -> Vec<String> {
+    fs::read_to_string(path)
+        .map(|content| content.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+pub fn write_history(path: &PathBuf, entries: &[String]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let mut content = entries.join("\n");
+    content.push('\n');
+    fs::write(path, content).map_err(|e| e.to_string())
+}
+
+/// Lists the paths starting with `prefix`, for Tab completion.
+///
+/// Directories end with `/`. Hidden files are only listed when `prefix` names them.
+pub fn complete_path(prefix: &str) -> Vec<String> {
+    let (dir, name_prefix) = match prefix.rfind('/') {
+        Some(i) => (&prefix[..=i], &prefix[i + 1..]),
+        None => ("", prefix),
+    };
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return Vec::new();
+    };
+
+    let mut paths: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(name_prefix)
+                || (name.starts_with('.') && !name_prefix.starts_with('.'))
+            {
+                return None;
+            }
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            Some(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads `bytes` from a file of their own as a large file.
+    fn load_large(name: &str, bytes: &[u8]) -> HBuffer {
+        let path = std::env::temp_dir().join(format!("heliolisk-{}-{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        let buffer = load_large_file(&path, None, &mut |_| {});
+        let _ = fs::remove_file(&path);
+        buffer.unwrap()
+    }
+
+    #[test]
+    fn writes_at_the_same_time_leave_one_whole_file() {
+        let dir = std::env::temp_dir().join(format!("heliolisk-{}-writes", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.to_string_lossy().to_string();
+                let mut buffer = HBuffer::new();
+                buffer.text = HeliosRope::from_str(&format!("{}\n", i).repeat(100_000));
+                std::thread::spawn(move || write_buffer_to_file(&buffer, Some(path)))
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+
+        let written = fs::read_to_string(&path).unwrap();
+        let first = &written[..2];
+        assert_eq!(written, first.repeat(100_000));
+        // No temp file is left behind
+        let names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(names, ["file.txt"]);
+    }
+
+    #[test]
+    fn files_are_written_with_the_line_break_they_were_read_with() {
+        let dir = std::env::temp_dir().join(format!("heliolisk-{}-breaks", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cases: [(&str, &[u8], LineEnding); 5] = [
+            ("unix", b"one\ntwo\n", LineEnding::Unix),
+            ("dos", b"one\r\ntwo\r\n", LineEnding::Dos),
+            ("mac", b"one\rtwo\r", LineEnding::Mac),
+            ("mixed", b"one\r\ntwo\n", LineEnding::Unix),
+            ("none", b"one", LineEnding::Unix),
+        ];
+        for (name, bytes, line_ending) in cases {
+            let path = dir.join(name);
+            fs::write(&path, bytes).unwrap();
+            let buffer = load_file(&path, None).unwrap();
+            assert_eq!(buffer.line_ending, line_ending, "{}", name);
+            assert!(!buffer.has_unsaved_changes());
+
+            let file_name = path.to_string_lossy().to_string();
+            write_buffer_to_file(&buffer, Some(file_name)).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), bytes, "{}", name);
+        }
+
+        // Another fileformat changes every line break
+        let path = dir.join("dos");
+        let mut buffer = load_file(&path, None).unwrap();
+        buffer.line_ending = LineEnding::Mac;
+        assert!(buffer.has_unsaved_changes());
+        write_buffer_to_file(&buffer, Some(path.to_string_lossy().to_string())).unwrap();
+        let written = fs::read(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(written, b"one\rtwo\r");
+    }
+
+    #[test]
+    fn large_files_keep_their_line_break() {
+        let buffer = load_large("dos", b"one\r\ntwo\r\n");
+        assert_eq!(buffer.line_ending, LineEnding::Dos);
+        assert_eq!(buffer.text.to_string(), "one\ntwo\n");
+        assert!(buffer.large);
+
+        let buffer = load_large("mac", b"one\rtwo\r");
+        assert_eq!(buffer.line_ending, LineEnding::Mac);
+        assert_eq!(buffer.text.to_string(), "one\ntwo\n");
+
+        // Mixed line breaks are kept as they are, past the part guessed from
+        let mut mixed = b"one\r\n".repeat(LOAD_CHUNK_BYTES / 5 + 1);
+        mixed.extend_from_slice(b"two\n");
+        let buffer = load_large("mixed", &mixed);
+        assert_eq!(buffer.line_ending, LineEnding::Unix);
+        assert_eq!(buffer.text.to_string().as_bytes(), mixed.as_slice());
+    }
+
+    #[test]
+    fn large_files_fall_back_to_other_encodings() {
+        // Valid UTF-8 for the part guessed from
+        let mut latin = b"a".repeat(LOAD_CHUNK_BYTES);
+        latin.extend_from_slice(b"\x93caf\xe9\x94\r\n");
+        let buffer = load_large("windows-1252", &latin);
+        assert_eq!(buffer.encoding, Encoding::Windows1252);
+        assert!(
+            buffer
+                .text
+                .to_string()
+                .ends_with("\u{201C}caf\u{E9}\u{201D}\r\n")
+        );
+
+        // Broken UTF-16 is kept byte for byte
+        let broken = [0xFF, 0xFE, b'a', 0, 0x3D, 0xD8];
+        let buffer = load_large("broken-utf-16", &broken);
+        assert_eq!(buffer.encoding, Encoding::Raw);
+        assert!(buffer.hex);
+        let mut written = Vec::new();
+        write_text(&buffer, &mut written).unwrap();
+        assert_eq!(written, broken);
+    }
+}
@@ -0,0 +1,101 @@
+/// The closed folds of a buffer, each shown as one line standing for all of its lines.
+#[derive(Clone, Default)]
+pub struct Folds {
+    /// First and last line of each closed fold. Folds may nest, and only the
+    /// outermost of those shows.
+    closed: Vec<(usize, usize)>,
+    /// Lines the text had when the folds were last moved along with it
+    line_count: usize,
+}
+
+impl Folds {
+    /// Closes the fold over the lines `first..=last`.
+    pub fn close(&mut self, first: usize, last: usize) {
+        if first < last && !self.closed.contains(&(first, last)) {
+            self.closed.push((first, last));
+        }
+    }
+
+    /// Opens the outermost closed fold over `line_idx`, the one shown for it.
+    /// Returns false if there's none.
+    pub fn open(&mut self, line_idx: usize) -> bool {
+        let Some(fold) = self.closed_at(line_idx) else {
+            return false;
+        };
+        self.closed.retain(|&closed| closed != fold);
+        true
+    }
+
+    /// Closes every fold in `folds`, replacing the ones closed before.
+    pub fn close_all(&mut self, folds: Vec<(usize, usize)>) {
+        self.closed = folds;
+    }
+
+    pub fn open_all(&mut self) {
+        self.closed.clear();
+    }
+
+    /// The outermost closed fold over `line_idx`.
+    pub fn closed_at(&self, line_idx: usize) -> Option<(usize, usize)> {
+        self.closed
+            .iter()
+            .filter(|&&(first, last)| first <= line_idx && line_idx <= last)
+            .max_by_key(|&&(first, last)| last - first)
+            .copied()
+    }
+
+    /// The line shown for `line_idx`: the first line of the closed fold it's in.
+    pub fn shown_line(&self, line_idx: usize) -> usize {
+        self.closed_at(line_idx)
+            .map_or(line_idx, |(first, _)| first)
+    }
+
+    /// The line shown on the row after the one showing `line_idx`.
+    pub fn next_shown_line(&self, line_idx: usize) -> usize {
+        self.closed_at(line_idx).map_or(line_idx, |(_, last)| last) + 1
+    }
+
+    /// The lines shown on `rows` rows from `first_line` on, stopping at `line_count`.
+    pub fn shown_lines(&self, first_line: usize, rows: usize, line_count: usize) -> Vec<usize> {
+        let mut lines = Vec::with_capacity(rows);
+        let mut line_idx = self.shown_line(first_line);
+        while lines.len() < rows && line_idx < line_count {
+            lines.push(line_idx);
+            line_idx = self.next_shown_line(line_idx);
+        }
+        lines
+    }
+
+    /// Rows between the one showing `first_line` and the one showing `line_idx`.
+    pub fn rows_between(&self, first_line: usize, line_idx: usize) -> usize {
+        let mut rows = 0;
+        let mut shown = self.shown_line(first_line);
+        let target = self.shown_line(line_idx);
+        while shown < target {
+            shown = self.next_shown_line(shown);
+            rows += 1;
+        }
+        rows
+    }
+
+    /// Moves the folds along with an edit of the text from `changed_line` on, after
+    /// which it has `line_count` lines. A fold the edit was made in is opened.
+    pub fn follow_edit(&mut self, changed_line: Option<usize>, line_count: usize) {
+        let added = line_count as isize - self.line_count as isize;
+        self.line_count = line_count;
+        let Some(changed_line) = changed_line else {
+            return;
+        };
+        self.closed.retain_mut(|(first, last)| {
+            if *last < changed_line {
+                return true;
+            }
+            if *first <= changed_line {
+                return false;
+            }
+            *first = first.saturating_add_signed(added);
+            *last = last.saturating_add_signed(added);
+            *first < *last && *last < line_count
+        });
+    }
+}
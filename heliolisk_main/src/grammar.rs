@@ -0,0 +1,548 @@
+use crate::register::Registers;
+use crate::text_object::TextObject;
+
+/// Operators that act on the text covered by a motion or selection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    IndentRight,
+    IndentLeft,
+    ToggleCase,
+    Lowercase,
+    Uppercase,
+}
+
+impl Operator {
+    /// Operators typed as a single key in Navigate Mode.
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            'd' => Some(Operator::Delete),
+            'c' => Some(Operator::Change),
+            'y' => Some(Operator::Yank),
+            '>' => Some(Operator::IndentRight),
+            '<' => Some(Operator::IndentLeft),
+            _ => None,
+        }
+    }
+
+    /// Operators typed after a `g` in Navigate Mode.
+    fn from_g_char(c: char) -> Option<Self> {
+        match c {
+            '~' => Some(Operator::ToggleCase),
+            'u' => Some(Operator::Lowercase),
+            'U' => Some(Operator::Uppercase),
+            _ => None,
+        }
+    }
+
+    /// Operators typed as a single key in Select Mode.
+    fn from_select_char(c: char) -> Option<Self> {
+        match c {
+            'd' | 'x' => Some(Operator::Delete),
+            'c' | 's' => Some(Operator::Change),
+            'y' => Some(Operator::Yank),
+            '>' => Some(Operator::IndentRight),
+            '<' => Some(Operator::IndentLeft),
+            '~' => Some(Operator::ToggleCase),
+            'u' => Some(Operator::Lowercase),
+            'U' => Some(Operator::Uppercase),
+            _ => None,
+        }
+    }
+
+    /// The key that repeats the operator to act on whole lines (`dd`, `gUU`).
+    fn line_char(self) -> char {
+        match self {
+            Operator::Delete => 'd',
+            Operator::Change => 'c',
+            Operator::Yank => 'y',
+            Operator::IndentRight => '>',
+            Operator::IndentLeft => '<',
+            Operator::ToggleCase => '~',
+            Operator::Lowercase => 'u',
+            Operator::Uppercase => 'U',
+        }
+    }
+}
+
+/// Cursor movements that can be used alone or as the target of an Operator.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward,
+    WordBackward,
+    WordEndForward,
+    LineStart,
+    LineFirstNonWhitespace,
+    LineEnd,
+    FileStart,
+    FileEnd,
+    ParagraphForward,
+    ParagraphBackward,
+    SearchNext,
+    SearchPrevious,
+    SearchWordForward,
+    SearchWordBackward,
+    /// `]f` and `[f`: the start of the next or previous function, by the syntax tree
+    NextFunction,
+    PreviousFunction,
+    /// `[u`: the start of the syntax node around the cursor
+    ParentNode,
+}
+
+impl Motion {
+    /// Linewise motions make operators act on whole lines.
+    pub fn is_linewise(self) -> bool {
+        matches!(
+            self,
+            Motion::Up | Motion::Down | Motion::FileStart | Motion::FileEnd
+        )
+    }
+
+    /// Inclusive motions include the character under the final cursor position.
+    pub fn is_inclusive(self) -> bool {
+        matches!(self, Motion::WordEndForward | Motion::LineEnd)
+    }
+
+    /// Jumps are recorded in the jump list, so Ctrl-O can return from them.
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            Motion::FileStart
+                | Motion::FileEnd
+                | Motion::ParagraphForward
+                | Motion::ParagraphBackward
+                | Motion::SearchNext
+                | Motion::SearchPrevious
+                | Motion::SearchWordForward
+                | Motion::SearchWordBackward
+                | Motion::NextFunction
+                | Motion::PreviousFunction
+                | Motion::ParentNode
+        )
+    }
+
+    /// Motions where a count is a line number rather than a repetition.
+    pub fn takes_line_number(self) -> bool {
+        matches!(self, Motion::FileStart | Motion::FileEnd)
+    }
+}
+
+/// What an Operator acts on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+    Motion(Motion),
+    /// Doubled operator (`dd`, `yy`, `>>`): acts on `count` whole lines.
+    Lines,
+    /// `iw`, `a(`, `it` and friends: acts on the object around the cursor.
+    TextObject(TextObject),
+}
+
+/// `z` commands, which open and close folds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FoldCommand {
+    /// `zo`
+    Open,
+    /// `zc`
+    Close,
+    /// `za`
+    Toggle,
+    /// `zR`
+    OpenAll,
+    /// `zM`
+    CloseAll,
+}
+
+/// Questions for the language server of the buffer, about the symbol under the cursor.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LspCommand {
+    /// `K`
+    Hover,
+    /// `gd`
+    Definition,
+    /// `gr`
+    References,
+}
+
+/// A fully parsed Navigate Mode command.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NormalCommand {
+    Move {
+        count: Option<usize>,
+        motion: Motion,
+    },
+    Operate {
+        count: Option<usize>,
+        register: Option<char>,
+        operator: Operator,
+        target: Target,
+    },
+    /// `p` puts after the cursor, `P` before it.
+    Put {
+        count: Option<usize>,
+        register: Option<char>,
+        before: bool,
+    },
+    /// `gt` goes to the next tab page, `gT` to the previous one.
+    TabPage {
+        count: Option<usize>,
+        backward: bool,
+    },
+    /// `g-` goes to the text state made before the current one, `g+` to the one
+    /// made after it, whichever undo branch they are on.
+    UndoTravel {
+        count: Option<usize>,
+        backward: bool,
+    },
+    Fold(FoldCommand),
+    Lsp(LspCommand),
+}
+
+/// A fully parsed Select Mode command.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SelectCommand {
+    Move {
+        count: Option<usize>,
+        motion: Motion,
+    },
+    Operate {
+        register: Option<char>,
+        operator: Operator,
+    },
+    /// Replaces the selection with register contents.
+    Put { register: Option<char> },
+    /// Selects (or grows the selection to) a text object.
+    SelectObject {
+        count: Option<usize>,
+        object: TextObject,
+    },
+}
+
+pub enum ParseResult<T = NormalCommand> {
+    /// The sequence is a valid prefix, wait for more keys.
+    Pending,
+    Complete(T),
+    Invalid,
+}
+
+/// Returns true if the key can begin a Navigate Mode grammar sequence.
+pub fn starts_sequence(c: char) -> bool {
+    matches!(c, '1'..='9' | 'g' | '"' | 'p' | 'P' | 'z' | 'K')
+        || Operator::from_char(c).is_some()
+        || parse_motion(&[c]).is_some()
+}
+
+/// Returns true if the key can begin a Select Mode grammar sequence.
+pub fn starts_select_sequence(c: char) -> bool {
+    matches!(c, '1'..='9' | 'g' | '"' | 'p' | 'P' | 'i' | 'a')
+        || Operator::from_select_char(c).is_some()
+        || parse_motion(&[c]).is_some()
+}
+
+/// Parses `["x] [count] operator [count] motion`, `["x] [count] operator operator`,
+/// `["x] [count] p`, `[count] motion`, `[count] gt`, `[count] g-`, `z` sequences,
+/// `K`, `gd` and `gr`.
+pub fn parse(seq: &str) -> ParseResult {
+    let chars: Vec<char> = seq.chars().collect();
+    let mut pos = 0;
+
+    let register = match parse_register(&chars, &mut pos) {
+        Ok(register) => register,
+        Err(result) => return result,
+    };
+
+    let first_count = parse_count(&chars, &mut pos);
+
+    let Some(&c) = chars.get(pos) else {
+        return ParseResult::Pending;
+    };
+
+    if (c == 'p' || c == 'P') && pos + 1 == chars.len() {
+        return ParseResult::Complete(NormalCommand::Put {
+            count: first_count,
+            register,
+            before: c == 'P',
+        });
+    }
+
+    if c == 'g'
+        && register.is_none()
+        && pos + 2 == chars.len()
+        && let Some(&next @ ('t' | 'T')) = chars.get(pos + 1)
+    {
+        return ParseResult::Complete(NormalCommand::TabPage {
+            count: first_count,
+            backward: next == 'T',
+        });
+    }
+
+    if c == 'g'
+        && register.is_none()
+        && pos + 2 == chars.len()
+        && let Some(&next @ ('-' | '+')) = chars.get(pos + 1)
+    {
+        return ParseResult::Complete(NormalCommand::UndoTravel {
+            count: first_count,
+            backward: next == '-',
+        });
+    }
+
+    if c == 'z' && register.is_none() && first_count.is_none() {
+        let command = match chars.get(pos + 1) {
+            None => return ParseResult::Pending,
+            Some(_) if pos + 2 != chars.len() => return ParseResult::Invalid,
+            Some('o') => FoldCommand::Open,
+            Some('c') => FoldCommand::Close,
+            Some('a') => FoldCommand::Toggle,
+            Some('R') => FoldCommand::OpenAll,
+            Some('M') => FoldCommand::CloseAll,
+            Some(_) => return ParseResult::Invalid,
+        };
+        return ParseResult::Complete(NormalCommand::Fold(command));
+    }
+
+    if register.is_none() && first_count.is_none() {
+        let command = match (c, chars.get(pos + 1)) {
+            ('K', None) => Some(LspCommand::Hover),
+            ('g', Some('d')) => Some(LspCommand::Definition),
+            ('g', Some('r')) => Some(LspCommand::References),
+            _ => None,
+        };
+        if let Some(command) = command {
+            return ParseResult::Complete(NormalCommand::Lsp(command));
+        }
+    }
+
+    let operator = match parse_operator(&chars[pos..]) {
+        Some(OperatorParse::Complete(operator, len)) => {
+            pos += len;
+            operator
+        }
+        Some(OperatorParse::Pending) => return ParseResult::Pending,
+        None => {
+            return match parse_motion(&chars[pos..]) {
+                Some(MotionParse::Complete(motion, len))
+                    if pos + len == chars.len() && register.is_none() =>
+                {
+                    ParseResult::Complete(NormalCommand::Move {
+                        count: first_count,
+                        motion,
+                    })
+                }
+                Some(MotionParse::Pending) => ParseResult::Pending,
+                _ => ParseResult::Invalid,
+            };
+        }
+    };
+
+    let second_count = parse_count(&chars, &mut pos);
+    let count = multiply_counts(first_count, second_count);
+
+    let Some(&c) = chars.get(pos) else {
+        return ParseResult::Pending;
+    };
+
+    if c == operator.line_char() && pos + 1 == chars.len() {
+        return ParseResult::Complete(NormalCommand::Operate {
+            count,
+            register,
+            operator,
+            target: Target::Lines,
+        });
+    }
+
+    if let Some(result) = parse_text_object(&chars[pos..]) {
+        return match result {
+            ParseResult::Complete(object) => ParseResult::Complete(NormalCommand::Operate {
+                count,
+                register,
+                operator,
+                target: Target::TextObject(object),
+            }),
+            ParseResult::Pending => ParseResult::Pending,
+            ParseResult::Invalid => ParseResult::Invalid,
+        };
+    }
+
+    match parse_motion(&chars[pos..]) {
+        Some(MotionParse::Complete(motion, len)) if pos + len == chars.len() => {
+            ParseResult::Complete(NormalCommand::Operate {
+                count,
+                register,
+                operator,
+                target: Target::Motion(motion),
+            })
+        }
+        Some(MotionParse::Pending) => ParseResult::Pending,
+        _ => ParseResult::Invalid,
+    }
+}
+
+/// Parses `["x] operator`, `["x] p` and `[count] motion` sequences in Select Mode.
+pub fn parse_select(seq: &str) -> ParseResult<SelectCommand> {
+    let chars: Vec<char> = seq.chars().collect();
+    let mut pos = 0;
+
+    let register = match parse_register(&chars, &mut pos) {
+        Ok(register) => register,
+        Err(result) => return result,
+    };
+
+    let count = parse_count(&chars, &mut pos);
+
+    let Some(&c) = chars.get(pos) else {
+        return ParseResult::Pending;
+    };
+
+    if count.is_none() && pos + 1 == chars.len() {
+        if c == 'p' || c == 'P' {
+            return ParseResult::Complete(SelectCommand::Put { register });
+        }
+        if let Some(operator) = Operator::from_select_char(c) {
+            return ParseResult::Complete(SelectCommand::Operate { register, operator });
+        }
+    }
+
+    if register.is_none()
+        && let Some(result) = parse_text_object(&chars[pos..])
+    {
+        return match result {
+            ParseResult::Complete(object) => {
+                ParseResult::Complete(SelectCommand::SelectObject { count, object })
+            }
+            ParseResult::Pending => ParseResult::Pending,
+            ParseResult::Invalid => ParseResult::Invalid,
+        };
+    }
+
+    match parse_motion(&chars[pos..]) {
+        Some(MotionParse::Complete(motion, len))
+            if pos + len == chars.len() && register.is_none() =>
+        {
+            ParseResult::Complete(SelectCommand::Move { count, motion })
+        }
+        Some(MotionParse::Pending) => ParseResult::Pending,
+        _ => ParseResult::Invalid,
+    }
+}
+
+/// Parses an optional `"x` register prefix.
+fn parse_register<T>(chars: &[char], pos: &mut usize) -> Result<Option<char>, ParseResult<T>> {
+    if chars.first() != Some(&'"') {
+        return Ok(None);
+    }
+    match chars.get(1) {
+        None => Err(ParseResult::Pending),
+        Some(&name) if Registers::is_valid_name(name) => {
+            *pos = 2;
+            Ok(Some(name))
+        }
+        Some(_) => Err(ParseResult::Invalid),
+    }
+}
+
+/// Parses an `i`/`a` text object, or returns None if `chars` doesn't start with one.
+fn parse_text_object(chars: &[char]) -> Option<ParseResult<TextObject>> {
+    let prefix = *chars.first()?;
+    if prefix != 'i' && prefix != 'a' {
+        return None;
+    }
+    Some(match chars.get(1) {
+        None => ParseResult::Pending,
+        Some(&c) if chars.len() == 2 => match TextObject::from_chars(prefix, c) {
+            Some(object) => ParseResult::Complete(object),
+            None => ParseResult::Invalid,
+        },
+        Some(_) => ParseResult::Invalid,
+    })
+}
+
+enum OperatorParse {
+    Pending,
+    Complete(Operator, usize),
+}
+
+fn parse_operator(chars: &[char]) -> Option<OperatorParse> {
+    let c = *chars.first()?;
+    if c == 'g' {
+        return match chars.get(1) {
+            None => Some(OperatorParse::Pending),
+            Some(&next) => Operator::from_g_char(next).map(|op| OperatorParse::Complete(op, 2)),
+        };
+    }
+    Operator::from_char(c).map(|op| OperatorParse::Complete(op, 1))
+}
+
+fn parse_count(chars: &[char], pos: &mut usize) -> Option<usize> {
+    let start = *pos;
+    while let Some(c) = chars.get(*pos) {
+        // A leading '0' is the LineStart motion, not a count
+        if c.is_ascii_digit() && !(*c == '0' && *pos == start) {
+            *pos += 1;
+        } else {
+            break;
+        }
+    }
+    if *pos == start {
+        return None;
+    }
+    chars[start..*pos].iter().collect::<String>().parse().ok()
+}
+
+fn multiply_counts(first: Option<usize>, second: Option<usize>) -> Option<usize> {
+    match (first, second) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1))),
+    }
+}
+
+enum MotionParse {
+    Pending,
+    Complete(Motion, usize),
+}
+
+fn parse_motion(chars: &[char]) -> Option<MotionParse> {
+    let motion = match chars.first()? {
+        'h' => Motion::Left,
+        'l' => Motion::Right,
+        'k' => Motion::Up,
+        'j' => Motion::Down,
+        'w' => Motion::WordForward,
+        'b' => Motion::WordBackward,
+        'e' => Motion::WordEndForward,
+        '0' => Motion::LineStart,
+        '^' => Motion::LineFirstNonWhitespace,
+        '$' => Motion::LineEnd,
+        'G' => Motion::FileEnd,
+        '}' => Motion::ParagraphForward,
+        '{' => Motion::ParagraphBackward,
+        'n' => Motion::SearchNext,
+        'N' => Motion::SearchPrevious,
+        '*' => Motion::SearchWordForward,
+        '#' => Motion::SearchWordBackward,
+        'g' => {
+            return match chars.get(1) {
+                None => Some(MotionParse::Pending),
+                Some('g') => Some(MotionParse::Complete(Motion::FileStart, 2)),
+                Some(_) => None,
+            };
+        }
+        bracket @ (']' | '[') => {
+            let motion = match (bracket, chars.get(1)) {
+                (_, None) => return Some(MotionParse::Pending),
+                (']', Some('f')) => Motion::NextFunction,
+                ('[', Some('f')) => Motion::PreviousFunction,
+                ('[', Some('u')) => Motion::ParentNode,
+                _ => return None,
+            };
+            return Some(MotionParse::Complete(motion, 2));
+        }
+        _ => return None,
+    };
+    Some(MotionParse::Complete(motion, 1))
+}
@@ -1,11 +1,14 @@
 use std::io::Result;
 use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use ratatui::{
     DefaultTerminal, Frame,
     crossterm::event::{self, Event, KeyEvent, KeyEventKind},
     layout::{Constraint, Direction, Layout, Rect},
-    style::Stylize,
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
     widgets::{Block, Paragraph, Widget},
 };
 
@@ -13,43 +16,209 @@ use crate::{
     EditorState,
     buffer::HBuffer,
     editor::{Editor, EditorAction, NavigateMode},
-    file_ops,
+    encoding::Encoding,
+    file_ops, hex,
+    line_ending::LineEnding,
+    swap::{self, SwapJob},
 };
 
+/// How often the swap files of buffers with unsaved changes are brought up to date.
+const SWAP_INTERVAL: Duration = Duration::from_secs(4);
+
 /// The Global App State for Heliolisk
 /// # Stores
 /// - Editor State
 /// - Quittable State
+/// - The background save and swap file threads
+/// - When to autosave
 pub struct Helios {
     editor_state: Option<EditorState>,
     should_quit: bool,
-    save_tx: Sender<std::result::Result<String, String>>,
-    save_rx: Receiver<std::result::Result<String, String>>,
+    save_tx: Sender<std::result::Result<Saved, String>>,
+    save_rx: Receiver<std::result::Result<Saved, String>>,
+    /// Feeds the swap thread, dropped to let it finish when quitting
+    swap_tx: Option<Sender<SwapJob>>,
+    swap_thread: Option<JoinHandle<()>>,
+    last_swap_update: Instant,
+    last_input: Instant,
+    /// Keys were pressed since the last autosave
+    autosave_pending: bool,
+}
+
+/// A background save that finished: the written file and the revision of its text.
+struct Saved {
+    file_name: String,
+    revision: u64,
+    line_ending: LineEnding,
+    encoding: Encoding,
+    /// Autosaves finish without a message
+    autosave: bool,
 }
 
 impl Helios {
     pub fn init(editor: Editor) -> Self {
         dbg!("Helios: Initialized Editor State");
         let (save_tx, save_rx) = mpsc::channel();
+        let (swap_tx, swap_thread) = swap::spawn_writer();
         Self {
             editor_state: Some(EditorState::Navigate(editor)),
             should_quit: false,
             save_tx,
             save_rx,
+            swap_tx: Some(swap_tx),
+            swap_thread: Some(swap_thread),
+            last_swap_update: Instant::now(),
+            last_input: Instant::now(),
+            autosave_pending: false,
         }
     }
 
     pub fn run(&mut self, terminal: &mut DefaultTerminal) -> color_eyre::Result<()> {
         while !self.should_quit {
             self.check_background_tasks();
+            self.check_file_loads();
             self.check_error_expiry();
+            self.update_swap_files();
+            self.check_autosave();
+            self.check_language_servers();
             terminal.draw(|frame| self.draw(frame))?;
             self.handle_events()?;
         }
 
+        self.close_swap_files();
+        match &mut self.editor_state {
+            Some(EditorState::Navigate(ed)) => ed.shutdown_language_servers(),
+            Some(EditorState::Command(ed)) => ed.shutdown_language_servers(),
+            Some(EditorState::Edit(ed)) => ed.shutdown_language_servers(),
+            Some(EditorState::Select(ed)) => ed.shutdown_language_servers(),
+            None => {}
+        }
         Ok(())
     }
 
+    /// Hands the swap thread the swap files to write or remove, every [`SWAP_INTERVAL`].
+    fn update_swap_files(&mut self) {
+        if self.last_swap_update.elapsed() < SWAP_INTERVAL {
+            return;
+        }
+        self.last_swap_update = Instant::now();
+
+        let jobs = match &mut self.editor_state {
+            Some(EditorState::Navigate(ed)) => ed.swap_jobs(),
+            Some(EditorState::Command(ed)) => ed.swap_jobs(),
+            Some(EditorState::Edit(ed)) => ed.swap_jobs(),
+            Some(EditorState::Select(ed)) => ed.swap_jobs(),
+            None => Vec::new(),
+        };
+        if let Some(swap_tx) = &self.swap_tx {
+            for job in jobs {
+                let _ = swap_tx.send(job);
+            }
+        }
+    }
+
+    /// How long typing must stop for before an autosave, None when `autosave` is off.
+    fn autosave_delay(&self) -> Option<Duration> {
+        match &self.editor_state {
+            Some(EditorState::Navigate(ed)) => ed.autosave_delay(),
+            Some(EditorState::Command(ed)) => ed.autosave_delay(),
+            Some(EditorState::Edit(ed)) => ed.autosave_delay(),
+            Some(EditorState::Select(ed)) => ed.autosave_delay(),
+            None => None,
+        }
+    }
+
+    /// Autosaves once typing has stopped for `autosavetime`.
+    fn check_autosave(&mut self) {
+        if let Some(delay) = self.autosave_delay()
+            && self.autosave_pending
+            && self.last_input.elapsed() >= delay
+        {
+            self.autosave();
+        }
+    }
+
+    /// Writes every named buffer with unsaved changes in the background, if `autosave` is set.
+    fn autosave(&mut self) {
+        if self.autosave_delay().is_none() {
+            return;
+        }
+        self.autosave_pending = false;
+        let buffers = match &self.editor_state {
+            Some(EditorState::Navigate(ed)) => ed.autosave_buffers(),
+            Some(EditorState::Command(ed)) => ed.autosave_buffers(),
+            Some(EditorState::Edit(ed)) => ed.autosave_buffers(),
+            Some(EditorState::Select(ed)) => ed.autosave_buffers(),
+            None => Vec::new(),
+        };
+        for (file_name, buffer) in buffers {
+            self.save_in_background(buffer, file_name, true);
+        }
+    }
+
+    /// Writes `buffer` to `file_name` on another thread, which reports to `save_rx`.
+    fn save_in_background(&self, buffer: HBuffer, file_name: String, autosave: bool) {
+        let tx = self.save_tx.clone();
+        std::thread::spawn(move || {
+            match file_ops::write_buffer_to_file(&buffer, Some(file_name.clone())) {
+                Ok(_) => {
+                    let _ = tx.send(Ok(Saved {
+                        file_name,
+                        revision: buffer.text.revision(),
+                        line_ending: buffer.line_ending,
+                        encoding: buffer.encoding,
+                        autosave,
+                    }));
+                }
+                Err(e) => {
+                    let failed = if autosave { "Autosave" } else { "Save" };
+                    let _ = tx.send(Err(format!("{} failed: {}", failed, e)));
+                }
+            }
+        });
+    }
+
+    /// Sends the language servers the edits made and acts on their answers.
+    fn check_language_servers(&mut self) {
+        match &mut self.editor_state {
+            Some(EditorState::Navigate(ed)) => ed.check_language_servers(),
+            Some(EditorState::Command(ed)) => ed.check_language_servers(),
+            Some(EditorState::Edit(ed)) => ed.check_language_servers(),
+            Some(EditorState::Select(ed)) => ed.check_language_servers(),
+            None => {}
+        }
+    }
+
+    /// Removes the swap files on a clean exit, waiting for the swap thread to finish.
+    fn close_swap_files(&mut self) {
+        let jobs = match &mut self.editor_state {
+            Some(EditorState::Navigate(ed)) => ed.close_swap_files(),
+            Some(EditorState::Command(ed)) => ed.close_swap_files(),
+            Some(EditorState::Edit(ed)) => ed.close_swap_files(),
+            Some(EditorState::Select(ed)) => ed.close_swap_files(),
+            None => Vec::new(),
+        };
+        if let Some(swap_tx) = self.swap_tx.take() {
+            for job in jobs {
+                let _ = swap_tx.send(job);
+            }
+        }
+        if let Some(swap_thread) = self.swap_thread.take() {
+            let _ = swap_thread.join();
+        }
+    }
+
+    /// Brings in the progress of large files loading in the background.
+    fn check_file_loads(&mut self) {
+        match &mut self.editor_state {
+            Some(EditorState::Navigate(ed)) => ed.check_loads(),
+            Some(EditorState::Command(ed)) => ed.check_loads(),
+            Some(EditorState::Edit(ed)) => ed.check_loads(),
+            Some(EditorState::Select(ed)) => ed.check_loads(),
+            None => {}
+        }
+    }
+
     pub fn check_error_expiry(&mut self) {
         if let Some(state) = &mut self.editor_state {
             match state {
@@ -63,11 +232,39 @@ impl Helios {
 
     pub fn check_background_tasks(&mut self) {
         while let Ok(res) = self.save_rx.try_recv() {
-            let msg = match res {
-                Ok(s) => s,
-                Err(e) => format!("Error: {}", e),
+            let msg = match &res {
+                Ok(saved) if saved.autosave => None,
+                Ok(saved) => Some(format!("Saved {}", saved.file_name)),
+                Err(e) => Some(format!("Error: {}", e)),
             };
             if let Some(state) = &mut self.editor_state {
+                if let Ok(Saved {
+                    file_name,
+                    revision,
+                    line_ending,
+                    encoding,
+                    ..
+                }) = &res
+                {
+                    let (revision, line_ending, encoding) = (*revision, *line_ending, *encoding);
+                    match state {
+                        EditorState::Navigate(ed) => {
+                            ed.mark_saved(file_name, revision, line_ending, encoding)
+                        }
+                        EditorState::Command(ed) => {
+                            ed.mark_saved(file_name, revision, line_ending, encoding)
+                        }
+                        EditorState::Edit(ed) => {
+                            ed.mark_saved(file_name, revision, line_ending, encoding)
+                        }
+                        EditorState::Select(ed) => {
+                            ed.mark_saved(file_name, revision, line_ending, encoding)
+                        }
+                    }
+                }
+                let Some(msg) = msg else {
+                    continue;
+                };
                 match state {
                     EditorState::Navigate(ed) => ed.set_error_line(msg),
                     EditorState::Command(ed) => ed.set_error_line(msg),
@@ -78,22 +275,49 @@ impl Helios {
         }
     }
 
+    /// Splits `area` into the tab line, the windows and the status line below them.
+    ///
+    /// The tab line is only shown when there are several tab pages. The status line
+    /// grows to fit a message spanning several lines, like `:ls`, up to half of the screen.
+    fn layout(&self, area: Rect) -> std::rc::Rc<[Rect]> {
+        let tab_count = match &self.editor_state {
+            Some(EditorState::Navigate(ed)) => ed.get_tabs().len(),
+            Some(EditorState::Command(ed)) => ed.get_tabs().len(),
+            Some(EditorState::Edit(ed)) => ed.get_tabs().len(),
+            Some(EditorState::Select(ed)) => ed.get_tabs().len(),
+            None => 1,
+        };
+        let tab_line_height = if tab_count > 1 { 1 } else { 0 };
+        let message_lines = match &self.editor_state {
+            Some(EditorState::Navigate(ed)) => ed.get_error_line().lines().count(),
+            Some(EditorState::Command(ed)) => ed.get_error_line().lines().count(),
+            Some(EditorState::Edit(ed)) => ed.get_error_line().lines().count(),
+            Some(EditorState::Select(ed)) => ed.get_error_line().lines().count(),
+            None => 1,
+        };
+        let status_height = (message_lines as u16).clamp(1, (area.height / 2).max(1));
+
+        Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![
+                Constraint::Length(tab_line_height),
+                Constraint::Min(1),
+                Constraint::Length(status_height),
+            ])
+            .split(area)
+    }
+
     pub fn draw(&mut self, frame: &mut Frame) {
         let area = frame.area();
-
-        let layout = Layout::default()
-            .direction(Direction::Vertical)
-            .constraints(vec![Constraint::Min(1), Constraint::Length(1)])
-            .split(area);
+        let layout = self.layout(area);
 
         // 1. Update Viewport (Mutation phase)
         if let Some(state) = &mut self.editor_state {
-            let height = (layout[0].height as usize).saturating_sub(2);
             match state {
-                EditorState::Navigate(ed) => ed.update_viewport(height),
-                EditorState::Command(ed) => ed.update_viewport(height),
-                EditorState::Edit(ed) => ed.update_viewport(height),
-                EditorState::Select(ed) => ed.update_viewport(height),
+                EditorState::Navigate(ed) => ed.update_layout(layout[1]),
+                EditorState::Command(ed) => ed.update_layout(layout[1]),
+                EditorState::Edit(ed) => ed.update_layout(layout[1]),
+                EditorState::Select(ed) => ed.update_layout(layout[1]),
             }
         }
 
@@ -102,14 +326,20 @@ impl Helios {
 
         // 3. Render Cursor and manage offsets (Immutable access)
         if let Some(state) = &self.editor_state {
-            let buffers = match state {
-                EditorState::Navigate(ed) => ed.get_buffers(),
-                EditorState::Command(ed) => ed.get_buffers(),
-                EditorState::Edit(ed) => ed.get_buffers(),
-                EditorState::Select(ed) => ed.get_buffers(),
+            let buffer = match state {
+                EditorState::Navigate(ed) => ed.get_active_buffer(),
+                EditorState::Command(ed) => ed.get_active_buffer(),
+                EditorState::Edit(ed) => ed.get_active_buffer(),
+                EditorState::Select(ed) => ed.get_active_buffer(),
             };
 
-            let height = (layout[0].height as usize).saturating_sub(2);
+            let window_area = match state {
+                EditorState::Navigate(ed) => ed.get_focused_window_area(),
+                EditorState::Command(ed) => ed.get_focused_window_area(),
+                EditorState::Edit(ed) => ed.get_focused_window_area(),
+                EditorState::Select(ed) => ed.get_focused_window_area(),
+            };
+            let height = (window_area.height as usize).saturating_sub(2);
             let (cursor_col, cursor_line) = match state {
                 EditorState::Navigate(ed) => ed.get_cursor_position(),
                 EditorState::Command(ed) => ed.get_cursor_position(),
@@ -124,25 +354,48 @@ impl Helios {
                 EditorState::Select(ed) => ed.get_scroll_offset(),
             };
 
+            let hex_cursor = match state {
+                EditorState::Navigate(ed) => ed.get_hex_cursor(),
+                EditorState::Command(ed) => ed.get_hex_cursor(),
+                EditorState::Edit(ed) => ed.get_hex_cursor(),
+                EditorState::Select(ed) => ed.get_hex_cursor(),
+            };
+
             // Calculate visual cursor position relative to the viewport
-            if cursor_line >= scroll_offset && cursor_line < scroll_offset + height {
-                let line_text = buffers[0].text.line(cursor_line);
-                let visual_col: usize = line_text
-                    .chars()
-                    .take(cursor_col)
-                    .map(|c| if c == '\t' { 4 } else { 1 })
-                    .sum();
-
-                let visual_cursor_y = cursor_line - scroll_offset;
-                let cursor_x = layout[0].x + visual_col as u16 + 1; // +1 for left border
-                let cursor_y = layout[0].y + visual_cursor_y as u16 + 1; // +1 for top border
-
-                if cursor_x < layout[0].x + layout[0].width - 1
-                    && cursor_y < layout[0].y + layout[0].height - 1
+            // Each closed fold above the cursor takes a single row
+            let cursor_row = buffer.folds.rows_between(scroll_offset, cursor_line);
+            if cursor_line >= scroll_offset && cursor_row < height {
+                let visual_col: usize = if buffer.hex {
+                    hex::cursor_column(cursor_col, hex_cursor)
+                } else {
+                    buffer
+                        .text
+                        .line(cursor_line)
+                        .chars()
+                        .take(cursor_col)
+                        .map(|c| if c == '\t' { 4 } else { 1 })
+                        .sum()
+                };
+
+                let visual_cursor_y = cursor_row;
+                // +1 for left border, then the gutter of diagnostic signs
+                let cursor_x = window_area.x + (visual_col + buffer.gutter_width()) as u16 + 1;
+                let cursor_y = window_area.y + visual_cursor_y as u16 + 1; // +1 for top border
+
+                if cursor_x < window_area.right().saturating_sub(1)
+                    && cursor_y < window_area.bottom().saturating_sub(1)
                 {
                     frame.set_cursor_position((cursor_x, cursor_y));
                 }
             }
+
+            // While typing a command, the cursor is on the command line
+            if let EditorState::Command(ed) = state
+                && let Some(col) = ed.get_command_cursor()
+            {
+                let cursor_x = layout[2].x + (col as u16).min(layout[2].width.saturating_sub(1));
+                frame.set_cursor_position((cursor_x, layout[2].y));
+            }
         }
     }
 
@@ -152,8 +405,11 @@ impl Helios {
                 // it's important to check that the event is a key press event as
                 // crossterm also emits key release and repeat events on Windows.
                 Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
+                    self.last_input = Instant::now();
+                    self.autosave_pending = true;
                     self.handle_key_event(key_event)
                 }
+                Event::FocusLost if self.autosave_pending => self.autosave(),
                 _ => {}
             };
         }
@@ -169,6 +425,9 @@ impl Helios {
                         EditorState::Navigate(editor)
                     }
                     EditorAction::EnterEditMode => EditorState::Edit(editor.enter_edit_mode()),
+                    EditorAction::EnterEditModeAfterChange => {
+                        EditorState::Edit(editor.enter_edit_mode_after_change())
+                    }
                     EditorAction::EnterEditModeInNewLine => {
                         let mut ed = editor.enter_edit_mode();
                         ed.open_line_below();
@@ -199,6 +458,9 @@ impl Helios {
                         EditorState::Command(editor.enter_command_mode())
                     }
                     EditorAction::EnterEditMode => EditorState::Edit(editor.enter_edit_mode()),
+                    EditorAction::EnterEditModeAfterChange => {
+                        EditorState::Edit(editor.enter_edit_mode_after_change())
+                    }
                     _ => EditorState::Select(editor),
                 },
                 EditorState::Command(mut editor) => match editor.handle_input(key_event) {
@@ -218,26 +480,16 @@ impl Helios {
                             .unwrap_or_else(|| "helios_test.txt".to_string());
 
                         // Update buffer path so future saves use it
-                        editor.get_active_buffer_mut().file_path = Some(effective_name.clone());
+                        editor
+                            .get_active_buffer_mut()
+                            .set_file_path(effective_name.clone());
 
+                        // Writing ends the undo step being recorded
+                        editor.get_active_buffer_mut().commit_undo_step();
                         let buffer_clone = editor.get_active_buffer().clone();
-                        let tx = self.save_tx.clone();
 
                         editor.set_error_line("Saving in background...".to_string());
-
-                        std::thread::spawn(move || {
-                            match file_ops::write_buffer_to_file(
-                                &buffer_clone,
-                                Some(effective_name.clone()),
-                            ) {
-                                Ok(_) => {
-                                    let _ = tx.send(Ok(format!("Saved {}", effective_name)));
-                                }
-                                Err(e) => {
-                                    let _ = tx.send(Err(format!("Save failed: {}", e)));
-                                }
-                            }
-                        });
+                        self.save_in_background(buffer_clone, effective_name, false);
 
                         EditorState::Command(editor)
                     }
@@ -249,12 +501,25 @@ impl Helios {
                             .or(current_path)
                             .unwrap_or_else(|| "helios_test.txt".to_string());
 
+                        editor.get_active_buffer_mut().commit_undo_step();
                         // We use get_active_buffer() instead of direct buffers access for consistency
                         let buffer = editor.get_active_buffer();
+                        let (revision, line_ending, encoding) =
+                            (buffer.text.revision(), buffer.line_ending, buffer.encoding);
 
                         match file_ops::write_buffer_to_file(buffer, Some(effective_name)) {
                             Ok(_) => {
-                                self.should_quit = true;
+                                editor.get_active_buffer_mut().mark_saved(
+                                    revision,
+                                    line_ending,
+                                    encoding,
+                                );
+                                // Quitting still stops at other buffers with unsaved changes
+                                match editor.quit_window(false) {
+                                    Ok(EditorAction::Quit) => self.should_quit = true,
+                                    Ok(_) => {}
+                                    Err(e) => editor.set_error_line(e),
+                                }
                             }
                             Err(s) => {
                                 let mut status = String::from("Error Occurred... ");
@@ -268,7 +533,11 @@ impl Helios {
                         self.should_quit = true;
                         EditorState::Command(editor)
                     }
-                    EditorAction::AddNewBuffer => EditorState::Command(editor),
+                    EditorAction::AddNewBuffer => {
+                        let mut editor = editor.enter_navigate_mode();
+                        editor.add_new_buffer();
+                        EditorState::Navigate(editor)
+                    }
                     _ => EditorState::Command(editor),
                 },
             });
@@ -276,39 +545,94 @@ impl Helios {
     }
 }
 
-pub fn initialize_app() -> Helios {
+/// Sets up the editor from the command line: `heliolisk [file]`, or `heliolisk -r`
+/// to list swap files left by crashed sessions and `heliolisk -r <file or number>`
+/// to recover one.
+///
+/// Returns None when there is nothing to edit, after listing swap files or failing
+/// to read the file.
+pub fn initialize_app() -> Option<Helios> {
     let args: Vec<String> = std::env::args().collect();
-    let initial_buffer = if let Some(file_name) = args.get(1) {
+    let recovering = args.get(1).is_some_and(|arg| arg == "-r");
+    if recovering && args.len() == 2 {
+        print_recoverable();
+        return None;
+    }
+
+    let mut file_name = args.get(if recovering { 2 } else { 1 }).cloned();
+    let mut recovered = None;
+    if recovering && let Some(number) = file_name.as_deref().and_then(|arg| arg.parse().ok()) {
+        let Some(swap) = swap::recoverable()
+            .into_iter()
+            .nth(usize::saturating_sub(number, 1))
+        else {
+            eprintln!("No swap file numbered {}", number);
+            return None;
+        };
+        file_name = swap.file_path.clone();
+        recovered = Some(swap);
+    }
+
+    let mut initial_buffer = if let Some(file_name) = &file_name {
         let path = std::path::PathBuf::from(file_name);
-        match file_ops::load_file(&path) {
-            Ok(buffer) => buffer,
-            Err(_) => {
-                // File likely doesn't exist, create new buffer with this path
-                let mut buffer = HBuffer::new();
-                buffer.file_path = Some(file_name.clone());
-                buffer.file_format = path
-                    .extension()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or("txt")
-                    .to_string();
-                buffer
+        if !path.exists() {
+            HBuffer::for_new_file(file_name)
+        } else if file_ops::is_large_file(&path) && !recovering {
+            HBuffer::for_loading(file_name)
+        } else {
+            // An empty buffer would overwrite the file with its first write
+            match file_ops::load_file(&path, None) {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    eprintln!("Can't open {}: {}", file_name, e);
+                    return None;
+                }
             }
         }
     } else {
         HBuffer::new()
     };
 
-    let editor = Editor::<NavigateMode>::new(vec![initial_buffer]);
+    let found_swap = initial_buffer.found_swap.take();
+    if recovering {
+        recovered = recovered.or(found_swap);
+    } else {
+        initial_buffer.found_swap = found_swap;
+    }
+
+    let loading = initial_buffer.loading;
+    let mut editor = Editor::<NavigateMode>::new(vec![initial_buffer]);
+    if loading {
+        editor.load_in_background(0, None);
+    }
+    editor.load_histories();
+    editor.load_language_servers();
+    match recovered {
+        Some(swap) => editor.recover_swap(swap),
+        None if recovering => editor.set_error_line("No swap file found".to_string()),
+        None => editor.show_swap_prompt(0),
+    }
+
+    Some(Helios::init(editor))
+}
 
-    Helios::init(editor)
+/// Lists the swap files `heliolisk -r <number>` can recover.
+fn print_recoverable() {
+    let swaps = swap::recoverable();
+    if swaps.is_empty() {
+        println!("No swap files found");
+        return;
+    }
+    println!("Swap files found:");
+    for (i, swap) in swaps.iter().enumerate() {
+        println!("{:>4}. {}", i + 1, swap.describe());
+    }
+    println!("Recover one with: heliolisk -r <file or number>");
 }
 
 impl Widget for &Helios {
     fn render(self, area: Rect, buf: &mut ratatui::buffer::Buffer) {
-        let layout = Layout::default()
-            .direction(Direction::Vertical)
-            .constraints(vec![Constraint::Min(1), Constraint::Length(1)])
-            .split(area);
+        let layout = self.layout(area);
         if let Some(state) = &self.editor_state {
             let buffers = match state {
                 EditorState::Navigate(ed) => ed.get_buffers(),
@@ -316,16 +640,39 @@ impl Widget for &Helios {
                 EditorState::Edit(ed) => ed.get_buffers(),
                 EditorState::Select(ed) => ed.get_buffers(),
             };
+            let windows = match state {
+                EditorState::Navigate(ed) => ed.get_windows(),
+                EditorState::Command(ed) => ed.get_windows(),
+                EditorState::Edit(ed) => ed.get_windows(),
+                EditorState::Select(ed) => ed.get_windows(),
+            };
 
-            let state_name = format!("{}", state);
-            let cursor_position = match state {
-                EditorState::Navigate(e) => e.get_cursor_position(),
-                EditorState::Edit(e) => e.get_cursor_position(),
-                EditorState::Select(e) => e.get_cursor_position(),
-                EditorState::Command(e) => e.get_cursor_position(),
+            let tabs = match state {
+                EditorState::Navigate(ed) => ed.get_tabs(),
+                EditorState::Command(ed) => ed.get_tabs(),
+                EditorState::Edit(ed) => ed.get_tabs(),
+                EditorState::Select(ed) => ed.get_tabs(),
             };
-            let (char_pos, line_pos) = cursor_position;
+            if tabs.len() > 1 {
+                let tab_style = Style::default().bg(Color::DarkGray).fg(Color::White);
+                let current_tab_style = Style::default().bg(Color::White).fg(Color::Black);
+                let spans: Vec<Span> = tabs
+                    .into_iter()
+                    .map(|(label, current)| {
+                        let style = if current {
+                            current_tab_style
+                        } else {
+                            tab_style
+                        };
+                        Span::styled(format!(" {} ", label), style)
+                    })
+                    .collect();
+                Paragraph::new(Line::from(spans))
+                    .style(tab_style)
+                    .render(layout[0], buf);
+            }
 
+            let state_name = format!("{}", state);
             let state_name = match state {
                 EditorState::Navigate(_) => state_name.white(),
                 EditorState::Edit(_) => state_name.green(),
@@ -333,44 +680,241 @@ impl Widget for &Helios {
                 EditorState::Command(_) => state_name.light_red(),
             };
 
-            let main_block = Block::bordered()
-                .title_bottom(state_name)
-                .title_top(
-                    buffers[0]
-                        .file_path
-                        .clone()
-                        .unwrap_or_else(|| ".txt".to_string()),
-                )
-                .title_bottom(format!("{}:{}", line_pos + 1, char_pos + 1));
+            let match_style = Style::default().bg(Color::Cyan).fg(Color::Black);
+            let current_match_style = Style::default().bg(Color::LightMagenta).fg(Color::Black);
+            let selection_style = Style::default().bg(Color::Yellow).fg(Color::Black);
+            let fold_style = Style::default().bg(Color::DarkGray).fg(Color::Cyan);
 
-            let scroll_offset = match state {
-                EditorState::Navigate(e) => e.get_scroll_offset(),
-                EditorState::Edit(e) => e.get_scroll_offset(),
-                EditorState::Select(e) => e.get_scroll_offset(),
-                EditorState::Command(e) => e.get_scroll_offset(),
-            };
+            for (window_area, buffer_index, view, focused) in windows {
+                let buffer = &buffers[buffer_index];
+                let mut title = buffer.display_name().to_string();
+                if buffer.has_unsaved_changes() {
+                    title.push_str(" [+]");
+                }
+                if buffer.read_only {
+                    title.push_str(" [RO]");
+                }
+                if buffer.loading {
+                    title.push_str(" [loading]");
+                }
+                if buffers.len() > 1 {
+                    title.push_str(&format!(" [{}/{}]", buffer_index + 1, buffers.len()));
+                }
 
-            let viewport_height = (layout[0].height as usize).saturating_sub(2);
+                // Only the focused window shows the mode, the others are dimmed
+                let mut main_block = Block::bordered().title_top(title);
+                if focused {
+                    main_block = main_block.title_bottom(state_name.clone());
+                } else {
+                    main_block = main_block.border_style(Style::default().fg(Color::DarkGray));
+                }
+                // A hex dump shows the offset of the byte instead of the line and column
+                let position = if buffer.hex {
+                    format!(
+                        "0x{:08x}",
+                        view.cursor_line * hex::BYTES_PER_ROW + view.cursor_col
+                    )
+                } else {
+                    format!("{}:{}", view.cursor_line + 1, view.cursor_col + 1)
+                };
+                let language = buffer
+                    .language()
+                    .map_or(String::new(), |language| format!("{} ", language.name()));
+                let main_block = main_block.title_bottom(format!(
+                    "{}  {}{} {}",
+                    position,
+                    language,
+                    buffer.encoding.name(),
+                    buffer.line_ending.name()
+                ));
+
+                let scroll_offset = view.scroll_offset;
+                let viewport_height = (window_area.height as usize).saturating_sub(2);
+
+                if buffer.hex {
+                    let rows = hex::row_count(buffer.char_count());
+                    let cursor_style = Style::default().bg(Color::DarkGray);
+                    let hex_lines: Vec<Line> = (0..viewport_height)
+                        .map(|i| {
+                            let row = scroll_offset + i;
+                            // The cursor may be on a new row, just past the last byte
+                            if row >= rows && row != view.cursor_line {
+                                return Line::default();
+                            }
+                            let mut highlights = Vec::new();
+                            if focused && row == view.cursor_line {
+                                for (start, end) in hex::byte_columns(view.cursor_col) {
+                                    highlights.push((start, end, cursor_style));
+                                }
+                            }
+                            styled_line(&hex::render_row(&buffer.text, row), &highlights)
+                        })
+                        .collect();
+                    Paragraph::new(hex_lines)
+                        .block(main_block)
+                        .render(window_area, buf);
+                    continue;
+                }
 
-            let ratatui_lines: Vec<ratatui::text::Line> = (0..viewport_height)
-                .map(|i| {
-                    let line_idx = scroll_offset + i;
-                    let line_cow = buffers[0].text.line(line_idx);
-                    // Remove newline characters for rendering if necessary, though Ratatui handles them usually.
-                    // Ropey lines include newlines.
-                    let line_str = line_cow
-                        .trim_end_matches(['\n', '\r'])
-                        .replace("\t", "    ");
-                    ratatui::text::Line::from(line_str)
-                })
-                .collect();
+                let selection = match state {
+                    EditorState::Select(e) if focused => Some(e.get_selection()),
+                    _ => None,
+                };
+
+                let shown_lines =
+                    buffer
+                        .folds
+                        .shown_lines(scroll_offset, viewport_height, buffer.line_count());
+                let last_visible_line = shown_lines.last().copied().unwrap_or(scroll_offset);
+                let (search_matches, current_match) = match state {
+                    EditorState::Navigate(e) => {
+                        e.get_search_highlights(buffer_index, scroll_offset, last_visible_line)
+                    }
+                    EditorState::Edit(e) => {
+                        e.get_search_highlights(buffer_index, scroll_offset, last_visible_line)
+                    }
+                    EditorState::Select(e) => {
+                        e.get_search_highlights(buffer_index, scroll_offset, last_visible_line)
+                    }
+                    EditorState::Command(e) => {
+                        e.get_search_highlights(buffer_index, scroll_offset, last_visible_line)
+                    }
+                };
+
+                let ratatui_lines: Vec<Line> = shown_lines
+                    .iter()
+                    .map(|&line_idx| {
+                        let line_cow = buffer.text.line(line_idx);
+                        // Remove newline characters for rendering if necessary, though Ratatui handles them usually.
+                        // Ropey lines include newlines.
+                        let line_str = line_cow.trim_end_matches(['\n', '\r']);
+
+                        // A closed fold shows as one line saying how many it hides
+                        if let Some((first, last)) = buffer.folds.closed_at(line_idx) {
+                            return Line::styled(
+                                format!("+--{:>3} lines: {}", last - first + 1, line_str.trim()),
+                                fold_style,
+                            );
+                        }
+                        let line_start = buffer.text.line_to_char(line_idx);
+                        let line_end = line_start + line_str.chars().count();
 
-            let para = Paragraph::new(ratatui_lines);
-            para.block(main_block).render(layout[0], buf);
+                        // Later highlights are drawn over earlier ones
+                        let tokens = match state {
+                            EditorState::Navigate(e) => {
+                                e.get_syntax_tokens(buffer_index, line_idx, line_str)
+                            }
+                            EditorState::Edit(e) => {
+                                e.get_syntax_tokens(buffer_index, line_idx, line_str)
+                            }
+                            EditorState::Select(e) => {
+                                e.get_syntax_tokens(buffer_index, line_idx, line_str)
+                            }
+                            EditorState::Command(e) => {
+                                e.get_syntax_tokens(buffer_index, line_idx, line_str)
+                            }
+                        };
+                        let mut highlights: Vec<_> = tokens
+                            .iter()
+                            .map(|token| (token.start, token.end, token.kind.style()))
+                            .collect();
+                        // Diagnostics underline their text, at least one cell of it
+                        let line_len = line_str.chars().count();
+                        for diagnostic in &buffer.diagnostics {
+                            if diagnostic.start.0 > line_idx || diagnostic.end.0 < line_idx {
+                                continue;
+                            }
+                            let start = if diagnostic.start.0 == line_idx {
+                                diagnostic.start.1
+                            } else {
+                                0
+                            };
+                            let end = if diagnostic.end.0 == line_idx {
+                                diagnostic.end.1
+                            } else {
+                                line_len
+                            };
+                            let color = diagnostic.severity.style().fg.unwrap_or(Color::Red);
+                            highlights.push((
+                                start,
+                                end.max(start + 1),
+                                Style::default().underlined().underline_color(color),
+                            ));
+                        }
+                        let on_line = |range: &std::ops::Range<usize>| {
+                            (range.start < line_end && range.end > line_start).then(|| {
+                                (
+                                    range.start.saturating_sub(line_start),
+                                    range.end - line_start,
+                                )
+                            })
+                        };
+                        for range in &search_matches {
+                            if let Some((start, end)) = on_line(range) {
+                                highlights.push((start, end, match_style));
+                            }
+                        }
+                        if let Some((start, end)) = current_match.as_ref().and_then(on_line) {
+                            highlights.push((start, end, current_match_style));
+                        }
+                        if let Some((start, end)) = selection.and_then(|selection| {
+                            selection.columns_on_line(line_idx, line_str.chars().count())
+                        }) {
+                            highlights.push((start, end, selection_style));
+                        }
+                        styled_line(line_str, &highlights)
+                    })
+                    .collect();
+
+                // Buffers opened in a language server have a gutter for diagnostic signs
+                let ratatui_lines: Vec<Line> = if buffer.gutter_width() > 0 {
+                    shown_lines
+                        .iter()
+                        .zip(ratatui_lines)
+                        .map(|(&line_idx, mut line)| {
+                            let sign = match buffer.diagnostic_on_line(line_idx) {
+                                Some(diagnostic) => Span::styled(
+                                    format!("{} ", diagnostic.severity.sign()),
+                                    diagnostic.severity.style(),
+                                ),
+                                None => Span::raw("  "),
+                            };
+                            line.spans.insert(0, sign);
+                            line
+                        })
+                        .collect()
+                } else {
+                    ratatui_lines
+                };
+
+                let para = Paragraph::new(ratatui_lines);
+                para.block(main_block).render(window_area, buf);
+
+                // The completion menu opens at the start of the completed word
+                if let EditorState::Edit(e) = state
+                    && focused
+                    && let Some(menu) = e.get_completion_menu()
+                    && menu.line >= scroll_offset
+                {
+                    let row = buffer.folds.rows_between(scroll_offset, menu.line);
+                    let col: usize = buffer
+                        .text
+                        .line(menu.line)
+                        .chars()
+                        .take(menu.start_col)
+                        .map(|c| if c == '\t' { 4 } else { 1 })
+                        .sum();
+                    let x = window_area.x + 1 + (buffer.gutter_width() + col) as u16;
+                    let y = window_area.y + 1 + row as u16;
+                    if row < viewport_height {
+                        menu.render(menu.area(x, y, window_area), buf);
+                    }
+                }
+            }
 
             let command_text = match state {
                 EditorState::Navigate(ed) => ed.get_command_line(),
-                EditorState::Command(ed) => ed.get_command_line(),
+                EditorState::Command(ed) => ed.get_command_display(),
                 EditorState::Edit(ed) => ed.get_command_line(),
                 EditorState::Select(ed) => ed.get_command_line(),
             };
@@ -392,7 +936,46 @@ impl Widget for &Helios {
                 Paragraph::new(command_text.clone())
             };
 
-            status_text.block(Block::new()).render(layout[1], buf);
+            status_text.block(Block::new()).render(layout[2], buf);
         }
     }
 }
+
+/// Renders a line with each `(start, end, style)` column range highlighted.
+///
+/// Ranges later in `highlights` are drawn over earlier ones, keeping what they
+/// leave unset, like the colors under an underline. A range running past the
+/// end of the line highlights one extra cell for the line break.
+fn styled_line(text: &str, highlights: &[(usize, usize, Style)]) -> Line<'static> {
+    let mut cells: Vec<(char, Style)> = text.chars().map(|c| (c, Style::default())).collect();
+    let len = cells.len();
+    if highlights.iter().any(|&(_, end, _)| end > len) {
+        cells.push((' ', Style::default()));
+    }
+
+    for &(start, end, style) in highlights {
+        let end = end.min(cells.len());
+        for cell in cells.iter_mut().take(end).skip(start) {
+            cell.1 = cell.1.patch(style);
+        }
+    }
+
+    let mut spans = Vec::new();
+    let mut run = String::new();
+    let mut run_style = Style::default();
+    for (c, style) in cells {
+        if style != run_style && !run.is_empty() {
+            spans.push(Span::styled(std::mem::take(&mut run), run_style));
+        }
+        run_style = style;
+        if c == '\t' {
+            run.push_str("    ");
+        } else {
+            run.push(c);
+        }
+    }
+    if !run.is_empty() {
+        spans.push(Span::styled(run, run_style));
+    }
+    Line::from(spans)
+}
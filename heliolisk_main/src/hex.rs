@@ -0,0 +1,78 @@
+use crate::encoding::raw_byte;
+use crate::rope::HeliosRope;
+
+/// Bytes shown on each row of a hex dump.
+pub const BYTES_PER_ROW: usize = 16;
+
+/// Column of a row's first hex byte, after its offset.
+const HEX_START: usize = 10;
+
+/// Column of a row's first ASCII char, after the hex bytes and a `|`.
+const ASCII_START: usize = HEX_START + 3 * BYTES_PER_ROW + 3;
+
+/// Where typing goes in the Edit Mode of a hex buffer.
+#[derive(Clone, Copy, Default)]
+pub struct HexCursor {
+    /// Typing ASCII chars rather than hex digits
+    pub ascii: bool,
+    /// The high half of the byte was typed and the low half comes next
+    pub low_nibble: bool,
+}
+
+/// Rows the dump of `len` bytes takes. An empty buffer still shows one.
+pub fn row_count(len: usize) -> usize {
+    len.div_ceil(BYTES_PER_ROW).max(1)
+}
+
+/// Byte at `offset` of a raw buffer, None past its end or for a char that isn't a byte.
+pub fn byte_at(text: &HeliosRope, offset: usize) -> Option<u8> {
+    text.char_at(offset).and_then(raw_byte)
+}
+
+/// One row of the dump, like `00000010  48 65 6c 6c 6f 20 77 6f  72 6c 64 0a 00 00 00 00  |Hello world.....|`.
+///
+/// A char typed into the buffer as text, which isn't a byte, shows as `??`.
+pub fn render_row(text: &HeliosRope, row: usize) -> String {
+    let start = row * BYTES_PER_ROW;
+    let mut line = format!("{:08x}  ", start);
+    let mut ascii = String::new();
+    for i in 0..BYTES_PER_ROW {
+        if i == BYTES_PER_ROW / 2 {
+            line.push(' ');
+        }
+        match text.char_at(start + i).map(raw_byte) {
+            Some(Some(byte)) => {
+                line.push_str(&format!("{:02x} ", byte));
+                ascii.push(if byte.is_ascii_graphic() || byte == b' ' {
+                    char::from(byte)
+                } else {
+                    '.'
+                });
+            }
+            Some(None) => {
+                line.push_str("?? ");
+                ascii.push('?');
+            }
+            None => line.push_str("   "),
+        }
+    }
+    line.push_str(" |");
+    line.push_str(&ascii);
+    line.push('|');
+    line
+}
+
+/// Screen column of the cursor on byte `col` of a row.
+pub fn cursor_column(col: usize, cursor: HexCursor) -> usize {
+    if cursor.ascii {
+        ASCII_START + col
+    } else {
+        HEX_START + 3 * col + col / (BYTES_PER_ROW / 2) + usize::from(cursor.low_nibble)
+    }
+}
+
+/// Column ranges byte `col` of a row takes, in the hex bytes and in the ASCII column.
+pub fn byte_columns(col: usize) -> [(usize, usize); 2] {
+    let hex = cursor_column(col, HexCursor::default());
+    [(hex, hex + 2), (ASCII_START + col, ASCII_START + col + 1)]
+}
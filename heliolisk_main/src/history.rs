@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use crate::file_ops;
+
+/// Number of entries kept in each history.
+const HISTORY_SIZE: usize = 100;
+
+/// Lines entered at a prompt, recalled with Up and Down.
+///
+/// Commands and searches keep separate histories. A history loaded from a file is
+/// written back to it whenever an entry is added.
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>,
+    /// Entry shown while browsing with Up and Down
+    browsing: Option<usize>,
+    /// What was typed before browsing started, restored past the newest entry
+    draft: String,
+    file: Option<PathBuf>,
+}
+
+impl History {
+    /// Loads the history kept in `file`, which is created on the first added entry.
+    pub fn load(file: PathBuf) -> Self {
+        let mut entries = file_ops::read_history(&file);
+        let excess = entries.len().saturating_sub(HISTORY_SIZE);
+        entries.drain(..excess);
+        Self {
+            entries,
+            file: Some(file),
+            ..Self::default()
+        }
+    }
+
+    /// Adds an entry as the newest one, removing an older copy of it.
+    pub fn add(&mut self, entry: &str) -> Result<(), String> {
+        self.reset();
+        if entry.trim().is_empty() {
+            return Ok(());
+        }
+
+        self.entries.retain(|e| e != entry);
+        self.entries.push(entry.to_string());
+        let excess = self.entries.len().saturating_sub(HISTORY_SIZE);
+        self.entries.drain(..excess);
+
+        match &self.file {
+            Some(file) => file_ops::write_history(file, &self.entries),
+            None => Ok(()),
+        }
+    }
+
+    /// Steps to the next older entry starting with what was typed before browsing.
+    pub fn older(&mut self, typed: &str) -> Option<String> {
+        if self.browsing.is_none() {
+            self.draft = typed.to_string();
+        }
+        let before = self.browsing.unwrap_or(self.entries.len());
+        let found = self.entries[..before]
+            .iter()
+            .rposition(|e| e.starts_with(&self.draft))?;
+        self.browsing = Some(found);
+        Some(self.entries[found].clone())
+    }
+
+    /// Steps to the next newer entry, or back to what was typed past the newest one.
+    pub fn newer(&mut self) -> Option<String> {
+        let current = self.browsing?;
+        let found = self.entries[current + 1..]
+            .iter()
+            .position(|e| e.starts_with(&self.draft))
+            .map(|offset| current + 1 + offset);
+        self.browsing = found;
+        Some(match found {
+            Some(found) => self.entries[found].clone(),
+            None => self.draft.clone(),
+        })
+    }
+
+    /// Stops browsing, so the next Up starts from the newest entry.
+    pub fn reset(&mut self) {
+        self.browsing = None;
+        self.draft.clear();
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use crate::buffer::HBuffer;
+use crate::menu::MenuItem;
+
+/// Lines on each side of the cursor searched for words in a large buffer, which is
+/// too long to search whole on every completion.
+const LARGE_BUFFER_LINES: usize = 1000;
+
+/// How a word of the open buffers ranks as a completion.
+#[derive(Default)]
+struct Candidate {
+    /// Found in another buffer than the one being edited
+    elsewhere: bool,
+    /// Fewest lines between the cursor and the word, in the buffer being edited
+    distance: usize,
+    count: usize,
+    /// Buffer the word was first found in, when it's another one
+    buffer_name: String,
+}
+
+/// Completions of the word before the cursor from the words of all open buffers,
+/// for `Ctrl-n` and `Ctrl-p` in Edit Mode. The word being completed starts at
+/// column `start_col` of the cursor line.
+///
+/// Words of the buffer being edited come first, the ones closest to the cursor
+/// first, then the ones found most often. Words only in other buffers follow.
+///
+/// Of a large buffer, only the lines around the cursor are searched, and other
+/// large buffers not at all.
+pub fn completions(
+    buffers: &[HBuffer],
+    active: usize,
+    cursor: (usize, usize),
+    start_col: usize,
+) -> Vec<MenuItem> {
+    let (cursor_line, cursor_col) = cursor;
+    let line = buffers[active].text.line(cursor_line);
+    let prefix: String = line
+        .chars()
+        .skip(start_col)
+        .take(cursor_col - start_col)
+        .collect();
+
+    let mut candidates: HashMap<String, Candidate> = HashMap::new();
+    // The buffer being edited first, so its words keep their distance
+    let order = std::iter::once(active).chain((0..buffers.len()).filter(|&i| i != active));
+    for index in order {
+        let buffer = &buffers[index];
+        if buffer.hex || buffer.loading || (buffer.large && index != active) {
+            continue;
+        }
+        let lines = if buffer.large {
+            cursor_line.saturating_sub(LARGE_BUFFER_LINES)
+                ..(cursor_line + LARGE_BUFFER_LINES + 1).min(buffer.line_count())
+        } else {
+            0..buffer.line_count()
+        };
+        for line_idx in lines {
+            let line = buffer.text.line(line_idx);
+            for (col, word) in words(&line) {
+                // The word being typed doesn't complete itself
+                if index == active && line_idx == cursor_line && col == start_col {
+                    continue;
+                }
+                if word.len() <= prefix.len() || !word.starts_with(&prefix) {
+                    continue;
+                }
+                let candidate = candidates
+                    .entry(word.to_string())
+                    .or_insert_with(|| Candidate {
+                        elsewhere: index != active,
+                        distance: usize::MAX,
+                        count: 0,
+                        buffer_name: buffer.display_name().to_string(),
+                    });
+                candidate.count += 1;
+                if index == active {
+                    candidate.distance = candidate.distance.min(line_idx.abs_diff(cursor_line));
+                }
+            }
+        }
+    }
+
+    let mut candidates: Vec<_> = candidates.into_iter().collect();
+    candidates.sort_by(|(a_word, a), (b_word, b)| {
+        (a.elsewhere, a.distance, std::cmp::Reverse(a.count), a_word).cmp(&(
+            b.elsewhere,
+            b.distance,
+            std::cmp::Reverse(b.count),
+            b_word,
+        ))
+    });
+    candidates
+        .into_iter()
+        .map(|(word, candidate)| MenuItem {
+            label: word.clone(),
+            detail: if candidate.elsewhere {
+                candidate.buffer_name
+            } else {
+                String::new()
+            },
+            insert: word.clone(),
+            filter: word,
+        })
+        .collect()
+}
+
+/// The words of `line` with the columns they start at.
+fn words(line: &str) -> impl Iterator<Item = (usize, &str)> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut chars = line.char_indices().enumerate().peekable();
+    std::iter::from_fn(move || {
+        let (col, (start, _)) = chars.find(|(_, (_, c))| is_word(*c))?;
+        let mut end = line.len();
+        while let Some(&(_, (i, c))) = chars.peek() {
+            if !is_word(c) {
+                end = i;
+                break;
+            }
+            chars.next();
+        }
+        Some((col, &line[start..end]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rope::HeliosRope;
+
+    fn buffer(name: &str, text: &str) -> HBuffer {
+        let mut buffer = HBuffer::new();
+        buffer.file_path = Some(name.to_string());
+        buffer.text = HeliosRope::from_str(text);
+        buffer
+    }
+
+    fn labels(items: &[MenuItem]) -> Vec<(&str, &str)> {
+        items
+            .iter()
+            .map(|item| (item.label.as_str(), item.detail.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn ranks_by_distance_then_frequency_then_other_buffers() {
+        let buffers = [
+            buffer(
+                "edited.rs",
+                "fold foo_far\nfootnote footnote\nfoo|\nfoobar fork fork\n",
+            ),
+            buffer("other.rs", "foreign footnote foreign\n"),
+        ];
+        let items = completions(&buffers, 0, (2, 2), 0);
+        assert_eq!(
+            labels(&items),
+            [
+                // One line away: found three times in all, twice, then once
+                ("footnote", ""),
+                ("fork", ""),
+                ("foobar", ""),
+                // Two lines away
+                ("fold", ""),
+                ("foo_far", ""),
+                ("foreign", "other.rs"),
+            ]
+        );
+        assert_eq!(items[0].insert, "footnote");
+    }
+
+    #[test]
+    fn completes_only_longer_words_with_the_typed_prefix() {
+        let buffers = [buffer("edited.rs", "foot fo Foot food\nfoo\n")];
+        let items = completions(&buffers, 0, (1, 3), 0);
+        assert_eq!(labels(&items), [("food", ""), ("foot", "")]);
+        // Nothing typed yet completes any word, but not the one at the cursor
+        let items = completions(&buffers, 0, (1, 0), 0);
+        assert_eq!(items.len(), 4);
+    }
+
+    #[test]
+    fn searches_only_near_the_cursor_in_large_buffers() {
+        let text = "nearly\n".to_string() + &"\n".repeat(LARGE_BUFFER_LINES) + "nearest\nne\n";
+        let mut large = buffer("large.log", &text);
+        large.large = true;
+        let mut other_large = buffer("other.log", "neat\n");
+        other_large.large = true;
+        let buffers = [large, other_large, buffer("small.rs", "nearby\n")];
+        let items = completions(&buffers, 0, (LARGE_BUFFER_LINES + 2, 2), 0);
+        assert_eq!(labels(&items), [("nearest", ""), ("nearby", "small.rs")]);
+    }
+}
@@ -0,0 +1,124 @@
+use std::borrow::Cow;
+
+/// How a file breaks its lines, the `fileformat` of its buffer.
+///
+/// Buffers always break lines with `\n`; the file's own line break is put back
+/// when it's written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LineEnding {
+    /// `\n`
+    #[default]
+    Unix,
+    /// `\r\n`
+    Dos,
+    /// `\r`, of old Macs
+    Mac,
+}
+
+impl LineEnding {
+    /// Finds the line break of a file's text.
+    ///
+    /// A file is only taken as `dos` when every `\n` follows a `\r`, so a file mixing
+    /// both keeps its `\r`s as text and is written back unchanged.
+    pub fn detect(text: &str) -> Self {
+        let line_feeds = text.matches('\n').count();
+        if line_feeds > 0 {
+            if text.matches("\r\n").count() == line_feeds {
+                Self::Dos
+            } else {
+                Self::Unix
+            }
+        } else if text.contains('\r') {
+            Self::Mac
+        } else {
+            Self::Unix
+        }
+    }
+
+    /// Name used by `:set fileformat`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Unix => "unix",
+            Self::Dos => "dos",
+            Self::Mac => "mac",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unix" => Some(Self::Unix),
+            "dos" => Some(Self::Dos),
+            "mac" => Some(Self::Mac),
+            _ => None,
+        }
+    }
+
+    /// Turns the line breaks of a file's text into `\n`.
+    pub fn normalize(self, text: &str) -> Cow<'_, str> {
+        match self {
+            Self::Unix => Cow::Borrowed(text),
+            Self::Dos => Cow::Owned(text.replace("\r\n", "\n")),
+            Self::Mac => Cow::Owned(text.replace('\r', "\n")),
+        }
+    }
+
+    /// Turns the `\n`s of a buffer's text into this line break, for writing it.
+    pub fn apply(self, text: &str) -> Cow<'_, str> {
+        match self {
+            Self::Unix => Cow::Borrowed(text),
+            Self::Dos => Cow::Owned(text.replace('\n', "\r\n")),
+            Self::Mac => Cow::Owned(text.replace('\n', "\r")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_line_break_of_a_file() {
+        let cases = [
+            ("", LineEnding::Unix),
+            ("one", LineEnding::Unix),
+            ("one\ntwo\n", LineEnding::Unix),
+            ("one\r\ntwo\r\n", LineEnding::Dos),
+            ("one\r\ntwo", LineEnding::Dos),
+            ("one\rtwo\r", LineEnding::Mac),
+            // Mixed line breaks are taken as `unix`, keeping the `\r`s as text
+            ("one\r\ntwo\n", LineEnding::Unix),
+            ("one\rtwo\n", LineEnding::Unix),
+            ("one\r\r\ntwo\r\n", LineEnding::Dos),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(LineEnding::detect(text), expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn line_breaks_round_trip() {
+        let cases = [
+            ("one\ntwo\n", LineEnding::Unix),
+            ("one\r\ntwo\r\n", LineEnding::Dos),
+            ("one\rtwo\r", LineEnding::Mac),
+            ("one\r\ntwo\n", LineEnding::Unix),
+            ("", LineEnding::Dos),
+        ];
+        for (file, line_ending) in cases {
+            let text = line_ending.normalize(file);
+            assert!(!text.contains("\r\n") || line_ending == LineEnding::Unix);
+            assert_eq!(line_ending.apply(&text), file, "{file:?}");
+        }
+        assert_eq!(LineEnding::Dos.normalize("a\r\nb\r\n"), "a\nb\n");
+        assert_eq!(LineEnding::Mac.normalize("a\rb\r"), "a\nb\n");
+        assert_eq!(LineEnding::Dos.apply("a\nb"), "a\r\nb");
+    }
+
+    #[test]
+    fn names() {
+        for line_ending in [LineEnding::Unix, LineEnding::Dos, LineEnding::Mac] {
+            assert_eq!(LineEnding::from_name(line_ending.name()), Some(line_ending));
+        }
+        assert_eq!(LineEnding::from_name("windows"), None);
+    }
+}
@@ -0,0 +1,1677 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+
+use ratatui::style::{Color, Style};
+use ropey::Rope;
+use serde_json::{Value, json};
+
+use crate::buffer::HBuffer;
+use crate::file_ops;
+use crate::menu::MenuItem;
+use crate::undo::Edit;
+
+/// How long servers get to exit when quitting, before they're killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Longest message taken from a server, so a broken header can't take all memory.
+const MAX_MESSAGE_LENGTH: usize = 64 << 20;
+
+/// Error code of a request the server dropped because the text changed under it.
+const CONTENT_MODIFIED: i64 = -32801;
+
+/// Names of the LSP completion item kinds, by number starting at 1.
+const COMPLETION_KINDS: &[&str] = &[
+    "text",
+    "method",
+    "function",
+    "constructor",
+    "field",
+    "variable",
+    "class",
+    "interface",
+    "module",
+    "property",
+    "unit",
+    "value",
+    "enum",
+    "keyword",
+    "snippet",
+    "color",
+    "file",
+    "reference",
+    "folder",
+    "enum member",
+    "constant",
+    "struct",
+    "event",
+    "operator",
+    "type parameter",
+];
+
+/// A place in a text as a line and a char column.
+pub type LineCol = (usize, usize);
+
+/// How a server counts the columns of its positions.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PositionEncoding {
+    Utf8,
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Reads the encoding a server chose, which is UTF-16 unless it says otherwise.
+    fn from_name(name: Option<&str>) -> Self {
+        match name {
+            Some("utf-8") => Self::Utf8,
+            Some("utf-32") => Self::Utf32,
+            _ => Self::Utf16,
+        }
+    }
+
+    /// Columns `c` takes up.
+    fn units(self, c: char) -> usize {
+        match self {
+            Self::Utf8 => c.len_utf8(),
+            Self::Utf16 => c.len_utf16(),
+            Self::Utf32 => 1,
+        }
+    }
+}
+
+/// How bad a diagnostic is, most severe first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+impl Severity {
+    fn from_number(number: Option<u64>) -> Self {
+        match number {
+            Some(2) => Self::Warning,
+            Some(3) => Self::Information,
+            Some(4) => Self::Hint,
+            _ => Self::Error,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+            Self::Information => "info",
+            Self::Hint => "hint",
+        }
+    }
+
+    /// Sign shown in the gutter of a line with a diagnostic.
+    pub fn sign(self) -> char {
+        match self {
+            Self::Error => 'E',
+            Self::Warning => 'W',
+            Self::Information => 'I',
+            Self::Hint => 'H',
+        }
+    }
+
+    pub fn style(self) -> Style {
+        Style::default().fg(match self {
+            Self::Error => Color::Red,
+            Self::Warning => Color::Yellow,
+            Self::Information => Color::Blue,
+            Self::Hint => Color::Cyan,
+        })
+    }
+}
+
+/// A problem a language server found in a buffer.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub start: LineCol,
+    pub end: LineCol,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// A range of a file a language server pointed at.
+#[derive(Clone, Debug)]
+pub struct Location {
+    /// Absolute path of the file
+    pub path: String,
+    pub start: LineCol,
+}
+
+/// A replacement a language server asked for, like one of the edits of a rename.
+#[derive(Clone, Debug)]
+pub struct TextEdit {
+    pub start: LineCol,
+    pub end: LineCol,
+    pub text: String,
+}
+
+/// Answers and notifications of the language servers, for the editor to act on.
+pub enum LspEvent {
+    /// Completions of the word starting at `start` in the file at `path`
+    Completion {
+        path: String,
+        start: LineCol,
+        items: Vec<MenuItem>,
+    },
+    /// Documentation of the symbol under the cursor, empty when there's none
+    Hover(String),
+    Definition(Vec<Location>),
+    References(Vec<Location>),
+    /// Edits making a rename, by absolute path of the file
+    Rename(Vec<(String, Vec<TextEdit>)>),
+    /// The diagnostics of the file at `path`, replacing the ones before
+    Diagnostics {
+        path: String,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// Something to tell, like a server exiting or failing a request
+    Message(String),
+}
+
+/// What a request sent to a server was for.
+enum Pending {
+    Initialize,
+    Completion { path: String, start: LineCol },
+    Hover,
+    Definition,
+    References,
+    Rename,
+    Shutdown,
+}
+
+/// A document a server has open: the text it has and its version.
+struct Document {
+    uri: String,
+    version: i64,
+    text: Rope,
+}
+
+/// A language server process, talked to over its stdin and stdout.
+struct Server {
+    file_format: String,
+    /// Command the server was started with
+    command: Vec<String>,
+    /// None for a server talked to over pipes of its own, like the stub of the tests
+    child: Option<Child>,
+    /// Messages for the writer thread to send
+    outgoing: Sender<Value>,
+    /// Messages the reader thread got
+    incoming: Receiver<Value>,
+    /// Messages held back until the server answers `initialize`, None after
+    queued: Option<Vec<Value>>,
+    next_id: u64,
+    pending: HashMap<u64, Pending>,
+    encoding: PositionEncoding,
+    /// Takes changed ranges rather than the whole text on each change
+    incremental: bool,
+    /// Wants the text along with `didSave`
+    save_text: bool,
+    trigger_characters: Vec<String>,
+    /// Open documents by absolute path
+    documents: HashMap<String, Document>,
+}
+
+/// The language servers of the editor, one per file format that has a command set
+/// in the config file or with `:lsp`.
+///
+/// Servers are started for the first buffer of their file format and kept in step
+/// with every buffer of it.
+#[derive(Default)]
+pub struct Lsp {
+    /// Server command of each file format
+    commands: BTreeMap<String, Vec<String>>,
+    servers: Vec<Server>,
+    /// Why the server of a file format isn't running, so it isn't started again
+    failed: BTreeMap<String, String>,
+    /// The completion request whose answer is still wanted, by file format and id
+    completion: Option<(String, u64)>,
+    /// Messages to hand out with the next events
+    messages: Vec<String>,
+}
+
+impl Lsp {
+    /// Reads the server commands from the `lsp` file of the config directory, one
+    /// `file_format = command args...` per line. Lines starting with `#` are comments.
+    pub fn load_config(&mut self) {
+        let Some(dir) = file_ops::config_dir() else {
+            return;
+        };
+        let Ok(config) = std::fs::read_to_string(dir.join("lsp")) else {
+            return;
+        };
+        for line in config.lines().map(str::trim) {
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some((file_format, command)) = line.split_once('=') {
+                let command: Vec<String> = command.split_whitespace().map(String::from).collect();
+                if !command.is_empty() {
+                    self.commands.insert(
+                        file_format.trim().trim_start_matches('.').to_string(),
+                        command,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Sets the server command of `file_format`, stopping the server running for it.
+    /// The new one starts with the next sync.
+    pub fn configure(&mut self, file_format: &str, command: Vec<String>) {
+        let file_format = file_format.trim_start_matches('.');
+        self.servers
+            .retain(|server| server.file_format != file_format);
+        self.failed.remove(file_format);
+        self.commands.insert(file_format.to_string(), command);
+    }
+
+    /// Stops the server of `file_format` so it starts again with the next sync,
+    /// even after it failed.
+    pub fn restart(&mut self, file_format: &str) -> Result<(), String> {
+        let file_format = file_format.trim_start_matches('.');
+        let command = self
+            .commands
+            .get(file_format)
+            .cloned()
+            .ok_or_else(|| format!("No language server configured for {}", file_format))?;
+        self.configure(file_format, command);
+        Ok(())
+    }
+
+    /// One line per configured file format for `:lsp`: its command and whether
+    /// its server runs.
+    pub fn describe(&self) -> String {
+        if self.commands.is_empty() {
+            return "No language servers configured".to_string();
+        }
+        self.commands
+            .iter()
+            .map(|(file_format, command)| {
+                let state = match (
+                    self.servers
+                        .iter()
+                        .find(|server| &server.file_format == file_format),
+                    self.failed.get(file_format),
+                ) {
+                    (Some(server), _) if server.command != *command => "restarting".to_string(),
+                    (Some(server), _) => {
+                        format!("running, {} document(s)", server.documents.len())
+                    }
+                    (None, Some(reason)) => reason.clone(),
+                    (None, None) => "not started".to_string(),
+                };
+                format!("{:>6}  {}  ({})", file_format, command.join(" "), state)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Brings the servers up to date with the buffers: opens new documents, sends
+    /// the edits made since the last sync and closes the documents of buffers that
+    /// are gone. Servers are started for file formats that get their first buffer.
+    pub fn sync(&mut self, buffers: &mut [HBuffer]) {
+        if self.servers.is_empty() && self.commands.is_empty() {
+            return;
+        }
+        let mut open = Vec::new();
+        for buffer in buffers.iter_mut() {
+            let path = document_path(buffer);
+            let server = match &path {
+                Some(_) => self.server_for(&buffer.file_format),
+                None => None,
+            };
+            let (Some(path), Some(server)) = (path, server) else {
+                buffer.language_server = false;
+                buffer.diagnostics.clear();
+                continue;
+            };
+            server.sync_document(&path, buffer);
+            buffer.language_server = true;
+            open.push((buffer.file_format.clone(), path));
+        }
+
+        for server in &mut self.servers {
+            let closed: Vec<String> = server
+                .documents
+                .keys()
+                .filter(|path| !open.contains(&(server.file_format.clone(), path.to_string())))
+                .cloned()
+                .collect();
+            for path in closed {
+                if let Some(document) = server.documents.remove(&path) {
+                    server.notify(
+                        "textDocument/didClose",
+                        json!({ "textDocument": { "uri": document.uri } }),
+                    );
+                }
+            }
+        }
+    }
+
+    /// The running server of `file_format`, started if it has a command and didn't fail.
+    fn server_for(&mut self, file_format: &str) -> Option<&mut Server> {
+        let command = self.commands.get(file_format)?;
+        if self.failed.contains_key(file_format) {
+            return None;
+        }
+        let index = match self
+            .servers
+            .iter()
+            .position(|server| server.file_format == file_format)
+        {
+            Some(index) => index,
+            None => match Server::spawn(file_format, command) {
+                Ok(server) => {
+                    self.servers.push(server);
+                    self.servers.len() - 1
+                }
+                Err(e) => {
+                    self.messages.push(format!(
+                        "Can't start language server {}: {}",
+                        command.join(" "),
+                        e
+                    ));
+                    self.failed
+                        .insert(file_format.to_string(), format!("failed to start: {}", e));
+                    return None;
+                }
+            },
+        };
+        Some(&mut self.servers[index])
+    }
+
+    /// The server that has the document of `buffer` open, with the document's path.
+    fn document_server(&mut self, buffer: &HBuffer) -> Result<(&mut Server, String), String> {
+        let path = document_path(buffer).ok_or("No language server for this buffer")?;
+        let server = self
+            .servers
+            .iter_mut()
+            .find(|server| server.documents.contains_key(&path))
+            .ok_or("No language server for this buffer")?;
+        Ok((server, path))
+    }
+
+    /// Sends a request about the place `at` in `buffer`, which must be synced.
+    fn request_at(
+        &mut self,
+        buffer: &HBuffer,
+        at: LineCol,
+        method: &str,
+        extra: Value,
+        pending: impl FnOnce(String) -> Pending,
+    ) -> Result<(String, u64), String> {
+        let (server, path) = self.document_server(buffer)?;
+        let mut params = json!({
+            "textDocument": { "uri": server.documents[&path].uri },
+            "position": position(&buffer.text.inner, buffer.char_index(at.0, at.1), server.encoding),
+        });
+        if let (Some(params), Value::Object(extra)) = (params.as_object_mut(), extra) {
+            params.extend(extra);
+        }
+        let id = server.request(method, params, pending(path));
+        Ok((server.file_format.clone(), id))
+    }
+
+    /// Asks for completions at `cursor` of the word starting at `start`. An answer
+    /// to an earlier completion request isn't wanted anymore.
+    pub fn request_completion(
+        &mut self,
+        buffer: &HBuffer,
+        cursor: LineCol,
+        start: LineCol,
+    ) -> Result<(), String> {
+        let request = self.request_at(
+            buffer,
+            cursor,
+            "textDocument/completion",
+            Value::Null,
+            |path| Pending::Completion { path, start },
+        )?;
+        self.completion = Some(request);
+        Ok(())
+    }
+
+    /// Drops the completion request being waited for, like when leaving Edit Mode.
+    pub fn cancel_completion(&mut self) {
+        self.completion = None;
+    }
+
+    /// True when typing `c` in `buffer` should ask its server for completions.
+    pub fn is_trigger_character(&mut self, buffer: &HBuffer, c: char) -> bool {
+        self.document_server(buffer).is_ok_and(|(server, _)| {
+            server
+                .trigger_characters
+                .iter()
+                .any(|trigger| trigger.ends_with(c))
+        })
+    }
+
+    pub fn request_hover(&mut self, buffer: &HBuffer, at: LineCol) -> Result<(), String> {
+        self.request_at(buffer, at, "textDocument/hover", Value::Null, |_| {
+            Pending::Hover
+        })?;
+        Ok(())
+    }
+
+    pub fn request_definition(&mut self, buffer: &HBuffer, at: LineCol) -> Result<(), String> {
+        self.request_at(buffer, at, "textDocument/definition", Value::Null, |_| {
+            Pending::Definition
+        })?;
+        Ok(())
+    }
+
+    pub fn request_references(&mut self, buffer: &HBuffer, at: LineCol) -> Result<(), String> {
+        let context = json!({ "context": { "includeDeclaration": true } });
+        self.request_at(buffer, at, "textDocument/references", context, |_| {
+            Pending::References
+        })?;
+        Ok(())
+    }
+
+    pub fn request_rename(
+        &mut self,
+        buffer: &HBuffer,
+        at: LineCol,
+        new_name: &str,
+    ) -> Result<(), String> {
+        let new_name = json!({ "newName": new_name });
+        self.request_at(buffer, at, "textDocument/rename", new_name, |_| {
+            Pending::Rename
+        })?;
+        Ok(())
+    }
+
+    /// Tells the server of `buffer` that it was written.
+    pub fn did_save(&mut self, buffer: &HBuffer) {
+        let Ok((server, path)) = self.document_server(buffer) else {
+            return;
+        };
+        let mut params = json!({ "textDocument": { "uri": server.documents[&path].uri } });
+        if server.save_text {
+            params["text"] = Value::String(buffer.text.to_string());
+        }
+        server.notify("textDocument/didSave", params);
+    }
+
+    /// Handles what the servers sent since the last call.
+    pub fn poll(&mut self, buffers: &[HBuffer]) -> Vec<LspEvent> {
+        let mut events: Vec<LspEvent> = self.messages.drain(..).map(LspEvent::Message).collect();
+        let mut texts = Texts {
+            buffers,
+            read: HashMap::new(),
+        };
+        let mut exited = Vec::new();
+        for (i, server) in self.servers.iter_mut().enumerate() {
+            loop {
+                match server.incoming.try_recv() {
+                    Ok(message) => {
+                        events.extend(server.handle_message(message, &self.completion, &mut texts))
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        exited.push(i);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for i in exited.into_iter().rev() {
+            let mut server = self.servers.remove(i);
+            events.push(LspEvent::Message(format!(
+                "Language server {} exited",
+                server.command.join(" ")
+            )));
+            // Its diagnostics go with it
+            for path in std::mem::take(&mut server.documents).into_keys() {
+                events.push(LspEvent::Diagnostics {
+                    path,
+                    diagnostics: Vec::new(),
+                });
+            }
+            self.failed
+                .insert(server.file_format.clone(), "exited".to_string());
+        }
+        events
+    }
+
+    /// Asks every server to exit, killing the ones that don't in time.
+    pub fn shutdown(&mut self) {
+        for server in &mut self.servers {
+            server.request("shutdown", Value::Null, Pending::Shutdown);
+            server.notify("exit", Value::Null);
+        }
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        for mut server in self.servers.drain(..) {
+            let Some(child) = &mut server.child else {
+                continue;
+            };
+            while Instant::now() < deadline && matches!(child.try_wait(), Ok(None)) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+impl Server {
+    /// Starts the server and asks it to initialize.
+    fn spawn(file_format: &str, command: &[String]) -> std::io::Result<Self> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| std::io::Error::other("no command"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            let _ = child.kill();
+            return Err(std::io::Error::other("no pipes"));
+        };
+        Ok(Self::connect(
+            file_format,
+            command,
+            Some(child),
+            stdin,
+            stdout,
+        ))
+    }
+
+    /// Asks the server at the other end of `input` and `output` to initialize, with
+    /// threads writing to one and reading from the other.
+    fn connect(
+        file_format: &str,
+        command: &[String],
+        child: Option<Child>,
+        input: impl Write + Send + 'static,
+        output: impl Read + Send + 'static,
+    ) -> Self {
+        let (outgoing, to_write) = mpsc::channel();
+        std::thread::spawn(move || write_messages(input, to_write));
+        let (read, incoming) = mpsc::channel();
+        std::thread::spawn(move || read_messages(output, read));
+
+        let mut server = Self {
+            file_format: file_format.to_string(),
+            command: command.to_vec(),
+            child,
+            outgoing,
+            incoming,
+            queued: None,
+            next_id: 1,
+            pending: HashMap::new(),
+            encoding: PositionEncoding::Utf16,
+            incremental: false,
+            save_text: false,
+            trigger_characters: Vec::new(),
+            documents: HashMap::new(),
+        };
+        let root = std::env::current_dir()
+            .ok()
+            .and_then(|dir| dir.to_str().map(file_uri));
+        server.request(
+            "initialize",
+            json!({
+                "processId": std::process::id(),
+                "clientInfo": { "name": "heliolisk" },
+                "rootUri": root,
+                "workspaceFolders": root.as_ref().map(|uri| json!([{ "uri": uri, "name": "root" }])),
+                "capabilities": {
+                    "general": { "positionEncodings": ["utf-32", "utf-16"] },
+                    "textDocument": {
+                        "synchronization": { "didSave": true },
+                        "completion": { "completionItem": { "snippetSupport": false } },
+                        "hover": { "contentFormat": ["plaintext", "markdown"] },
+                        "definition": { "linkSupport": true },
+                        "references": {},
+                        "rename": {},
+                        "publishDiagnostics": {},
+                    },
+                },
+            }),
+            Pending::Initialize,
+        );
+        server.queued = Some(Vec::new());
+        server
+    }
+
+    fn send(&mut self, message: Value) {
+        match &mut self.queued {
+            Some(queued) => queued.push(message),
+            None => {
+                let _ = self.outgoing.send(message);
+            }
+        }
+    }
+
+    /// Sends a request, returning its id.
+    fn request(&mut self, method: &str, params: Value, pending: Pending) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, pending);
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        id
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    /// Opens the document of `buffer` at `path`, or sends the edits made to it since
+    /// the last sync.
+    fn sync_document(&mut self, path: &str, buffer: &mut HBuffer) {
+        let edits = buffer.text.take_sync_edits();
+        let (encoding, incremental) = (self.encoding, self.incremental);
+        let Some(document) = self.documents.get_mut(path) else {
+            let uri = file_uri(path);
+            self.notify(
+                "textDocument/didOpen",
+                json!({
+                    "textDocument": {
+                        "uri": uri,
+                        "languageId": language_id(&buffer.file_format),
+                        "version": 1,
+                        "text": buffer.text.to_string(),
+                    }
+                }),
+            );
+            let document = Document {
+                uri,
+                version: 1,
+                text: buffer.text.inner.clone(),
+            };
+            self.documents.insert(path.to_string(), document);
+            return;
+        };
+
+        let changes = match edits {
+            Some(edits) if edits.is_empty() => return,
+            Some(edits) if incremental => content_changes(&mut document.text, &edits, encoding),
+            _ => None,
+        };
+        let changes = changes.unwrap_or_else(|| vec![json!({ "text": buffer.text.to_string() })]);
+        document.text = buffer.text.inner.clone();
+        document.version += 1;
+        let params = json!({
+            "textDocument": { "uri": document.uri, "version": document.version },
+            "contentChanges": changes,
+        });
+        self.notify("textDocument/didChange", params);
+    }
+
+    /// Handles one message of the server, returning what the editor should know of it.
+    fn handle_message(
+        &mut self,
+        message: Value,
+        completion: &Option<(String, u64)>,
+        texts: &mut Texts,
+    ) -> Option<LspEvent> {
+        let method = message["method"].as_str();
+        let id = message.get("id").cloned();
+        match (method, id) {
+            // Requests of the server are answered without doing anything
+            (Some(method), Some(id)) => {
+                let result = match method {
+                    "workspace/configuration" => Value::Array(vec![
+                        Value::Null;
+                        message["params"]["items"]
+                            .as_array()
+                            .map_or(0, Vec::len)
+                    ]),
+                    "workspace/applyEdit" => json!({ "applied": false }),
+                    _ => Value::Null,
+                };
+                self.send(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+                None
+            }
+            (Some(method), None) => self.handle_notification(method, &message["params"], texts),
+            (None, Some(id)) => {
+                let id = id.as_u64()?;
+                let pending = self.pending.remove(&id)?;
+                if let Some(error) = message.get("error") {
+                    return (error["code"].as_i64() != Some(CONTENT_MODIFIED)).then(|| {
+                        LspEvent::Message(format!(
+                            "Language server error: {}",
+                            error["message"].as_str().unwrap_or("unknown")
+                        ))
+                    });
+                }
+                let wanted = completion.as_ref().is_some_and(|(file_format, wanted)| {
+                    *file_format == self.file_format && *wanted == id
+                });
+                self.handle_response(pending, &message["result"], wanted, texts)
+            }
+            (None, None) => None,
+        }
+    }
+
+    fn handle_notification(
+        &mut self,
+        method: &str,
+        params: &Value,
+        texts: &mut Texts,
+    ) -> Option<LspEvent> {
+        match method {
+            "textDocument/publishDiagnostics" => {
+                let path = uri_path(params["uri"].as_str()?)?;
+                let mut diagnostics: Vec<Diagnostic> = params["diagnostics"]
+                    .as_array()?
+                    .iter()
+                    .filter_map(|diagnostic| {
+                        let (start, end) =
+                            texts.range(&path, &diagnostic["range"], self.encoding)?;
+                        Some(Diagnostic {
+                            start,
+                            end,
+                            severity: Severity::from_number(diagnostic["severity"].as_u64()),
+                            message: diagnostic["message"].as_str()?.to_string(),
+                        })
+                    })
+                    .collect();
+                diagnostics.sort_by_key(|diagnostic| (diagnostic.start, diagnostic.severity));
+                Some(LspEvent::Diagnostics { path, diagnostics })
+            }
+            // Errors and warnings are shown, lesser messages only logged by servers
+            "window/showMessage" if params["type"].as_u64().is_some_and(|kind| kind <= 2) => {
+                Some(LspEvent::Message(params["message"].as_str()?.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_response(
+        &mut self,
+        pending: Pending,
+        result: &Value,
+        wanted_completion: bool,
+        texts: &mut Texts,
+    ) -> Option<LspEvent> {
+        match pending {
+            Pending::Initialize => {
+                self.read_capabilities(&result["capabilities"]);
+                let queued = self.queued.take().unwrap_or_default();
+                self.notify("initialized", json!({}));
+                for message in queued {
+                    self.send(message);
+                }
+                None
+            }
+            Pending::Completion { path, start } if wanted_completion => {
+                let list = result.get("items").unwrap_or(result);
+                let mut items: Vec<(&str, MenuItem)> = list
+                    .as_array()?
+                    .iter()
+                    .filter_map(|item| {
+                        let label = item["label"].as_str()?;
+                        let edit = &item["textEdit"];
+                        let insert = edit["newText"]
+                            .as_str()
+                            .or(item["insertText"].as_str())
+                            .unwrap_or(label);
+                        let detail = item["detail"].as_str().map(String::from).or_else(|| {
+                            let kind = item["kind"].as_u64()? as usize;
+                            COMPLETION_KINDS
+                                .get(kind.checked_sub(1)?)
+                                .map(|k| k.to_string())
+                        });
+                        let sort = item["sortText"].as_str().unwrap_or(label);
+                        Some((
+                            sort,
+                            MenuItem {
+                                label: label.to_string(),
+                                detail: detail.unwrap_or_default(),
+                                insert: insert.to_string(),
+                                filter: item["filterText"].as_str().unwrap_or(label).to_string(),
+                            },
+                        ))
+                    })
+                    .collect();
+                items.sort_by(|a, b| a.0.cmp(b.0));
+
+                // The server may complete from another column than the word start
+                let start = list
+                    .as_array()?
+                    .iter()
+                    .find_map(|item| {
+                        let edit = &item["textEdit"];
+                        let range = edit.get("range").or(edit.get("insert"))?;
+                        texts.range(&path, range, self.encoding)
+                    })
+                    .map(|(edit_start, _)| edit_start)
+                    .filter(|edit_start| edit_start.0 == start.0)
+                    .unwrap_or(start);
+                Some(LspEvent::Completion {
+                    path,
+                    start,
+                    items: items.into_iter().map(|(_, item)| item).collect(),
+                })
+            }
+            Pending::Completion { .. } | Pending::Shutdown => None,
+            Pending::Hover => Some(LspEvent::Hover(hover_text(&result["contents"]))),
+            Pending::Definition => Some(LspEvent::Definition(self.locations(result, texts))),
+            Pending::References => Some(LspEvent::References(self.locations(result, texts))),
+            Pending::Rename => {
+                let mut files: Vec<(String, Vec<TextEdit>)> = Vec::new();
+                let changes = result["changes"].as_object().into_iter().flatten();
+                let document_changes = result["documentChanges"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|change| {
+                        Some((change["textDocument"]["uri"].as_str()?, &change["edits"]))
+                    });
+                for (uri, edits) in changes
+                    .map(|(uri, edits)| (uri.as_str(), edits))
+                    .chain(document_changes)
+                {
+                    let Some(path) = uri_path(uri) else {
+                        continue;
+                    };
+                    let edits = edits
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|edit| {
+                            let (start, end) = texts.range(&path, &edit["range"], self.encoding)?;
+                            Some(TextEdit {
+                                start,
+                                end,
+                                text: edit["newText"].as_str()?.to_string(),
+                            })
+                        })
+                        .collect();
+                    files.push((path, edits));
+                }
+                Some(LspEvent::Rename(files))
+            }
+        }
+    }
+
+    fn read_capabilities(&mut self, capabilities: &Value) {
+        self.encoding = PositionEncoding::from_name(capabilities["positionEncoding"].as_str());
+        let sync = &capabilities["textDocumentSync"];
+        self.incremental = sync.as_u64().or(sync["change"].as_u64()) == Some(2);
+        self.save_text = sync["save"]["includeText"].as_bool().unwrap_or(false);
+        self.trigger_characters = capabilities["completionProvider"]["triggerCharacters"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|c| c.as_str().map(String::from))
+            .collect();
+    }
+
+    /// Reads a `Location`, a list of them or a list of `LocationLink`s.
+    fn locations(&self, result: &Value, texts: &mut Texts) -> Vec<Location> {
+        let list = match result {
+            Value::Array(list) => list.iter().collect(),
+            Value::Object(_) => vec![result],
+            _ => Vec::new(),
+        };
+        list.into_iter()
+            .filter_map(|location| {
+                let uri = location["uri"]
+                    .as_str()
+                    .or(location["targetUri"].as_str())?;
+                let range = location
+                    .get("targetSelectionRange")
+                    .unwrap_or(&location["range"]);
+                let path = uri_path(uri)?;
+                let (start, _) = texts.range(&path, range, self.encoding)?;
+                Some(Location { path, start })
+            })
+            .collect()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Texts of the files positions point into, from their buffers or read from disk,
+/// to turn the positions into char columns.
+struct Texts<'a> {
+    buffers: &'a [HBuffer],
+    read: HashMap<String, Option<Rope>>,
+}
+
+impl Texts<'_> {
+    fn text(&mut self, path: &str) -> Option<&Rope> {
+        if let Some(buffer) = self
+            .buffers
+            .iter()
+            .find(|buffer| document_path(buffer).as_deref() == Some(path))
+        {
+            return Some(&buffer.text.inner);
+        }
+        self.read
+            .entry(path.to_string())
+            .or_insert_with(|| {
+                let text = std::fs::read_to_string(path).ok()?;
+                Some(Rope::from_str(&text))
+            })
+            .as_ref()
+    }
+
+    /// The start and end of an LSP range in the file at `path`.
+    fn range(
+        &mut self,
+        path: &str,
+        range: &Value,
+        encoding: PositionEncoding,
+    ) -> Option<(LineCol, LineCol)> {
+        let text = self.text(path)?;
+        Some((
+            line_col(text, &range["start"], encoding)?,
+            line_col(text, &range["end"], encoding)?,
+        ))
+    }
+}
+
+/// The absolute path a language server knows the file of `buffer` by. None for a
+/// buffer without a file or one not shown as text.
+pub fn document_path(buffer: &HBuffer) -> Option<String> {
+    if buffer.hex || buffer.large || buffer.loading {
+        return None;
+    }
+    buffer.absolute_path.clone()
+}
+
+/// `path` relative to the working directory when it's inside it, for showing it.
+pub fn display_path(path: &str) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|dir| {
+            Path::new(path)
+                .strip_prefix(dir)
+                .ok()
+                .and_then(|relative| relative.to_str().map(String::from))
+        })
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Language id of a file format, as servers expect it in `didOpen`.
+fn language_id(file_format: &str) -> &str {
+    match file_format {
+        "rs" => "rust",
+        "py" => "python",
+        "h" => "c",
+        "cc" | "cxx" | "hpp" => "cpp",
+        "sh" | "bash" => "shellscript",
+        "js" => "javascript",
+        "ts" => "typescript",
+        "md" => "markdown",
+        "yml" => "yaml",
+        other => other,
+    }
+}
+
+/// A `file://` URI for an absolute path.
+fn file_uri(path: &str) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            uri.push(char::from(byte));
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+/// The absolute path of a `file://` URI.
+fn uri_path(uri: &str) -> Option<String> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let byte = match encoded[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(encoded.get(i + 1..i + 3)?).ok()?;
+                i += 2;
+                u8::from_str_radix(hex, 16).ok()?
+            }
+            byte => byte,
+        };
+        bytes.push(byte);
+        i += 1;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The LSP position of `char_idx` in `text`.
+fn position(text: &Rope, char_idx: usize, encoding: PositionEncoding) -> Value {
+    let line = text.char_to_line(char_idx);
+    let character: usize = text
+        .slice(text.line_to_char(line)..char_idx)
+        .chars()
+        .map(|c| encoding.units(c))
+        .sum();
+    json!({ "line": line, "character": character })
+}
+
+/// The line and char column of an LSP position in `text`, clamped to the text.
+fn line_col(text: &Rope, position: &Value, encoding: PositionEncoding) -> Option<LineCol> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+    if line >= text.len_lines() {
+        let last = text.len_lines() - 1;
+        return Some((last, text.line(last).len_chars()));
+    }
+    let mut units = 0;
+    let mut col = 0;
+    for c in text.line(line).chars() {
+        if units >= character || c == '\n' || c == '\r' {
+            break;
+        }
+        units += encoding.units(c);
+        col += 1;
+    }
+    Some((line, col))
+}
+
+/// The changed ranges of `edits`, which are made to `text` too. None when they
+/// don't fit the text, which is then sent whole.
+fn content_changes(
+    text: &mut Rope,
+    edits: &[Edit],
+    encoding: PositionEncoding,
+) -> Option<Vec<Value>> {
+    edits
+        .iter()
+        .map(|edit| {
+            let end = edit.at
+                + if edit.inserted {
+                    0
+                } else {
+                    edit.text.chars().count()
+                };
+            if end > text.len_chars() {
+                return None;
+            }
+            let change = json!({
+                "range": {
+                    "start": position(text, edit.at, encoding),
+                    "end": position(text, end, encoding),
+                },
+                "text": if edit.inserted { edit.text.as_str() } else { "" },
+            });
+            edit.apply(text);
+            Some(change)
+        })
+        .collect()
+}
+
+/// The text of hover contents: markup, a marked string or a list of them. Code
+/// fences of markdown are left out.
+fn hover_text(contents: &Value) -> String {
+    let text = match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .map(hover_text)
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Value::Object(_) => contents["value"].as_str().unwrap_or_default().to_string(),
+        _ => String::new(),
+    };
+    text.lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// Writes each message with its `Content-Length` header, until the server's stdin
+/// closes or the editor drops the sender.
+fn write_messages(mut stdin: impl Write, messages: Receiver<Value>) {
+    for message in messages {
+        if write_message(&mut stdin, &message).is_err() {
+            break;
+        }
+    }
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> std::io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// Reads messages until the server's stdout closes, which tells the editor it exited.
+fn read_messages(stdout: impl Read, messages: Sender<Value>) {
+    let mut reader = BufReader::new(stdout);
+    while let Some(message) = read_message(&mut reader) {
+        if messages.send(message).is_err() {
+            break;
+        }
+    }
+}
+
+/// Reads one message framed by its headers, None at the end of the stream or
+/// when the message is too long to take. A message that isn't JSON reads as null.
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            length = value.trim().parse().ok();
+        }
+    }
+    let length = length.filter(|&length| length <= MAX_MESSAGE_LENGTH)?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rope::HeliosRope;
+    use std::io::Cursor;
+
+    /// A language server answering over pipes, handing each message it gets to
+    /// `log`: it takes UTF-16 positions and incremental changes, reports a
+    /// diagnostic on every opened document and has two completions. Every symbol
+    /// is defined, referenced and renamed at columns 4 and 7 of the first line.
+    fn stub_server(requests: impl Read, mut responses: impl Write, log: Sender<Value>) {
+        let mut reader = BufReader::new(requests);
+        while let Some(message) = read_message(&mut reader) {
+            let id = message["id"].clone();
+            let uri = message["params"]["textDocument"]["uri"].clone();
+            let range = |start: u64, end: u64| {
+                json!({
+                    "start": { "line": 0, "character": start },
+                    "end": { "line": 0, "character": end },
+                })
+            };
+            let reply = match message["method"].as_str() {
+                Some("initialize") => Some(json!({ "jsonrpc": "2.0", "id": id, "result": {
+                    "capabilities": {
+                        "textDocumentSync": { "openClose": true, "change": 2 },
+                        "completionProvider": { "triggerCharacters": ["."] },
+                    }
+                }})),
+                Some("textDocument/didOpen") => Some(json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": {
+                        "uri": message["params"]["textDocument"]["uri"],
+                        "diagnostics": [{
+                            "range": {
+                                "start": { "line": 0, "character": 7 },
+                                "end": { "line": 0, "character": 8 },
+                            },
+                            "severity": 2,
+                            "message": "unused",
+                        }],
+                    },
+                })),
+                Some("textDocument/completion") => {
+                    Some(json!({ "jsonrpc": "2.0", "id": id, "result": {
+                        "isIncomplete": false,
+                        "items": [
+                            { "label": "zeta", "sortText": "b" },
+                            { "label": "alpha", "kind": 3, "sortText": "a", "insertText": "alpha()" },
+                        ],
+                    }}))
+                }
+                Some("textDocument/hover") => Some(json!({ "jsonrpc": "2.0", "id": id, "result": {
+                    "contents": { "kind": "markdown", "value": "```rust\nlet a: i32\n```\nA number" },
+                }})),
+                Some("textDocument/definition") => {
+                    Some(json!({ "jsonrpc": "2.0", "id": id, "result": [{
+                        "targetUri": uri,
+                        "targetRange": range(0, 11),
+                        "targetSelectionRange": range(7, 8),
+                    }]}))
+                }
+                Some("textDocument/references") => {
+                    Some(json!({ "jsonrpc": "2.0", "id": id, "result": [
+                        { "uri": uri, "range": range(4, 6) },
+                        { "uri": uri, "range": range(7, 8) },
+                    ]}))
+                }
+                Some("textDocument/rename") => {
+                    let new_name = &message["params"]["newName"];
+                    Some(json!({ "jsonrpc": "2.0", "id": id, "result": {
+                        "changes": { uri.as_str().unwrap_or_default(): [
+                            { "range": range(4, 6), "newText": new_name },
+                            { "range": range(7, 8), "newText": new_name },
+                        ]},
+                    }}))
+                }
+                _ => None,
+            };
+            let _ = log.send(message);
+            if let Some(reply) = reply
+                && write_message(&mut responses, &reply).is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    /// A language server process in `sh`, replaying canned answers to `initialize`,
+    /// hover and `shutdown` and appending each method it gets to the file named by
+    /// its argument.
+    const MOCK_SERVER: &str = r#"
+        while IFS= read -r header; do
+            header=$(printf '%s' "$header" | tr -d '\r')
+            case $header in
+                Content-Length:*) length=${header#*: } ;;
+                '')
+                    body=$(dd bs=1 count=$length 2>/dev/null)
+                    method=$(printf '%s' "$body" | sed -n 's/.*"method":"\([^"]*\)".*/\1/p')
+                    id=$(printf '%s' "$body" | sed -n 's/.*"id":\([0-9]*\).*/\1/p')
+                    echo "$method" >> "$1"
+                    case $method in
+                        initialize) result='{"capabilities":{"hoverProvider":true}}' ;;
+                        textDocument/hover) result='{"contents":"From a process"}' ;;
+                        shutdown) result=null ;;
+                        exit) exit 0 ;;
+                        *) continue ;;
+                    esac
+                    reply="{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":$result}"
+                    printf 'Content-Length: %d\r\n\r\n%s' ${#reply} "$reply"
+                    ;;
+            esac
+        done
+    "#;
+
+    /// An editor-side client of `rs` files talking to a stub server.
+    fn connect_stub() -> (Lsp, Receiver<Value>) {
+        let (requests, to_server) = std::io::pipe().unwrap();
+        let (from_server, responses) = std::io::pipe().unwrap();
+        let (log, logged) = mpsc::channel();
+        std::thread::spawn(move || stub_server(requests, responses, log));
+
+        let command = vec!["stub".to_string()];
+        let mut lsp = Lsp::default();
+        lsp.commands.insert("rs".to_string(), command.clone());
+        lsp.servers.push(Server::connect(
+            "rs",
+            &command,
+            None,
+            to_server,
+            from_server,
+        ));
+        (lsp, logged)
+    }
+
+    /// Polls `lsp` until it gives an event `wanted` picks, failing after a second.
+    fn poll_until<T>(
+        lsp: &mut Lsp,
+        buffers: &[HBuffer],
+        mut wanted: impl FnMut(LspEvent) -> Option<T>,
+    ) -> T {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            if let Some(found) = lsp.poll(buffers).into_iter().find_map(&mut wanted) {
+                return found;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("no such event from the server");
+    }
+
+    fn logged_messages(logged: &Receiver<Value>, count: usize) -> Vec<Value> {
+        (0..count)
+            .map(|_| logged.recv_timeout(Duration::from_secs(1)).unwrap())
+            .collect()
+    }
+
+    fn rust_buffer(text: &str) -> HBuffer {
+        let path = std::env::temp_dir().join("heliolisk-lsp-stub.rs");
+        let mut buffer = HBuffer::new();
+        buffer.set_file_path(path.to_string_lossy().to_string());
+        buffer.text = HeliosRope::from_str(text);
+        buffer
+    }
+
+    #[test]
+    fn read_message_follows_content_length() {
+        let mut stream = Cursor::new(
+            "Content-Length: 8\r\nContent-Type: application/json\r\n\r\n{\"a\":1}\n\
+             content-length: 3\r\n\r\nnop"
+                .as_bytes(),
+        );
+        assert_eq!(read_message(&mut stream), Some(json!({ "a": 1 })));
+        assert_eq!(read_message(&mut stream), Some(Value::Null));
+        assert_eq!(read_message(&mut stream), None);
+
+        let mut written = Vec::new();
+        write_message(&mut written, &json!({ "id": 7 })).unwrap();
+        assert_eq!(
+            read_message(&mut Cursor::new(written)),
+            Some(json!({ "id": 7 }))
+        );
+    }
+
+    #[test]
+    fn read_message_refuses_huge_lengths() {
+        let header = format!("Content-Length: {}\r\n\r\n{{}}", MAX_MESSAGE_LENGTH + 1);
+        assert_eq!(read_message(&mut Cursor::new(header.as_bytes())), None);
+        assert_eq!(
+            read_message(&mut Cursor::new(
+                &b"Content-Length: 99999999999999999999\r\n\r\n"[..]
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn positions_count_units_of_their_encoding() {
+        let text = Rope::from_str("a😀b\nc");
+        assert_eq!(
+            position(&text, 2, PositionEncoding::Utf16),
+            json!({ "line": 0, "character": 3 })
+        );
+        assert_eq!(
+            position(&text, 2, PositionEncoding::Utf8),
+            json!({ "line": 0, "character": 5 })
+        );
+        assert_eq!(
+            position(&text, 2, PositionEncoding::Utf32),
+            json!({ "line": 0, "character": 2 })
+        );
+        let at = json!({ "line": 0, "character": 3 });
+        assert_eq!(line_col(&text, &at, PositionEncoding::Utf16), Some((0, 2)));
+        // Past the end of a line or of the text is clamped to it
+        let past = json!({ "line": 0, "character": 40 });
+        assert_eq!(
+            line_col(&text, &past, PositionEncoding::Utf16),
+            Some((0, 3))
+        );
+        let past = json!({ "line": 5, "character": 0 });
+        assert_eq!(
+            line_col(&text, &past, PositionEncoding::Utf16),
+            Some((1, 1))
+        );
+    }
+
+    #[test]
+    fn content_changes_replay_edits_on_the_shadow() {
+        let mut shadow = Rope::from_str("one\ntwo\n");
+        let edits = [
+            Edit::insert(4, "2".to_string()),
+            Edit::remove(0, "one".to_string()),
+        ];
+        let changes = content_changes(&mut shadow, &edits, PositionEncoding::Utf16).unwrap();
+        assert_eq!(shadow.to_string(), "\n2two\n");
+        assert_eq!(
+            changes[0]["range"]["start"],
+            json!({ "line": 1, "character": 0 })
+        );
+        assert_eq!(changes[0]["text"], "2");
+        assert_eq!(
+            changes[1]["range"]["end"],
+            json!({ "line": 0, "character": 3 })
+        );
+        assert_eq!(changes[1]["text"], "");
+
+        // Edits that don't fit the text are sent as the whole text instead
+        let edits = [Edit::remove(5, "far away".to_string())];
+        assert!(content_changes(&mut shadow, &edits, PositionEncoding::Utf16).is_none());
+    }
+
+    #[test]
+    fn syncs_documents_with_the_stub_server() {
+        let (mut lsp, logged) = connect_stub();
+        let mut buffers = vec![rust_buffer("let 😀 = a;\n")];
+        lsp.sync(&mut buffers);
+        assert!(buffers[0].language_server);
+
+        // The diagnostic of the opened document comes in UTF-16 columns
+        let diagnostics = poll_until(&mut lsp, &buffers, |event| match event {
+            LspEvent::Diagnostics { diagnostics, .. } => Some(diagnostics),
+            _ => None,
+        });
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].start, diagnostics[0].end), ((0, 6), (0, 7)));
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+
+        let messages = logged_messages(&logged, 3);
+        let methods: Vec<_> = messages.iter().map(|m| m["method"].clone()).collect();
+        assert_eq!(
+            methods,
+            ["initialize", "initialized", "textDocument/didOpen"]
+        );
+        let opened = &messages[2]["params"]["textDocument"];
+        assert_eq!(opened["languageId"], "rust");
+        assert_eq!(opened["version"], 1);
+        assert_eq!(opened["text"], "let 😀 = a;\n");
+
+        // Edits go as changed ranges, in the order they were made
+        buffers[0].insert_text(9, "bc");
+        buffers[0].delete_range(0..4);
+        lsp.sync(&mut buffers);
+        let changed = &logged_messages(&logged, 1)[0];
+        assert_eq!(changed["method"], "textDocument/didChange");
+        assert_eq!(changed["params"]["textDocument"]["version"], 2);
+        assert_eq!(
+            changed["params"]["contentChanges"],
+            json!([
+                {
+                    "range": {
+                        "start": { "line": 0, "character": 10 },
+                        "end": { "line": 0, "character": 10 },
+                    },
+                    "text": "bc",
+                },
+                {
+                    "range": {
+                        "start": { "line": 0, "character": 0 },
+                        "end": { "line": 0, "character": 4 },
+                    },
+                    "text": "",
+                },
+            ])
+        );
+
+        // Nothing changed, nothing sent
+        lsp.sync(&mut buffers);
+        assert!(logged.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn completes_with_the_stub_server() {
+        let (mut lsp, logged) = connect_stub();
+        let mut buffers = vec![rust_buffer("x.al\n")];
+        lsp.sync(&mut buffers);
+        lsp.request_completion(&buffers[0], (0, 4), (0, 2)).unwrap();
+
+        let (path, start, items) = poll_until(&mut lsp, &buffers, |event| match event {
+            LspEvent::Completion { path, start, items } => Some((path, start, items)),
+            _ => None,
+        });
+        assert_eq!(Some(path), document_path(&buffers[0]));
+        assert_eq!(start, (0, 2));
+        let labels: Vec<_> = items.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(labels, ["alpha", "zeta"]);
+        assert_eq!(items[0].insert, "alpha()");
+        assert_eq!(items[0].detail, "function");
+        // Known once the server said what it can do
+        assert!(lsp.is_trigger_character(&buffers[0], '.'));
+        assert!(!lsp.is_trigger_character(&buffers[0], 'a'));
+
+        let messages = logged_messages(&logged, 4);
+        assert_eq!(messages[3]["method"], "textDocument/completion");
+        assert_eq!(
+            messages[3]["params"]["position"],
+            json!({ "line": 0, "character": 4 })
+        );
+
+        // An answer to a completion no longer wanted is dropped
+        lsp.request_completion(&buffers[0], (0, 4), (0, 2)).unwrap();
+        lsp.cancel_completion();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(lsp.poll(&buffers).is_empty());
+    }
+
+    #[test]
+    fn answers_requests_about_symbols_with_the_stub_server() {
+        let (mut lsp, logged) = connect_stub();
+        let mut buffers = vec![rust_buffer("let 😀 = a;\n")];
+        lsp.sync(&mut buffers);
+        let path = document_path(&buffers[0]).unwrap();
+
+        lsp.request_hover(&buffers[0], (0, 6)).unwrap();
+        let hover = poll_until(&mut lsp, &buffers, |event| match event {
+            LspEvent::Hover(text) => Some(text),
+            _ => None,
+        });
+        assert_eq!(hover, "let a: i32\nA number");
+
+        // Columns come back in chars, past the two UTF-16 units of the emoji
+        lsp.request_definition(&buffers[0], (0, 6)).unwrap();
+        let definition = poll_until(&mut lsp, &buffers, |event| match event {
+            LspEvent::Definition(locations) => Some(locations),
+            _ => None,
+        });
+        let places: Vec<_> = definition
+            .iter()
+            .map(|l| (l.path.as_str(), l.start))
+            .collect();
+        assert_eq!(places, [(path.as_str(), (0, 6))]);
+
+        lsp.request_references(&buffers[0], (0, 6)).unwrap();
+        let references = poll_until(&mut lsp, &buffers, |event| match event {
+            LspEvent::References(locations) => Some(locations),
+            _ => None,
+        });
+        let starts: Vec<_> = references.iter().map(|l| l.start).collect();
+        assert_eq!(starts, [(0, 4), (0, 6)]);
+
+        lsp.request_rename(&buffers[0], (0, 6), "b").unwrap();
+        let files = poll_until(&mut lsp, &buffers, |event| match event {
+            LspEvent::Rename(files) => Some(files),
+            _ => None,
+        });
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, path);
+        let edits: Vec<_> = files[0]
+            .1
+            .iter()
+            .map(|edit| (edit.start, edit.end, edit.text.as_str()))
+            .collect();
+        assert_eq!(edits, [((0, 4), (0, 5), "b"), ((0, 6), (0, 7), "b")]);
+
+        let messages = logged_messages(&logged, 7);
+        let requests: Vec<_> = messages[3..]
+            .iter()
+            .map(|m| (m["method"].as_str().unwrap(), &m["params"]["position"]))
+            .collect();
+        let at = json!({ "line": 0, "character": 7 });
+        assert_eq!(
+            requests,
+            [
+                ("textDocument/hover", &at),
+                ("textDocument/definition", &at),
+                ("textDocument/references", &at),
+                ("textDocument/rename", &at),
+            ]
+        );
+        assert_eq!(messages[5]["params"]["context"]["includeDeclaration"], true);
+        assert_eq!(messages[6]["params"]["newName"], "b");
+    }
+
+    #[test]
+    fn talks_to_a_mock_server_process() {
+        let log = std::env::temp_dir().join(format!("heliolisk-lsp-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        let command = ["sh", "-c", MOCK_SERVER, "sh", &log.to_string_lossy()];
+        let mut lsp = Lsp::default();
+        lsp.configure("rs", command.map(String::from).to_vec());
+        let mut buffers = vec![rust_buffer("fn main() {}\n")];
+        lsp.sync(&mut buffers);
+        assert!(buffers[0].language_server);
+        assert!(lsp.describe().contains("(running, 1 document(s))"));
+
+        lsp.request_hover(&buffers[0], (0, 3)).unwrap();
+        let hover = poll_until(&mut lsp, &buffers, |event| match event {
+            LspEvent::Hover(text) => Some(text),
+            _ => None,
+        });
+        assert_eq!(hover, "From a process");
+
+        // The server is asked to exit and waited for
+        lsp.shutdown();
+        assert!(lsp.servers.is_empty());
+        let methods = std::fs::read_to_string(&log).unwrap();
+        let _ = std::fs::remove_file(&log);
+        assert_eq!(
+            methods.lines().collect::<Vec<_>>(),
+            [
+                "initialize",
+                "initialized",
+                "textDocument/didOpen",
+                "textDocument/hover",
+                "shutdown",
+                "exit",
+            ]
+        );
+    }
+
+    #[test]
+    fn forgets_server_processes_that_exit_or_dont_start() {
+        let mut lsp = Lsp::default();
+        lsp.configure("rs", ["sh", "-c", "exit 3"].map(String::from).to_vec());
+        let mut buffers = vec![rust_buffer("fn main() {}\n")];
+        lsp.sync(&mut buffers);
+        assert!(buffers[0].language_server);
+
+        // Its documents lose their diagnostics, and it isn't started again
+        let mut messages = Vec::new();
+        let path = poll_until(&mut lsp, &buffers, |event| match event {
+            LspEvent::Message(message) => {
+                messages.push(message);
+                None
+            }
+            LspEvent::Diagnostics { path, diagnostics } if diagnostics.is_empty() => Some(path),
+            _ => None,
+        });
+        assert_eq!(Some(path), document_path(&buffers[0]));
+        assert_eq!(messages, ["Language server sh -c exit 3 exited"]);
+        lsp.sync(&mut buffers);
+        assert!(!buffers[0].language_server);
+        assert!(lsp.describe().contains("(exited)"));
+
+        lsp.configure("rs", vec!["/nonexistent/language-server".to_string()]);
+        lsp.sync(&mut buffers);
+        assert!(!buffers[0].language_server);
+        let message = poll_until(&mut lsp, &buffers, |event| match event {
+            LspEvent::Message(message) => Some(message),
+            _ => None,
+        });
+        assert!(message.starts_with("Can't start language server /nonexistent/language-server"));
+        assert!(lsp.describe().contains("(failed to start: "));
+    }
+}
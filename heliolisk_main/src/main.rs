@@ -1,8 +1,30 @@
 mod buffer;
 mod editor;
+mod encoding;
+mod ex;
 mod file_ops;
+mod fold;
+mod grammar;
 mod helios;
+mod hex;
+mod history;
+mod keyword;
+mod line_ending;
+mod lsp;
+mod menu;
+mod options;
+mod register;
 mod rope;
+mod search;
+mod selection;
+mod substitute;
+mod swap;
+mod syntax;
+mod syntax_tree;
+mod text_object;
+mod undo;
+mod view;
+mod window;
 
 use crate::{
     editor::{CommandMode, EditMode, Editor, NavigateMode, SelectMode},
@@ -10,6 +32,10 @@ use crate::{
 };
 
 use color_eyre::Result;
+use ratatui::crossterm::{
+    event::{DisableFocusChange, EnableFocusChange},
+    execute,
+};
 
 pub enum EditorState {
     Navigate(Editor<NavigateMode>),
@@ -30,10 +56,15 @@ impl std::fmt::Display for EditorState {
 }
 
 fn main() -> Result<()> {
-    let mut app: Helios = initialize_app();
+    let Some(mut app): Option<Helios> = initialize_app() else {
+        return Ok(());
+    };
     color_eyre::install()?;
     let mut terminal = ratatui::init();
+    // Losing focus autosaves, which needs the terminal to report it
+    let _ = execute!(std::io::stdout(), EnableFocusChange);
     let result = app.run(&mut terminal);
+    let _ = execute!(std::io::stdout(), DisableFocusChange);
     ratatui::restore();
     result
 }
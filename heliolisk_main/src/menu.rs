@@ -0,0 +1,165 @@
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Style},
+    text::{Line, Span},
+    widgets::{Clear, Widget},
+};
+
+/// Most candidates the menu shows at once; it scrolls through the rest.
+pub const MENU_HEIGHT: usize = 10;
+/// Widest the menu gets, in cells.
+const MENU_WIDTH: usize = 60;
+
+/// One candidate of the completion menu.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MenuItem {
+    pub label: String,
+    /// Shown after the label, like the kind or type of the completed word
+    pub detail: String,
+    /// Replaces the word being completed
+    pub insert: String,
+    /// Matched against the word typed so far
+    pub filter: String,
+}
+
+/// Candidates for the word before the cursor in Edit Mode, shown in a popup
+/// below it. The candidates not matching what's typed are hidden.
+pub struct CompletionMenu {
+    /// Line and column where the completed word starts
+    pub line: usize,
+    pub start_col: usize,
+    items: Vec<MenuItem>,
+    /// Indices in `items` of the candidates matching the typed word
+    shown: Vec<usize>,
+    /// Index in `shown` of the selected candidate
+    selected: usize,
+    /// Index in `shown` of the first candidate in view
+    scroll: usize,
+}
+
+impl CompletionMenu {
+    pub fn new(line: usize, start_col: usize, items: Vec<MenuItem>) -> Self {
+        Self {
+            line,
+            start_col,
+            shown: (0..items.len()).collect(),
+            items,
+            selected: 0,
+            scroll: 0,
+        }
+    }
+
+    /// Shows the candidates matching `typed`, which has to appear in their filter
+    /// text in order, ignoring case. Returns false if none match.
+    pub fn filter(&mut self, typed: &str) -> bool {
+        let typed = typed.to_lowercase();
+        self.shown = (0..self.items.len())
+            .filter(|&i| {
+                let mut filter = self.items[i].filter.chars().flat_map(char::to_lowercase);
+                typed.chars().all(|c| filter.any(|f| f == c))
+            })
+            .collect();
+        self.selected = 0;
+        self.scroll = 0;
+        !self.shown.is_empty()
+    }
+
+    /// Selects the next candidate, or the previous one, wrapping around at the ends.
+    pub fn select_next(&mut self, backward: bool) {
+        let count = self.shown.len().max(1);
+        self.selected = if backward {
+            (self.selected + count - 1) % count
+        } else {
+            (self.selected + 1) % count
+        };
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + MENU_HEIGHT {
+            self.scroll = self.selected + 1 - MENU_HEIGHT;
+        }
+    }
+
+    pub fn selected(&self) -> Option<&MenuItem> {
+        self.shown.get(self.selected).map(|&i| &self.items[i])
+    }
+
+    /// Where the menu is drawn: below the cell `(x, y)` the completed word starts
+    /// at, or above it when there's more room there, kept inside `bounds`.
+    pub fn area(&self, x: u16, y: u16, bounds: Rect) -> Rect {
+        let width = self
+            .shown
+            .iter()
+            .map(|&i| item_text(&self.items[i]).chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(MENU_WIDTH) as u16
+            + 2;
+        let rows = self.shown.len().min(MENU_HEIGHT) as u16;
+        let below = bounds.bottom().saturating_sub(y + 1);
+        let above = y.saturating_sub(bounds.y);
+        let (top, height) = if below >= rows || below >= above {
+            (y + 1, rows.min(below))
+        } else {
+            (y - rows.min(above), rows.min(above))
+        };
+        let width = width.min(bounds.width);
+        let left = x.min(bounds.right().saturating_sub(width));
+        Rect::new(left, top, width, height)
+    }
+}
+
+impl Widget for &CompletionMenu {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let style = Style::default().bg(Color::DarkGray).fg(Color::White);
+        let selected_style = Style::default().bg(Color::White).fg(Color::Black);
+        let detail_style = Style::default().bg(Color::DarkGray).fg(Color::Gray);
+        let scrollbar_style = Style::default().bg(Color::Gray);
+        let width = area.width.saturating_sub(2) as usize;
+        let rows = area.height as usize;
+
+        // The thumb of the scrollbar only shows when not all candidates fit
+        let count = self.shown.len();
+        let thumb = (count > rows && rows > 0).then(|| {
+            let size = (rows * rows).div_ceil(count).max(1);
+            let start = self.scroll * (rows - size) / (count - rows).max(1);
+            start..start + size
+        });
+
+        Clear.render(area, buf);
+        for (row, &i) in self.shown.iter().skip(self.scroll).take(rows).enumerate() {
+            let item = &self.items[i];
+            let selected = self.scroll + row == self.selected;
+            let label: String = item.label.chars().take(width).collect();
+            let detail_width = width.saturating_sub(label.chars().count() + 2);
+            let detail: String = item.detail.chars().take(detail_width).collect();
+            let padding = width - label.chars().count() - detail.chars().count();
+            let (label_style, detail_style) = if selected {
+                (selected_style, selected_style)
+            } else {
+                (style, detail_style)
+            };
+            let scrollbar = match &thumb {
+                Some(thumb) if thumb.contains(&row) => Span::styled(" ", scrollbar_style),
+                _ => Span::styled(" ", style),
+            };
+            let line = Line::from(vec![
+                Span::styled(" ", label_style),
+                Span::styled(label, label_style),
+                Span::styled(" ".repeat(padding), label_style),
+                Span::styled(detail, detail_style),
+                scrollbar,
+            ]);
+            line.render(Rect::new(area.x, area.y + row as u16, area.width, 1), buf);
+        }
+    }
+}
+
+/// What a candidate shows: its label and detail with room between them.
+fn item_text(item: &MenuItem) -> String {
+    if item.detail.is_empty() {
+        item.label.clone()
+    } else {
+        format!("{}  {}", item.label, item.detail)
+    }
+}
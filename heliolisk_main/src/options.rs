@@ -0,0 +1,139 @@
+/// Declares an option changed with `:set`.
+pub struct OptionSpec {
+    pub name: &'static str,
+    pub short: &'static str,
+}
+
+/// Every option `:set` knows.
+pub const OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "hlsearch",
+        short: "hls",
+    },
+    OptionSpec {
+        name: "incsearch",
+        short: "is",
+    },
+    OptionSpec {
+        name: "autosave",
+        short: "as",
+    },
+    OptionSpec {
+        name: "autosavetime",
+        short: "ast",
+    },
+    OptionSpec {
+        name: "syntax",
+        short: "syn",
+    },
+    // Each buffer has its own, so the editor sets it rather than `Options`
+    OptionSpec {
+        name: "fileformat",
+        short: "ff",
+    },
+];
+
+/// Editor settings changed with `:set`
+///
+/// # Holds:
+/// - Whether search matches are highlighted (`hlsearch`)
+/// - Whether searches move the cursor while the pattern is typed (`incsearch`)
+/// - Whether named buffers are written when typing stops or the terminal loses
+///   focus (`autosave`), and how long typing must stop for, in milliseconds
+///   (`autosavetime`)
+/// - Whether buffers are syntax highlighted (`syntax`)
+pub struct Options {
+    pub hlsearch: bool,
+    pub incsearch: bool,
+    pub autosave: bool,
+    pub autosavetime: u64,
+    pub syntax: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            hlsearch: true,
+            incsearch: true,
+            autosave: false,
+            autosavetime: 2000,
+            syntax: true,
+        }
+    }
+}
+
+impl Options {
+    /// Applies one `:set` argument: `name`, `noname`, `invname`, `name!` or `name?`,
+    /// or `name=value` and `name?` for a number.
+    ///
+    /// Returns a message to show, like `nohlsearch` for `:set hls?`.
+    pub fn set(&mut self, arg: &str) -> Result<Option<String>, String> {
+        let invalid = || format!("Unknown option: {}", arg);
+
+        // A number is shown by its name alone too
+        let shown = arg.strip_suffix('?').unwrap_or(arg);
+        if let Some((spec, value)) = self.find_number(shown) {
+            return Ok(Some(format!("{}={}", spec.name, value)));
+        }
+        if let Some((name, value)) = arg.split_once('=') {
+            let (_, number) = self
+                .find_number(name)
+                .ok_or_else(|| format!("Invalid argument: {}", arg))?;
+            *number = value
+                .parse()
+                .map_err(|_| format!("Number required after =: {}", arg))?;
+            return Ok(None);
+        }
+
+        if let Some(name) = arg.strip_suffix('?') {
+            let (spec, value) = self.find(name).ok_or_else(invalid)?;
+            return Ok(Some(format!(
+                "{}{}",
+                if *value { "" } else { "no" },
+                spec.name
+            )));
+        }
+
+        let (name, change): (&str, fn(bool) -> bool) = if let Some(name) = arg.strip_suffix('!') {
+            (name, |value| !value)
+        } else if let Some(name) = arg.strip_prefix("inv") {
+            (name, |value| !value)
+        } else if let Some(name) = arg
+            .strip_prefix("no")
+            .filter(|name| self.find(name).is_some())
+        {
+            (name, |_| false)
+        } else {
+            (arg, |_| true)
+        };
+
+        let (_, value) = self.find(name).ok_or_else(invalid)?;
+        *value = change(*value);
+        Ok(None)
+    }
+
+    fn find(&mut self, name: &str) -> Option<(&'static OptionSpec, &mut bool)> {
+        let spec = OPTIONS
+            .iter()
+            .find(|spec| spec.name == name || spec.short == name)?;
+        let value = match spec.name {
+            "hlsearch" => &mut self.hlsearch,
+            "incsearch" => &mut self.incsearch,
+            "autosave" => &mut self.autosave,
+            "syntax" => &mut self.syntax,
+            _ => return None,
+        };
+        Some((spec, value))
+    }
+
+    fn find_number(&mut self, name: &str) -> Option<(&'static OptionSpec, &mut u64)> {
+        let spec = OPTIONS
+            .iter()
+            .find(|spec| spec.name == name || spec.short == name)?;
+        let value = match spec.name {
+            "autosavetime" => &mut self.autosavetime,
+            _ => return None,
+        };
+        Some((spec, value))
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use crate::selection::SelectionKind;
+
+/// Number of deletes remembered in the numbered registers `"1` to `"9`.
+const NUMBERED_REGISTER_COUNT: usize = 9;
+
+/// Text stored in a register, along with how it was captured.
+#[derive(Clone, Default)]
+pub struct Register {
+    pub text: String,
+    /// Linewise content is put as whole lines, blockwise content as a rectangle.
+    pub kind: SelectionKind,
+}
+
+/// Registers shared by every buffer open in the Editor
+///
+/// # Holds:
+/// - The unnamed register `""`, used when no register is given
+/// - The yank register `"0`, holding the last yank
+/// - The numbered registers `"1` to `"9`, holding the last deletes
+/// - The named registers `"a` to `"z` (`"A` to `"Z` append)
+#[derive(Default)]
+pub struct Registers {
+    unnamed: Register,
+    yank: Register,
+    numbered: Vec<Register>,
+    named: HashMap<char, Register>,
+}
+
+impl Registers {
+    /// Returns true if `name` can be used after a `"` prefix.
+    pub fn is_valid_name(name: char) -> bool {
+        name == '"' || name.is_ascii_digit() || name.is_ascii_alphabetic()
+    }
+
+    pub fn get(&self, name: Option<char>) -> Option<&Register> {
+        let register = match name.unwrap_or('"') {
+            '"' => &self.unnamed,
+            '0' => &self.yank,
+            n @ '1'..='9' => self.numbered.get(n as usize - '1' as usize)?,
+            n if n.is_ascii_alphabetic() => self.named.get(&n.to_ascii_lowercase())?,
+            _ => return None,
+        };
+        if register.text.is_empty() {
+            None
+        } else {
+            Some(register)
+        }
+    }
+
+    pub fn record_yank(&mut self, name: Option<char>, register: Register) {
+        self.unnamed = match self.write_named(name, &register) {
+            Some(named) => named,
+            None => {
+                self.yank = register.clone();
+                register
+            }
+        };
+    }
+
+    pub fn record_delete(&mut self, name: Option<char>, register: Register) {
+        self.unnamed = match self.write_named(name, &register) {
+            Some(named) => named,
+            None => {
+                self.numbered.insert(0, register.clone());
+                self.numbered.truncate(NUMBERED_REGISTER_COUNT);
+                register
+            }
+        };
+    }
+
+    /// Writes into a named register and returns its new contents,
+    /// or None if `name` isn't a named register.
+    fn write_named(&mut self, name: Option<char>, register: &Register) -> Option<Register> {
+        let name = name.filter(|n| n.is_ascii_alphabetic())?;
+
+        let entry = self.named.entry(name.to_ascii_lowercase()).or_default();
+        if name.is_ascii_uppercase() && !entry.text.is_empty() {
+            // Appending linewise text to a charwise register starts a new line
+            let linewise = register.kind == SelectionKind::Linewise;
+            if linewise && !entry.text.ends_with('\n') {
+                entry.text.push('\n');
+            }
+            entry.text.push_str(&register.text);
+            if linewise {
+                entry.kind = SelectionKind::Linewise;
+            }
+        } else {
+            *entry = register.clone();
+        }
+        Some(entry.clone())
+    }
+}
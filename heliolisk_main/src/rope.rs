@@ -1,22 +1,202 @@
 use ropey::Rope;
 use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use tree_sitter::{InputEdit, Point};
+
+use crate::undo::Edit;
+
+/// Source of revisions, shared by all ropes so no two edits get the same one.
+static NEXT_REVISION: AtomicU64 = AtomicU64::new(1);
+
+/// Most edits kept for the syntax tree between two parses. After more the text is
+/// parsed from scratch, which is quicker than applying them one by one.
+const MAX_TREE_EDITS: usize = 256;
+
+/// Most edits kept for a language server between two syncs. After more the whole
+/// text is sent instead.
+const MAX_SYNC_EDITS: usize = 1024;
+
+/// A revision no text has had yet.
+pub fn next_revision() -> u64 {
+    NEXT_REVISION.fetch_add(1, Ordering::Relaxed)
+}
 
 #[derive(Clone, Default)]
 pub struct HeliosRope {
     pub inner: Rope,
+    /// Changes with every edit. Undoing brings back the revision of the restored
+    /// text, so undoing back to the saved text brings back the saved revision.
+    revision: u64,
+    /// Edits since the last undo step was committed, and when the first was made
+    changes: Vec<Edit>,
+    changed_at: Option<SystemTime>,
+    /// First char changed since the highlighting last caught up with the text
+    changed_from: Option<usize>,
+    /// Edits since the syntax tree was last parsed, None while no tree is kept in
+    /// step with the text or after too many edits to keep
+    tree_edits: Option<Vec<InputEdit>>,
+    /// Edits since the text was last sent to a language server, None while no
+    /// server keeps a copy of it or after too many edits to keep
+    sync_edits: Option<Vec<Edit>>,
 }
 
 impl HeliosRope {
     pub fn new() -> Self {
-        Self { inner: Rope::new() }
+        Self {
+            inner: Rope::new(),
+            revision: 0,
+            changes: Vec::new(),
+            changed_at: None,
+            changed_from: None,
+            tree_edits: None,
+            sync_edits: None,
+        }
     }
 
     pub fn from_str(text: &str) -> Self {
         Self {
             inner: Rope::from_str(text),
+            revision: 0,
+            changes: Vec::new(),
+            changed_at: None,
+            changed_from: None,
+            tree_edits: None,
+            sync_edits: None,
+        }
+    }
+
+    /// Wraps a rope built elsewhere, like one streamed from a large file.
+    pub fn from_rope(inner: Rope) -> Self {
+        Self {
+            inner,
+            revision: 0,
+            changes: Vec::new(),
+            changed_at: None,
+            changed_from: None,
+            tree_edits: None,
+            sync_edits: None,
         }
     }
 
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Gives the text the revision of an undo state it was brought back to.
+    pub fn set_revision(&mut self, revision: u64) {
+        self.revision = revision;
+    }
+
+    fn record(&mut self, edit: Edit) {
+        self.revision = next_revision();
+        self.mark_changed(edit.at, &edit.text, edit.inserted);
+        self.changed_at.get_or_insert_with(SystemTime::now);
+        if let Some(last) = self.changes.last_mut()
+            && last.merge(&edit)
+        {
+            return;
+        }
+        self.changes.push(edit);
+    }
+
+    /// Notes that `text` was inserted at `char_idx`, or removed from there, for the
+    /// highlighting, the syntax tree and the language server. Edits made to `inner` directly, like by
+    /// undoing, are noted by whoever made them.
+    pub fn mark_changed(&mut self, char_idx: usize, text: &str, inserted: bool) {
+        self.changed_from = Some(
+            self.changed_from
+                .map_or(char_idx, |from| from.min(char_idx)),
+        );
+
+        if let Some(edits) = self.sync_edits.as_mut() {
+            let edit = if inserted {
+                Edit::insert(char_idx, text.to_string())
+            } else {
+                Edit::remove(char_idx, text.to_string())
+            };
+            // Typing a word sends it as one change
+            let merged = edits.last_mut().is_some_and(|last| last.merge(&edit));
+            if !merged && edits.len() < MAX_SYNC_EDITS {
+                edits.push(edit);
+            } else if !merged {
+                self.sync_edits = None;
+            }
+        }
+
+        let Some(edits) = self.tree_edits.as_mut() else {
+            return;
+        };
+        if edits.len() >= MAX_TREE_EDITS {
+            self.tree_edits = None;
+            return;
+        }
+        let start_byte = self.inner.char_to_byte(char_idx);
+        let row = self.inner.byte_to_line(start_byte);
+        let start = Point {
+            row,
+            column: start_byte - self.inner.line_to_byte(row),
+        };
+        let end = match text.rfind('\n') {
+            Some(last_break) => Point {
+                row: row + text.matches('\n').count(),
+                column: text.len() - last_break - 1,
+            },
+            None => Point {
+                row,
+                column: start.column + text.len(),
+            },
+        };
+        let end_byte = start_byte + text.len();
+        edits.push(if inserted {
+            InputEdit {
+                start_byte,
+                old_end_byte: start_byte,
+                new_end_byte: end_byte,
+                start_position: start,
+                old_end_position: start,
+                new_end_position: end,
+            }
+        } else {
+            InputEdit {
+                start_byte,
+                old_end_byte: end_byte,
+                new_end_byte: start_byte,
+                start_position: start,
+                old_end_position: end,
+                new_end_position: start,
+            }
+        });
+    }
+
+    /// Takes the first char changed since the last call.
+    pub fn take_changed_from(&mut self) -> Option<usize> {
+        self.changed_from.take()
+    }
+
+    /// Takes the edits made since the last call, for parsing the text again from the
+    /// tree parsed then. None when it has to be parsed from scratch.
+    pub fn take_tree_edits(&mut self) -> Option<Vec<InputEdit>> {
+        self.tree_edits.replace(Vec::new())
+    }
+
+    /// Takes the edits made since the last call, for sending them to a language
+    /// server. None when the whole text has to be sent.
+    pub fn take_sync_edits(&mut self) -> Option<Vec<Edit>> {
+        self.sync_edits.replace(Vec::new())
+    }
+
+    /// The edits [`HeliosRope::take_changes`] would take, with the time of the first one.
+    pub fn changes(&self) -> Option<(&[Edit], SystemTime)> {
+        Some((&self.changes, self.changed_at?))
+    }
+
+    /// Takes the edits made since the last call, with the time of the first one.
+    pub fn take_changes(&mut self) -> Option<(Vec<Edit>, SystemTime)> {
+        let changed_at = self.changed_at.take()?;
+        Some((std::mem::take(&mut self.changes), changed_at))
+    }
+
     pub fn len_lines(&self) -> usize {
         self.inner.len_lines()
     }
@@ -35,15 +215,45 @@ impl HeliosRope {
     pub fn insert_char(&mut self, char_idx: usize, ch: char) {
         if char_idx <= self.len_chars() {
             self.inner.insert_char(char_idx, ch);
+            self.record(Edit::insert(char_idx, ch.to_string()));
+        }
+    }
+
+    pub fn insert(&mut self, char_idx: usize, text: &str) {
+        if char_idx <= self.len_chars() && !text.is_empty() {
+            self.inner.insert(char_idx, text);
+            self.record(Edit::insert(char_idx, text.to_string()));
         }
     }
 
     pub fn remove(&mut self, char_range: std::ops::Range<usize>) {
-        if char_range.end <= self.len_chars() {
-            self.inner.remove(char_range);
+        if char_range.end <= self.len_chars() && char_range.start < char_range.end {
+            let removed = self.inner.slice(char_range.clone()).to_string();
+            self.inner.remove(char_range.clone());
+            self.record(Edit::remove(char_range.start, removed));
         }
     }
 
+    /// Returns the char at `char_idx`, or None past the end of the rope.
+    pub fn char_at(&self, char_idx: usize) -> Option<char> {
+        if char_idx >= self.len_chars() {
+            return None;
+        }
+        Some(self.inner.char(char_idx))
+    }
+
+    /// Returns an iterator over the chars starting at `char_idx`, which can also walk backward.
+    pub fn chars_at(&self, char_idx: usize) -> ropey::iter::Chars<'_> {
+        self.inner.chars_at(char_idx.min(self.len_chars()))
+    }
+
+    /// Copies a range of chars out of the rope, clamped to its length.
+    pub fn slice_to_string(&self, char_range: std::ops::Range<usize>) -> String {
+        let end = char_range.end.min(self.len_chars());
+        let start = char_range.start.min(end);
+        self.inner.slice(start..end).to_string()
+    }
+
     pub fn line_to_char(&self, line_idx: usize) -> usize {
         if line_idx >= self.len_lines() {
             return self.len_chars();
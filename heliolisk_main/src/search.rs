@@ -0,0 +1,345 @@
+use std::ops::Range;
+
+use crate::rope::HeliosRope;
+
+/// A search typed at the `/` or `?` prompt, or started with `*` / `#`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Search {
+    pub pattern: String,
+    /// `?` and `#` search towards the start of the buffer.
+    pub backward: bool,
+    /// `*` and `#` only match whole words.
+    pub whole_word: bool,
+}
+
+/// A match, and whether the search wrapped around the buffer to reach it.
+pub struct SearchMatch {
+    pub range: Range<usize>,
+    pub wrapped: bool,
+}
+
+/// Search state of the Editor
+///
+/// # Holds:
+/// - The last search, repeated by `n` and `N`
+/// - The match previewed while typing at the prompt
+/// - Whether matches are highlighted (until `:noh`)
+#[derive(Default)]
+pub struct SearchState {
+    pub last: Option<Search>,
+    /// Cursor position when the prompt was opened, restored if the search is cancelled.
+    pub origin: (usize, usize),
+    pub preview: Option<Range<usize>>,
+    pub highlight: bool,
+}
+
+impl Search {
+    /// Finds the next match after `char_idx` (or before it, searching backward),
+    /// wrapping around the buffer if needed.
+    pub fn find_from(
+        &self,
+        text: &HeliosRope,
+        char_idx: usize,
+        backward: bool,
+    ) -> Option<SearchMatch> {
+        let matcher = Matcher::new(&self.pattern, backward)?;
+        let len = text.len_chars();
+        let char_idx = char_idx.min(len);
+
+        let (ahead, wrapped) = if backward {
+            (
+                self.scan_backward(text, &matcher, char_idx + matcher.len() - 1, 0),
+                char_idx..len,
+            )
+        } else {
+            (
+                self.scan_forward(text, &matcher, char_idx + 1, len),
+                0..char_idx + 1,
+            )
+        };
+
+        if let Some(range) = ahead {
+            return Some(SearchMatch {
+                range,
+                wrapped: false,
+            });
+        }
+
+        let range = if backward {
+            self.scan_backward(text, &matcher, wrapped.end, wrapped.start)
+        } else {
+            self.scan_forward(text, &matcher, wrapped.start, wrapped.end)
+        }?;
+        Some(SearchMatch {
+            range,
+            wrapped: true,
+        })
+    }
+
+    /// Returns every match starting inside `char_range`.
+    pub fn matches_in(&self, text: &HeliosRope, char_range: Range<usize>) -> Vec<Range<usize>> {
+        let Some(matcher) = Matcher::new(&self.pattern, false) else {
+            return Vec::new();
+        };
+
+        let mut matches = Vec::new();
+        let mut start = char_range.start;
+        while let Some(found) = self.scan_forward(text, &matcher, start, char_range.end) {
+            start = found.start + 1;
+            matches.push(found);
+        }
+        matches
+    }
+
+    /// Finds the first match starting in `start..limit`.
+    fn scan_forward(
+        &self,
+        text: &HeliosRope,
+        matcher: &Matcher,
+        start: usize,
+        limit: usize,
+    ) -> Option<Range<usize>> {
+        if start >= text.len_chars() {
+            return None;
+        }
+
+        let mut state = 0;
+        for (offset, c) in text.chars_at(start).enumerate() {
+            let pos = start + offset;
+            // Matches must start before the limit
+            if pos + 1 > limit + matcher.len() - 1 {
+                return None;
+            }
+            state = matcher.step(state, c);
+            if state == matcher.len() {
+                state = matcher.failure[state - 1];
+                let found = pos + 1 - matcher.len()..pos + 1;
+                if self.accepts(text, &found) {
+                    return Some(found);
+                }
+            }
+        }
+        None
+    }
+
+    /// Finds the last match ending at or before `end` and starting at or after `limit`.
+    fn scan_backward(
+        &self,
+        text: &HeliosRope,
+        matcher: &Matcher,
+        end: usize,
+        limit: usize,
+    ) -> Option<Range<usize>> {
+        let end = end.min(text.len_chars());
+        let mut chars = text.chars_at(end);
+        let mut pos = end;
+        let mut state = 0;
+
+        while let Some(c) = chars.prev() {
+            pos -= 1;
+            if pos < limit {
+                return None;
+            }
+            state = matcher.step(state, c);
+            if state == matcher.len() {
+                state = matcher.failure[state - 1];
+                let found = pos..pos + matcher.len();
+                if self.accepts(text, &found) {
+                    return Some(found);
+                }
+            }
+        }
+        None
+    }
+
+    fn accepts(&self, text: &HeliosRope, found: &Range<usize>) -> bool {
+        if !self.whole_word {
+            return true;
+        }
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let before = found
+            .start
+            .checked_sub(1)
+            .and_then(|i| text.char_at(i))
+            .is_some_and(is_word);
+        let after = text.char_at(found.end).is_some_and(is_word);
+        !before && !after
+    }
+}
+
+/// Knuth-Morris-Pratt matcher, so the rope is streamed once without backtracking.
+struct Matcher {
+    pattern: Vec<char>,
+    failure: Vec<usize>,
+}
+
+impl Matcher {
+    /// Builds a matcher for `pattern`, reversed for backward scans.
+    fn new(pattern: &str, reversed: bool) -> Option<Self> {
+        let mut pattern: Vec<char> = pattern.chars().collect();
+        if pattern.is_empty() {
+            return None;
+        }
+        if reversed {
+            pattern.reverse();
+        }
+
+        let mut failure = vec![0; pattern.len()];
+        let mut k = 0;
+        for i in 1..pattern.len() {
+            while k > 0 && pattern[i] != pattern[k] {
+                k = failure[k - 1];
+            }
+            if pattern[i] == pattern[k] {
+                k += 1;
+            }
+            failure[i] = k;
+        }
+        Some(Self { pattern, failure })
+    }
+
+    fn len(&self) -> usize {
+        self.pattern.len()
+    }
+
+    /// Advances the number of pattern chars matched so far by one char of text.
+    fn step(&self, mut state: usize, c: char) -> usize {
+        while state > 0 && self.pattern[state] != c {
+            state = self.failure[state - 1];
+        }
+        if self.pattern[state] == c {
+            state += 1;
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "one two one\nthree one";
+
+    fn search(pattern: &str) -> Search {
+        Search {
+            pattern: pattern.to_string(),
+            backward: false,
+            whole_word: false,
+        }
+    }
+
+    /// The match found from `char_idx`, with whether the search wrapped.
+    fn find(
+        text: &str,
+        pattern: &str,
+        char_idx: usize,
+        backward: bool,
+    ) -> Option<(Range<usize>, bool)> {
+        search(pattern)
+            .find_from(&HeliosRope::from_str(text), char_idx, backward)
+            .map(|found| (found.range, found.wrapped))
+    }
+
+    #[test]
+    fn failure_tables() {
+        let cases: [(&str, &[usize]); 5] = [
+            ("a", &[0]),
+            ("abab", &[0, 0, 1, 2]),
+            ("aaaa", &[0, 1, 2, 3]),
+            ("aabaaab", &[0, 1, 0, 1, 2, 2, 3]),
+            ("abcabd", &[0, 0, 0, 1, 2, 0]),
+        ];
+        for (pattern, failure) in cases {
+            assert_eq!(Matcher::new(pattern, false).unwrap().failure, failure);
+        }
+        let reversed = Matcher::new("aab", true).unwrap();
+        assert_eq!(reversed.pattern, ['b', 'a', 'a']);
+        assert_eq!(reversed.failure, [0, 0, 0]);
+        assert!(Matcher::new("", false).is_none());
+    }
+
+    #[test]
+    fn forward_searches_wrap_at_the_end() {
+        let cases = [
+            (0, Some((8..11, false))),
+            (8, Some((18..21, false))),
+            (18, Some((0..3, true))),
+            (20, Some((0..3, true))),
+            (99, Some((0..3, true))),
+        ];
+        for (char_idx, expected) in cases {
+            assert_eq!(find(TEXT, "one", char_idx, false), expected, "{char_idx}");
+        }
+    }
+
+    #[test]
+    fn backward_searches_wrap_at_the_start() {
+        let cases = [
+            (18, Some((8..11, false))),
+            (8, Some((0..3, false))),
+            // From inside a match, its start
+            (9, Some((8..11, false))),
+            (2, Some((0..3, false))),
+            (0, Some((18..21, true))),
+        ];
+        for (char_idx, expected) in cases {
+            assert_eq!(find(TEXT, "one", char_idx, true), expected, "{char_idx}");
+        }
+    }
+
+    #[test]
+    fn the_match_at_the_cursor_comes_last() {
+        assert_eq!(find("x one y", "one", 2, false), Some((2..5, true)));
+        assert_eq!(find("x one y", "one", 2, true), Some((2..5, true)));
+        // Overlapping matches are each found
+        assert_eq!(find("aaaa", "aa", 0, false), Some((1..3, false)));
+        assert_eq!(find("aaaa", "aa", 3, true), Some((2..4, false)));
+    }
+
+    #[test]
+    fn matches_span_line_breaks() {
+        assert_eq!(find(TEXT, "one\nthree", 0, false), Some((8..17, false)));
+        assert_eq!(find(TEXT, "one\nthree", 20, true), Some((8..17, false)));
+        assert_eq!(find(TEXT, "\n", 0, false), Some((11..12, false)));
+    }
+
+    #[test]
+    fn nothing_to_find() {
+        assert_eq!(find(TEXT, "", 0, false), None);
+        assert_eq!(find(TEXT, "", 0, true), None);
+        let longer = format!("{} and more", TEXT);
+        assert_eq!(find(TEXT, &longer, 0, false), None);
+        assert_eq!(find(TEXT, &longer, 20, true), None);
+        assert_eq!(find("", "a", 0, false), None);
+        assert_eq!(find("", "a", 0, true), None);
+        assert_eq!(find(TEXT, "four", 5, false), None);
+    }
+
+    #[test]
+    fn counts_chars_not_bytes() {
+        assert_eq!(find("é😀é😀", "é😀", 0, false), Some((2..4, false)));
+        assert_eq!(find("é😀é😀", "é😀", 2, true), Some((0..2, false)));
+    }
+
+    #[test]
+    fn whole_words_and_all_matches() {
+        let mut words = search("one");
+        words.whole_word = true;
+        let text = HeliosRope::from_str("one ones bone one");
+        let found = words.find_from(&text, 0, false).unwrap();
+        assert_eq!((found.range, found.wrapped), (14..17, false));
+        let found = words.find_from(&text, 14, true).unwrap();
+        assert_eq!((found.range, found.wrapped), (0..3, false));
+
+        assert_eq!(
+            search("one").matches_in(&text, 0..17),
+            [0..3, 4..7, 10..13, 14..17]
+        );
+        assert_eq!(search("one").matches_in(&text, 1..14), [4..7, 10..13]);
+        assert_eq!(words.matches_in(&text, 0..17), [0..3, 14..17]);
+        assert_eq!(
+            search("aa").matches_in(&HeliosRope::from_str("aaaa"), 0..4),
+            [0..2, 1..3, 2..4]
+        );
+    }
+}
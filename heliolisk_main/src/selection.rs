@@ -0,0 +1,74 @@
+/// How a selection (and the register text captured from it) spans the buffer.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum SelectionKind {
+    /// `v`: every char between the anchor and the cursor
+    #[default]
+    Charwise,
+    /// `V`: every line between the anchor and the cursor
+    Linewise,
+    /// `Ctrl-v`: the rectangle with the anchor and the cursor as corners
+    Blockwise,
+}
+
+/// An anchored selection in Select Mode.
+///
+/// Positions are (line, col) pairs, like the Editor's cursor.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Selection {
+    pub anchor: (usize, usize),
+    pub cursor: (usize, usize),
+    pub kind: SelectionKind,
+}
+
+impl Selection {
+    /// Returns the anchor and cursor ordered by position in the buffer.
+    pub fn ordered(&self) -> ((usize, usize), (usize, usize)) {
+        if self.anchor <= self.cursor {
+            (self.anchor, self.cursor)
+        } else {
+            (self.cursor, self.anchor)
+        }
+    }
+
+    /// First and last line touched by the selection.
+    pub fn line_span(&self) -> (usize, usize) {
+        let (from, to) = self.ordered();
+        (from.0, to.0)
+    }
+
+    /// Left and right column of a blockwise selection, inclusive.
+    pub fn block_columns(&self) -> (usize, usize) {
+        let left = self.anchor.1.min(self.cursor.1);
+        let right = self.anchor.1.max(self.cursor.1);
+        (left, right)
+    }
+
+    /// Columns selected on `line_idx` as a half-open range.
+    ///
+    /// `line_len` is the line's length without its line break. The range may end at
+    /// `line_len + 1` when the line break itself is selected.
+    pub fn columns_on_line(&self, line_idx: usize, line_len: usize) -> Option<(usize, usize)> {
+        let (first, last) = self.line_span();
+        if line_idx < first || line_idx > last {
+            return None;
+        }
+
+        let (from, to) = self.ordered();
+        match self.kind {
+            SelectionKind::Linewise => Some((0, line_len + 1)),
+            SelectionKind::Blockwise => {
+                let (left, right) = self.block_columns();
+                Some((left, right + 1))
+            }
+            SelectionKind::Charwise => {
+                let start = if line_idx == from.0 { from.1 } else { 0 };
+                let end = if line_idx == to.0 {
+                    to.1 + 1
+                } else {
+                    line_len + 1
+                };
+                Some((start, end))
+            }
+        }
+    }
+}
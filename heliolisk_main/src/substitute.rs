@@ -0,0 +1,305 @@
+use regex::{Captures, Regex, RegexBuilder};
+
+/// A parsed `:s/pattern/replacement/flags`.
+///
+/// Patterns use the syntax of the `regex` crate, so groups are written `(...)`.
+/// In the replacement, `&` and `\0` insert the whole match, `\1` to `\9` insert
+/// capture groups and `\r` or `\n` insert a line break.
+pub struct Substitute {
+    pub regex: Regex,
+    pub replacement: String,
+    /// `g`: replace every match on a line instead of only the first
+    pub global: bool,
+    /// `c`: ask before each replacement
+    pub confirm: bool,
+    /// `n`: count the matches instead of replacing them
+    pub count_only: bool,
+}
+
+impl Substitute {
+    /// Parses the part of the command following `s`.
+    ///
+    /// An empty pattern reuses `last_pattern`.
+    pub fn parse(input: &str, last_pattern: Option<&str>) -> Result<Self, String> {
+        let mut chars = input.chars();
+        let delimiter = chars
+            .next()
+            .filter(|c| !c.is_alphanumeric() && !c.is_whitespace() && *c != '\\')
+            .ok_or_else(|| "Substitute needs a pattern, like s/old/new/".to_string())?;
+
+        let (pattern, rest) = split_at_delimiter(chars.as_str(), delimiter);
+        let (replacement, flags) = match rest {
+            Some(rest) => {
+                let (replacement, flags) = split_at_delimiter(rest, delimiter);
+                (replacement, flags.unwrap_or(""))
+            }
+            None => (String::new(), ""),
+        };
+
+        let pattern = if pattern.is_empty() {
+            last_pattern
+                .map(regex::escape)
+                .ok_or_else(|| "No previous regular expression".to_string())?
+        } else {
+            pattern
+        };
+
+        let (mut global, mut confirm, mut ignore_case) = (false, false, false);
+        let mut count_only = false;
+        for flag in flags.trim_end().chars() {
+            match flag {
+                'g' => global = true,
+                'c' => confirm = true,
+                'n' => count_only = true,
+                'i' => ignore_case = true,
+                'I' => ignore_case = false,
+                _ => return Err(format!("Trailing characters: {}", flags)),
+            }
+        }
+
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| format!("Invalid pattern: {}", e))?;
+
+        Ok(Self {
+            regex,
+            replacement,
+            global,
+            confirm,
+            count_only,
+        })
+    }
+
+    /// Finds the first match in `line` starting at or after byte `from`.
+    pub fn find_at<'l>(&self, line: &'l str, from: usize) -> Option<Captures<'l>> {
+        if from > line.len() {
+            return None;
+        }
+        self.regex.captures_at(line, from)
+    }
+
+    /// Replaces the matches in `line` (only the first unless the `g` flag was given).
+    ///
+    /// Returns the new line and the number of replacements, or None if nothing matched.
+    pub fn replace_line(&self, line: &str) -> Option<(String, usize)> {
+        let mut result = String::new();
+        let mut copied = 0;
+        let mut count = 0;
+
+        let mut from = 0;
+        while let Some(captures) = self.find_at(line, from) {
+            let found = captures.get(0)?;
+            from = next_search_start(line, found.start(), found.end());
+            // An empty match right after the previous match isn't replaced again
+            if found.is_empty() && count > 0 && found.start() == copied {
+                continue;
+            }
+            result.push_str(&line[copied..found.start()]);
+            result.push_str(&self.expand(&captures));
+            copied = found.end();
+            count += 1;
+
+            if !self.global {
+                break;
+            }
+        }
+
+        if count == 0 {
+            return None;
+        }
+        result.push_str(&line[copied..]);
+        Some((result, count))
+    }
+
+    /// Builds the replacement text for one match.
+    pub fn expand(&self, captures: &Captures) -> String {
+        let group = |i: usize| captures.get(i).map_or("", |m| m.as_str());
+
+        let mut expanded = String::new();
+        let mut chars = self.replacement.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '&' => expanded.push_str(group(0)),
+                '\\' => match chars.next() {
+                    Some(d @ '0'..='9') => expanded.push_str(group(d as usize - '0' as usize)),
+                    Some('r') | Some('n') => expanded.push('\n'),
+                    Some('t') => expanded.push('\t'),
+                    Some(other) => expanded.push(other),
+                    None => expanded.push('\\'),
+                },
+                _ => expanded.push(c),
+            }
+        }
+        expanded
+    }
+}
+
+/// Byte offset to search from after a match, stepping past empty matches.
+pub fn next_search_start(line: &str, start: usize, end: usize) -> usize {
+    if end > start {
+        return end;
+    }
+    line[end..]
+        .chars()
+        .next()
+        .map_or(end + 1, |c| end + c.len_utf8())
+}
+
+/// Splits `input` at the first unescaped `delimiter`.
+///
+/// An escaped delimiter loses its backslash; other escapes are kept for the regex
+/// or the replacement. Returns None as the rest when the delimiter is missing.
+fn split_at_delimiter(input: &str, delimiter: char) -> (String, Option<&str>) {
+    let mut part = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == delimiter {
+            return (part, Some(&input[i + c.len_utf8()..]));
+        }
+        if c == '\\' {
+            match chars.next() {
+                Some((_, escaped)) if escaped == delimiter => part.push(escaped),
+                Some((_, escaped)) => {
+                    part.push('\\');
+                    part.push(escaped);
+                }
+                None => part.push('\\'),
+            }
+        } else {
+            part.push(c);
+        }
+    }
+    (part, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Substitute {
+        Substitute::parse(input, None).unwrap()
+    }
+
+    /// Runs `:s` with `input` on `line`, giving the new line and the replacements.
+    fn replace(input: &str, line: &str) -> Option<(String, usize)> {
+        parse(input).replace_line(line)
+    }
+
+    #[test]
+    fn flags() {
+        let cases = [
+            ("/a/b/", (false, false, false)),
+            ("/a/b/g", (true, false, false)),
+            ("/a/b/c", (false, true, false)),
+            ("/a/b/n", (false, false, true)),
+            ("/a/b/gcn ", (true, true, true)),
+            ("/a/b", (false, false, false)),
+            ("/a", (false, false, false)),
+        ];
+        for (input, expected) in cases {
+            let substitute = parse(input);
+            let flags = (substitute.global, substitute.confirm, substitute.count_only);
+            assert_eq!(flags, expected, "{input}");
+        }
+
+        assert_eq!(replace("/a/b/i", "xAa"), Some(("xba".to_string(), 1)));
+        assert_eq!(replace("/a/b/gi", "xAa"), Some(("xbb".to_string(), 2)));
+        assert_eq!(replace("/a/b/iI", "xAa"), Some(("xAb".to_string(), 1)));
+        assert_eq!(
+            Substitute::parse("/a/b/gx", None).err().as_deref(),
+            Some("Trailing characters: gx")
+        );
+    }
+
+    #[test]
+    fn delimiters() {
+        let cases = [
+            ("/a\\/b/c\\/d/", "a/b", "c/d"),
+            ("#a/b#c#g", "a/b a/b", "c c"),
+            ("+a+b+", "a+a", "b+a"),
+            ("/a/x", "aa", "xa"),
+            // A pattern without replacement removes the match
+            ("/a", "bab", "bb"),
+            // Escapes of anything but the delimiter are left to the regex
+            ("/a\\.b/x/g", "axb a.b", "axb x"),
+        ];
+        for (input, line, expected) in cases {
+            assert_eq!(
+                replace(input, line).map(|(line, _)| line).as_deref(),
+                Some(expected),
+                "{input}"
+            );
+        }
+
+        for input in ["", "a/b/", " /a/b/", "\\a\\b\\"] {
+            assert!(Substitute::parse(input, None).is_err(), "{input:?}");
+        }
+        assert!(Substitute::parse("/(/x/", None).is_err());
+    }
+
+    #[test]
+    fn empty_patterns_reuse_the_last_search() {
+        let substitute = Substitute::parse("//x/g", Some("a.b")).unwrap();
+        assert_eq!(
+            substitute.replace_line("a.b axb"),
+            Some(("x axb".to_string(), 1))
+        );
+        assert_eq!(
+            Substitute::parse("//x/", None).err().as_deref(),
+            Some("No previous regular expression")
+        );
+    }
+
+    #[test]
+    fn replacements_expand_matches_and_groups() {
+        let cases = [
+            ("/(\\w+) (\\w+)/\\2 \\1/", "hello world", "world hello"),
+            ("/o/[&]/g", "foo", "f[o][o]"),
+            ("/o/[\\0]/", "foo", "f[o]o"),
+            ("/o/\\&/g", "foo", "f&&"),
+            ("/o/\\\\/", "foo", "f\\o"),
+            ("/(a)|(b)/[\\2]/g", "ab", "[][b]"),
+            ("/, /\\r/g", "a, b, c", "a\nb\nc"),
+            ("/ /\\t/", "a b", "a\tb"),
+            ("/a/x\\", "a", "x\\"),
+        ];
+        for (input, line, expected) in cases {
+            assert_eq!(
+                replace(input, line).map(|(line, _)| line).as_deref(),
+                Some(expected),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn counts_replacements_on_a_line() {
+        assert_eq!(replace("/o/0/", "foo"), Some(("f0o".to_string(), 1)));
+        assert_eq!(replace("/o/0/g", "foo"), Some(("f00".to_string(), 2)));
+        assert_eq!(replace("/z/0/g", "foo"), None);
+        assert_eq!(replace("/o/0/g", ""), None);
+    }
+
+    #[test]
+    fn empty_matches_step_over_each_char() {
+        let cases = [
+            ("/x*/-/g", "abc", "-a-b-c-", 4),
+            // Not again right after the match before it
+            ("/x*/-/g", "axxb", "-a-b-", 3),
+            ("/x*/-/g", "éü", "-é-ü-", 3),
+            ("/x*/-/g", "", "-", 1),
+            ("/x*/-/", "abc", "-abc", 1),
+            ("/$/!/g", "ab", "ab!", 1),
+            ("/\\b/|/g", "ab cd", "|ab| |cd|", 4),
+        ];
+        for (input, line, expected, count) in cases {
+            assert_eq!(
+                replace(input, line),
+                Some((expected.to_string(), count)),
+                "{input} on {line:?}"
+            );
+        }
+        assert!(parse("/x*/-/").find_at("abc", 4).is_none());
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+use crate::file_ops;
+use crate::rope::HeliosRope;
+
+/// Starts every swap file, followed by the writer's pid and the file name.
+const SWAP_FILE_MAGIC: &str = "HLSWAP1";
+
+/// Unsaved text of a buffer, written aside so it survives a crash.
+#[derive(Clone)]
+pub struct Swap {
+    /// Where the swap file is
+    pub path: PathBuf,
+    /// Process that wrote it
+    pub pid: u32,
+    /// File the text belongs to, None for a buffer without a name
+    pub file_path: Option<String>,
+    /// When it was last written
+    pub time: SystemTime,
+    pub text: String,
+}
+
+impl Swap {
+    /// Reads the swap file at `path`, None if it isn't one.
+    pub fn read(path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(path).ok()?;
+        let mut parts = content.splitn(4, '\n');
+        if parts.next()? != SWAP_FILE_MAGIC {
+            return None;
+        }
+        let pid = parts.next()?.parse().ok()?;
+        let file_path = Some(parts.next()?.to_string()).filter(|name| !name.is_empty());
+        let text = parts.next()?.to_string();
+        let time = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        Some(Self {
+            path: path.to_path_buf(),
+            pid,
+            file_path,
+            time,
+            text,
+        })
+    }
+
+    /// True while the process that wrote the swap file is still editing.
+    pub fn is_running(&self) -> bool {
+        Path::new("/proc").join(self.pid.to_string()).exists()
+    }
+
+    /// One line about the swap file, like `notes.txt  modified 5 minutes ago, pid 412`.
+    pub fn describe(&self) -> String {
+        format!(
+            "{}  modified {}, pid {}{}",
+            self.file_path.as_deref().unwrap_or("[No Name]"),
+            crate::undo::describe_age(self.time),
+            self.pid,
+            if self.is_running() {
+                " (still running)"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+/// A change to make to the swap files, done by the swap thread.
+pub enum SwapJob {
+    Write {
+        path: PathBuf,
+        file_path: Option<String>,
+        text: HeliosRope,
+    },
+    Remove(PathBuf),
+}
+
+/// Directory swap files are kept in.
+fn swap_dir() -> Option<PathBuf> {
+    Some(file_ops::data_dir()?.join("swap"))
+}
+
+/// The swap file for a buffer: named after a hash of the full path of its file, or
+/// after this process and the buffer's number for a buffer without a name.
+pub fn swap_file_path(file_path: Option<&str>, buffer: usize) -> Option<PathBuf> {
+    let name = match file_path {
+        Some(file_path) => {
+            let full_path = fs::canonicalize(file_path)
+                .or_else(|_| std::path::absolute(file_path))
+                .ok()?;
+            let hash = file_ops::hash_chunks([full_path.to_string_lossy().as_bytes()]);
+            format!("{:016x}.swp", hash)
+        }
+        None => format!("noname-{}-{}.swp", std::process::id(), buffer),
+    };
+    Some(swap_dir()?.join(name))
+}
+
+/// The swap file another Heliolisk left for `file_path`.
+pub fn find(file_path: &str) -> Option<Swap> {
+    let swap = Swap::read(&swap_file_path(Some(file_path), 0)?)?;
+    (swap.pid != std::process::id()).then_some(swap)
+}
+
+/// Every swap file that isn't this process's, oldest first.
+pub fn recoverable() -> Vec<Swap> {
+    let Some(Ok(entries)) = swap_dir().map(fs::read_dir) else {
+        return Vec::new();
+    };
+    let mut swaps: Vec<Swap> = entries
+        .filter_map(|entry| Swap::read(&entry.ok()?.path()))
+        .filter(|swap| swap.pid != std::process::id())
+        .collect();
+    swaps.sort_by_key(|swap| swap.time);
+    swaps
+}
+
+/// Starts the thread that writes and removes swap files, in the order jobs are sent.
+///
+/// The thread ends once the sender is dropped and every job is done.
+pub fn spawn_writer() -> (Sender<SwapJob>, JoinHandle<()>) {
+    let (swap_tx, swap_rx) = mpsc::channel();
+    let handle = std::thread::spawn(move || {
+        for job in swap_rx {
+            // A swap file that can't be written is retried with the next change
+            let _ = match job {
+                SwapJob::Write {
+                    path,
+                    file_path,
+                    text,
+                } => write(&path, file_path.as_deref(), &text),
+                SwapJob::Remove(path) => fs::remove_file(path),
+            };
+        }
+    });
+    (swap_tx, handle)
+}
+
+fn write(path: &Path, file_path: Option<&str>, text: &HeliosRope) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let header = format!(
+        "{}\n{}\n{}\n",
+        SWAP_FILE_MAGIC,
+        std::process::id(),
+        file_path.unwrap_or("")
+    );
+    let mut content = header.into_bytes();
+    for chunk in text.inner.chunks() {
+        content.extend_from_slice(chunk.as_bytes());
+    }
+    fs::write(path, content)
+}
@@ -0,0 +1,826 @@
+use ratatui::style::{Color, Modifier, Style};
+
+use crate::rope::HeliosRope;
+
+/// Languages highlighted out of the box.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Language {
+    Rust,
+    Toml,
+    Markdown,
+    Json,
+    Python,
+    C,
+    Shell,
+}
+
+/// What a run of highlighted chars is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenKind {
+    Keyword,
+    Type,
+    Function,
+    /// Rust macros and C preprocessor directives
+    Macro,
+    /// Rust attributes and Python decorators
+    Attribute,
+    Constant,
+    Number,
+    String,
+    Comment,
+    /// Shell variables and Rust lifetimes
+    Variable,
+    /// Keys of TOML and JSON
+    Key,
+    /// Markdown headings and TOML tables
+    Heading,
+    Emphasis,
+    /// Markdown code spans and blocks
+    Code,
+    Link,
+}
+
+impl TokenKind {
+    pub fn style(self) -> Style {
+        let style = Style::default();
+        match self {
+            Self::Keyword => style.fg(Color::Magenta),
+            Self::Type => style.fg(Color::Yellow),
+            Self::Function => style.fg(Color::Blue),
+            Self::Macro | Self::Attribute => style.fg(Color::Cyan),
+            Self::Constant | Self::Number => style.fg(Color::LightRed),
+            Self::String | Self::Code => style.fg(Color::Green),
+            Self::Comment => style.fg(Color::DarkGray).add_modifier(Modifier::ITALIC),
+            Self::Variable => style.fg(Color::LightCyan),
+            Self::Key => style.fg(Color::LightBlue),
+            Self::Heading => style.fg(Color::Yellow).add_modifier(Modifier::BOLD),
+            Self::Emphasis => style.add_modifier(Modifier::BOLD),
+            Self::Link => style
+                .fg(Color::LightBlue)
+                .add_modifier(Modifier::UNDERLINED),
+        }
+    }
+}
+
+/// Highlighted chars `start..end` of a line, counted in chars.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Token {
+    pub start: usize,
+    pub end: usize,
+    pub kind: TokenKind,
+}
+
+/// How a string ends.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Quote {
+    close: char,
+    /// Closed by three quotes, as in Python and TOML
+    triple: bool,
+    /// Backslashes don't escape
+    raw: bool,
+    /// `#`s after the closing quote, for a Rust raw string
+    hashes: u8,
+}
+
+/// What a line starts inside of, left open by the lines before it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LineState {
+    #[default]
+    Normal,
+    /// A block comment, nested `depth` deep
+    Comment {
+        depth: u8,
+    },
+    String(Quote),
+    /// A fenced Markdown code block, closed by a fence of the same char
+    CodeBlock {
+        fence: char,
+    },
+}
+
+impl Language {
+    /// The language of a file format, which is the extension of the file's name.
+    pub fn from_file_format(format: &str) -> Option<Self> {
+        match format.trim_start_matches('.').to_ascii_lowercase().as_str() {
+            "rs" => Some(Self::Rust),
+            "toml" => Some(Self::Toml),
+            "md" | "markdown" => Some(Self::Markdown),
+            "json" => Some(Self::Json),
+            "py" | "pyw" => Some(Self::Python),
+            "c" | "h" => Some(Self::C),
+            "sh" | "bash" | "zsh" => Some(Self::Shell),
+            _ => None,
+        }
+    }
+
+    /// The language of a script from its `#!` line, like `#!/usr/bin/env python3`.
+    pub fn from_shebang(first_line: &str) -> Option<Self> {
+        let command = first_line.strip_prefix("#!")?;
+        let mut words = command.split_whitespace();
+        let mut program = words.next()?.rsplit('/').next()?;
+        if program == "env" {
+            program = words.find(|word| !word.starts_with('-'))?;
+        }
+        let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+        match program {
+            "sh" | "bash" | "zsh" | "dash" | "ksh" => Some(Self::Shell),
+            "python" => Some(Self::Python),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Rust => "rust",
+            Self::Toml => "toml",
+            Self::Markdown => "markdown",
+            Self::Json => "json",
+            Self::Python => "python",
+            Self::C => "c",
+            Self::Shell => "sh",
+        }
+    }
+
+    fn keywords(self) -> &'static [&'static str] {
+        match self {
+            Self::Rust => &[
+                "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else",
+                "enum", "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+                "move", "mut", "pub", "ref", "return", "self", "static", "struct", "super",
+                "trait", "type", "unsafe", "use", "where", "while", "yield",
+            ],
+            Self::Python => &[
+                "and", "as", "assert", "async", "await", "break", "class", "continue", "def",
+                "del", "elif", "else", "except", "finally", "for", "from", "global", "if",
+                "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return",
+                "try", "while", "with", "yield", "match", "case",
+            ],
+            Self::C => &[
+                "auto", "break", "case", "const", "continue", "default", "do", "else", "enum",
+                "extern", "for", "goto", "if", "inline", "register", "restrict", "return",
+                "sizeof", "static", "struct", "switch", "typedef", "union", "volatile", "while",
+            ],
+            Self::Shell => &[
+                "if", "then", "else", "elif", "fi", "for", "while", "until", "do", "done", "case",
+                "esac", "in", "function", "return", "local", "export", "readonly", "select",
+                "break", "continue", "exit", "shift", "source", "declare", "unset",
+            ],
+            Self::Toml | Self::Json | Self::Markdown => &[],
+        }
+    }
+
+    fn types(self) -> &'static [&'static str] {
+        match self {
+            Self::Rust => &[
+                "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16",
+                "i32", "i64", "i128", "isize", "f32", "f64", "Self",
+            ],
+            Self::C => &[
+                "void", "char", "short", "int", "long", "float", "double", "signed", "unsigned",
+                "bool", "size_t", "ssize_t", "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t",
+                "uint16_t", "uint32_t", "uint64_t", "FILE",
+            ],
+            Self::Python => &[
+                "int", "float", "str", "bytes", "bool", "list", "dict", "set", "tuple", "object",
+            ],
+            _ => &[],
+        }
+    }
+
+    fn constants(self) -> &'static [&'static str] {
+        match self {
+            Self::Rust => &["true", "false", "None", "Some", "Ok", "Err"],
+            Self::Python => &["True", "False", "None", "self"],
+            Self::C => &["NULL", "true", "false"],
+            Self::Toml => &["true", "false", "inf", "nan"],
+            Self::Json => &["true", "false", "null"],
+            Self::Shell | Self::Markdown => &[],
+        }
+    }
+
+    fn line_comment(self) -> Option<&'static str> {
+        match self {
+            Self::Rust | Self::C => Some("//"),
+            Self::Python | Self::Shell | Self::Toml => Some("#"),
+            Self::Json | Self::Markdown => None,
+        }
+    }
+
+    fn block_comment(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Self::Rust | Self::C => Some(("/*", "*/")),
+            _ => None,
+        }
+    }
+
+    /// Strings may run over several lines without ending a line in a backslash.
+    fn multiline_strings(self) -> bool {
+        matches!(self, Self::Rust | Self::Shell)
+    }
+
+    /// Highlights one line, without its line break, that starts in `state`.
+    ///
+    /// Returns its tokens and the state the next line starts in.
+    pub fn highlight_line(self, line: &str, state: LineState) -> (Vec<Token>, LineState) {
+        let chars: Vec<char> = line.chars().collect();
+        let mut scanner = Scanner {
+            language: self,
+            chars: &chars,
+            tokens: Vec::new(),
+        };
+        let state = if self == Self::Markdown {
+            scanner.markdown(state)
+        } else {
+            scanner.code(state)
+        };
+        (scanner.tokens, state)
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Splits a line into tokens.
+struct Scanner<'a> {
+    language: Language,
+    chars: &'a [char],
+    tokens: Vec<Token>,
+}
+
+impl Scanner<'_> {
+    fn push(&mut self, start: usize, end: usize, kind: TokenKind) {
+        if start < end {
+            self.tokens.push(Token { start, end, kind });
+        }
+    }
+
+    fn starts_with(&self, at: usize, pattern: &str) -> bool {
+        (at..)
+            .zip(pattern.chars())
+            .all(|(i, c)| self.chars.get(i) == Some(&c))
+    }
+
+    fn at(&self, i: usize) -> Option<char> {
+        self.chars.get(i).copied()
+    }
+
+    /// Index of the first char from `from` on that isn't a space or tab.
+    fn skip_blanks(&self, from: usize) -> usize {
+        let mut i = from;
+        while matches!(self.at(i), Some(' ' | '\t')) {
+            i += 1;
+        }
+        i
+    }
+
+    /// End of the word starting at `from`.
+    fn word_end(&self, from: usize) -> usize {
+        let mut i = from;
+        while self.at(i).is_some_and(is_word_char) {
+            i += 1;
+        }
+        i
+    }
+
+    /// Highlights a line of a programming or config language.
+    fn code(&mut self, state: LineState) -> LineState {
+        let len = self.chars.len();
+        let mut i = 0;
+
+        // Finish what the line before left open
+        match state {
+            LineState::Comment { depth } => {
+                let (end, depth) = self.block_comment(0, depth);
+                self.push(0, end, TokenKind::Comment);
+                if depth > 0 {
+                    return LineState::Comment { depth };
+                }
+                i = end;
+            }
+            LineState::String(quote) => {
+                let (end, closed) = self.string_end(0, quote);
+                self.push(0, end, TokenKind::String);
+                if !closed {
+                    return self.open_string(quote);
+                }
+                i = end;
+            }
+            LineState::Normal | LineState::CodeBlock { .. } => {}
+        }
+
+        let first = self.skip_blanks(i);
+        match self.language {
+            Language::C if self.at(first) == Some('#') => {
+                let end = self.word_end(self.skip_blanks(first + 1));
+                self.push(first, end, TokenKind::Macro);
+                i = self.skip_blanks(end);
+                // The file of `#include <stdio.h>`
+                if self.at(i) == Some('<')
+                    && let Some(close) = (i..len).find(|&j| self.chars[j] == '>')
+                {
+                    self.push(i, close + 1, TokenKind::String);
+                    i = close + 1;
+                }
+            }
+            Language::Python if self.at(first) == Some('@') => {
+                let mut end = first + 1;
+                while self.at(end).is_some_and(|c| is_word_char(c) || c == '.') {
+                    end += 1;
+                }
+                self.push(first, end, TokenKind::Attribute);
+                i = end;
+            }
+            Language::Toml if self.at(first) == Some('[') => {
+                let end = (first..len)
+                    .rfind(|&j| self.chars[j] == ']')
+                    .map_or(len, |j| j + 1);
+                self.push(first, end, TokenKind::Heading);
+                i = end;
+            }
+            Language::Toml => {
+                let mut end = first;
+                while self
+                    .at(end)
+                    .is_some_and(|c| is_word_char(c) || c == '-' || c == '.')
+                {
+                    end += 1;
+                }
+                if end > first && self.at(self.skip_blanks(end)) == Some('=') {
+                    self.push(first, end, TokenKind::Key);
+                    i = end;
+                }
+            }
+            _ => {}
+        }
+
+        while i < len {
+            let c = self.chars[i];
+
+            if let Some(comment) = self.language.line_comment()
+                && self.starts_with(i, comment)
+                // A shell `#` inside a word, like `$#`, doesn't start a comment
+                && (self.language != Language::Shell
+                    || i == 0
+                    || self.chars[i - 1].is_whitespace()
+                    || self.chars[i - 1] == ';')
+            {
+                self.push(i, len, TokenKind::Comment);
+                return LineState::Normal;
+            }
+
+            if let Some((open, _)) = self.language.block_comment()
+                && self.starts_with(i, open)
+            {
+                let (end, depth) = self.block_comment(i + open.chars().count(), 1);
+                self.push(i, end, TokenKind::Comment);
+                if depth > 0 {
+                    return LineState::Comment { depth };
+                }
+                i = end;
+                continue;
+            }
+
+            if let Some((opening, quote)) = self.string_start(i) {
+                let (end, closed) = self.string_end(i + opening, quote);
+                let kind = if self.language == Language::Json
+                    && self.at(self.skip_blanks(end)) == Some(':')
+                {
+                    TokenKind::Key
+                } else {
+                    TokenKind::String
+                };
+                self.push(i, end, kind);
+                if !closed {
+                    return self.open_string(quote);
+                }
+                i = end;
+                continue;
+            }
+
+            if self.language == Language::Rust {
+                if c == '#' && matches!(self.at(i + 1), Some('[' | '!')) {
+                    let end = self.attribute_end(i);
+                    self.push(i, end, TokenKind::Attribute);
+                    i = end;
+                    continue;
+                }
+                // A lifetime, as the char literals were taken as strings
+                if c == '\''
+                    && self
+                        .at(i + 1)
+                        .is_some_and(|c| c.is_alphabetic() || c == '_')
+                {
+                    let end = self.word_end(i + 1);
+                    self.push(i, end, TokenKind::Variable);
+                    i = end;
+                    continue;
+                }
+            }
+
+            if self.language == Language::Shell && c == '$' {
+                let end = match self.at(i + 1) {
+                    Some('{') => (i..len)
+                        .find(|&j| self.chars[j] == '}')
+                        .map_or(len, |j| j + 1),
+                    Some(c) if c.is_alphabetic() || c == '_' => self.word_end(i + 1),
+                    Some(c) if c.is_ascii_digit() || "?#@*!$-".contains(c) => i + 2,
+                    _ => i + 1,
+                };
+                if end > i + 1 {
+                    self.push(i, end, TokenKind::Variable);
+                }
+                i = end;
+                continue;
+            }
+
+            if c.is_ascii_digit() {
+                let mut end = i + 1;
+                while let Some(c) = self.at(end) {
+                    // `1.5` but not the range `1..5` or the method call `1.max(2)`
+                    let fraction = c == '.' && self.at(end + 1).is_some_and(|c| c.is_ascii_digit());
+                    if !(is_word_char(c) || fraction) {
+                        break;
+                    }
+                    end += 1;
+                }
+                self.push(i, end, TokenKind::Number);
+                i = end;
+                continue;
+            }
+
+            if c.is_alphabetic() || c == '_' {
+                let end = self.word_end(i);
+                let kind = self.word_kind(i, end);
+                let end = if kind == Some(TokenKind::Macro) {
+                    end + 1
+                } else {
+                    end
+                };
+                if let Some(kind) = kind {
+                    self.push(i, end, kind);
+                }
+                i = end;
+                continue;
+            }
+
+            i += 1;
+        }
+        LineState::Normal
+    }
+
+    /// What the word at `start..end` is, None for a plain name.
+    fn word_kind(&self, start: usize, end: usize) -> Option<TokenKind> {
+        let word: String = self.chars[start..end].iter().collect();
+        let language = self.language;
+        if language.keywords().contains(&word.as_str()) {
+            return Some(TokenKind::Keyword);
+        }
+        if language.constants().contains(&word.as_str()) {
+            return Some(TokenKind::Constant);
+        }
+        if language.types().contains(&word.as_str()) {
+            return Some(TokenKind::Type);
+        }
+        if matches!(language, Language::Shell | Language::Toml | Language::Json) {
+            return None;
+        }
+        if language == Language::Rust && self.at(end) == Some('!') && self.at(end + 1) != Some('=')
+        {
+            return Some(TokenKind::Macro);
+        }
+        if word.chars().count() > 1
+            && word
+                .chars()
+                .all(|c| c.is_uppercase() || c.is_ascii_digit() || c == '_')
+        {
+            return Some(TokenKind::Constant);
+        }
+        if language == Language::Rust && word.starts_with(char::is_uppercase) {
+            return Some(TokenKind::Type);
+        }
+        if self.at(self.skip_blanks(end)) == Some('(') {
+            return Some(TokenKind::Function);
+        }
+        None
+    }
+
+    /// The length of the opening of a string starting at `i` and how it ends.
+    fn string_start(&self, i: usize) -> Option<(usize, Quote)> {
+        let quote = |close, raw| Quote {
+            close,
+            triple: false,
+            raw,
+            hashes: 0,
+        };
+        // A prefix like Rust's `b` or Python's `f` is only one at the start of a word
+        if i > 0 && is_word_char(self.chars[i - 1]) {
+            return None;
+        }
+        match self.language {
+            Language::Rust => {
+                let mut j = i;
+                if matches!(self.at(j), Some('b' | 'c')) {
+                    j += 1;
+                }
+                let raw = self.at(j) == Some('r');
+                if raw {
+                    j += 1;
+                }
+                let mut hashes = 0;
+                while raw && self.at(j) == Some('#') {
+                    hashes += 1;
+                    j += 1;
+                }
+                match self.at(j) {
+                    Some('"') => Some((
+                        j + 1 - i,
+                        Quote {
+                            hashes,
+                            ..quote('"', raw)
+                        },
+                    )),
+                    // A char literal, like `'a'` or `'\n'`, rather than a lifetime
+                    Some('\'')
+                        if !raw
+                            && (self.at(j + 1) == Some('\\') || self.at(j + 2) == Some('\'')) =>
+                    {
+                        Some((j + 1 - i, quote('\'', false)))
+                    }
+                    _ => None,
+                }
+            }
+            Language::Python | Language::Toml => {
+                let mut j = i;
+                let mut raw = false;
+                if self.language == Language::Python {
+                    while j - i < 2 && self.at(j).is_some_and(|c| "rRbBfFuU".contains(c)) {
+                        raw |= matches!(self.chars[j], 'r' | 'R');
+                        j += 1;
+                    }
+                }
+                let close = self.at(j).filter(|&c| c == '"' || c == '\'')?;
+                // TOML's literal strings are in single quotes
+                raw |= self.language == Language::Toml && close == '\'';
+                let triple = self.at(j + 1) == Some(close) && self.at(j + 2) == Some(close);
+                let opening = if triple { j + 3 - i } else { j + 1 - i };
+                Some((
+                    opening,
+                    Quote {
+                        triple,
+                        ..quote(close, raw)
+                    },
+                ))
+            }
+            Language::C => match self.at(i)? {
+                c @ ('"' | '\'') => Some((1, quote(c, false))),
+                _ => None,
+            },
+            Language::Shell => match self.at(i)? {
+                '"' => Some((1, quote('"', false))),
+                '\'' => Some((1, quote('\'', true))),
+                _ => None,
+            },
+            Language::Json => (self.at(i)? == '"').then(|| (1, quote('"', false))),
+            Language::Markdown => None,
+        }
+    }
+
+    /// Where a string whose text starts at `from` ends, and whether it's closed on this line.
+    fn string_end(&self, from: usize, quote: Quote) -> (usize, bool) {
+        let len = self.chars.len();
+        let quotes = if quote.triple { 3 } else { 1 };
+        let mut i = from;
+        while i < len {
+            let c = self.chars[i];
+            if c == '\\' && !quote.raw {
+                i += 2;
+                continue;
+            }
+            if c == quote.close
+                && (0..quotes).all(|n| self.at(i + n) == Some(quote.close))
+                && (0..usize::from(quote.hashes)).all(|n| self.at(i + quotes + n) == Some('#'))
+            {
+                return (i + quotes + usize::from(quote.hashes), true);
+            }
+            i += 1;
+        }
+        (len, false)
+    }
+
+    /// The state after a line ending inside a string: the next line is still in it
+    /// if strings can run over lines.
+    fn open_string(&self, quote: Quote) -> LineState {
+        let continued = self.chars.last() == Some(&'\\') && !quote.raw;
+        if quote.triple || self.language.multiline_strings() || continued {
+            LineState::String(quote)
+        } else {
+            LineState::Normal
+        }
+    }
+
+    /// Where a block comment whose text starts at `from` ends, and how deeply
+    /// nested it still is at that point.
+    fn block_comment(&self, from: usize, mut depth: u8) -> (usize, u8) {
+        let Some((open, close)) = self.language.block_comment() else {
+            return (from, 0);
+        };
+        let nested = self.language == Language::Rust;
+        let mut i = from;
+        while i < self.chars.len() {
+            if self.starts_with(i, close) {
+                i += close.chars().count();
+                depth -= 1;
+                if depth == 0 {
+                    return (i, 0);
+                }
+            } else if nested && self.starts_with(i, open) {
+                i += open.chars().count();
+                depth = depth.saturating_add(1);
+            } else {
+                i += 1;
+            }
+        }
+        (i, depth)
+    }
+
+    /// End of a Rust attribute starting at `from`, after its closing bracket.
+    fn attribute_end(&self, from: usize) -> usize {
+        let mut depth = 0;
+        for i in from..self.chars.len() {
+            match self.chars[i] {
+                '[' => depth += 1,
+                ']' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return i + 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.chars.len()
+    }
+
+    /// Highlights a line of Markdown.
+    fn markdown(&mut self, state: LineState) -> LineState {
+        let len = self.chars.len();
+        let first = self.skip_blanks(0);
+        let fence = self.at(first).filter(|&c| {
+            (c == '`' || c == '~') && self.starts_with(first, &c.to_string().repeat(3))
+        });
+
+        if let LineState::CodeBlock { fence: open } = state {
+            self.push(0, len, TokenKind::Code);
+            return if fence == Some(open) {
+                LineState::Normal
+            } else {
+                state
+            };
+        }
+        if let Some(fence) = fence {
+            self.push(0, len, TokenKind::Code);
+            return LineState::CodeBlock { fence };
+        }
+
+        match self.at(first) {
+            Some('#') => {
+                let level = self.chars[first..]
+                    .iter()
+                    .take_while(|&&c| c == '#')
+                    .count();
+                if level <= 6 && matches!(self.at(first + level), None | Some(' ')) {
+                    self.push(0, len, TokenKind::Heading);
+                    return LineState::Normal;
+                }
+            }
+            Some('>') => {
+                self.push(0, len, TokenKind::Comment);
+                return LineState::Normal;
+            }
+            _ => {}
+        }
+
+        // A list item's marker, like `-`, `*` or `1.`
+        let mut i = first;
+        let digits = self.chars[first..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        let marker = match self.at(first) {
+            Some('-' | '*' | '+') => 1,
+            _ if digits > 0 && matches!(self.at(first + digits), Some('.' | ')')) => digits + 1,
+            _ => 0,
+        };
+        if marker > 0 && matches!(self.at(first + marker), None | Some(' ')) {
+            self.push(first, first + marker, TokenKind::Keyword);
+            i = first + marker;
+        }
+
+        while i < len {
+            let end = match self.chars[i] {
+                '`' => self.closing_run(i, TokenKind::Code),
+                c @ ('*' | '_') if c == '*' || i == 0 || !is_word_char(self.chars[i - 1]) => {
+                    self.closing_run(i, TokenKind::Emphasis)
+                }
+                '[' => self.link_end(i),
+                _ => None,
+            };
+            match end {
+                Some(end) => i = end,
+                None => i += 1,
+            }
+        }
+        LineState::Normal
+    }
+
+    /// Highlights a code span or emphasis from the run of marks at `from` to the
+    /// same run closing it, returning where it ends. None if the run isn't closed.
+    fn closing_run(&mut self, from: usize, kind: TokenKind) -> Option<usize> {
+        let mark = self.chars[from];
+        let run = self.chars[from..]
+            .iter()
+            .take_while(|&&c| c == mark)
+            .count();
+        let text = from + run;
+        if self.at(text).is_none_or(char::is_whitespace) {
+            return None;
+        }
+        let mut i = text;
+        while i < self.chars.len() {
+            let closing = self.chars[i..].iter().take_while(|&&c| c == mark).count();
+            if closing == run && !self.chars[i - 1].is_whitespace() {
+                self.push(from, i + run, kind);
+                return Some(i + run);
+            }
+            i += closing.max(1);
+        }
+        None
+    }
+
+    /// Highlights a link like `[text](url)` starting at `from`, returning where it ends.
+    fn link_end(&mut self, from: usize) -> Option<usize> {
+        let len = self.chars.len();
+        let close = (from..len).find(|&j| self.chars[j] == ']')?;
+        if self.at(close + 1) != Some('(') {
+            return None;
+        }
+        let end = (close + 1..len).find(|&j| self.chars[j] == ')')? + 1;
+        self.push(from, end, TokenKind::Link);
+        Some(end)
+    }
+}
+
+/// Syntax highlighting of a buffer.
+///
+/// Keeps the state each line starts in, found by highlighting the lines before it,
+/// so a line is highlighted on its own when it's drawn. An edit only forgets the
+/// states after the line it was made on.
+#[derive(Clone, Default)]
+pub struct Highlighter {
+    language: Option<Language>,
+    /// State at the start of each line, for the lines highlighted so far
+    states: Vec<LineState>,
+}
+
+impl Highlighter {
+    /// Finds the states of the lines through `last_line`, after forgetting the ones
+    /// following `changed_line`, which the text changed on.
+    pub fn update(
+        &mut self,
+        language: Option<Language>,
+        text: &HeliosRope,
+        changed_line: Option<usize>,
+        last_line: usize,
+    ) {
+        if language != self.language {
+            self.language = language;
+            self.states.clear();
+        }
+        if let Some(line) = changed_line {
+            self.states.truncate(line + 1);
+        }
+        let Some(language) = self.language else {
+            return;
+        };
+        if self.states.is_empty() {
+            self.states.push(LineState::Normal);
+        }
+        let last_line = last_line.min(text.len_lines().saturating_sub(1));
+        while self.states.len() <= last_line {
+            let line_idx = self.states.len() - 1;
+            let line = text.line(line_idx);
+            let (_, next) =
+                language.highlight_line(line.trim_end_matches(['\n', '\r']), self.states[line_idx]);
+            self.states.push(next);
+        }
+    }
+
+    /// Tokens of a line, which are none until `update` has reached it.
+    pub fn tokens(&self, line: &str, line_idx: usize) -> Vec<Token> {
+        match (self.language, self.states.get(line_idx)) {
+            (Some(language), Some(&state)) => language.highlight_line(line, state).0,
+            _ => Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::ops::Range;
+use std::sync::OnceLock;
+
+use tree_sitter::{InputEdit, Node, Parser, Query, QueryCursor, StreamingIterator, Tree};
+
+use crate::rope::HeliosRope;
+use crate::syntax::{Language, Token, TokenKind};
+
+/// Languages with a tree-sitter grammar compiled in, whose buffers keep a syntax tree.
+const TREE_LANGUAGES: [Language; 5] = [
+    Language::Rust,
+    Language::C,
+    Language::Python,
+    Language::Json,
+    Language::Shell,
+];
+
+/// The tree-sitter grammar of a language and the query its highlighting comes from.
+fn grammar(language: Language) -> Option<(tree_sitter::Language, &'static str)> {
+    match language {
+        Language::Rust => Some((
+            tree_sitter_rust::LANGUAGE.into(),
+            tree_sitter_rust::HIGHLIGHTS_QUERY,
+        )),
+        Language::C => Some((
+            tree_sitter_c::LANGUAGE.into(),
+            tree_sitter_c::HIGHLIGHT_QUERY,
+        )),
+        Language::Python => Some((
+            tree_sitter_python::LANGUAGE.into(),
+            tree_sitter_python::HIGHLIGHTS_QUERY,
+        )),
+        Language::Json => Some((
+            tree_sitter_json::LANGUAGE.into(),
+            tree_sitter_json::HIGHLIGHTS_QUERY,
+        )),
+        Language::Shell => Some((
+            tree_sitter_bash::LANGUAGE.into(),
+            tree_sitter_bash::HIGHLIGHT_QUERY,
+        )),
+        Language::Toml | Language::Markdown => None,
+    }
+}
+
+pub fn has_grammar(language: Language) -> bool {
+    TREE_LANGUAGES.contains(&language)
+}
+
+/// The highlighting query of a language, compiled the first time it's needed.
+fn highlight_query(language: Language) -> Option<&'static Query> {
+    static QUERIES: [OnceLock<Option<Query>>; TREE_LANGUAGES.len()] =
+        [const { OnceLock::new() }; TREE_LANGUAGES.len()];
+    let index = TREE_LANGUAGES.iter().position(|&l| l == language)?;
+    QUERIES[index]
+        .get_or_init(|| {
+            let (grammar, source) = grammar(language)?;
+            Query::new(&grammar, source).ok()
+        })
+        .as_ref()
+}
+
+/// What a capture of a highlighting query is, None for the ones left plain.
+fn capture_kind(name: &str) -> Option<TokenKind> {
+    let kind = match name {
+        "function.macro" => TokenKind::Macro,
+        "variable.builtin" => TokenKind::Keyword,
+        "string.special.key" | "property" => TokenKind::Key,
+        "constructor" => TokenKind::Type,
+        "boolean" => TokenKind::Constant,
+        "number" => TokenKind::Number,
+        "escape" => TokenKind::String,
+        "attribute" => TokenKind::Attribute,
+        "label" => TokenKind::Variable,
+        _ => match name.split('.').next()? {
+            "comment" => TokenKind::Comment,
+            "keyword" => TokenKind::Keyword,
+            "function" => TokenKind::Function,
+            "type" => TokenKind::Type,
+            "constant" => TokenKind::Constant,
+            "string" => TokenKind::String,
+            _ => return None,
+        },
+    };
+    Some(kind)
+}
+
+/// Node kinds `]f`, `[f` and the `f` text object move over.
+fn function_kinds(language: Language) -> &'static [&'static str] {
+    match language {
+        Language::Rust => &["function_item", "function_signature_item"],
+        Language::C | Language::Python | Language::Shell => &["function_definition"],
+        _ => &[],
+    }
+}
+
+/// A live syntax tree of a buffer's text, parsed again after each edit from the
+/// parts the edits touched.
+#[derive(Clone)]
+pub struct SyntaxTree {
+    language: Language,
+    tree: Tree,
+}
+
+impl SyntaxTree {
+    /// Parses `text` from scratch.
+    pub fn new(language: Language, text: &HeliosRope) -> Option<Self> {
+        Self::parse(language, text, None)
+    }
+
+    /// Parses `text` again after `edits` were made to the text this tree was parsed from.
+    pub fn reparse(mut self, text: &HeliosRope, edits: &[InputEdit]) -> Option<Self> {
+        for edit in edits {
+            self.tree.edit(edit);
+        }
+        Self::parse(self.language, text, Some(&self.tree))
+    }
+
+    fn parse(language: Language, text: &HeliosRope, old_tree: Option<&Tree>) -> Option<Self> {
+        let (grammar, _) = grammar(language)?;
+        let mut parser = Parser::new();
+        parser.set_language(&grammar).ok()?;
+        let len = text.inner.len_bytes();
+        let tree = parser.parse_with_options(
+            &mut |byte, _| {
+                if byte >= len {
+                    return &[][..];
+                }
+                let (chunk, chunk_start, _, _) = text.inner.chunk_at_byte(byte);
+                &chunk.as_bytes()[byte - chunk_start..]
+            },
+            old_tree,
+            None,
+        )?;
+        Some(Self { language, tree })
+    }
+
+    pub fn language(&self) -> Language {
+        self.language
+    }
+
+    /// Tokens of line `line_idx`, whose text without its line break is `line`.
+    pub fn tokens(&self, text: &HeliosRope, line_idx: usize, line: &str) -> Vec<Token> {
+        let Some(query) = highlight_query(self.language) else {
+            return Vec::new();
+        };
+        let line_start = text.inner.line_to_byte(line_idx.min(text.len_lines()));
+        let line_end = line_start + line.len();
+        let column = |byte: usize| {
+            let byte = byte.clamp(line_start, line_end) - line_start;
+            line[..byte].chars().count()
+        };
+
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(line_start..line_end);
+        let node_text = |node: Node| {
+            text.inner
+                .byte_slice(node.byte_range())
+                .chunks()
+                .map(str::as_bytes)
+        };
+        let mut captures = cursor.captures(query, self.tree.root_node(), node_text);
+        let mut tokens = Vec::new();
+        while let Some((found, index)) = captures.next() {
+            let capture = found.captures[*index];
+            let Some(kind) = capture_kind(query.capture_names()[capture.index as usize]) else {
+                continue;
+            };
+            let range = capture.node.byte_range();
+            let (start, end) = (column(range.start), column(range.end));
+            if start < end {
+                tokens.push(Token { start, end, kind });
+            }
+        }
+        // Enclosing tokens come first so the ones inside them are drawn over them,
+        // and of two for the same text the query's earlier pattern wins
+        tokens.sort_by_key(|token| (token.start, std::cmp::Reverse(token.end)));
+        tokens.dedup_by(|later, earlier| later.start == earlier.start && later.end == earlier.end);
+        tokens
+    }
+
+    /// The smallest named node covering the bytes `range`.
+    fn node_covering(&self, range: Range<usize>) -> Option<Node<'_>> {
+        self.tree
+            .root_node()
+            .named_descendant_for_byte_range(range.start, range.end)
+    }
+
+    /// Where the next function starts after `char_idx`, or the previous one before it.
+    pub fn function_start(
+        &self,
+        text: &HeliosRope,
+        char_idx: usize,
+        backward: bool,
+    ) -> Option<usize> {
+        let byte = text.inner.char_to_byte(char_idx);
+        let kinds = function_kinds(self.language);
+        let mut found = None;
+        let mut cursor = self.tree.walk();
+        // Walks the tree in order, skipping the subtrees that end before the cursor
+        // when looking forward
+        'walk: loop {
+            let node = cursor.node();
+            let start = node.start_byte();
+            if kinds.contains(&node.kind()) {
+                if backward && start < byte {
+                    found = Some(start);
+                } else if !backward && start > byte {
+                    found = Some(start);
+                    break;
+                }
+            }
+            let skip = (!backward && node.end_byte() <= byte) || (backward && start >= byte);
+            if !skip && cursor.goto_first_child() {
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    break 'walk;
+                }
+            }
+        }
+        found.map(|byte| text.inner.byte_to_char(byte))
+    }
+
+    /// Where the node enclosing the one at `char_idx` starts, the nearest one starting
+    /// before the cursor, so going there again keeps going up the tree.
+    pub fn parent_start(&self, text: &HeliosRope, char_idx: usize) -> Option<usize> {
+        let byte = text.inner.char_to_byte(char_idx);
+        let mut node = self.node_covering(byte..byte)?;
+        while node.start_byte() >= byte {
+            node = node.parent()?;
+        }
+        Some(text.inner.byte_to_char(node.start_byte()))
+    }
+
+    /// Chars of the text a node covers, or of its named children when `inner`, like
+    /// the statements of a block without its braces.
+    fn node_chars(text: &HeliosRope, node: Node, inner: bool) -> Range<usize> {
+        let count = node.named_child_count();
+        let bytes = match (
+            inner,
+            node.named_child(0),
+            count.checked_sub(1).and_then(|last| node.named_child(last)),
+        ) {
+            (true, Some(first), Some(last)) => first.start_byte()..last.end_byte(),
+            _ => node.byte_range(),
+        };
+        text.inner.byte_to_char(bytes.start)..text.inner.byte_to_char(bytes.end)
+    }
+
+    /// The chars of the smallest syntax node covering `range`, or of the next one
+    /// up when `grow` and the smallest is `range` itself.
+    pub fn node_range(
+        &self,
+        text: &HeliosRope,
+        range: Range<usize>,
+        inner: bool,
+        grow: bool,
+    ) -> Option<Range<usize>> {
+        let bytes = text.inner.char_to_byte(range.start)..text.inner.char_to_byte(range.end);
+        let mut node = self.node_covering(bytes)?;
+        loop {
+            let chars = Self::node_chars(text, node, inner);
+            if chars.start <= range.start && chars.end >= range.end && !(grow && chars == range) {
+                return Some(chars);
+            }
+            node = node.parent()?;
+        }
+    }
+
+    /// The chars of the function around `range`, or of its body when `inner`. With
+    /// `grow`, a function already covering exactly `range` gives the one around it.
+    pub fn function_range(
+        &self,
+        text: &HeliosRope,
+        range: Range<usize>,
+        inner: bool,
+        grow: bool,
+    ) -> Option<Range<usize>> {
+        let kinds = function_kinds(self.language);
+        let bytes = text.inner.char_to_byte(range.start)..text.inner.char_to_byte(range.end);
+        let mut node = self
+            .tree
+            .root_node()
+            .descendant_for_byte_range(bytes.start, bytes.end)?;
+        loop {
+            if kinds.contains(&node.kind()) {
+                let chars = match node.child_by_field_name("body").filter(|_| inner) {
+                    Some(body) => Self::node_chars(text, body, true),
+                    None => Self::node_chars(text, node, false),
+                };
+                if chars.start <= range.start && chars.end >= range.end && !(grow && chars == range)
+                {
+                    return Some(chars);
+                }
+            }
+            node = node.parent()?;
+        }
+    }
+
+    /// Lines a node spans, not counting a line it ends at the very start of.
+    fn node_lines(node: Node) -> (usize, usize) {
+        let (start, end) = (node.start_position(), node.end_position());
+        let last = if end.column == 0 && end.row > start.row {
+            end.row - 1
+        } else {
+            end.row
+        };
+        (start.row, last)
+    }
+
+    /// Folds around line `line_idx`, as first and last lines, innermost first. A fold
+    /// is any syntax node over several lines, below the whole file.
+    pub fn folds_at(&self, text: &HeliosRope, line_idx: usize) -> Vec<(usize, usize)> {
+        let line = text.line(line_idx);
+        let indent = line.len() - line.trim_start().len();
+        let byte = text.inner.line_to_byte(line_idx.min(text.len_lines())) + indent;
+        let Some(mut node) = self.tree.root_node().descendant_for_byte_range(byte, byte) else {
+            return Vec::new();
+        };
+        let mut folds: Vec<(usize, usize)> = Vec::new();
+        while let Some(parent) = node.parent() {
+            let (first, last) = Self::node_lines(node);
+            if first < last
+                && first <= line_idx
+                && line_idx <= last
+                && !folds.contains(&(first, last))
+            {
+                folds.push((first, last));
+            }
+            node = parent;
+        }
+        folds
+    }
+
+    /// Every fold of the file, outermost first.
+    pub fn folds(&self) -> Vec<(usize, usize)> {
+        let mut folds = Vec::new();
+        let mut cursor = self.tree.walk();
+        if !cursor.goto_first_child() {
+            return folds;
+        }
+        'walk: loop {
+            let node = cursor.node();
+            let (first, last) = Self::node_lines(node);
+            let multiline = first < last;
+            if multiline && node.is_named() {
+                folds.push((first, last));
+            }
+            if multiline && cursor.goto_first_child() {
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() || cursor.depth() == 0 {
+                    break 'walk;
+                }
+            }
+        }
+        folds.sort_by_key(|&(first, last)| (first, std::cmp::Reverse(last)));
+        folds.dedup();
+        folds
+    }
+}
@@ -0,0 +1,741 @@
+use std::ops::Range;
+
+use crate::buffer::HBuffer;
+use crate::rope::HeliosRope;
+
+/// The kinds of text an `i`/`a` text object can cover.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TextObjectKind {
+    /// `w`: a run of word chars, a run of punctuation, or a run of whitespace
+    Word,
+    /// `W`: a run of non-whitespace chars
+    BigWord,
+    /// `s`: text up to and including a `.`, `!` or `?`
+    Sentence,
+    /// `p`: a block of non-blank (or blank) lines
+    Paragraph,
+    /// `"`, `'` and `` ` ``: a quoted string on the current line
+    Quote(char),
+    /// `(`, `[`, `{` and `<`: a bracketed block, possibly over several lines
+    Bracket(char, char),
+    /// `t`: an XML/HTML element
+    Tag,
+    /// `n`: a node of the syntax tree, its named children for `in`
+    SyntaxNode,
+    /// `f`: a function of the syntax tree, its body for `if`
+    Function,
+}
+
+/// A text object such as `iw` or `a(`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TextObject {
+    pub kind: TextObjectKind,
+    /// `a` objects include surrounding whitespace or delimiters, `i` objects don't.
+    pub around: bool,
+}
+
+impl TextObject {
+    /// Builds a text object from its `i`/`a` prefix and object key.
+    pub fn from_chars(prefix: char, c: char) -> Option<Self> {
+        let around = match prefix {
+            'i' => false,
+            'a' => true,
+            _ => return None,
+        };
+        let kind = match c {
+            'w' => TextObjectKind::Word,
+            'W' => TextObjectKind::BigWord,
+            's' => TextObjectKind::Sentence,
+            'p' => TextObjectKind::Paragraph,
+            '"' | '\'' | '`' => TextObjectKind::Quote(c),
+            '(' | ')' | 'b' => TextObjectKind::Bracket('(', ')'),
+            '[' | ']' => TextObjectKind::Bracket('[', ']'),
+            '{' | '}' | 'B' => TextObjectKind::Bracket('{', '}'),
+            '<' | '>' => TextObjectKind::Bracket('<', '>'),
+            't' => TextObjectKind::Tag,
+            'n' => TextObjectKind::SyntaxNode,
+            'f' => TextObjectKind::Function,
+            _ => return None,
+        };
+        Some(Self { kind, around })
+    }
+
+    /// Paragraphs are always operated on as whole lines.
+    pub fn is_linewise(self) -> bool {
+        self.kind == TextObjectKind::Paragraph
+    }
+
+    /// Enclosing objects grow outwards to the next enclosing pair, the rest grow forwards.
+    fn is_enclosing(self) -> bool {
+        matches!(
+            self.kind,
+            TextObjectKind::Quote(_)
+                | TextObjectKind::Bracket(..)
+                | TextObjectKind::Tag
+                | TextObjectKind::SyntaxNode
+                | TextObjectKind::Function
+        )
+    }
+
+    /// Finds the object around `char_idx`, grown `count - 1` more times.
+    pub fn range(self, buffer: &HBuffer, char_idx: usize, count: usize) -> Option<Range<usize>> {
+        let mut range = self.find(buffer, char_idx)?;
+        for _ in 1..count {
+            match self.grow(buffer, range.clone()) {
+                Some(grown) => range = grown,
+                None => break,
+            }
+        }
+        Some(range)
+    }
+
+    /// Returns a range covering `range` and the next object after (or around) it.
+    ///
+    /// Syntax objects grow to the next node or function up the syntax tree.
+    pub fn grow(self, buffer: &HBuffer, range: Range<usize>) -> Option<Range<usize>> {
+        let text = &buffer.text;
+        match self.kind {
+            TextObjectKind::SyntaxNode => {
+                return buffer
+                    .syntax_tree
+                    .as_ref()?
+                    .node_range(text, range, !self.around, true);
+            }
+            TextObjectKind::Function => {
+                return buffer.syntax_tree.as_ref()?.function_range(
+                    text,
+                    range,
+                    !self.around,
+                    true,
+                );
+            }
+            _ => {}
+        }
+        if !self.is_enclosing() {
+            let next = self.find(buffer, range.end)?;
+            return Some(range.start.min(next.start)..range.end.max(next.end));
+        }
+
+        let mut idx = range.start.checked_sub(1)?;
+        loop {
+            let outer = self.find(buffer, idx)?;
+            if outer.start <= range.start && outer.end >= range.end && outer != range {
+                return Some(outer);
+            }
+            idx = idx.min(outer.start).checked_sub(1)?;
+        }
+    }
+
+    /// Finds the object around `char_idx`.
+    pub fn find(self, buffer: &HBuffer, char_idx: usize) -> Option<Range<usize>> {
+        let text = &buffer.text;
+        if char_idx >= text.len_chars() {
+            return None;
+        }
+        match self.kind {
+            TextObjectKind::Word => word(text, char_idx, self.around, word_class),
+            TextObjectKind::BigWord => word(text, char_idx, self.around, big_word_class),
+            TextObjectKind::Sentence => sentence(text, char_idx, self.around),
+            TextObjectKind::Paragraph => paragraph(text, char_idx, self.around),
+            TextObjectKind::Quote(quote) => quoted(text, char_idx, quote, self.around),
+            TextObjectKind::Bracket(open, close) => {
+                bracketed(text, char_idx, open, close, self.around)
+            }
+            TextObjectKind::Tag => tag(text, char_idx, self.around),
+            TextObjectKind::SyntaxNode => buffer.syntax_tree.as_ref()?.node_range(
+                text,
+                char_idx..char_idx + 1,
+                !self.around,
+                false,
+            ),
+            TextObjectKind::Function => buffer.syntax_tree.as_ref()?.function_range(
+                text,
+                char_idx..char_idx + 1,
+                !self.around,
+                false,
+            ),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum CharClass {
+    LineBreak,
+    Whitespace,
+    Word,
+    Punctuation,
+}
+
+fn word_class(c: char) -> CharClass {
+    if c == '\n' {
+        CharClass::LineBreak
+    } else if c.is_whitespace() {
+        CharClass::Whitespace
+    } else if c.is_alphanumeric() || c == '_' {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+fn big_word_class(c: char) -> CharClass {
+    match word_class(c) {
+        CharClass::Punctuation => CharClass::Word,
+        class => class,
+    }
+}
+
+/// Expands `char_idx` to the run of chars sharing its class, within one line.
+fn run_of_class(
+    text: &HeliosRope,
+    char_idx: usize,
+    class_of: fn(char) -> CharClass,
+) -> Range<usize> {
+    let class = text.char_at(char_idx).map(class_of);
+    let mut start = char_idx;
+    while start > 0 && text.char_at(start - 1).map(class_of) == class {
+        start -= 1;
+    }
+    let mut end = char_idx;
+    while text.char_at(end).map(class_of) == class {
+        end += 1;
+    }
+    start..end
+}
+
+fn word(
+    text: &HeliosRope,
+    char_idx: usize,
+    around: bool,
+    class_of: fn(char) -> CharClass,
+) -> Option<Range<usize>> {
+    let class = class_of(text.char_at(char_idx)?);
+    if class == CharClass::LineBreak {
+        return None;
+    }
+
+    let run = run_of_class(text, char_idx, class_of);
+    if !around {
+        return Some(run);
+    }
+
+    if class == CharClass::Whitespace {
+        // Whitespace plus the word after it
+        return match text.char_at(run.end).map(class_of) {
+            Some(CharClass::Word | CharClass::Punctuation) => {
+                Some(run.start..run_of_class(text, run.end, class_of).end)
+            }
+            _ => Some(run),
+        };
+    }
+
+    // The word plus the whitespace after it, or before it when there is none after
+    if text.char_at(run.end).map(class_of) == Some(CharClass::Whitespace) {
+        return Some(run.start..run_of_class(text, run.end, class_of).end);
+    }
+    if run.start > 0 && text.char_at(run.start - 1).map(class_of) == Some(CharClass::Whitespace) {
+        return Some(run_of_class(text, run.start - 1, class_of).start..run.end);
+    }
+    Some(run)
+}
+
+fn is_sentence_end(c: char) -> bool {
+    matches!(c, '.' | '!' | '?')
+}
+
+/// Closing punctuation allowed between a sentence end and the whitespace after it.
+fn is_sentence_closer(c: char) -> bool {
+    matches!(c, ')' | ']' | '"' | '\'')
+}
+
+fn is_blank_line(text: &HeliosRope, line_idx: usize) -> bool {
+    text.line(line_idx).trim().is_empty()
+}
+
+fn sentence(text: &HeliosRope, char_idx: usize, around: bool) -> Option<Range<usize>> {
+    let len = text.len_chars();
+    if is_blank_line(text, text.char_to_line(char_idx)) {
+        return None;
+    }
+
+    // Whitespace between sentences belongs to the sentence after it
+    let mut idx = char_idx;
+    while text.char_at(idx).is_some_and(|c| c.is_whitespace()) {
+        idx += 1;
+    }
+    if idx >= len {
+        return None;
+    }
+
+    // Walk back to the first char after the previous sentence end or blank line
+    let mut start = idx;
+    while start > 0 {
+        if !text.char_at(start - 1)?.is_whitespace() {
+            start -= 1;
+            continue;
+        }
+
+        let mut run_start = start - 1;
+        while run_start > 0 && text.char_at(run_start - 1)?.is_whitespace() {
+            run_start -= 1;
+        }
+        let line_breaks = text.slice_to_string(run_start..start).matches('\n').count();
+
+        let mut before = run_start;
+        while before > 0 && is_sentence_closer(text.char_at(before - 1)?) {
+            before -= 1;
+        }
+        let after_sentence = before > 0 && is_sentence_end(text.char_at(before - 1)?);
+
+        if run_start == 0 || line_breaks >= 2 || after_sentence {
+            break;
+        }
+        start = run_start;
+    }
+
+    // Walk forward to the sentence end or the next blank line
+    let mut end = idx;
+    while end < len {
+        let c = text.char_at(end)?;
+        if is_sentence_end(c) {
+            let mut after = end + 1;
+            while text.char_at(after).is_some_and(is_sentence_closer) {
+                after += 1;
+            }
+            if text.char_at(after).is_none_or(|c| c.is_whitespace()) {
+                end = after;
+                break;
+            }
+        }
+        if c == '\n' && text.char_at(end + 1).is_none_or(|c| c == '\n') {
+            break;
+        }
+        end += 1;
+    }
+
+    if around {
+        while text.char_at(end).is_some_and(|c| c == ' ' || c == '\t') {
+            end += 1;
+        }
+    }
+    Some(start..end)
+}
+
+fn paragraph(text: &HeliosRope, char_idx: usize, around: bool) -> Option<Range<usize>> {
+    let line_count = text.len_lines();
+    let line = text.char_to_line(char_idx);
+    let blank = is_blank_line(text, line);
+
+    let mut first = line;
+    while first > 0 && is_blank_line(text, first - 1) == blank {
+        first -= 1;
+    }
+    let mut last = line;
+    while last + 1 < line_count && is_blank_line(text, last + 1) == blank {
+        last += 1;
+    }
+
+    if around {
+        if last + 1 < line_count {
+            last += 1;
+            while last + 1 < line_count && is_blank_line(text, last + 1) != blank {
+                last += 1;
+            }
+        } else {
+            // Nothing follows, so take the lines before it instead
+            while first > 0 && is_blank_line(text, first - 1) != blank {
+                first -= 1;
+            }
+        }
+    }
+
+    Some(text.line_to_char(first)..text.line_to_char(last + 1))
+}
+
+fn quoted(text: &HeliosRope, char_idx: usize, quote: char, around: bool) -> Option<Range<usize>> {
+    let line_idx = text.char_to_line(char_idx);
+    let line_start = text.line_to_char(line_idx);
+    let col = char_idx - line_start;
+    let chars: Vec<char> = text.line(line_idx).chars().collect();
+
+    let quotes: Vec<usize> = (0..chars.len())
+        .filter(|&i| chars[i] == quote && (i == 0 || chars[i - 1] != '\\'))
+        .collect();
+
+    let (open, close) = quotes
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, close)| col <= close)?;
+
+    if !around {
+        return Some(line_start + open + 1..line_start + close);
+    }
+
+    let mut start = open;
+    let mut end = close + 1;
+    if chars.get(end).is_some_and(|c| *c == ' ' || *c == '\t') {
+        while chars.get(end).is_some_and(|c| *c == ' ' || *c == '\t') {
+            end += 1;
+        }
+    } else {
+        while start > 0 && (chars[start - 1] == ' ' || chars[start - 1] == '\t') {
+            start -= 1;
+        }
+    }
+    Some(line_start + start..line_start + end)
+}
+
+fn bracketed(
+    text: &HeliosRope,
+    char_idx: usize,
+    open: char,
+    close: char,
+    around: bool,
+) -> Option<Range<usize>> {
+    let open_idx = if text.char_at(char_idx) == Some(open) {
+        char_idx
+    } else {
+        let mut depth = 0;
+        let mut idx = char_idx;
+        loop {
+            idx = idx.checked_sub(1)?;
+            match text.char_at(idx) {
+                Some(c) if c == close => depth += 1,
+                Some(c) if c == open => {
+                    if depth == 0 {
+                        break idx;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+    };
+
+    let mut depth = 0;
+    let mut close_idx = open_idx + 1;
+    loop {
+        match text.char_at(close_idx)? {
+            c if c == open => depth += 1,
+            c if c == close => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+        close_idx += 1;
+    }
+
+    if around {
+        return Some(open_idx..close_idx + 1);
+    }
+
+    let mut start = open_idx + 1;
+    let mut end = close_idx;
+    // A block spanning lines keeps its brackets on their own lines
+    if text.char_at(start) == Some('\n') {
+        start += 1;
+        let close_line = text.char_to_line(close_idx);
+        let close_line_start = text.line_to_char(close_line);
+        if close_line_start >= start
+            && text
+                .slice_to_string(close_line_start..close_idx)
+                .trim()
+                .is_empty()
+        {
+            end = close_line_start;
+        }
+    }
+    Some(start..end.max(start))
+}
+
+/// An opening or closing XML/HTML tag, `start..end` covering `<` through `>`.
+struct Tag {
+    start: usize,
+    end: usize,
+    name: String,
+    closing: bool,
+    self_closing: bool,
+}
+
+fn parse_tag(text: &HeliosRope, start: usize) -> Option<Tag> {
+    if text.char_at(start) != Some('<') {
+        return None;
+    }
+    let mut idx = start + 1;
+    let closing = text.char_at(idx) == Some('/');
+    if closing {
+        idx += 1;
+    }
+
+    let mut name = String::new();
+    while let Some(c) = text.char_at(idx) {
+        if c.is_alphanumeric() || matches!(c, '-' | '_' | ':' | '.') {
+            name.push(c);
+            idx += 1;
+        } else {
+            break;
+        }
+    }
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut prev = ' ';
+    loop {
+        let c = text.char_at(idx)?;
+        idx += 1;
+        if c == '>' {
+            break;
+        }
+        if c == '<' {
+            return None;
+        }
+        prev = c;
+    }
+
+    Some(Tag {
+        start,
+        end: idx,
+        name,
+        closing,
+        self_closing: prev == '/',
+    })
+}
+
+/// Finds the closing tag matching `open`, skipping nested elements of the same name.
+fn matching_close_tag(text: &HeliosRope, open: &Tag) -> Option<Tag> {
+    let mut depth = 0;
+    let mut idx = open.end;
+    while idx < text.len_chars() {
+        if let Some(tag) = parse_tag(text, idx)
+            && tag.name == open.name
+            && !tag.self_closing
+        {
+            if !tag.closing {
+                depth += 1;
+            } else if depth == 0 {
+                return Some(tag);
+            } else {
+                depth -= 1;
+            }
+            idx = tag.end;
+            continue;
+        }
+        idx += 1;
+    }
+    None
+}
+
+fn tag(text: &HeliosRope, char_idx: usize, around: bool) -> Option<Range<usize>> {
+    let mut idx = char_idx + 1;
+    while idx > 0 {
+        idx -= 1;
+        let Some(open) = parse_tag(text, idx) else {
+            continue;
+        };
+        if open.closing || open.self_closing {
+            continue;
+        }
+        let Some(close) = matching_close_tag(text, &open) else {
+            continue;
+        };
+        if close.end > char_idx {
+            return if around {
+                Some(open.start..close.end)
+            } else {
+                Some(open.end..close.start)
+            };
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Selects `object` (like `"iw"`) around the `|` in `text`, grown to `count`,
+    /// and marks the selection with `«` and `»`.
+    fn select(text: &str, object: &str, count: usize) -> Option<String> {
+        let cursor = text.chars().position(|c| c == '|').expect("cursor");
+        let text = text.replacen('|', "", 1);
+        let mut buffer = HBuffer::new();
+        buffer.text = HeliosRope::from_str(&text);
+
+        let mut keys = object.chars();
+        let object = TextObject::from_chars(keys.next()?, keys.next()?).expect("object");
+        let range = object.range(&buffer, cursor, count)?;
+        let chars: Vec<char> = text.chars().collect();
+        Some(format!(
+            "{}«{}»{}",
+            String::from_iter(&chars[..range.start]),
+            String::from_iter(&chars[range.clone()]),
+            String::from_iter(&chars[range.end..]),
+        ))
+    }
+
+    fn check(cases: &[(&str, &str, usize, Option<&str>)]) {
+        for (text, object, count, expected) in cases {
+            assert_eq!(
+                select(text, object, *count).as_deref(),
+                *expected,
+                "{} {} in {:?}",
+                count,
+                object,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn words() {
+        check(&[
+            ("foo |bar baz", "iw", 1, Some("foo «bar» baz")),
+            ("foo |bar baz", "aw", 1, Some("foo «bar »baz")),
+            // No whitespace after the word, so the whitespace before it
+            ("foo |bar\nbaz", "aw", 1, Some("foo« bar»\nbaz")),
+            ("foo b|ar", "aw", 1, Some("foo« bar»")),
+            ("|foo", "aw", 1, Some("«foo»")),
+            ("foo|  bar", "iw", 1, Some("foo«  »bar")),
+            ("foo|  bar", "aw", 1, Some("foo«  bar»")),
+            ("a.|.b", "iw", 1, Some("a«..»b")),
+            ("x a.|b c", "iW", 1, Some("x «a.b» c")),
+            ("x a.|b c", "aW", 1, Some("x «a.b »c")),
+            ("foo|\nbar", "iw", 1, None),
+            ("|a b c", "iw", 2, Some("«a »b c")),
+            ("|a b c", "aw", 2, Some("«a b »c")),
+            // Counts stop growing at the end of the text
+            ("a |b", "aw", 3, Some("a« b»")),
+        ]);
+    }
+
+    #[test]
+    fn sentences() {
+        check(&[
+            (
+                "One. Tw|o three. Four.",
+                "is",
+                1,
+                Some("One. «Two three.» Four."),
+            ),
+            (
+                "One. Tw|o three. Four.",
+                "as",
+                1,
+                Some("One. «Two three. »Four."),
+            ),
+            ("|One (two). Three", "is", 1, Some("«One (two).» Three")),
+            ("One.\n\nT|wo\nthree", "is", 1, Some("One.\n\n«Two\nthree»")),
+            ("One.\n|\nTwo", "is", 1, None),
+        ]);
+    }
+
+    #[test]
+    fn paragraphs() {
+        check(&[
+            ("a\nb|\n\nc\n", "ip", 1, Some("«a\nb\n»\nc\n")),
+            ("a\nb|\n\nc\n", "ap", 1, Some("«a\nb\n\n»c\n")),
+            // On blank lines, the blank lines are the paragraph
+            ("a\n\n|\n\nb\n", "ip", 1, Some("a\n«\n\n\n»b\n")),
+            ("a\n\n|\n\nb\n", "ap", 1, Some("a\n«\n\n\nb\n»")),
+            // Nothing after the last paragraph, so the blank lines before it
+            ("a\n\n|b", "ap", 1, Some("a\n«\nb»")),
+            ("|a\n\nb\n\nc", "ap", 2, Some("«a\n\nb\n\n»c")),
+        ]);
+    }
+
+    #[test]
+    fn quotes() {
+        check(&[
+            ("say \"h|i\" now", "i\"", 1, Some("say \"«hi»\" now")),
+            ("say \"h|i\" now", "a\"", 1, Some("say «\"hi\" »now")),
+            // On either quote itself
+            ("say |\"hi\" now", "i\"", 1, Some("say \"«hi»\" now")),
+            ("say \"hi|\" now", "i\"", 1, Some("say \"«hi»\" now")),
+            // Before the first quote, the first string
+            ("|x 'a' 'b'", "i'", 1, Some("x '«a»' 'b'")),
+            ("x 'a' 'b|'", "i'", 1, Some("x 'a' '«b»'")),
+            ("\"a\\\"|b\"", "i\"", 1, Some("\"«a\\\"b»\"")),
+            ("x \"a|\"", "a\"", 1, Some("x« \"a\"»")),
+            ("\"\"|", "i\"", 1, None),
+            ("\"a|", "i\"", 1, None),
+            // Only on the cursor line
+            ("\"a\n|b\"", "i\"", 1, None),
+        ]);
+    }
+
+    #[test]
+    fn brackets() {
+        check(&[
+            ("f(a, |b)", "i(", 1, Some("f(«a, b»)")),
+            ("f(a, |b)", "a)", 1, Some("f«(a, b)»")),
+            ("f|(a)", "ib", 1, Some("f(«a»)")),
+            ("f(a|)", "ib", 1, Some("f(«a»)")),
+            ("((a)|)", "i(", 1, Some("(«(a)»)")),
+            ("(|)", "i(", 1, Some("(«»)")),
+            ("[a [b|] c]", "i[", 2, Some("[«a [b] c»]")),
+            // Counts past the outermost pair stop at it
+            ("[a [b|] c]", "a[", 5, Some("«[a [b] c]»")),
+            ("(a|", "i(", 1, None),
+            ("a|)", "i(", 1, None),
+            ("(a) |b)", "i(", 1, None),
+            ("(\na|\n", "i(", 1, None),
+            ("x = <|y>", "i<", 1, Some("x = <«y»>")),
+        ]);
+
+        let text = "fn f() {\n    if x {\n        |y\n    }\n}\n";
+        check(&[
+            (
+                text,
+                "i{",
+                1,
+                Some("fn f() {\n    if x {\n«        y\n»    }\n}\n"),
+            ),
+            (
+                text,
+                "a{",
+                1,
+                Some("fn f() {\n    if x «{\n        y\n    }»\n}\n"),
+            ),
+            (
+                text,
+                "iB",
+                2,
+                Some("fn f() {\n«    if x {\n        y\n    }\n»}\n"),
+            ),
+            (
+                text,
+                "iB",
+                3,
+                Some("fn f() {\n«    if x {\n        y\n    }\n»}\n"),
+            ),
+            (
+                text,
+                "a}",
+                2,
+                Some("fn f() «{\n    if x {\n        y\n    }\n}»\n"),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn tags() {
+        check(&[
+            ("<a><b>|x</b></a>", "it", 1, Some("<a><b>«x»</b></a>")),
+            ("<a><b>|x</b></a>", "at", 1, Some("<a>«<b>x</b>»</a>")),
+            ("<a><b>|x</b></a>", "it", 2, Some("<a>«<b>x</b>»</a>")),
+            ("<a><b>|x</b></a>", "at", 2, Some("«<a><b>x</b></a>»")),
+            ("<d><d>x</d>|y</d>", "it", 1, Some("<d>«<d>x</d>y»</d>")),
+            (
+                "<a href=\"x\"><br/>|x</a>",
+                "it",
+                1,
+                Some("<a href=\"x\">«<br/>x»</a>"),
+            ),
+            ("<a>|x", "it", 1, None),
+        ]);
+    }
+}
@@ -0,0 +1,854 @@
+use ropey::Rope;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use crate::rope::{self, HeliosRope};
+
+/// Most changes kept per buffer; the oldest are forgotten first.
+const MAX_UNDO_LEVELS: usize = 1000;
+/// Most bytes of inserted and removed text kept per buffer.
+const MAX_UNDO_BYTES: usize = 10 * 1024 * 1024;
+/// Most bytes of text written to an undo file; older states are left out first.
+const MAX_UNDO_FILE_BYTES: usize = 2 * 1024 * 1024;
+
+/// Starts every undo file, with the version of its layout.
+const UNDO_FILE_MAGIC: &[u8] = b"HLUNDO1\n";
+/// Stands for a missing parent or redo child in an undo file.
+const NO_STATE: u64 = u64::MAX;
+
+/// One edit of a rope, keeping the text it inserted or removed so it can be reverted.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Edit {
+    /// Char index the edit starts at
+    pub at: usize,
+    /// Whether `text` was inserted at `at`, or removed from there
+    pub inserted: bool,
+    pub text: String,
+    /// Length of `text` in chars
+    len: usize,
+}
+
+impl Edit {
+    pub fn insert(at: usize, text: String) -> Self {
+        let len = text.chars().count();
+        Self {
+            at,
+            inserted: true,
+            text,
+            len,
+        }
+    }
+
+    pub fn remove(at: usize, text: String) -> Self {
+        let len = text.chars().count();
+        Self {
+            at,
+            inserted: false,
+            text,
+            len,
+        }
+    }
+
+    /// Folds `next` into this edit when it continues it, like a typed char after
+    /// the ones before it or a backspace before the ones it already removed.
+    pub fn merge(&mut self, next: &Edit) -> bool {
+        match (self.inserted, next.inserted) {
+            (true, true) if next.at == self.at + self.len => {
+                self.text.push_str(&next.text);
+            }
+            // Delete key: each char comes from the same place
+            (false, false) if next.at == self.at => {
+                self.text.push_str(&next.text);
+            }
+            // Backspace: each char comes from before the last one
+            (false, false) if next.at + next.len == self.at => {
+                self.text.insert_str(0, &next.text);
+                self.at = next.at;
+            }
+            _ => return false,
+        }
+        self.len += next.len;
+        true
+    }
+
+    pub fn apply(&self, rope: &mut Rope) {
+        if self.inserted {
+            rope.insert(self.at, &self.text);
+        } else {
+            rope.remove(self.at..self.at + self.len);
+        }
+    }
+
+    fn revert(&self, rope: &mut Rope) {
+        if self.inserted {
+            rope.remove(self.at..self.at + self.len);
+        } else {
+            rope.insert(self.at, &self.text);
+        }
+    }
+}
+
+/// How far `:earlier` and `:later` go: a number of changes, or a time span like `5m`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UndoDistance {
+    Steps(usize),
+    Time(Duration),
+}
+
+impl UndoDistance {
+    /// Reads `10`, `30s`, `5m`, `2h` or `1d`.
+    pub fn parse(arg: &str) -> Option<Self> {
+        let unit = match arg.chars().last()? {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            c if c.is_ascii_digit() => {
+                return arg.parse().ok().filter(|n| *n > 0).map(Self::Steps);
+            }
+            _ => return None,
+        };
+        let amount: u64 = arg[..arg.len() - 1].parse().ok()?;
+        Some(Self::Time(Duration::from_secs(amount.checked_mul(unit)?)))
+    }
+}
+
+/// A text state in the undo tree: the edits that lead to it from its parent.
+#[derive(Clone)]
+struct State {
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// Child redo goes to, the one last undone or made
+    redo: Option<usize>,
+    edits: Vec<Edit>,
+    time: SystemTime,
+    /// Revision the text has in this state
+    revision: u64,
+}
+
+/// A branch tip listed by `:undolist`.
+pub struct Leaf {
+    pub seq: usize,
+    /// Changes between the original text and this state
+    pub changes: usize,
+    pub time: SystemTime,
+    pub revision: u64,
+}
+
+/// Every text state of a buffer, in a tree so changes made after an undo start a
+/// new branch instead of dropping the undone ones.
+///
+/// States are numbered in the order they were made, which is the order `g-` and
+/// `g+` walk. The original text is the root; when the tree grows past its limits
+/// the root moves forward and the oldest states are forgotten.
+#[derive(Clone)]
+pub struct UndoTree {
+    states: BTreeMap<usize, State>,
+    root: usize,
+    current: usize,
+    next_seq: usize,
+    /// Bytes of text held by all edits
+    size: usize,
+}
+
+impl Default for UndoTree {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl UndoTree {
+    /// A tree holding only the original text, at `revision`.
+    pub fn new(revision: u64) -> Self {
+        let root = State {
+            parent: None,
+            children: Vec::new(),
+            redo: None,
+            edits: Vec::new(),
+            time: SystemTime::now(),
+            revision,
+        };
+        Self {
+            states: BTreeMap::from([(0, root)]),
+            root: 0,
+            current: 0,
+            next_seq: 1,
+            size: 0,
+        }
+    }
+
+    /// Number of the current state, 0 for the original text.
+    pub fn seq(&self) -> usize {
+        self.current
+    }
+
+    /// True when no change was made or kept.
+    pub fn is_empty(&self) -> bool {
+        self.states.len() == 1
+    }
+
+    pub fn is_at_root(&self) -> bool {
+        self.current == self.root
+    }
+
+    /// When the current state was made.
+    pub fn time(&self) -> SystemTime {
+        self.states[&self.current].time
+    }
+
+    /// Adds a state after the current one, reached by `edits`.
+    pub fn commit(&mut self, edits: Vec<Edit>, time: SystemTime, revision: u64) {
+        if edits.is_empty() {
+            return;
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.size += edits.iter().map(|edit| edit.text.len()).sum::<usize>();
+
+        let parent = self.state_mut(self.current);
+        parent.children.push(seq);
+        parent.redo = Some(seq);
+        self.states.insert(
+            seq,
+            State {
+                parent: Some(self.current),
+                children: Vec::new(),
+                redo: None,
+                edits,
+                time,
+                revision,
+            },
+        );
+        self.current = seq;
+        self.prune();
+    }
+
+    /// Reverts the current state's edits. Returns the char index they started at,
+    /// or None at the original text.
+    pub fn undo(&mut self, text: &mut HeliosRope) -> Option<usize> {
+        let state = &self.states[&self.current];
+        let parent = state.parent?;
+        for edit in state.edits.iter().rev() {
+            edit.revert(&mut text.inner);
+            text.mark_changed(edit.at, &edit.text, !edit.inserted);
+        }
+        let cursor = state.edits.first().map_or(0, |edit| edit.at);
+
+        let undone = self.current;
+        let parent_state = self.state_mut(parent);
+        parent_state.redo = Some(undone);
+        text.set_revision(parent_state.revision);
+        self.current = parent;
+        Some(cursor)
+    }
+
+    /// Applies the edits of the state last undone from here. Returns the char index
+    /// they started at, or None when there's nothing to redo.
+    pub fn redo(&mut self, text: &mut HeliosRope) -> Option<usize> {
+        let child = self.states[&self.current].redo?;
+        let state = &self.states[&child];
+        for edit in &state.edits {
+            edit.apply(&mut text.inner);
+            text.mark_changed(edit.at, &edit.text, edit.inserted);
+        }
+        text.set_revision(state.revision);
+        let cursor = state.edits.first().map_or(0, |edit| edit.at);
+        self.current = child;
+        Some(cursor)
+    }
+
+    /// Undoes and redoes along the tree until `target` is the current state.
+    ///
+    /// Returns where the last edit started, or None if nothing changed.
+    pub fn go_to(&mut self, target: usize, text: &mut HeliosRope) -> Option<usize> {
+        if !self.states.contains_key(&target) || target == self.current {
+            return None;
+        }
+        // `path` runs from the target up to the root
+        let mut path = vec![target];
+        while let Some(parent) = self.states[path.last()?].parent {
+            path.push(parent);
+        }
+
+        let mut cursor = None;
+        while !path.contains(&self.current) {
+            cursor = self.undo(text);
+        }
+        let common = path.iter().position(|seq| *seq == self.current)?;
+        for seq in path[..common].iter().rev() {
+            self.state_mut(self.current).redo = Some(*seq);
+            cursor = self.redo(text);
+        }
+        cursor
+    }
+
+    /// The state `steps` changes before (or after) the current one in the order
+    /// they were made, stopping at the oldest and newest.
+    pub fn step_target(&self, steps: usize, backward: bool) -> usize {
+        let steps = steps.max(1);
+        if backward {
+            let mut older = self.states.range(..self.current).rev().map(|(seq, _)| *seq);
+            older.nth(steps - 1).unwrap_or(self.root)
+        } else {
+            let mut newer = self.states.range(self.current + 1..).map(|(seq, _)| *seq);
+            let last = *self.states.keys().next_back().unwrap_or(&self.current);
+            newer.nth(steps - 1).unwrap_or(last)
+        }
+    }
+
+    /// The newest state made at least `span` before (or at most `span` after) the
+    /// current one.
+    pub fn time_target(&self, span: Duration, backward: bool) -> usize {
+        let now = self.time();
+        if backward {
+            let limit = now.checked_sub(span).unwrap_or(SystemTime::UNIX_EPOCH);
+            self.states
+                .range(..self.current)
+                .rev()
+                .find(|(_, state)| state.time <= limit)
+                .map_or(self.root, |(seq, _)| *seq)
+        } else {
+            let limit = now.checked_add(span).unwrap_or(now);
+            self.states
+                .range(self.current + 1..)
+                .filter(|(_, state)| state.time <= limit)
+                .map(|(seq, _)| *seq)
+                .next_back()
+                .unwrap_or(self.current)
+        }
+    }
+
+    /// The tips of all branches, oldest first.
+    pub fn leaves(&self) -> Vec<Leaf> {
+        self.states
+            .iter()
+            .filter(|(seq, state)| state.children.is_empty() && **seq != self.root)
+            .map(|(seq, state)| Leaf {
+                seq: *seq,
+                changes: self.depth(*seq),
+                time: state.time,
+                revision: state.revision,
+            })
+            .collect()
+    }
+
+    /// Writes the tree out for an undo file, along with the file it belongs to and
+    /// a hash of `text`, which it was saved with. Changes of `text` not committed
+    /// yet are written as a state of their own, so the current state is that text.
+    pub fn encode(&self, text: &HeliosRope, path: &str, content_hash: u64) -> Vec<u8> {
+        let mut tree = self.clone();
+        if let Some((edits, time)) = text.changes() {
+            tree.commit(edits.to_vec(), time, text.revision());
+        }
+        tree.prune_to(MAX_UNDO_LEVELS, MAX_UNDO_FILE_BYTES);
+
+        let mut bytes = UNDO_FILE_MAGIC.to_vec();
+        put_u64(&mut bytes, content_hash);
+        put_str(&mut bytes, path);
+        for value in [tree.root, tree.current, tree.next_seq, tree.states.len()] {
+            put_u64(&mut bytes, value as u64);
+        }
+        for (seq, state) in &tree.states {
+            let time = state
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs());
+            put_u64(&mut bytes, *seq as u64);
+            put_u64(&mut bytes, state.parent.map_or(NO_STATE, |seq| seq as u64));
+            put_u64(&mut bytes, state.redo.map_or(NO_STATE, |seq| seq as u64));
+            put_u64(&mut bytes, time);
+            put_u64(&mut bytes, state.edits.len() as u64);
+            for edit in &state.edits {
+                bytes.push(u8::from(edit.inserted));
+                put_u64(&mut bytes, edit.at as u64);
+                put_str(&mut bytes, &edit.text);
+            }
+        }
+        bytes
+    }
+
+    /// Reads a tree written by [`UndoTree::encode`] for a text of `len_chars` chars,
+    /// giving its current state `revision`. Returns None if the bytes aren't a whole
+    /// undo file, or describe a tree that doesn't fit the text.
+    pub fn decode(bytes: &[u8], len_chars: usize, revision: u64) -> Option<Self> {
+        let mut reader = UndoReader::new(bytes)?;
+        reader.u64()?;
+        reader.string()?;
+        let root = reader.usize()?;
+        let current = reader.usize()?;
+        let next_seq = reader.usize()?;
+        let count = reader.usize()?;
+
+        let mut states = BTreeMap::new();
+        let mut size = 0;
+        for _ in 0..count {
+            let seq = reader.usize()?;
+            let parent = reader.state()?;
+            let redo = reader.state()?;
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(reader.u64()?);
+            let mut edits = Vec::new();
+            for _ in 0..reader.u64()? {
+                let inserted = reader.byte()? != 0;
+                let at = reader.usize()?;
+                let text = reader.string()?;
+                size += text.len();
+                edits.push(if inserted {
+                    Edit::insert(at, text)
+                } else {
+                    Edit::remove(at, text)
+                });
+            }
+            // Revisions only mean something within a session, so the others get new ones
+            let revision = if seq == current {
+                revision
+            } else {
+                rope::next_revision()
+            };
+            states.insert(
+                seq,
+                State {
+                    parent,
+                    children: Vec::new(),
+                    redo,
+                    edits,
+                    time,
+                    revision,
+                },
+            );
+        }
+
+        let links: Vec<(usize, usize)> = states
+            .iter()
+            .filter_map(|(seq, state)| Some((state.parent?, *seq)))
+            .collect();
+        for (parent, child) in links {
+            states.get_mut(&parent)?.children.push(child);
+        }
+        if !states.contains_key(&root) || !states.contains_key(&current) {
+            return None;
+        }
+        let tree = Self {
+            states,
+            root,
+            current,
+            next_seq,
+            size,
+        };
+        tree.fits(len_chars).then_some(tree)
+    }
+
+    /// Checks a decoded tree: every state reaches the root without a cycle, every
+    /// redo goes to a child, and every edit lies within the text it's made on when
+    /// the current state's text has `len_chars` chars.
+    fn fits(&self, len_chars: usize) -> bool {
+        let newest = self.states.keys().next_back();
+        if self.states[&self.root].parent.is_some() || newest >= Some(&self.next_seq) {
+            return false;
+        }
+        for (seq, state) in &self.states {
+            if state
+                .redo
+                .is_some_and(|redo| !state.children.contains(&redo))
+            {
+                return false;
+            }
+            let mut ancestor = *seq;
+            for _ in 0..self.states.len() {
+                match self.states[&ancestor].parent {
+                    Some(parent) => ancestor = parent,
+                    None => break,
+                }
+            }
+            if ancestor != self.root {
+                return false;
+            }
+        }
+
+        // Text length of the root, from the current state back up
+        let mut len = Some(len_chars);
+        let mut seq = self.current;
+        while let Some(parent) = self.states[&seq].parent {
+            for edit in &self.states[&seq].edits {
+                len = len.and_then(|len| match edit.inserted {
+                    true => len.checked_sub(edit.len),
+                    false => len.checked_add(edit.len),
+                });
+            }
+            seq = parent;
+        }
+        let Some(root_len) = len else {
+            return false;
+        };
+
+        // Every edit of every state, applied down from the root
+        let mut pending = vec![(self.root, root_len)];
+        while let Some((seq, len)) = pending.pop() {
+            for child in &self.states[&seq].children {
+                let mut len = Some(len);
+                for edit in &self.states[child].edits {
+                    len = len.and_then(|len| match edit.inserted {
+                        true => (edit.at <= len).then(|| len + edit.len),
+                        false => edit
+                            .at
+                            .checked_add(edit.len)
+                            .filter(|end| *end <= len)
+                            .map(|_| len - edit.len),
+                    });
+                }
+                match len {
+                    Some(len) => pending.push((*child, len)),
+                    None => return false,
+                }
+            }
+        }
+        true
+    }
+
+    fn depth(&self, mut seq: usize) -> usize {
+        let mut depth = 0;
+        while let Some(parent) = self.states[&seq].parent {
+            seq = parent;
+            depth += 1;
+        }
+        depth
+    }
+
+    fn state_mut(&mut self, seq: usize) -> &mut State {
+        self.states.get_mut(&seq).expect("undo state exists")
+    }
+
+    /// Forgets the oldest states while the tree is over its limits. The child of the
+    /// root leading to the current state becomes the new root, and the branches that
+    /// don't lead there go with the old one.
+    fn prune(&mut self) {
+        self.prune_to(MAX_UNDO_LEVELS, MAX_UNDO_BYTES);
+    }
+
+    fn prune_to(&mut self, levels: usize, bytes: usize) {
+        while (self.states.len() > levels + 1 || self.size > bytes) && self.current != self.root {
+            let mut keep = self.current;
+            while let Some(parent) = self.states[&keep].parent
+                && parent != self.root
+            {
+                keep = parent;
+            }
+
+            let root = self.states.remove(&self.root).expect("undo root exists");
+            for child in root.children.into_iter().filter(|child| *child != keep) {
+                self.remove_branch(child);
+            }
+            let new_root = self.state_mut(keep);
+            let freed: usize = new_root.edits.iter().map(|edit| edit.text.len()).sum();
+            new_root.edits = Vec::new();
+            new_root.parent = None;
+            self.size -= freed;
+            self.root = keep;
+        }
+    }
+
+    fn remove_branch(&mut self, seq: usize) {
+        if let Some(state) = self.states.remove(&seq) {
+            self.size -= state
+                .edits
+                .iter()
+                .map(|edit| edit.text.len())
+                .sum::<usize>();
+            for child in state.children {
+                self.remove_branch(child);
+            }
+        }
+    }
+}
+
+/// Reads the file name and text hash an undo file was written with.
+pub fn decode_header(bytes: &[u8]) -> Option<(String, u64)> {
+    let mut reader = UndoReader::new(bytes)?;
+    let content_hash = reader.u64()?;
+    Some((reader.string()?, content_hash))
+}
+
+fn put_u64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_str(bytes: &mut Vec<u8>, text: &str) {
+    put_u64(bytes, text.len() as u64);
+    bytes.extend_from_slice(text.as_bytes());
+}
+
+/// Reads the values of an undo file in the order [`UndoTree::encode`] wrote them.
+struct UndoReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> UndoReader<'a> {
+    fn new(bytes: &'a [u8]) -> Option<Self> {
+        Some(Self {
+            bytes: bytes.strip_prefix(UNDO_FILE_MAGIC)?,
+        })
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.bytes.len() {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        usize::try_from(self.u64()?).ok()
+    }
+
+    fn state(&mut self) -> Option<Option<usize>> {
+        match self.u64()? {
+            NO_STATE => Some(None),
+            seq => Some(Some(usize::try_from(seq).ok()?)),
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+}
+
+/// How long ago `time` was, like `5 seconds ago`.
+pub fn describe_age(time: SystemTime) -> String {
+    let seconds = time.elapsed().map_or(0, |elapsed| elapsed.as_secs());
+    let (amount, unit) = match seconds {
+        0..60 => (seconds, "second"),
+        60..3600 => (seconds / 60, "minute"),
+        3600..86400 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    format!(
+        "{} {}{} ago",
+        amount,
+        unit,
+        if amount == 1 { "" } else { "s" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inserts `inserted` at `at` and commits it as a state made `secs` after `start`.
+    fn change(tree: &mut UndoTree, text: &mut HeliosRope, at: usize, inserted: &str, secs: u64) {
+        let edit = Edit::insert(at, inserted.to_string());
+        edit.apply(&mut text.inner);
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 + secs);
+        tree.commit(vec![edit], time, rope::next_revision());
+    }
+
+    /// A tree with two branches: "a" then "ab", and "a" then "ac" made after
+    /// undoing "ab". The current state is "ac".
+    fn branched() -> (UndoTree, HeliosRope) {
+        let mut tree = UndoTree::new(0);
+        let mut text = HeliosRope::new();
+        change(&mut tree, &mut text, 0, "a", 10);
+        change(&mut tree, &mut text, 1, "b", 20);
+        tree.undo(&mut text);
+        change(&mut tree, &mut text, 1, "c", 30);
+        (tree, text)
+    }
+
+    fn round_trip(tree: &UndoTree, text: &HeliosRope) -> UndoTree {
+        let bytes = tree.encode(text, "/tmp/file.txt", 42);
+        assert_eq!(
+            decode_header(&bytes),
+            Some(("/tmp/file.txt".to_string(), 42))
+        );
+        UndoTree::decode(&bytes, text.len_chars(), 7).unwrap()
+    }
+
+    #[test]
+    fn round_trip_keeps_every_branch() {
+        let (tree, mut text) = branched();
+        let mut tree = round_trip(&tree, &text);
+        assert_eq!(tree.seq(), 3);
+        let leaves: Vec<_> = tree
+            .leaves()
+            .iter()
+            .map(|leaf| (leaf.seq, leaf.changes))
+            .collect();
+        assert_eq!(leaves, [(2, 2), (3, 2)]);
+        assert_eq!(
+            tree.time(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_030)
+        );
+
+        tree.go_to(2, &mut text);
+        assert_eq!(text.to_string(), "ab");
+        tree.go_to(3, &mut text);
+        assert_eq!(text.to_string(), "ac");
+        assert_eq!(text.revision(), 7);
+        tree.undo(&mut text);
+        tree.undo(&mut text);
+        assert_eq!(text.to_string(), "");
+        assert!(tree.is_at_root());
+        assert_eq!(tree.undo(&mut text), None);
+
+        // Redo follows the branch last made
+        tree.redo(&mut text);
+        tree.redo(&mut text);
+        assert_eq!(text.to_string(), "ac");
+    }
+
+    #[test]
+    fn changes_not_committed_yet_are_written_as_a_state() {
+        let (tree, mut text) = branched();
+        text.insert(2, "de");
+        let bytes = tree.encode(&text, "/tmp/file.txt", 42);
+        // Neither the tree nor the text gave them up
+        assert_eq!(tree.seq(), 3);
+        assert!(text.changes().is_some());
+
+        let mut decoded = UndoTree::decode(&bytes, text.len_chars(), 7).unwrap();
+        assert_eq!(decoded.seq(), 4);
+        decoded.undo(&mut text);
+        assert_eq!(text.to_string(), "ac");
+    }
+
+    #[test]
+    fn decode_refuses_broken_files() {
+        let (tree, text) = branched();
+        let bytes = tree.encode(&text, "/tmp/file.txt", 42);
+        assert!(UndoTree::decode(&bytes, text.len_chars(), 0).is_some());
+        for len in 0..bytes.len() {
+            assert!(
+                UndoTree::decode(&bytes[..len], text.len_chars(), 0).is_none(),
+                "cut at {}",
+                len
+            );
+        }
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert!(UndoTree::decode(&wrong_magic, text.len_chars(), 0).is_none());
+        assert!(decode_header(&wrong_magic).is_none());
+        // Fewer chars than the changes to the current state inserted
+        assert!(UndoTree::decode(&bytes, 1, 0).is_none());
+
+        let broken: [(_, fn(&mut UndoTree)); 4] = [
+            ("cycle", |tree| tree.state_mut(1).parent = Some(3)),
+            ("parentless state", |tree| tree.state_mut(2).parent = None),
+            ("missing redo", |tree| tree.state_mut(1).redo = Some(9)),
+            ("offset out of range", |tree| {
+                tree.state_mut(2).edits[0].at = 5
+            }),
+        ];
+        for (name, breaks) in broken {
+            let mut tree = tree.clone();
+            breaks(&mut tree);
+            let bytes = tree.encode(&text, "/tmp/file.txt", 42);
+            assert!(
+                UndoTree::decode(&bytes, text.len_chars(), 0).is_none(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn pruning_moves_the_root_along_the_current_branch() {
+        let (mut tree, mut text) = branched();
+        change(&mut tree, &mut text, 2, "d", 40);
+        change(&mut tree, &mut text, 3, "e", 50);
+
+        // The branch to "ab" goes with the old root
+        tree.prune_to(2, MAX_UNDO_BYTES);
+        assert_eq!(
+            tree.leaves()
+                .iter()
+                .map(|leaf| leaf.seq)
+                .collect::<Vec<_>>(),
+            [5]
+        );
+        assert_eq!(tree.size, 2);
+
+        let mut tree = round_trip(&tree, &text);
+        assert_eq!(tree.undo(&mut text), Some(3));
+        assert_eq!(tree.undo(&mut text), Some(2));
+        assert_eq!(text.to_string(), "ac");
+        assert!(tree.is_at_root());
+        assert_eq!(tree.undo(&mut text), None);
+        assert_eq!(tree.go_to(2, &mut text), None);
+    }
+
+    #[test]
+    fn undo_files_leave_out_the_oldest_text_over_their_limit() {
+        let mut tree = UndoTree::new(0);
+        let mut text = HeliosRope::new();
+        let big = "x".repeat(MAX_UNDO_FILE_BYTES * 3 / 4);
+        change(&mut tree, &mut text, 0, &big, 10);
+        change(&mut tree, &mut text, 0, &big, 20);
+        change(&mut tree, &mut text, 0, "y", 30);
+
+        let mut decoded = round_trip(&tree, &text);
+        assert_eq!(decoded.size, big.len() + 1);
+        decoded.undo(&mut text);
+        decoded.undo(&mut text);
+        assert_eq!(text.len_chars(), big.len());
+        assert!(decoded.is_at_root());
+        // The tree itself keeps everything
+        assert_eq!(tree.leaves()[0].changes, 3);
+    }
+
+    #[test]
+    fn steps_follow_the_order_changes_were_made() {
+        let (mut tree, mut text) = branched();
+        // `g-` from "ac" goes to "ab", made just before it on the other branch
+        assert_eq!(tree.step_target(1, true), 2);
+        assert_eq!(tree.step_target(2, true), 1);
+        assert_eq!(tree.step_target(9, true), 0);
+        assert_eq!(tree.step_target(1, false), 3);
+
+        tree.go_to(1, &mut text);
+        assert_eq!(tree.step_target(1, false), 2);
+        assert_eq!(tree.step_target(9, false), 3);
+        // No count counts as one
+        assert_eq!(tree.step_target(0, true), 0);
+    }
+
+    #[test]
+    fn time_spans_go_to_the_newest_state_within_them() {
+        let (mut tree, mut text) = branched();
+        // `:earlier 15s` from "ac", made at 30s, goes to "a" at 10s
+        assert_eq!(tree.time_target(Duration::from_secs(15), true), 1);
+        assert_eq!(tree.time_target(Duration::from_secs(10), true), 2);
+        assert_eq!(tree.time_target(Duration::from_secs(5), true), 2);
+        assert_eq!(tree.time_target(Duration::from_secs(3600), true), 0);
+
+        tree.go_to(1, &mut text);
+        // `:later 15s` from 10s stops at 20s, `:later 1h` goes to the newest
+        assert_eq!(tree.time_target(Duration::from_secs(15), false), 2);
+        assert_eq!(tree.time_target(Duration::from_secs(3600), false), 3);
+        assert_eq!(tree.time_target(Duration::from_secs(5), false), 1);
+    }
+
+    #[test]
+    fn undo_distances_parse() {
+        assert_eq!(UndoDistance::parse("10"), Some(UndoDistance::Steps(10)));
+        assert_eq!(
+            UndoDistance::parse("5m"),
+            Some(UndoDistance::Time(Duration::from_secs(300)))
+        );
+        assert_eq!(
+            UndoDistance::parse("1d"),
+            Some(UndoDistance::Time(Duration::from_secs(86400)))
+        );
+        assert_eq!(UndoDistance::parse("0"), None);
+        assert_eq!(UndoDistance::parse("5x"), None);
+        assert_eq!(UndoDistance::parse("m"), None);
+    }
+}
@@ -0,0 +1,76 @@
+/// Number of positions kept in a jump list.
+const JUMP_LIST_SIZE: usize = 100;
+
+/// How a buffer is being looked at.
+///
+/// The Editor works on the view of the focused buffer. Every other buffer keeps
+/// its own, so focusing it again puts everything back where it was.
+///
+/// # Holds:
+/// - The cursor (line, col)
+/// - The first visible line
+/// - The lines of the last selection, for the `'<` and `'>` marks
+/// - The jump list walked with Ctrl-O and Ctrl-I
+#[derive(Clone, Default)]
+pub struct View {
+    pub cursor_line: usize,
+    pub cursor_col: usize,
+    pub scroll_offset: usize,
+    /// First and last line of the last selection
+    pub last_selection: Option<(usize, usize)>,
+    pub jumps: JumpList,
+}
+
+impl View {
+    /// Moves the cursor back inside a buffer of `line_count` lines, which may have
+    /// shrunk since the view was last used.
+    pub fn clamp_to(&mut self, line_count: usize) {
+        let last_line = line_count.saturating_sub(1);
+        self.cursor_line = self.cursor_line.min(last_line);
+        self.scroll_offset = self.scroll_offset.min(self.cursor_line);
+        self.last_selection = self
+            .last_selection
+            .map(|(first, last)| (first.min(last_line), last.min(last_line)));
+    }
+}
+
+/// Positions the cursor jumped away from, with `G`, a search or `:N`.
+#[derive(Clone, Default)]
+pub struct JumpList {
+    jumps: Vec<(usize, usize)>,
+    /// Entry Ctrl-O and Ctrl-I last moved to, `jumps.len()` when not walking the list
+    index: usize,
+}
+
+impl JumpList {
+    /// Records a jump from `position`, dropping an older jump from the same line.
+    pub fn push(&mut self, position: (usize, usize)) {
+        self.jumps.retain(|jump| jump.0 != position.0);
+        self.jumps.push(position);
+        let excess = self.jumps.len().saturating_sub(JUMP_LIST_SIZE);
+        self.jumps.drain(..excess);
+        self.index = self.jumps.len();
+    }
+
+    /// Steps to the next older jump (Ctrl-O).
+    ///
+    /// `current` is recorded first when starting to walk, so Ctrl-I can come back to it.
+    pub fn back(&mut self, current: (usize, usize)) -> Option<(usize, usize)> {
+        if self.index == self.jumps.len() {
+            self.push(current);
+            self.index = self.jumps.len() - 1;
+        }
+        self.index = self.index.checked_sub(1)?;
+        Some(self.jumps[self.index])
+    }
+
+    /// Steps to the next newer jump (Ctrl-I).
+    pub fn forward(&mut self) -> Option<(usize, usize)> {
+        let next = self.index + 1;
+        if next >= self.jumps.len() {
+            return None;
+        }
+        self.index = next;
+        Some(self.jumps[next])
+    }
+}
@@ -0,0 +1,430 @@
+use ratatui::layout::Rect;
+
+use crate::view::View;
+
+/// Smallest height or width a window is resized to, borders included.
+const MIN_WINDOW_SIZE: u16 = 3;
+
+/// How a split lays out its panes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SplitDirection {
+    /// `:split`: panes stacked from top to bottom
+    Horizontal,
+    /// `:vsplit`: panes side by side
+    Vertical,
+}
+
+/// A side to look for a neighbouring window on, for `Ctrl-w h/j/k/l`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Left,
+    Below,
+    Above,
+    Right,
+}
+
+/// A window showing one of the Editor's buffers.
+///
+/// Several windows may show the same buffer, each with its own view.
+#[derive(Clone, Default)]
+pub struct Window {
+    /// Index of the shown buffer
+    pub buffer: usize,
+    /// View kept here while another window is focused
+    pub view: View,
+}
+
+/// A node of the window layout: a single window, or panes split in one direction.
+#[derive(Clone, PartialEq, Debug)]
+enum Pane {
+    Window(usize),
+    /// Each child with its share of the space
+    Split {
+        direction: SplitDirection,
+        children: Vec<(Pane, u16)>,
+    },
+}
+
+/// The windows on screen, arranged as a tree of splits.
+///
+/// Windows are numbered in the order they were opened; closing one renumbers the
+/// ones after it.
+pub struct WindowLayout {
+    root: Pane,
+    windows: Vec<Window>,
+    focused: usize,
+}
+
+impl WindowLayout {
+    /// A layout with a single window showing `buffer`.
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            root: Pane::Window(0),
+            windows: vec![Window {
+                buffer,
+                view: View::default(),
+            }],
+            focused: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    pub fn focused(&self) -> usize {
+        self.focused
+    }
+
+    pub fn set_focused(&mut self, window: usize) {
+        if window < self.windows.len() {
+            self.focused = window;
+        }
+    }
+
+    pub fn window(&self, window: usize) -> &Window {
+        &self.windows[window]
+    }
+
+    pub fn focused_window_mut(&mut self) -> &mut Window {
+        &mut self.windows[self.focused]
+    }
+
+    pub fn windows_mut(&mut self) -> impl Iterator<Item = &mut Window> {
+        self.windows.iter_mut()
+    }
+
+    /// Splits the focused window in two, putting `window` above or left of it.
+    ///
+    /// Returns the number of the new window, which isn't focused yet.
+    pub fn split(&mut self, direction: SplitDirection, window: Window) -> usize {
+        let new = self.windows.len();
+        self.windows.push(window);
+        split_pane(&mut self.root, self.focused, new, direction);
+        new
+    }
+
+    /// Closes `window`, giving its space to its neighbours.
+    ///
+    /// The last window can't be closed. When the focused window is closed, the
+    /// window before it takes the focus. Returns false if nothing was closed.
+    pub fn close(&mut self, window: usize) -> bool {
+        if self.windows.len() == 1 || window >= self.windows.len() {
+            return false;
+        }
+
+        let order = self.order();
+        let position = order.iter().position(|w| *w == window).unwrap_or(0);
+        let next_focus = if window == self.focused {
+            order[if position > 0 { position - 1 } else { 1 }]
+        } else {
+            self.focused
+        };
+
+        remove_pane(&mut self.root, window);
+        renumber(&mut self.root, window);
+        self.windows.remove(window);
+        self.focused = if next_focus > window {
+            next_focus - 1
+        } else {
+            next_focus
+        };
+        true
+    }
+
+    /// Closes every window but the focused one.
+    pub fn only(&mut self) {
+        let window = self.windows.swap_remove(self.focused);
+        self.windows = vec![window];
+        self.root = Pane::Window(0);
+        self.focused = 0;
+    }
+
+    /// Gives every pane of every split the same share of space (`Ctrl-w =`).
+    pub fn equalize(&mut self) {
+        equalize(&mut self.root);
+    }
+
+    /// Window numbers from top left to bottom right, the order `Ctrl-w w` cycles in.
+    pub fn order(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        collect_windows(&self.root, &mut order);
+        order
+    }
+
+    /// The area each window is drawn in when the layout fills `area`.
+    pub fn areas(&self, area: Rect) -> Vec<(usize, Rect)> {
+        let mut areas = Vec::new();
+        collect_areas(&self.root, area, &mut areas);
+        areas
+    }
+
+    /// The window next to the focused one on `side`, preferring the one nearest to
+    /// the `(x, y)` screen position of the cursor.
+    pub fn neighbor(&self, area: Rect, side: Side, cursor: (u16, u16)) -> Option<usize> {
+        let areas = self.areas(area);
+        let (_, from) = *areas.iter().find(|(w, _)| *w == self.focused)?;
+        let (cursor_x, cursor_y) = cursor;
+
+        let adjacent = areas.iter().filter(|(w, to)| {
+            *w != self.focused
+                && match side {
+                    Side::Left => {
+                        to.right() == from.x && overlaps(to.y, to.bottom(), from.y, from.bottom())
+                    }
+                    Side::Right => {
+                        to.x == from.right() && overlaps(to.y, to.bottom(), from.y, from.bottom())
+                    }
+                    Side::Above => {
+                        to.bottom() == from.y && overlaps(to.x, to.right(), from.x, from.right())
+                    }
+                    Side::Below => {
+                        to.y == from.bottom() && overlaps(to.x, to.right(), from.x, from.right())
+                    }
+                }
+        });
+        let distance = |to: &Rect| match side {
+            Side::Left | Side::Right => distance(cursor_y, to.y, to.bottom()),
+            Side::Above | Side::Below => distance(cursor_x, to.x, to.right()),
+        };
+        adjacent.min_by_key(|(_, to)| distance(to)).map(|(w, _)| *w)
+    }
+
+    /// Sets the size of the focused window along `direction`: its height for
+    /// [`SplitDirection::Horizontal`], its width for [`SplitDirection::Vertical`].
+    ///
+    /// `size` gets the current size and returns the wanted one. The space is taken
+    /// from or given to the pane after it (or before it, for the last pane).
+    pub fn resize(&mut self, area: Rect, direction: SplitDirection, size: &dyn Fn(u16) -> u16) {
+        resize_pane(&mut self.root, area, self.focused, direction, size);
+    }
+}
+
+fn split_pane(pane: &mut Pane, target: usize, new: usize, direction: SplitDirection) -> bool {
+    match pane {
+        Pane::Window(w) if *w == target => {
+            *pane = Pane::Split {
+                direction,
+                children: vec![(Pane::Window(new), 1), (Pane::Window(target), 1)],
+            };
+            true
+        }
+        Pane::Window(_) => false,
+        Pane::Split {
+            direction: split_direction,
+            children,
+        } => {
+            let position = children
+                .iter()
+                .position(|(child, _)| *child == Pane::Window(target));
+            // A split in the same direction gets one more pane instead of nesting
+            if *split_direction == direction
+                && let Some(position) = position
+            {
+                if children[position].1 < 2 {
+                    for (_, weight) in children.iter_mut() {
+                        *weight = weight.saturating_mul(2);
+                    }
+                }
+                let weight = children[position].1;
+                let half = weight / 2;
+                children[position].1 = weight - half;
+                children.insert(position, (Pane::Window(new), half.max(1)));
+                return true;
+            }
+            children
+                .iter_mut()
+                .any(|(child, _)| split_pane(child, target, new, direction))
+        }
+    }
+}
+
+fn remove_pane(pane: &mut Pane, target: usize) -> bool {
+    let Pane::Split { children, .. } = pane else {
+        return false;
+    };
+    if let Some(position) = children
+        .iter()
+        .position(|(child, _)| *child == Pane::Window(target))
+    {
+        let (_, weight) = children.remove(position);
+        // The space goes to the pane before, or after for the first one
+        let neighbor = position.saturating_sub(1).min(children.len() - 1);
+        children[neighbor].1 += weight;
+    } else if !children
+        .iter_mut()
+        .any(|(child, _)| remove_pane(child, target))
+    {
+        return false;
+    }
+
+    if children.len() == 1 {
+        let (child, _) = children.remove(0);
+        *pane = child;
+    }
+    true
+}
+
+/// Lowers the numbers of windows after a closed one.
+fn renumber(pane: &mut Pane, closed: usize) {
+    match pane {
+        Pane::Window(w) => {
+            if *w > closed {
+                *w -= 1;
+            }
+        }
+        Pane::Split { children, .. } => {
+            for (child, _) in children {
+                renumber(child, closed);
+            }
+        }
+    }
+}
+
+fn equalize(pane: &mut Pane) {
+    if let Pane::Split { children, .. } = pane {
+        for (child, weight) in children {
+            *weight = 1;
+            equalize(child);
+        }
+    }
+}
+
+fn collect_windows(pane: &Pane, order: &mut Vec<usize>) {
+    match pane {
+        Pane::Window(w) => order.push(*w),
+        Pane::Split { children, .. } => {
+            for (child, _) in children {
+                collect_windows(child, order);
+            }
+        }
+    }
+}
+
+fn collect_areas(pane: &Pane, area: Rect, areas: &mut Vec<(usize, Rect)>) {
+    match pane {
+        Pane::Window(w) => areas.push((*w, area)),
+        Pane::Split {
+            direction,
+            children,
+        } => {
+            for ((child, _), child_area) in
+                children.iter().zip(split_area(area, *direction, children))
+            {
+                collect_areas(child, child_area, areas);
+            }
+        }
+    }
+}
+
+fn contains_window(pane: &Pane, target: usize) -> bool {
+    match pane {
+        Pane::Window(w) => *w == target,
+        Pane::Split { children, .. } => children
+            .iter()
+            .any(|(child, _)| contains_window(child, target)),
+    }
+}
+
+/// Resizes the pane holding `target` in the innermost split along `direction`.
+///
+/// Returns false when no split along `direction` holds the window.
+fn resize_pane(
+    pane: &mut Pane,
+    area: Rect,
+    target: usize,
+    direction: SplitDirection,
+    size: &dyn Fn(u16) -> u16,
+) -> bool {
+    let Pane::Split {
+        direction: split_direction,
+        children,
+    } = pane
+    else {
+        return false;
+    };
+    let Some(position) = children
+        .iter()
+        .position(|(child, _)| contains_window(child, target))
+    else {
+        return false;
+    };
+
+    let areas = split_area(area, *split_direction, children);
+    if resize_pane(
+        &mut children[position].0,
+        areas[position],
+        target,
+        direction,
+        size,
+    ) {
+        return true;
+    }
+    if *split_direction != direction || children.len() < 2 {
+        return false;
+    }
+
+    // Weights become the sizes on screen, so the change is exact
+    let mut sizes: Vec<u16> = areas.iter().map(|area| extent(*area, direction)).collect();
+    let other = if position + 1 < sizes.len() {
+        position + 1
+    } else {
+        position - 1
+    };
+    let available = sizes[position] + sizes[other];
+    let largest = available
+        .saturating_sub(MIN_WINDOW_SIZE)
+        .max(MIN_WINDOW_SIZE);
+    let wanted = size(sizes[position])
+        .clamp(MIN_WINDOW_SIZE, largest)
+        .min(available);
+    sizes[position] = wanted;
+    sizes[other] = available - wanted;
+
+    for ((_, weight), size) in children.iter_mut().zip(sizes) {
+        *weight = size.max(1);
+    }
+    true
+}
+
+/// Divides `area` between the children in proportion to their weights.
+fn split_area(area: Rect, direction: SplitDirection, children: &[(Pane, u16)]) -> Vec<Rect> {
+    let total = extent(area, direction);
+    let weights: u32 = children.iter().map(|(_, w)| u32::from(*w)).sum();
+
+    let mut areas = Vec::with_capacity(children.len());
+    let mut start = 0u16;
+    let mut weight_before = 0u32;
+    for (_, weight) in children {
+        weight_before += u32::from(*weight);
+        let end = (u32::from(total) * weight_before / weights.max(1)) as u16;
+        let length = end - start;
+        areas.push(match direction {
+            SplitDirection::Horizontal => Rect::new(area.x, area.y + start, area.width, length),
+            SplitDirection::Vertical => Rect::new(area.x + start, area.y, length, area.height),
+        });
+        start = end;
+    }
+    areas
+}
+
+fn extent(area: Rect, direction: SplitDirection) -> u16 {
+    match direction {
+        SplitDirection::Horizontal => area.height,
+        SplitDirection::Vertical => area.width,
+    }
+}
+
+fn overlaps(start: u16, end: u16, other_start: u16, other_end: u16) -> bool {
+    start < other_end && other_start < end
+}
+
+fn distance(at: u16, start: u16, end: u16) -> u16 {
+    if at < start {
+        start - at
+    } else if at >= end {
+        at - end + 1
+    } else {
+        0
+    }
+}